@@ -65,7 +65,9 @@ async fn main() {
                 NodeMessage::Dialog(d) => tracing::info!("{d}"),
                 NodeMessage::Warning(e) => tracing::warn!("{e}"),
                 NodeMessage::StateChange(s) => tracing::info!("State update: {s}"),
-                NodeMessage::Block(b) => drop(b),
+                NodeMessage::Transaction(tx) => {
+                    tracing::info!("Relevant transaction: {}", tx.transaction.compute_txid());
+                }
                 NodeMessage::BlocksDisconnected(r) => {
                     let _ = r;
                 }
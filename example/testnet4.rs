@@ -57,7 +57,9 @@ async fn main() {
             match message {
                 NodeMessage::Dialog(d) => tracing::info!("{d}"),
                 NodeMessage::Warning(e) => tracing::warn!("{e}"),
-                NodeMessage::Block(b) => drop(b),
+                NodeMessage::Transaction(tx) => {
+                    tracing::info!("Relevant transaction: {}", tx.transaction.compute_txid());
+                }
                 NodeMessage::BlocksDisconnected(r) => {
                     for dc in r {
                         let warning = format!("Block disconnected {}", dc.height);
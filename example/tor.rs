@@ -74,7 +74,9 @@ async fn main() {
             NodeMessage::Dialog(d) => tracing::info!("{}", d),
             NodeMessage::Warning(e) => tracing::warn!("{}", e),
             NodeMessage::StateChange(_) => (),
-            NodeMessage::Block(b) => drop(b),
+            NodeMessage::Transaction(tx) => {
+                tracing::info!("Relevant transaction: {}", tx.transaction.compute_txid());
+            }
             NodeMessage::BlocksDisconnected(r) => {
                 let _ = r;
             }
@@ -0,0 +1,191 @@
+use bitcoin::{params::Params, script::Builder, Block, Network, ScriptBuf};
+
+use super::error::BlockScanError;
+
+// The start of a coinbase output that commits to the witness data of the block
+const WITNESS_COMMITMENT_MAGIC: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Checks that do not require the filter of the block. The merkle root only commits to the transactions
+// without witness data, so a peer could otherwise strip or replace the witnesses.
+pub(crate) fn check_block(
+    block: &Block,
+    height: u32,
+    network: Network,
+) -> Result<(), BlockScanError> {
+    if !block.check_merkle_root() {
+        return Err(BlockScanError::InvalidMerkleRoot);
+    }
+    if !check_witness(block) {
+        return Err(BlockScanError::InvalidWitnessCommitment);
+    }
+    if height >= Params::new(network).bip34_height && !check_coinbase_height(block, height) {
+        return Err(BlockScanError::InvalidCoinbaseHeight);
+    }
+    Ok(())
+}
+
+// After BIP34 the coinbase script must start with the height, encoded as Bitcoin Core would push it
+fn check_coinbase_height(block: &Block, height: u32) -> bool {
+    let expected = Builder::new().push_int(height as i64).into_script();
+    block
+        .coinbase()
+        .and_then(|coinbase| coinbase.input.first())
+        .map_or(false, |input| {
+            input.script_sig.as_bytes().starts_with(expected.as_bytes())
+        })
+}
+
+// A block with a witness commitment must have the witness reserved value in the coinbase, otherwise
+// the witnesses were removed and the block looks like it has no witness data at all.
+fn check_witness(block: &Block) -> bool {
+    if let Some(coinbase) = block.coinbase() {
+        let has_commitment = coinbase.output.iter().any(|output| {
+            output.script_pubkey.len() >= 38
+                && output.script_pubkey.as_bytes()[0..6] == WITNESS_COMMITMENT_MAGIC
+        });
+        if has_commitment
+            && coinbase
+                .input
+                .first()
+                .map_or(true, |input| input.witness.is_empty())
+        {
+            return false;
+        }
+    }
+    block.check_witness_commitment()
+}
+
+// The output scripts of the block that must be in the BIP-158 filter
+pub(crate) fn filter_scripts(block: &Block) -> impl Iterator<Item = &ScriptBuf> {
+    block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .map(|output| &output.script_pubkey)
+        .filter(|script| !script.is_empty() && !script.is_op_return())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute,
+        bip158::BlockFilter,
+        block::{Header, Version},
+        hashes::Hash,
+        script::Builder,
+        transaction, Amount, Block, BlockHash, CompactTarget, Network, OutPoint, ScriptBuf,
+        Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness,
+    };
+
+    use crate::{chain::error::BlockScanError, filters::Filter};
+
+    use super::{check_block, filter_scripts, WITNESS_COMMITMENT_MAGIC};
+
+    fn coinbase(height: i64, commitment: Option<[u8; 32]>) -> Transaction {
+        let mut output = vec![TxOut {
+            value: Amount::from_sat(50),
+            script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+        }];
+        if let Some(commitment) = commitment {
+            let mut script = WITNESS_COMMITMENT_MAGIC.to_vec();
+            script.extend_from_slice(&commitment);
+            output.push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::from_bytes(script),
+            });
+        }
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(height).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[0u8; 32]]),
+            }],
+            output,
+        }
+    }
+
+    fn block(height: i64) -> Block {
+        let spend = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(bitcoin::Txid::all_zeros(), 1),
+                witness: Witness::from_slice(&[[1u8; 72]]),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(40),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x00, 0x14, 0x02]),
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase(height, None), spend],
+        };
+        let witness_root = block.witness_root().unwrap();
+        let commitment = Block::compute_witness_commitment(&witness_root, &[0u8; 32]);
+        block.txdata[0] = coinbase(height, Some(commitment.to_byte_array()));
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_checks_witness_and_coinbase_height() {
+        let valid = block(10);
+        assert!(check_block(&valid, 10, Network::Signet).is_ok());
+        assert!(matches!(
+            check_block(&valid, 11, Network::Signet),
+            Err(BlockScanError::InvalidCoinbaseHeight)
+        ));
+        // Coinbase heights are not enforced before BIP34 activates
+        assert!(check_block(&valid, 11, Network::Regtest).is_ok());
+        let mut stripped = valid.clone();
+        for tx in stripped.txdata.iter_mut() {
+            for input in tx.input.iter_mut() {
+                input.witness.clear();
+            }
+        }
+        assert!(matches!(
+            check_block(&stripped, 10, Network::Signet),
+            Err(BlockScanError::InvalidWitnessCommitment)
+        ));
+        let mut mutated = valid.clone();
+        mutated.txdata[1].input[0].witness = Witness::from_slice(&[[2u8; 72]]);
+        assert!(matches!(
+            check_block(&mutated, 10, Network::Signet),
+            Err(BlockScanError::InvalidWitnessCommitment)
+        ));
+        let mut mutated = valid;
+        mutated.txdata[1].output[0].value = Amount::from_sat(41);
+        assert!(matches!(
+            check_block(&mutated, 10, Network::Signet),
+            Err(BlockScanError::InvalidMerkleRoot)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_block_scripts_match_filter() {
+        let block_10 = block(10);
+        let prevout = ScriptBuf::from_bytes(vec![0x51]);
+        let block_filter =
+            BlockFilter::new_script_filter(&block_10, |_| Ok(prevout.clone())).unwrap();
+        let mut filter = Filter::new(block_filter.content, block_10.block_hash());
+        assert!(filter
+            .contains_all(filter_scripts(&block_10))
+            .await
+            .unwrap());
+        let mut mutated = block_10.clone();
+        mutated.txdata[1].output[0].script_pubkey = ScriptBuf::from_bytes(vec![0x00, 0x14, 0x03]);
+        assert!(!filter.contains_all(filter_scripts(&mutated)).await.unwrap());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{collections::HashMap, time::Duration};
+
+use bitcoin::{Block, BlockHash};
+use tokio::time::Instant;
+
+use crate::{
+    core::{error::FetchBlockError, messages::BlockSender},
+    IndexedBlock,
+};
+
+const FETCH_TIMEOUT_SECS: u64 = 60;
+// Blocks fetched by the client are kept in memory until they arrive, so only a few may be pending
+const MAX_PENDING_FETCHES: usize = 10;
+
+#[derive(Debug)]
+struct PendingFetch {
+    oneshot: BlockSender,
+    deadline: Instant,
+}
+
+// Blocks the client asked for by hash, answered only to the requester.
+#[derive(Debug)]
+pub(crate) struct BlockFetches {
+    pending: HashMap<BlockHash, Vec<PendingFetch>>,
+    timeout: Duration,
+}
+
+impl BlockFetches {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout: Duration::from_secs(FETCH_TIMEOUT_SECS),
+        }
+    }
+
+    // Wait for a block, or give the sender back if there are too many blocks pending
+    pub(crate) fn add(&mut self, hash: BlockHash, oneshot: BlockSender) -> Result<(), BlockSender> {
+        if self.pending.values().map(Vec::len).sum::<usize>() >= MAX_PENDING_FETCHES {
+            return Err(oneshot);
+        }
+        self.pending.entry(hash).or_default().push(PendingFetch {
+            oneshot,
+            deadline: Instant::now() + self.timeout,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn contains(&self, hash: &BlockHash) -> bool {
+        self.pending.contains_key(hash)
+    }
+
+    // Send the block to everyone that asked for it, returning if anyone did
+    pub(crate) fn answer(&mut self, block: &Block, height: u32) -> bool {
+        match self.pending.remove(&block.block_hash()) {
+            Some(fetches) => {
+                for fetch in fetches {
+                    let _ = fetch
+                        .oneshot
+                        .send(Ok(IndexedBlock::new(height, block.clone())));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Give up on the blocks that took too long, returning the hashes nobody is waiting for anymore
+    pub(crate) fn expire(&mut self) -> Vec<BlockHash> {
+        let now = Instant::now();
+        for fetches in self.pending.values_mut() {
+            let (expired, waiting): (Vec<PendingFetch>, Vec<PendingFetch>) = fetches
+                .drain(..)
+                .partition(|fetch| fetch.deadline < now || fetch.oneshot.is_closed());
+            *fetches = waiting;
+            for fetch in expired {
+                let _ = fetch.oneshot.send(Err(FetchBlockError::Timeout));
+            }
+        }
+        let expired: Vec<BlockHash> = self
+            .pending
+            .iter()
+            .filter(|(_, fetches)| fetches.is_empty())
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.pending.remove(hash);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{block::Header, consensus::deserialize, hashes::Hash, Block, BlockHash};
+    use tokio::sync::oneshot;
+
+    use crate::core::error::FetchBlockError;
+
+    use super::{BlockFetches, MAX_PENDING_FETCHES};
+
+    #[tokio::test]
+    async fn test_fetches_are_answered_and_limited() {
+        let header: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let block = Block {
+            header,
+            txdata: Vec::new(),
+        };
+        let mut fetches = BlockFetches::new();
+        let (tx, rx) = oneshot::channel();
+        assert!(fetches.add(block.block_hash(), tx).is_ok());
+        assert!(fetches.contains(&block.block_hash()));
+        let mut receivers = Vec::new();
+        for _ in 1..MAX_PENDING_FETCHES {
+            let (tx, rx) = oneshot::channel();
+            assert!(fetches.add(BlockHash::all_zeros(), tx).is_ok());
+            receivers.push(rx);
+        }
+        let (tx, _) = oneshot::channel();
+        assert!(fetches.add(block.block_hash(), tx).is_err());
+        assert!(!fetches.answer(
+            &Block {
+                header: Header { nonce: 1, ..header },
+                txdata: Vec::new()
+            },
+            8
+        ));
+        assert!(fetches.answer(&block, 8));
+        assert!(!fetches.contains(&block.block_hash()));
+        let indexed_block = rx.await.unwrap().unwrap();
+        assert_eq!(indexed_block.height, 8);
+        assert_eq!(indexed_block.block.block_hash(), block.block_hash());
+        // Requests whose receiver was dropped are forgotten
+        drop(receivers);
+        assert_eq!(fetches.expire(), vec![BlockHash::all_zeros()]);
+        let (tx, rx) = oneshot::channel();
+        fetches.timeout = std::time::Duration::ZERO;
+        assert!(fetches.add(block.block_hash(), tx).is_ok());
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        assert_eq!(fetches.expire(), vec![block.block_hash()]);
+        assert!(matches!(rx.await.unwrap(), Err(FetchBlockError::Timeout)));
+    }
+}
@@ -1,77 +1,208 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
 use bitcoin::BlockHash;
 use tokio::time::Instant;
 
-const SPAM_LIMIT: u64 = 5;
+type PeerId = u32;
 
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+// Blocks may be large, so each peer only has a few requests at a time
+const MAX_REQUESTS_PER_PEER: usize = 4;
+// Peers that fail this many block requests in a row are disconnected
+const MAX_FAILURES: u32 = 3;
+// The misbehaviour score of a peer that keeps failing block requests, out of a ban threshold of 100
+pub(crate) const FAILED_BLOCKS_PENALTY: u32 = 10;
+
+// A block requested from a single peer
+#[derive(Debug)]
+struct Request {
+    peer: PeerId,
+    deadline: Instant,
+}
+
+// Blocks with matches in the filters, downloaded from many peers at once. Blocks that are not found or
+// not received in time are requested again from a different peer. Lower blocks are requested first.
 #[derive(Debug)]
 pub(crate) struct BlockQueue {
     queue: VecDeque<BlockHash>,
-    want: Option<BlockHash>,
-    last_req: Instant,
+    in_flight: HashMap<BlockHash, Request>,
+    // The height of every block that is queued or in flight
+    heights: HashMap<BlockHash, u32>,
+    // The peers that already failed to send a block
+    tried: HashMap<BlockHash, HashSet<PeerId>>,
+    failures: HashMap<PeerId, u32>,
+    timeout: Duration,
 }
 
 impl BlockQueue {
     pub(crate) fn new() -> Self {
         Self {
             queue: VecDeque::new(),
-            want: None,
-            last_req: Instant::now(),
+            in_flight: HashMap::new(),
+            heights: HashMap::new(),
+            tried: HashMap::new(),
+            failures: HashMap::new(),
+            timeout: Duration::from_secs(REQUEST_TIMEOUT_SECS),
         }
     }
 
-    pub(crate) fn add(&mut self, block: BlockHash) {
+    pub(crate) fn add(&mut self, block: BlockHash, height: u32) {
         if !self.contains(&block) {
-            self.queue.push_front(block)
+            self.queue.push_front(block);
+            self.heights.insert(block, height);
         }
     }
 
     pub(crate) fn contains(&mut self, block: &BlockHash) -> bool {
-        self.queue.contains(block) || self.want.map_or(false, |hash| hash.eq(block))
-    }
-
-    pub(crate) fn pop(&mut self) -> Option<BlockHash> {
-        match self.want {
-            Some(block) => {
-                if Instant::now().duration_since(self.last_req).as_secs() < SPAM_LIMIT {
-                    None
-                } else {
-                    self.last_req = Instant::now();
-                    Some(block)
-                }
-            }
-            None => {
-                self.last_req = Instant::now();
-                let block = self.queue.pop_back();
-                self.want = block;
-                block
-            }
+        self.queue.contains(block) || self.in_flight.contains_key(block)
+    }
+
+    // The next block to request from this peer, if the peer may take more requests
+    pub(crate) fn pop(&mut self, peer: PeerId) -> Option<BlockHash> {
+        let requests = self
+            .in_flight
+            .values()
+            .filter(|request| request.peer.eq(&peer))
+            .count();
+        if requests >= MAX_REQUESTS_PER_PEER {
+            return None;
+        }
+        let index = self
+            .queue
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, block)| {
+                self.tried
+                    .get(*block)
+                    .map_or(true, |peers| !peers.contains(&peer))
+            })
+            .min_by_key(|(_, block)| self.heights.get(*block).copied().unwrap_or(u32::MAX))
+            .map(|(index, _)| index)?;
+        let block = self.queue.remove(index)?;
+        self.in_flight.insert(
+            block,
+            Request {
+                peer,
+                deadline: Instant::now() + self.timeout,
+            },
+        );
+        Some(block)
+    }
+
+    #[cfg(test)]
+    fn need(&self, block: &BlockHash) -> bool {
+        self.in_flight.contains_key(block)
+    }
+
+    // The block is waiting on this peer
+    pub(crate) fn requested_from(&self, peer: PeerId, block: &BlockHash) -> bool {
+        self.in_flight
+            .get(block)
+            .map_or(false, |request| request.peer.eq(&peer))
+    }
+
+    // The block was received from the peer it was requested from
+    pub(crate) fn receive(&mut self, peer: PeerId, hash: &BlockHash) {
+        if !self.requested_from(peer, hash) {
+            return;
         }
+        self.in_flight.remove(hash);
+        self.failures.remove(&peer);
+        self.heights.remove(hash);
+        self.tried.remove(hash);
+    }
+
+    // The lowest block that was not received yet
+    pub(crate) fn lowest_height(&self) -> Option<u32> {
+        self.heights.values().min().copied()
     }
 
-    pub(crate) fn need(&self, block: &BlockHash) -> bool {
-        self.want.map_or(false, |hash| hash.eq(block))
+    // The peer does not have the block, so it should be requested from another peer
+    pub(crate) fn not_found(&mut self, peer: PeerId, hash: &BlockHash) {
+        if self
+            .in_flight
+            .get(hash)
+            .map_or(false, |request| request.peer.eq(&peer))
+        {
+            self.retry(*hash);
+        }
     }
 
-    pub(crate) fn receive(&mut self, hash: &BlockHash) {
-        if let Some(want) = self.want {
-            if want.eq(hash) {
-                self.want = None;
+    // Request the blocks again of peers that took too long to respond, returning the peers that timed out
+    pub(crate) fn expire(&mut self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let expired: Vec<(BlockHash, PeerId)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.deadline < now)
+            .map(|(block, request)| (*block, request.peer))
+            .collect();
+        for (block, _) in expired.iter() {
+            self.retry(*block);
+        }
+        let mut peers: Vec<PeerId> = expired.into_iter().map(|(_, peer)| peer).collect();
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+
+    // Request the blocks of peers that are no longer connected from the remaining peers
+    pub(crate) fn retain_peers(&mut self, peers: &[PeerId]) {
+        let gone: Vec<BlockHash> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| !peers.contains(&request.peer))
+            .map(|(block, _)| *block)
+            .collect();
+        for block in gone {
+            if let Some(request) = self.in_flight.remove(&block) {
+                self.tried.entry(block).or_default().insert(request.peer);
+                self.queue.push_back(block);
+            }
+        }
+        self.failures.retain(|peer, _| peers.contains(peer));
+        // Every connected peer failed to send the block, so any peer may try again
+        for tried in self.tried.values_mut() {
+            if peers.iter().all(|peer| tried.contains(peer)) {
+                tried.clear();
             }
         }
     }
 
+    // Peers that failed too many block requests in a row
+    pub(crate) fn failing_peers(&mut self) -> Vec<PeerId> {
+        let failing: Vec<PeerId> = self
+            .failures
+            .iter()
+            .filter(|(_, failures)| **failures >= MAX_FAILURES)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in failing.iter() {
+            self.failures.remove(peer);
+        }
+        failing
+    }
+
     pub(crate) fn complete(&self) -> bool {
-        self.want.is_none() && self.queue.is_empty()
+        self.in_flight.is_empty() && self.queue.is_empty()
     }
 
     pub(crate) fn remove(&mut self, hashes: &[BlockHash]) {
         self.queue.retain(|hash| !hashes.contains(hash));
-        if let Some(want) = self.want {
-            if hashes.contains(&want) {
-                self.want = None;
-            }
+        self.in_flight.retain(|hash, _| !hashes.contains(hash));
+        self.heights.retain(|hash, _| !hashes.contains(hash));
+        self.tried.retain(|hash, _| !hashes.contains(hash));
+    }
+
+    fn retry(&mut self, block: BlockHash) {
+        if let Some(request) = self.in_flight.remove(&block) {
+            *self.failures.entry(request.peer).or_default() += 1;
+            self.tried.entry(block).or_default().insert(request.peer);
+            self.queue.push_back(block);
         }
     }
 }
@@ -80,6 +211,8 @@ impl BlockQueue {
 mod test {
     use std::str::FromStr;
 
+    use bitcoin::hashes::Hash;
+
     use super::*;
 
     #[test]
@@ -94,79 +227,132 @@ mod test {
             BlockHash::from_str("000000254633c01d43534d80981c3d1e0f4f3541cce2af68084e7631832d2572")
                 .unwrap();
         let mut queue = BlockQueue::new();
-        queue.add(hash_1);
-        queue.add(hash_2);
-        queue.add(hash_3);
-        queue.add(hash_1);
+        queue.add(hash_1, 1);
+        queue.add(hash_2, 2);
+        queue.add(hash_3, 3);
+        queue.add(hash_1, 1);
         assert_eq!(queue.queue.len(), 3);
-        assert_eq!(queue.pop(), Some(hash_1));
-        assert_eq!(queue.pop(), None);
-        assert_eq!(queue.want, Some(hash_1));
+        assert_eq!(queue.pop(1), Some(hash_1));
         assert!(queue.need(&hash_1));
-        queue.receive(&hash_1);
-        assert_eq!(queue.want, None);
-        assert_eq!(queue.pop(), Some(hash_2));
-        assert_eq!(queue.want, Some(hash_2));
+        // Blocks are requested from many peers at once
+        assert_eq!(queue.pop(2), Some(hash_2));
         assert!(queue.need(&hash_2));
-        queue.receive(&hash_2);
-        assert_eq!(queue.pop(), Some(hash_3));
+        // Only the peer the block was requested from may send it
+        queue.receive(2, &hash_1);
+        assert!(queue.need(&hash_1));
+        queue.receive(1, &hash_1);
+        assert!(!queue.need(&hash_1));
+        assert_eq!(queue.lowest_height(), Some(2));
+        assert_eq!(queue.pop(1), Some(hash_3));
         assert!(!queue.complete());
-        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.pop(1), None);
         assert!(!queue.complete());
         assert!(queue.need(&hash_3));
-        queue.receive(&hash_2);
+        queue.receive(2, &hash_2);
         assert!(queue.need(&hash_3));
         assert!(!queue.complete());
-        queue.receive(&hash_3);
+        queue.receive(1, &hash_3);
         assert!(queue.complete());
         assert!(!queue.need(&hash_3));
-        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.pop(1), None);
+    }
+
+    #[test]
+    fn test_lower_blocks_are_requested_first() {
+        let mut queue = BlockQueue::new();
+        queue.add(BlockHash::from_byte_array([3; 32]), 30);
+        queue.add(BlockHash::from_byte_array([1; 32]), 10);
+        queue.add(BlockHash::from_byte_array([2; 32]), 20);
+        assert_eq!(queue.pop(1), Some(BlockHash::from_byte_array([1; 32])));
+        assert_eq!(queue.pop(2), Some(BlockHash::from_byte_array([2; 32])));
+        assert_eq!(queue.lowest_height(), Some(10));
+        assert_eq!(queue.pop(1), Some(BlockHash::from_byte_array([3; 32])));
+    }
+
+    #[test]
+    fn test_requests_per_peer_are_limited() {
+        let mut queue = BlockQueue::new();
+        for byte in 0..=MAX_REQUESTS_PER_PEER as u8 {
+            queue.add(BlockHash::from_byte_array([byte; 32]), byte as u32);
+        }
+        for _ in 0..MAX_REQUESTS_PER_PEER {
+            assert!(queue.pop(1).is_some());
+        }
+        assert_eq!(queue.pop(1), None);
+        assert_eq!(
+            queue.pop(2),
+            Some(BlockHash::from_byte_array(
+                [MAX_REQUESTS_PER_PEER as u8; 32]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_not_found_retries_other_peer() {
+        let hash_1 =
+            BlockHash::from_str("0000007a93b953158a12aef32eb9cc4366eb1eea5892fb04afbeec421c29319d")
+                .unwrap();
+        let mut queue = BlockQueue::new();
+        queue.add(hash_1, 1);
+        assert_eq!(queue.pop(1), Some(hash_1));
+        // Only the peer the block was requested from may say it was not found
+        queue.not_found(2, &hash_1);
+        assert!(queue.need(&hash_1));
+        queue.not_found(1, &hash_1);
+        assert!(!queue.need(&hash_1));
+        assert!(queue.contains(&hash_1));
+        assert_eq!(queue.pop(1), None);
+        assert_eq!(queue.pop(2), Some(hash_1));
+        // Once every peer has failed, any peer may try again
+        queue.not_found(2, &hash_1);
+        queue.retain_peers(&[1, 2]);
+        assert_eq!(queue.pop(1), Some(hash_1));
+        queue.receive(1, &hash_1);
+        assert!(queue.complete());
+    }
+
+    #[test]
+    fn test_failing_peers() {
+        let mut queue = BlockQueue::new();
+        for byte in 0..MAX_FAILURES as u8 {
+            let hash = BlockHash::from_byte_array([byte; 32]);
+            queue.add(hash, byte as u32);
+            assert_eq!(queue.pop(1), Some(hash));
+            assert!(queue.failing_peers().is_empty());
+            queue.not_found(1, &hash);
+        }
+        assert_eq!(queue.failing_peers(), vec![1]);
+        assert!(queue.failing_peers().is_empty());
+        // Blocks of disconnected peers are requested again
+        let hash = BlockHash::from_byte_array([0; 32]);
+        assert_eq!(queue.pop(2), Some(hash));
+        queue.retain_peers(&[1]);
+        assert!(!queue.need(&hash));
+        assert_eq!(queue.pop(1), Some(hash));
     }
 
     #[tokio::test]
     #[ignore = "wastes time"]
     async fn test_laggy_peer() {
-        use std::time::Duration;
         let hash_1 =
             BlockHash::from_str("0000007a93b953158a12aef32eb9cc4366eb1eea5892fb04afbeec421c29319d")
                 .unwrap();
         let hash_2 =
             BlockHash::from_str("0000009e41d363546c5126c045bdef80e863324ac87f2bec88927a53662f6c0b")
                 .unwrap();
-        let hash_3 =
-            BlockHash::from_str("000000254633c01d43534d80981c3d1e0f4f3541cce2af68084e7631832d2572")
-                .unwrap();
         let mut queue = BlockQueue::new();
-        queue.add(hash_1);
-        queue.add(hash_2);
-        queue.add(hash_3);
-        queue.add(hash_1);
-        assert_eq!(queue.queue.len(), 3);
-        assert_eq!(queue.pop(), Some(hash_1));
-        tokio::time::sleep(Duration::from_secs(6)).await;
-        assert_eq!(queue.pop(), Some(hash_1));
-        assert_eq!(queue.want, Some(hash_1));
-        assert!(queue.need(&hash_1));
-        queue.receive(&hash_1);
+        queue.add(hash_1, 1);
+        queue.add(hash_2, 2);
+        assert_eq!(queue.pop(1), Some(hash_1));
+        assert!(queue.expire().is_empty());
+        tokio::time::sleep(Duration::from_secs(REQUEST_TIMEOUT_SECS + 1)).await;
+        assert_eq!(queue.expire(), vec![1]);
         assert!(!queue.need(&hash_1));
-        assert_eq!(queue.want, None);
-        assert_eq!(queue.pop(), Some(hash_2));
-        assert_eq!(queue.want, Some(hash_2));
-        assert!(queue.need(&hash_2));
-        queue.receive(&hash_2);
-        assert_eq!(queue.pop(), Some(hash_3));
-        assert!(!queue.complete());
-        assert_eq!(queue.pop(), None);
-        assert!(!queue.complete());
-        queue.receive(&hash_2);
-        assert!(!queue.complete());
-        tokio::time::sleep(Duration::from_secs(6)).await;
-        assert_eq!(queue.pop(), Some(hash_3));
-        assert!(queue.need(&hash_3));
-        assert!(!queue.complete());
-        queue.receive(&hash_3);
+        assert_eq!(queue.pop(1), Some(hash_2));
+        assert_eq!(queue.pop(2), Some(hash_1));
+        queue.receive(2, &hash_1);
+        queue.receive(1, &hash_2);
         assert!(queue.complete());
-        assert_eq!(queue.pop(), None);
     }
 
     #[test]
@@ -181,18 +367,18 @@ mod test {
             BlockHash::from_str("000000254633c01d43534d80981c3d1e0f4f3541cce2af68084e7631832d2572")
                 .unwrap();
         let mut queue = BlockQueue::new();
-        queue.add(hash_1);
-        queue.add(hash_2);
-        queue.add(hash_3);
-        queue.add(hash_1);
+        queue.add(hash_1, 1);
+        queue.add(hash_2, 2);
+        queue.add(hash_3, 3);
+        queue.add(hash_1, 1);
         assert_eq!(queue.queue.len(), 3);
-        assert_eq!(queue.pop(), Some(hash_1));
-        assert_eq!(queue.want, Some(hash_1));
+        assert_eq!(queue.pop(1), Some(hash_1));
+        assert!(queue.need(&hash_1));
         queue.remove(&[hash_1]);
         assert!(!queue.need(&hash_1));
-        assert_eq!(queue.want, None);
         queue.remove(&[hash_2]);
         assert_eq!(queue.queue.len(), 1);
-        assert_eq!(queue.pop(), Some(hash_3));
+        assert_eq!(queue.lowest_height(), Some(3));
+        assert_eq!(queue.pop(1), Some(hash_3));
     }
 }
@@ -1,21 +1,28 @@
 extern crate alloc;
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     sync::Arc,
 };
 
 use bitcoin::{
     block::Header,
+    merkle_tree::MerkleBlock,
     p2p::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
-    Block, BlockHash, CompactTarget, Network, ScriptBuf, TxOut, Work,
+    Block, BlockHash, CompactTarget, FilterHash, FilterHeader, Network, OutPoint, ScriptBuf,
+    Transaction, Txid, Work,
 };
 use tokio::sync::Mutex;
 
 use super::{
+    block_check::{check_block, filter_scripts},
+    block_fetch::BlockFetches,
     block_queue::BlockQueue,
     checkpoints::{HeaderCheckpoint, HeaderCheckpoints},
     error::{BlockScanError, HeaderSyncError},
     header_chain::HeaderChain,
+    keychain::{Keychain, KeychainMatch, KeychainTracker},
+    mempool::Mempool,
+    versionbits::{Deployment, DeploymentTracker, DeploymentUpdate},
 };
 #[cfg(feature = "filter-control")]
 use crate::IndexedFilter;
@@ -23,48 +30,88 @@ use crate::{
     chain::header_batch::HeadersBatch,
     core::{
         dialog::Dialog,
-        error::HeaderPersistenceError,
-        messages::{NodeMessage, Warning},
+        error::{FetchBlockError, HeaderPersistenceError},
+        messages::{
+            BlockRequest, CatchUp, DeepReorg, NodeMessage, SpentOutPoint, UnconfirmedTransaction,
+            Warning,
+        },
+        ReorgConfig, ReorgPolicy,
     },
-    db::traits::HeaderStore,
+    db::{traits::HeaderStore, PersistedFilter},
     filters::{
         cfheader_batch::CFHeaderBatch,
         cfheader_chain::{AppendAttempt, CFHeaderChain, QueuedCFHeader},
         error::{CFHeaderSyncError, CFilterSyncError},
+        filter_cache::FilterCache,
         filter_chain::FilterChain,
-        Filter, CF_HEADER_BATCH_SIZE, FILTER_BATCH_SIZE,
+        filter_scheduler::FilterScheduler,
+        rescan::Rescan,
+        Filter, CF_HEADER_BATCH_SIZE,
     },
-    prelude::MEDIAN_TIME_PAST,
-    IndexedBlock,
+    prelude::{Median, MEDIAN_TIME_PAST},
+    IndexedBlock, IndexedTransaction,
 };
 
-const MAX_REORG_DEPTH: u32 = 5_000;
-const REORG_LOOKBACK: u32 = 7;
 const MAX_HEADER_SIZE: usize = 20_000;
 const FILTER_BASIC: u8 = 0x00;
+// The most headers, filter headers and filters a peer may ask for at once, from BIP 157
+const MAX_SERVED_HEADERS: usize = 2_000;
+pub(crate) const MAX_SERVED_FILTER_HEADERS: u32 = 2_000;
+pub(crate) const MAX_SERVED_FILTERS: u32 = 1_000;
+// Bitcoin Core sends at most this many locators
+const MAX_LOCATORS: usize = 101;
+// The most outputs paying to watched scripts that are remembered to recognize their spends
+const MAX_RECEIVED_OUTPOINTS: usize = 50_000;
 
 #[derive(Debug)]
 pub(crate) struct Chain<H: HeaderStore> {
     header_chain: HeaderChain,
     cf_header_chain: CFHeaderChain,
     filter_chain: FilterChain,
+    filter_scheduler: FilterScheduler,
+    filter_cache: FilterCache,
+    persist_filters: bool,
+    rescan: Option<Rescan>,
     checkpoints: HeaderCheckpoints,
     network: Network,
     db: Arc<Mutex<H>>,
     best_known_height: Option<u32>,
     scripts: HashSet<ScriptBuf>,
+    outpoints: HashMap<OutPoint, ScriptBuf>,
+    // Unspent outputs to watched scripts, with the height they were received at
+    received: HashMap<OutPoint, (ScriptBuf, u32)>,
+    queued_filters: HashMap<BlockHash, Filter>,
+    deliver_blocks: bool,
     block_queue: BlockQueue,
+    block_fetches: BlockFetches,
+    // Received blocks waiting on lower blocks, so blocks are scanned in order of height
+    downloaded: BTreeMap<u32, Block>,
+    #[cfg(feature = "filter-control")]
+    requested_blocks: HashSet<BlockHash>,
+    reorg_config: ReorgConfig,
+    pending_reorg: Option<Vec<Header>>,
+    rejected_reorgs: HashSet<BlockHash>,
+    deployments: DeploymentTracker,
+    keychains: KeychainTracker,
+    mempool: Mempool,
     dialog: Dialog,
 }
 
 #[allow(dead_code)]
 impl<H: HeaderStore> Chain<H> {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         network: Network,
-        scripts: HashSet<ScriptBuf>,
+        mut scripts: HashSet<ScriptBuf>,
+        keychains: Vec<Keychain>,
         anchor: HeaderCheckpoint,
         filter_startpoint_opt: Option<u32>,
         checkpoints: HeaderCheckpoints,
+        reorg_config: ReorgConfig,
+        deployments: Vec<Deployment>,
+        deliver_blocks: bool,
+        filter_cache_size: usize,
+        persist_filters: bool,
         dialog: Dialog,
         db: H,
         quorum_required: usize,
@@ -74,6 +121,11 @@ impl<H: HeaderStore> Chain<H> {
         // Use filter startpoint, or the checkpoint height
         let filter_anchor = filter_startpoint_opt.unwrap_or(anchor.height);
         let filter_chain = FilterChain::new(filter_anchor);
+        let filter_scheduler = FilterScheduler::new(filter_anchor);
+        let mut keychain_tracker = KeychainTracker::new();
+        for keychain in keychains {
+            scripts.extend(keychain_tracker.add(keychain));
+        }
         Chain {
             header_chain,
             checkpoints,
@@ -81,9 +133,27 @@ impl<H: HeaderStore> Chain<H> {
             db: Arc::new(Mutex::new(db)),
             cf_header_chain,
             filter_chain,
+            filter_scheduler,
+            filter_cache: FilterCache::new(filter_cache_size),
+            persist_filters,
+            rescan: None,
             best_known_height: None,
             scripts,
+            outpoints: HashMap::new(),
+            received: HashMap::new(),
+            queued_filters: HashMap::new(),
+            deliver_blocks,
             block_queue: BlockQueue::new(),
+            block_fetches: BlockFetches::new(),
+            downloaded: BTreeMap::new(),
+            #[cfg(feature = "filter-control")]
+            requested_blocks: HashSet::new(),
+            reorg_config,
+            pending_reorg: None,
+            rejected_reorgs: HashSet::new(),
+            deployments: DeploymentTracker::new(network, deployments, anchor.height),
+            keychains: keychain_tracker,
+            mempool: Mempool::new(),
             dialog,
         }
     }
@@ -221,8 +291,8 @@ impl<H: HeaderStore> Chain<H> {
             // We should try to catch any reorgs if we are on a fresh start.
             // The database may have a header that is useful to the remote node
             // that is not currently in memory.
-            if self.header_chain.inner_len() < REORG_LOOKBACK as usize {
-                let older_locator = self.height().saturating_sub(REORG_LOOKBACK);
+            if self.header_chain.inner_len() < self.reorg_config.lookback as usize {
+                let older_locator = self.height().saturating_sub(self.reorg_config.lookback);
                 let mut db_lock = self.db.lock().await;
                 let hash = db_lock.hash_at(older_locator).await;
                 if let Ok(Some(locator)) = hash {
@@ -303,6 +373,7 @@ impl<H: HeaderStore> Chain<H> {
             })
         }
         self.header_chain.set_headers(loaded_headers);
+        self.update_deployments().await;
         Ok(())
     }
 
@@ -317,6 +388,10 @@ impl<H: HeaderStore> Chain<H> {
     // Sync the chain with headers from a peer, adjusting to reorgs if needed
     pub(crate) async fn sync_chain(&mut self, message: Vec<Header>) -> Result<(), HeaderSyncError> {
         let header_batch = HeadersBatch::new(message).map_err(|_| HeaderSyncError::EmptyMessage)?;
+        // The client has not decided on a deep reorganization yet, so only extensions of our tip are accepted
+        if self.pending_reorg.is_some() && self.tip().ne(&header_batch.first().prev_blockhash) {
+            return Err(HeaderSyncError::PendingReorg);
+        }
         // If our chain already has the last header in the message there is no new information
         if self.contains_hash(header_batch.last().block_hash()) {
             return Ok(());
@@ -333,6 +408,7 @@ impl<H: HeaderStore> Chain<H> {
                 if self.tip().eq(&header_batch.first().prev_blockhash) {
                     self.audit_difficulty(self.height(), &header_batch).await?;
                     self.header_chain.extend(header_batch.inner());
+                    self.update_deployments().await;
                     return Ok(());
                 }
                 // We see if we have this previous hash in the database, and reload our
@@ -345,6 +421,7 @@ impl<H: HeaderStore> Chain<H> {
                 self.evaluate_fork(&header_batch).await?;
             }
         };
+        self.update_deployments().await;
         self.manage_memory().await;
         Ok(())
     }
@@ -424,6 +501,68 @@ impl<H: HeaderStore> Chain<H> {
         Ok(())
     }
 
+    // Evaluate the state of the tracked deployments for every period of blocks we have not seen yet.
+    // If the header store fails to load a header, the period is evaluated again on the next update.
+    async fn update_deployments(&mut self) {
+        if self.deployments.is_empty() {
+            return;
+        }
+        let period = self.deployments.period();
+        let mut boundary = self.deployments.next_boundary();
+        while boundary <= self.height() {
+            let median_time_past = match self.median_time_past_at(boundary).await {
+                Ok(median_time_past) => median_time_past,
+                Err(_) => return,
+            };
+            let mut headers = Vec::new();
+            if self.deployments.needs_signals() {
+                for height in (boundary + 1 - period)..=boundary {
+                    match self.fetch_header(height).await {
+                        Ok(Some(header)) => headers.push(header),
+                        Ok(None) => break,
+                        Err(_) => return,
+                    }
+                }
+            }
+            for update in self.deployments.apply(boundary, median_time_past, &headers) {
+                self.dialog
+                    .send_dialog(format!(
+                        "Deployment {} is {} at height {}",
+                        update.name, update.state, update.height
+                    ))
+                    .await;
+                self.dialog
+                    .send_data(NodeMessage::DeploymentStateChange(update))
+                    .await;
+            }
+            boundary += period;
+        }
+    }
+
+    // The median time of the eleven blocks ending at this height, if these blocks are known
+    async fn median_time_past_at(
+        &mut self,
+        height: u32,
+    ) -> Result<Option<u32>, HeaderPersistenceError<H::Error>> {
+        let start = match height.checked_sub(MEDIAN_TIME_PAST as u32 - 1) {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let mut times = Vec::with_capacity(MEDIAN_TIME_PAST);
+        for past in start..=height {
+            match self.fetch_header(past).await? {
+                Some(header) => times.push(header.time),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(times.median()))
+    }
+
+    // The current state of the tracked deployments
+    pub(crate) fn deployment_states(&self) -> Vec<DeploymentUpdate> {
+        self.deployments.states()
+    }
+
     // Audit the difficulty adjustment of the blocks we received
 
     // This function draws from the neutrino implemention, where even if a fork is valid
@@ -438,6 +577,12 @@ impl<H: HeaderStore> Chain<H> {
             .filter(|header| !self.contains_header(header))
             .copied()
             .collect();
+        // The client chose to stay on our chain instead of this fork
+        if let Some(first) = uncommon.first() {
+            if self.rejected_reorgs.contains(&first.block_hash()) {
+                return Err(HeaderSyncError::RejectedReorg);
+            }
+        }
         let challenge_chainwork = uncommon
             .iter()
             .map(|header| header.work())
@@ -454,20 +599,41 @@ impl<H: HeaderStore> Chain<H> {
         if let Some(stem) = stem_position {
             let current_chainwork = self.header_chain.chainwork_after_height(stem);
             if current_chainwork.lt(&challenge_chainwork) {
-                self.dialog.send_dialog("Valid reorganization found").await;
-                let reorged = self.header_chain.extend(&uncommon);
-                let removed_hashes = &reorged
-                    .iter()
-                    .map(|disconnect| disconnect.header.block_hash())
-                    .collect::<Vec<BlockHash>>();
-                self.clear_compact_filter_queue();
-                self.cf_header_chain.remove(removed_hashes);
-                self.filter_chain.remove(removed_hashes);
-                self.block_queue.remove(removed_hashes);
-                self.dialog
-                    .send_data(NodeMessage::BlocksDisconnected(reorged))
-                    .await;
-                self.flush_over_height(stem).await;
+                let depth = self.height() - stem;
+                if depth > self.reorg_config.max_depth {
+                    self.dialog
+                        .send_warning(Warning::UnexpectedSyncError {
+                            warning: format!(
+                                "Peer sent us a fork of depth {} which exceeds the maximum reorganization depth",
+                                depth
+                            ),
+                        })
+                        .await;
+                    return Err(HeaderSyncError::ExceedsMaxReorgDepth);
+                }
+                if depth >= self.reorg_config.alarm_depth {
+                    let old_tip = HeaderCheckpoint::new(self.height(), self.tip());
+                    let new_tip = HeaderCheckpoint::new(
+                        stem + uncommon.len() as u32,
+                        uncommon
+                            .last()
+                            .ok_or(HeaderSyncError::FloatingHeaders)?
+                            .block_hash(),
+                    );
+                    self.dialog
+                        .send_data(NodeMessage::DeepReorg(DeepReorg::new(
+                            depth, old_tip, new_tip,
+                        )))
+                        .await;
+                    if matches!(self.reorg_config.policy, ReorgPolicy::Halt) {
+                        self.dialog
+                            .send_dialog("Waiting on the client to confirm a deep reorganization")
+                            .await;
+                        self.pending_reorg = Some(uncommon);
+                        return Err(HeaderSyncError::PendingReorg);
+                    }
+                }
+                self.reorganize(stem, &uncommon).await;
                 Ok(())
             } else {
                 self.dialog
@@ -482,6 +648,84 @@ impl<H: HeaderStore> Chain<H> {
         }
     }
 
+    // Switch to the fork starting after the stem, removing any data associated with the disconnected blocks
+    async fn reorganize(&mut self, stem: u32, uncommon: &[Header]) {
+        self.dialog.send_dialog("Valid reorganization found").await;
+        let reorged = self.header_chain.extend(uncommon);
+        let removed_hashes = &reorged
+            .iter()
+            .map(|disconnect| disconnect.header.block_hash())
+            .collect::<Vec<BlockHash>>();
+        self.clear_compact_filter_queue();
+        self.cf_header_chain.remove(removed_hashes);
+        self.filter_chain.remove(removed_hashes);
+        self.filter_scheduler.reset_from(stem + 1);
+        if let Some(rescan) = self.rescan.as_mut() {
+            rescan.scheduler.reset_from(stem + 1);
+        }
+        self.filter_cache.remove_from(stem + 1);
+        self.block_queue.remove(removed_hashes);
+        self.downloaded.retain(|height, _| *height <= stem);
+        self.received.retain(|_, (_, height)| *height <= stem);
+        self.dialog
+            .send_data(NodeMessage::BlocksDisconnected(reorged))
+            .await;
+        self.flush_over_height(stem).await;
+        self.deployments.rewind(stem);
+        self.update_deployments().await;
+    }
+
+    // Follow a deep reorganization that is waiting on the client. Returns if a reorganization occured.
+    // Our chain may have been extended in the meantime, so the fork must still have more work.
+    pub(crate) async fn confirm_reorg(&mut self) -> bool {
+        let uncommon = match self.pending_reorg.take() {
+            Some(uncommon) => uncommon,
+            None => return false,
+        };
+        let stem_hash = match uncommon.first() {
+            Some(header) => header.prev_blockhash,
+            None => return false,
+        };
+        let challenge_chainwork = match uncommon
+            .iter()
+            .map(|header| header.work())
+            .reduce(|acc, next| acc + next)
+        {
+            Some(work) => work,
+            None => return false,
+        };
+        match self.height_of_hash(stem_hash).await {
+            Some(stem)
+                if self
+                    .header_chain
+                    .chainwork_after_height(stem)
+                    .lt(&challenge_chainwork) =>
+            {
+                self.reorganize(stem, &uncommon).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Stay on our chain instead of a deep reorganization that is waiting on the client. The fork is
+    // ignored for the rest of the session. Returns if there was a reorganization to reject.
+    pub(crate) async fn reject_reorg(&mut self) -> bool {
+        let first = match self
+            .pending_reorg
+            .take()
+            .and_then(|uncommon| uncommon.first().copied())
+        {
+            Some(header) => header,
+            None => return false,
+        };
+        self.dialog
+            .send_dialog("Staying on the current chain after a deep reorganization was rejected")
+            .await;
+        self.rejected_reorgs.insert(first.block_hash());
+        true
+    }
+
     async fn audit_difficulty(
         &mut self,
         height_start: u32,
@@ -602,18 +846,21 @@ impl<H: HeaderStore> Chain<H> {
         };
         match maybe_height {
             Some(height) => {
-                // This is a very generous check to ensure a peer cannot get us to load an
+                // The maximum reorganization depth also ensures a peer cannot get us to load an
                 // absurd amount of headers into RAM. Because headers come in batches of 2,000,
                 // we wouldn't accept a fork of a depth more than around 2,000 anyway.
                 // The only reorgs that have ever been recorded are of depth 1.
-                if self.height() - height > MAX_REORG_DEPTH {
-                    return Err(HeaderSyncError::FloatingHeaders);
+                if self.height() - height > self.reorg_config.max_depth {
+                    return Err(HeaderSyncError::ExceedsMaxReorgDepth);
                 } else {
                     let older_anchor = HeaderCheckpoint::new(height, prev_hash);
                     self.header_chain = HeaderChain::new(older_anchor);
                     self.cf_header_chain =
                         CFHeaderChain::new(older_anchor, self.cf_header_chain.quorum_required());
                     self.filter_chain = FilterChain::new(older_anchor.height);
+                    self.filter_scheduler.clear(older_anchor.height);
+                    self.filter_cache.remove_from(0);
+                    self.rescan = None;
                 }
             }
             None => return Err(HeaderSyncError::FloatingHeaders),
@@ -653,13 +900,53 @@ impl<H: HeaderStore> Chain<H> {
         }
         // Check for any obvious faults
         self.audit_cf_headers(&batch).await?;
+        let start_height = self.cf_header_chain.height() + 1;
+        let filter_headers = if self.persist_filters {
+            batch.inner()
+        } else {
+            Vec::new()
+        };
         // We already have a message like this. Verify they are the same
-        if self.cf_header_chain.has_queue() {
-            Ok(self.cf_header_chain.verify(&mut batch).await)
+        let attempt = if self.cf_header_chain.has_queue() {
+            self.cf_header_chain.verify(&mut batch).await
         } else {
             // Associate the block hashes with the filter hashes and add them to the queue
             let queue = self.construct_cf_header_queue(&mut batch).await?;
-            Ok(self.cf_header_chain.set_queue(queue).await)
+            self.cf_header_chain.set_queue(queue).await
+        };
+        if matches!(attempt, AppendAttempt::Extended) {
+            self.persist_filter_headers(start_height, filter_headers)
+                .await;
+        }
+        Ok(attempt)
+    }
+
+    // Save the filter headers that extended the chain, so they may be served to other peers
+    async fn persist_filter_headers(
+        &mut self,
+        start_height: u32,
+        filter_headers: Vec<(FilterHeader, FilterHash)>,
+    ) {
+        if filter_headers.is_empty() {
+            return;
+        }
+        let mut persisted = BTreeMap::new();
+        for (index, (filter_header, filter_hash)) in filter_headers.into_iter().enumerate() {
+            let height = start_height + index as u32;
+            if let Some(block_hash) = self.blockhash_at_height(height).await {
+                persisted.insert(
+                    height,
+                    PersistedFilter::new(block_hash, filter_header, filter_hash),
+                );
+            }
+        }
+        let mut db = self.db.lock().await;
+        if let Err(e) = db.write_filter_headers(&persisted).await {
+            self.dialog
+                .send_warning(Warning::FailedPersistance {
+                    warning: format!("Could not save filter headers: {e}"),
+                })
+                .await;
         }
     }
 
@@ -732,12 +1019,22 @@ impl<H: HeaderStore> Chain<H> {
     // Handle a new filter
     pub(crate) async fn sync_filter(
         &mut self,
+        peer_id: u32,
         filter_message: CFilter,
     ) -> Result<Option<GetCFilters>, CFilterSyncError> {
+        if self
+            .rescan
+            .as_ref()
+            .map_or(false, |rescan| rescan.scheduler.is_busy(peer_id))
+        {
+            return self.rescan_filter(peer_id, filter_message).await;
+        }
         if self.is_filters_synced() {
+            self.filter_scheduler
+                .complete(peer_id, &filter_message.block_hash);
             return Ok(None);
         }
-        let mut filter = Filter::new(filter_message.filter, filter_message.block_hash);
+        let filter = Filter::new(filter_message.filter, filter_message.block_hash);
         let expected_filter_hash = self.cf_header_chain.hash_at(&filter_message.block_hash);
         // Disallow any filter that we do not have a block hash for
         match expected_filter_hash {
@@ -747,16 +1044,104 @@ impl<H: HeaderStore> Chain<H> {
                 }
             }
             None => {
+                // The blocks were reorganized after the request was made
+                if self.filter_scheduler.is_cancelled(peer_id) {
+                    self.filter_scheduler
+                        .complete(peer_id, &filter_message.block_hash);
+                    return Ok(None);
+                }
                 return Err(CFilterSyncError::UnknownFilterHash);
             }
         }
 
-        #[cfg(feature = "filter-control")]
+        // The range may have been requested from more than one peer
+        if !self.filter_chain.contains(&filter_message.block_hash) {
+            self.check_filter(filter, None).await?;
+            self.filter_chain.put_hash(filter_message.block_hash).await;
+        }
+        if self
+            .filter_scheduler
+            .complete(peer_id, &filter_message.block_hash)
+            && !self.is_filters_synced()
         {
-            let height = self
-                .height_of_hash(filter_message.block_hash)
+            Ok(self.next_filter_message(peer_id).await)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Handle a filter that was requested to rescan a range of blocks
+    async fn rescan_filter(
+        &mut self,
+        peer_id: u32,
+        filter_message: CFilter,
+    ) -> Result<Option<GetCFilters>, CFilterSyncError> {
+        let filter = Filter::new(filter_message.filter, filter_message.block_hash);
+        match self.cf_header_chain.hash_at(&filter_message.block_hash) {
+            Some(ref_hash) => {
+                if filter.filter_hash().ne(ref_hash) {
+                    return Err(CFilterSyncError::MisalignedFilterHash);
+                }
+            }
+            None => {
+                // The blocks were reorganized after the request was made
+                if let Some(rescan) = self.rescan.as_mut() {
+                    if rescan.scheduler.is_cancelled(peer_id) {
+                        rescan
+                            .scheduler
+                            .complete(peer_id, &filter_message.block_hash);
+                        return Ok(None);
+                    }
+                }
+                return Err(CFilterSyncError::UnknownFilterHash);
+            }
+        }
+        let scripts = self
+            .rescan
+            .as_ref()
+            .and_then(|rescan| rescan.scripts().cloned());
+        self.check_filter(filter, scripts.as_ref()).await?;
+        let completed = self.rescan.as_mut().map_or(false, |rescan| {
+            rescan
+                .scheduler
+                .complete(peer_id, &filter_message.block_hash)
+        });
+        if !completed {
+            return Ok(None);
+        }
+        self.finish_rescan().await;
+        Ok(self.next_filter_message(peer_id).await)
+    }
+
+    // Check a filter for any of the scripts we are looking for, or only the given scripts, keeping it in the cache
+    #[cfg_attr(feature = "filter-control", allow(unused_variables))]
+    async fn check_filter(
+        &mut self,
+        filter: Filter,
+        scripts: Option<&HashSet<ScriptBuf>>,
+    ) -> Result<(), CFilterSyncError> {
+        let block_hash = *filter.block_hash();
+        let height = self
+            .height_of_hash(block_hash)
+            .await
+            .ok_or(CFilterSyncError::UnknownFilterHash)?;
+        self.filter_cache.insert(height, &filter);
+        if self.persist_filters {
+            let mut db = self.db.lock().await;
+            if let Err(e) = db
+                .write_filter(height, &block_hash, filter.contents())
                 .await
-                .ok_or(CFilterSyncError::UnknownFilterHash)?;
+            {
+                self.dialog
+                    .send_warning(Warning::FailedPersistance {
+                        warning: format!("Could not save the filter at height {height}: {e}"),
+                    })
+                    .await;
+            }
+        }
+
+        #[cfg(feature = "filter-control")]
+        {
             let indexed_filter = IndexedFilter::new(height, filter);
             self.dialog
                 .send_data(NodeMessage::IndexedFilter(indexed_filter))
@@ -764,59 +1149,232 @@ impl<H: HeaderStore> Chain<H> {
         }
 
         #[cfg(not(feature = "filter-control"))]
-        if !self.block_queue.contains(&filter_message.block_hash)
-            && filter
-                .contains_any(&self.scripts)
-                .await
-                .map_err(CFilterSyncError::Filter)?
         {
-            // Add to the block queue
-            self.block_queue.add(filter_message.block_hash);
+            let mut filter = filter;
+            let queued = self.queued_filters.contains_key(&block_hash);
+            let matches = !queued
+                && match scripts {
+                    Some(scripts) => filter.contains_any(scripts.iter()).await,
+                    None => {
+                        filter
+                            .contains_any(self.scripts.iter().chain(self.outpoints.values()))
+                            .await
+                    }
+                }
+                .map_err(CFilterSyncError::Filter)?;
+            // Unconfirmed transactions may be included in, or conflict with, any new block
+            let unconfirmed = !queued && scripts.is_none() && !self.mempool.is_empty();
+            if matches || unconfirmed {
+                // Add to the block queue
+                self.block_queue.add(block_hash, height);
+                if matches {
+                    self.dialog
+                        .send_dialog(format!("Found script at block: {}", block_hash))
+                        .await;
+                }
+                // Keep the filter in case the scripts change before the block arrives
+                self.queued_filters.insert(block_hash, filter);
+            }
+        }
+        Ok(())
+    }
+
+    // The next range of filters to request from a peer, if the peer is not busy and there are filters left to request
+    pub(crate) async fn next_filter_message(&mut self, peer_id: u32) -> Option<GetCFilters> {
+        if self.filter_scheduler.is_busy(peer_id)
+            || self
+                .rescan
+                .as_ref()
+                .map_or(false, |rescan| rescan.scheduler.is_busy(peer_id))
+        {
+            return None;
+        }
+        let best_height = self.cf_header_chain.height().min(self.height());
+        if let Some((start_height, stop_height)) = self.filter_scheduler.next_range(best_height) {
+            let stop_hash = match self.blockhash_at_height(stop_height).await {
+                Some(stop_hash) => stop_hash,
+                None => {
+                    self.filter_scheduler.release(start_height, stop_height);
+                    return None;
+                }
+            };
             self.dialog
-                .send_dialog(format!(
-                    "Found script at block: {}",
-                    filter_message.block_hash
-                ))
+                .chain_update(
+                    self.height(),
+                    self.cf_header_chain.height(),
+                    self.filter_chain.height(),
+                    self.best_known_height.unwrap_or(self.height()),
+                )
                 .await;
+            self.filter_scheduler
+                .assign(peer_id, start_height, stop_height, stop_hash);
+            return Some(GetCFilters {
+                filter_type: FILTER_BASIC,
+                start_height,
+                stop_hash,
+            });
+        }
+        // Rescans are only requested once there is nothing left to sync
+        let (start_height, stop_height) =
+            self.rescan.as_mut()?.scheduler.next_range(best_height)?;
+        let stop_hash = self.blockhash_at_height(stop_height).await;
+        let rescan = self.rescan.as_mut()?;
+        match stop_hash {
+            Some(stop_hash) => {
+                rescan
+                    .scheduler
+                    .assign(peer_id, start_height, stop_height, stop_hash);
+                Some(GetCFilters {
+                    filter_type: FILTER_BASIC,
+                    start_height,
+                    stop_hash,
+                })
+            }
+            None => {
+                rescan.scheduler.release(start_height, stop_height);
+                None
+            }
         }
+    }
 
-        self.filter_chain.put_hash(filter_message.block_hash).await;
-        let stop_hash = self
-            .filter_chain
-            .last_stop_hash_request()
-            .ok_or(CFilterSyncError::UnrequestedStophash)?;
-        if filter_message.block_hash.eq(&stop_hash) {
-            if !self.is_filters_synced() {
-                Ok(Some(self.next_filter_message().await))
-            } else {
-                Ok(None)
+    // Hand out the filter ranges of peers that disconnected or did not respond in time, returning the peers that timed out
+    pub(crate) fn expire_filter_requests(&mut self, connected: &[u32]) -> Vec<u32> {
+        self.filter_scheduler.retain_peers(connected);
+        let mut expired = self.filter_scheduler.expire();
+        if let Some(rescan) = self.rescan.as_mut() {
+            rescan.scheduler.retain_peers(connected);
+            expired.extend(rescan.scheduler.expire());
+        }
+        expired
+    }
+
+    // Check the filters of a range of blocks for the current scripts again. Filters in the cache are checked
+    // immediately, and the remaining filters are requested from peers.
+    pub(crate) async fn rescan_range(&mut self, start_height: u32, end_height: Option<u32>) {
+        // Filters may only be checked against the filter headers we have
+        let start_height = start_height.max(self.cf_header_chain.anchor_height() + 1);
+        let end_height = end_height.unwrap_or(self.height()).min(self.height());
+        self.start_rescan(start_height, end_height, None).await;
+    }
+
+    // Add scripts to look for, checking the filters that were already scanned from a height onward for the new scripts
+    pub(crate) async fn add_scripts(
+        &mut self,
+        scripts: HashSet<ScriptBuf>,
+        from_height: Option<u32>,
+    ) -> bool {
+        let new_scripts: HashSet<ScriptBuf> = scripts
+            .into_iter()
+            .filter(|script| self.scripts.insert(script.clone()))
+            .collect();
+        match from_height {
+            Some(height) => {
+                let start_height = height.max(self.filter_chain.anchor_height() + 1);
+                self.catch_up_from(start_height, new_scripts).await
             }
-        } else {
-            Ok(None)
+            None => self.catch_up(new_scripts).await,
         }
     }
 
-    // Next filter message, if there is one
-    pub(crate) async fn next_filter_message(&mut self) -> GetCFilters {
-        let stop_hash_index = self.filter_chain.height() + FILTER_BATCH_SIZE + 1;
-        let stop_hash = self
-            .blockhash_at_height(stop_hash_index)
-            .await
-            .unwrap_or(self.tip());
+    // Check the filters that were scanned before the scripts were added
+    async fn catch_up(&mut self, scripts: HashSet<ScriptBuf>) -> bool {
+        let start_height = self.filter_chain.anchor_height() + 1;
+        self.catch_up_from(start_height, scripts).await
+    }
+
+    // Check the filters that were scanned from a height onward for new scripts
+    async fn catch_up_from(&mut self, start_height: u32, scripts: HashSet<ScriptBuf>) -> bool {
+        // Filters are checked by the client
+        if cfg!(feature = "filter-control") {
+            return false;
+        }
+        let end_height = self.filter_scheduler.requested_height();
+        if scripts.is_empty() || start_height > end_height {
+            return false;
+        }
+        self.start_rescan(start_height, end_height, Some(scripts))
+            .await;
+        true
+    }
+
+    async fn start_rescan(
+        &mut self,
+        start_height: u32,
+        end_height: u32,
+        scripts: Option<HashSet<ScriptBuf>>,
+    ) {
+        if start_height > end_height {
+            return;
+        }
         self.dialog
-            .chain_update(
-                self.height(),
-                self.cf_header_chain.height(),
-                self.filter_chain.height(),
-                self.best_known_height.unwrap_or(self.height()),
-            )
+            .send_dialog(format!(
+                "Rescanning filters from {} to {}",
+                start_height, end_height
+            ))
             .await;
-        self.filter_chain.set_last_stop_hash(stop_hash);
-        GetCFilters {
-            filter_type: FILTER_BASIC,
-            start_height: self.filter_chain.height() + 1,
-            stop_hash,
+        // Only the heights with a cached filter are looked up, and the gaps between them are downloaded
+        let mut missing: Vec<(u32, u32)> = Vec::new();
+        let mut next_missing = start_height;
+        for height in self.filter_cache.heights(start_height..=end_height) {
+            let cached = match self.blockhash_at_height(height).await {
+                Some(block_hash) => self.filter_cache.get(height, &block_hash).cloned(),
+                None => None,
+            };
+            let filter = match cached {
+                Some(filter) => filter,
+                None => continue,
+            };
+            if let Err(e) = self.check_filter(filter, scripts.as_ref()).await {
+                self.dialog
+                    .send_warning(Warning::UnexpectedSyncError {
+                        warning: format!("Unexpected error checking a cached filter: {}", e),
+                    })
+                    .await;
+            }
+            if height > next_missing {
+                missing.push((next_missing, height - 1));
+            }
+            next_missing = height + 1;
+        }
+        if next_missing <= end_height {
+            missing.push((next_missing, end_height));
+        }
+        // Filters the sync has yet to download will be checked when they arrive
+        let missing = self.filter_scheduler.unscheduled(missing);
+        let rescan = Rescan::new(missing, start_height, end_height, scripts);
+        match self.rescan.as_mut() {
+            Some(current) => current.merge(rescan),
+            None => self.rescan = Some(rescan),
         }
+        self.finish_rescan().await;
+    }
+
+    // Stop rescanning once every filter in the range was checked
+    async fn finish_rescan(&mut self) {
+        let best_height = self.cf_header_chain.height().min(self.height());
+        if !self
+            .rescan
+            .as_ref()
+            .map_or(false, |rescan| rescan.scheduler.is_done(best_height))
+        {
+            return;
+        }
+        self.dialog.send_dialog("Finished rescanning filters").await;
+        let catch_up = self.rescan.take().and_then(|rescan| rescan.into_catch_up());
+        if let Some((scripts, start_height, end_height)) = catch_up {
+            self.dialog
+                .send_data(NodeMessage::CatchUpComplete(CatchUp::new(
+                    scripts,
+                    start_height,
+                    end_height,
+                )))
+                .await;
+        }
+    }
+
+    // Is there a range of filters left to rescan
+    pub(crate) fn is_rescanning(&self) -> bool {
+        self.rescan.is_some()
     }
 
     // Are we synced with filters
@@ -824,53 +1382,545 @@ impl<H: HeaderStore> Chain<H> {
         self.height().le(&self.filter_chain.height())
     }
 
-    // Pop a block from the queue of interesting blocks
-    pub(crate) fn next_block(&mut self) -> Option<BlockHash> {
-        self.block_queue.pop()
+    // Pop a block from the queue of interesting blocks to request from this peer
+    pub(crate) fn next_block(&mut self, peer: u32) -> Option<BlockHash> {
+        self.block_queue.pop(peer)
+    }
+
+    // Request the blocks of peers that disconnected or did not respond in time again, returning the peers that timed out
+    pub(crate) fn expire_block_requests(&mut self, connected: &[u32]) -> Vec<u32> {
+        self.block_queue.retain_peers(connected);
+        self.block_queue.expire()
+    }
+
+    // A peer did not have the blocks we asked for
+    pub(crate) fn blocks_not_found(&mut self, peer: u32, hashes: &[BlockHash]) {
+        for hash in hashes {
+            self.block_queue.not_found(peer, hash);
+        }
     }
 
-    // Are there any blocks left in the queue
+    // Peers that keep failing to send blocks
+    pub(crate) fn failing_block_peers(&mut self) -> Vec<u32> {
+        self.block_queue.failing_peers()
+    }
+
+    // Are there any blocks left in the queue, or waiting to be scanned
     pub(crate) fn block_queue_empty(&self) -> bool {
-        self.block_queue.complete()
+        self.block_queue.complete() && self.downloaded.is_empty()
+    }
+
+    // The headers after the first locator in our chain, for a peer syncing from this node. Only the headers
+    // in memory are served, so the chain is not held up reading the database.
+    pub(crate) async fn serve_headers(
+        &self,
+        locators: &[BlockHash],
+        stop_hash: BlockHash,
+    ) -> Vec<Header> {
+        let mut start_height = None;
+        for locator in locators.iter().take(MAX_LOCATORS) {
+            if let Some(height) = self.header_chain.height_of_hash(*locator).await {
+                start_height = Some(height);
+                break;
+            }
+        }
+        let mut headers = Vec::new();
+        let start_height = match start_height {
+            Some(height) => height,
+            None => return headers,
+        };
+        for height in start_height + 1..=self.height() {
+            if headers.len() >= MAX_SERVED_HEADERS {
+                break;
+            }
+            match self.header_chain.header_at_height(height) {
+                Some(header) => {
+                    headers.push(*header);
+                    if header.block_hash().eq(&stop_hash) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        headers
+    }
+
+    // The blocks from a height up to the stop hash a peer asked filters for, if there are no more than the limit.
+    // Only the headers in memory are looked up, and the filters are read from the database after releasing the chain.
+    pub(crate) async fn served_blocks(
+        &self,
+        filter_type: u8,
+        start_height: u32,
+        stop_hash: BlockHash,
+        limit: u32,
+    ) -> Option<Vec<(u32, BlockHash)>> {
+        if filter_type != FILTER_BASIC {
+            return None;
+        }
+        let stop_height = self.header_chain.height_of_hash(stop_hash).await?;
+        if start_height > stop_height || stop_height - start_height >= limit {
+            return None;
+        }
+        (start_height..=stop_height)
+            .map(|height| {
+                self.header_chain
+                    .header_at_height(height)
+                    .map(|header| (height, header.block_hash()))
+            })
+            .collect()
+    }
+
+    pub(crate) fn db(&self) -> Arc<Mutex<H>> {
+        Arc::clone(&self.db)
     }
 
-    // Make sure we have this hash in our chain, check the merkle root, and pass the block
-    pub(crate) async fn check_send_block(&mut self, block: Block) -> Result<(), BlockScanError> {
+    // Make sure the block was requested from this peer and is in our chain, check the block commitments and filter,
+    // and scan every received block that is no longer waiting on a lower block.
+    // Returns if filters that were already scanned must be checked again for newly derived keychain scripts.
+    pub(crate) async fn check_send_block(
+        &mut self,
+        peer: u32,
+        block: Block,
+    ) -> Result<bool, BlockScanError> {
         let block_hash = block.block_hash();
-        if !self.block_queue.need(&block_hash) {
-            return Ok(());
+        if !self.block_queue.requested_from(peer, &block_hash) {
+            return Ok(false);
         }
         let height = self
             .height_of_hash(block_hash)
             .await
             .ok_or(BlockScanError::NoBlockHash)?;
-        if !block.check_merkle_root() {
-            return Err(BlockScanError::InvalidMerkleRoot);
+        check_block(&block, height, self.network)?;
+        // The filter that triggered the download must include every output script of the block
+        if let Some(filter) = self.queued_filters.get_mut(&block_hash) {
+            if !filter
+                .contains_all(filter_scripts(&block))
+                .await
+                .unwrap_or(false)
+            {
+                return Err(BlockScanError::FilterMismatch);
+            }
+        }
+        self.block_queue.receive(peer, &block_hash);
+        let scan = self.requested_for_scan(&block_hash);
+        self.queued_filters.remove(&block_hash);
+        #[cfg(feature = "filter-control")]
+        self.requested_blocks.remove(&block_hash);
+        // Blocks fetched by the client are only sent to the client
+        if self.block_fetches.answer(&block, height) && !scan {
+            // Any block may include or conflict with unconfirmed transactions
+            self.resolve_unconfirmed(&block, height).await;
+            return Ok(false);
+        }
+        // Outputs must be seen before they are spent, so blocks are scanned in order of height
+        self.downloaded.insert(height, block);
+        Ok(self.scan_downloaded().await)
+    }
+
+    // Scan the received blocks that are no longer waiting on a lower block.
+    // Returns if filters that were already scanned must be checked again for newly derived keychain scripts.
+    pub(crate) async fn scan_downloaded(&mut self) -> bool {
+        let mut catching_up = false;
+        while let Some(next_height) = self.downloaded.keys().next().copied() {
+            if self
+                .block_queue
+                .lowest_height()
+                .map_or(false, |lowest| lowest < next_height)
+            {
+                break;
+            }
+            if let Some(block) = self.downloaded.remove(&next_height) {
+                catching_up |= self.scan_block(block, next_height).await;
+            }
+        }
+        catching_up
+    }
+
+    // Pass the relevant transactions and spent outpoints of a block in our chain.
+    // Returns if filters that were already scanned must be checked again for newly derived keychain scripts.
+    async fn scan_block(&mut self, block: Block, height: u32) -> bool {
+        self.resolve_unconfirmed(&block, height).await;
+        for spent in self.scan_inputs(&block, height) {
+            self.dialog
+                .send_dialog(format!(
+                    "Outpoint {} spent by {}",
+                    spent.outpoint,
+                    spent.spending_tx.compute_txid()
+                ))
+                .await;
+            self.dialog
+                .send_data(NodeMessage::OutPointSpent(spent))
+                .await;
+        }
+        let transactions = self.scan_transactions(&block, height);
+        let catching_up = self.update_keychains(&transactions, height).await;
+        for transaction in transactions {
+            self.dialog
+                .send_data(NodeMessage::Transaction(transaction))
+                .await;
+        }
+        // Explicitly requested blocks are always delivered
+        if self.deliver_blocks || cfg!(feature = "filter-control") {
+            self.dialog
+                .send_data(NodeMessage::Block(IndexedBlock::new(height, block)))
+                .await;
+        }
+        catching_up
+    }
+
+    // Find the transactions in the block that pay to or spend from a watched script. Spends are only
+    // recognized for outputs the node has seen, or outpoints watched explicitly.
+    fn scan_transactions(&mut self, block: &Block, height: u32) -> Vec<IndexedTransaction> {
+        let block_hash = block.block_hash();
+        let mut relevant = Vec::new();
+        for tx in block.txdata.iter() {
+            let txid = tx.compute_txid();
+            let mut matched_scripts = Vec::new();
+            let mut inputs = Vec::new();
+            for (index, input) in tx.input.iter().enumerate() {
+                let script = self
+                    .received
+                    .remove(&input.previous_output)
+                    .map(|(script, _)| script)
+                    .or_else(|| self.outpoints.get(&input.previous_output).cloned());
+                if let Some(script) = script {
+                    inputs.push(index);
+                    matched_scripts.push(script);
+                }
+            }
+            let mut outputs = Vec::new();
+            for (index, output) in tx.output.iter().enumerate() {
+                if self.scripts.contains(&output.script_pubkey) {
+                    outputs.push(index);
+                    matched_scripts.push(output.script_pubkey.clone());
+                    self.received.insert(
+                        OutPoint::new(txid, index as u32),
+                        (output.script_pubkey.clone(), height),
+                    );
+                }
+            }
+            if inputs.is_empty() && outputs.is_empty() {
+                continue;
+            }
+            matched_scripts.sort();
+            matched_scripts.dedup();
+            relevant.push((tx, txid, matched_scripts, inputs, outputs));
+        }
+        self.prune_received(MAX_RECEIVED_OUTPOINTS);
+        if relevant.is_empty() {
+            return Vec::new();
+        }
+        // One proof covers every relevant transaction in the block
+        let txids: HashSet<Txid> = relevant.iter().map(|(_, txid, ..)| *txid).collect();
+        let proof = MerkleBlock::from_block_with_predicate(block, |id| txids.contains(id));
+        relevant
+            .into_iter()
+            .map(|(tx, _, matched_scripts, inputs, outputs)| {
+                IndexedTransaction::new(
+                    tx.clone(),
+                    height,
+                    block_hash,
+                    matched_scripts,
+                    inputs,
+                    outputs,
+                    proof.clone(),
+                )
+            })
+            .collect()
+    }
+
+    // Forget the oldest received outputs when there are too many to remember
+    fn prune_received(&mut self, limit: usize) {
+        while self.received.len() > limit {
+            let oldest = match self.received.values().map(|(_, height)| *height).min() {
+                Some(height) => height,
+                None => return,
+            };
+            self.received.retain(|_, (_, height)| *height > oldest);
+        }
+    }
+
+    // The announced transactions that were not requested yet. Transactions are only relevant once every block
+    // was scanned, so the outputs they spend are known, and if there is anything to look for.
+    pub(crate) fn need_transactions(&mut self, txids: Vec<Txid>) -> Vec<Txid> {
+        if (self.scripts.is_empty() && self.outpoints.is_empty())
+            || !self.is_synced()
+            || !self.is_filters_synced()
+            || !self.block_queue_empty()
+        {
+            return Vec::new();
+        }
+        txids
+            .into_iter()
+            .filter(|txid| self.mempool.need(*txid))
+            .collect()
+    }
+
+    // Check a transaction relayed by a peer for watched scripts and outpoints
+    pub(crate) async fn check_unconfirmed(&mut self, tx: Transaction) {
+        let txid = tx.compute_txid();
+        if self.mempool.contains(&txid) {
+            return;
+        }
+        let mut matched_scripts = Vec::new();
+        let mut inputs = Vec::new();
+        for (index, input) in tx.input.iter().enumerate() {
+            let script = self
+                .received
+                .get(&input.previous_output)
+                .map(|(script, _)| script)
+                .or_else(|| self.outpoints.get(&input.previous_output))
+                .or_else(|| self.mempool.received(&input.previous_output));
+            if let Some(script) = script {
+                inputs.push(index);
+                matched_scripts.push(script.clone());
+            }
+        }
+        let mut outputs = Vec::new();
+        for (index, output) in tx.output.iter().enumerate() {
+            if self.scripts.contains(&output.script_pubkey) {
+                outputs.push(index);
+                matched_scripts.push(output.script_pubkey.clone());
+            }
         }
-        self.block_queue.receive(&block_hash);
+        if inputs.is_empty() && outputs.is_empty() {
+            return;
+        }
+        matched_scripts.sort();
+        matched_scripts.dedup();
+        self.mempool.insert(tx.clone(), &outputs);
         self.dialog
-            .send_data(NodeMessage::Block(IndexedBlock::new(height, block)))
+            .send_dialog(format!("Unconfirmed transaction {txid} is relevant"))
             .await;
-        Ok(())
+        self.dialog
+            .send_data(NodeMessage::MempoolTransaction(
+                UnconfirmedTransaction::new(tx, matched_scripts, inputs, outputs),
+            ))
+            .await;
+    }
+
+    // Report the unconfirmed transactions that were included in a block, or conflict with the block
+    async fn resolve_unconfirmed(&mut self, block: &Block, height: u32) {
+        let (confirmed, dropped) = self.mempool.resolve(block);
+        for txid in confirmed {
+            self.dialog
+                .send_data(NodeMessage::MempoolConfirmed(txid, height))
+                .await;
+        }
+        for txid in dropped {
+            self.dialog
+                .send_data(NodeMessage::MempoolDropped(txid))
+                .await;
+        }
+    }
+
+    // Report the unconfirmed transactions that peers would no longer relay
+    pub(crate) async fn expire_unconfirmed(&mut self) {
+        for txid in self.mempool.expire() {
+            self.dialog
+                .send_data(NodeMessage::MempoolDropped(txid))
+                .await;
+        }
+    }
+
+    // Find any inputs in the block that spend a watched outpoint
+    fn scan_inputs(&self, block: &Block, height: u32) -> Vec<SpentOutPoint> {
+        if self.outpoints.is_empty() {
+            return Vec::new();
+        }
+        let mut spends = Vec::new();
+        for tx in block.txdata.iter() {
+            for (input_index, input) in tx.input.iter().enumerate() {
+                if self.outpoints.contains_key(&input.previous_output) {
+                    spends.push(SpentOutPoint::new(
+                        input.previous_output,
+                        tx.clone(),
+                        input_index,
+                        height,
+                    ));
+                }
+            }
+        }
+        spends
+    }
+
+    // Report the scripts derived from a keychain that were used, and watch more scripts past them.
+    // Filters that were already scanned from this block onward are checked for the new scripts.
+    async fn update_keychains(&mut self, transactions: &[IndexedTransaction], height: u32) -> bool {
+        let mut new_scripts = HashSet::new();
+        for transaction in transactions {
+            for script in transaction.matched_scripts.iter() {
+                let (keychain, index) = match self.keychains.lookup(script) {
+                    Some((keychain, index)) => (keychain.to_string(), index),
+                    None => continue,
+                };
+                self.dialog
+                    .send_data(NodeMessage::KeychainMatch(KeychainMatch {
+                        keychain,
+                        index,
+                        script: script.clone(),
+                        txid: transaction.transaction.compute_txid(),
+                        height,
+                    }))
+                    .await;
+                for new_script in self.keychains.mark_used(script) {
+                    if self.scripts.insert(new_script.clone()) {
+                        new_scripts.insert(new_script);
+                    }
+                }
+            }
+        }
+        if new_scripts.is_empty() {
+            return false;
+        }
+        self.dialog
+            .send_dialog(format!(
+                "Watching {} more scripts derived from keychains",
+                new_scripts.len()
+            ))
+            .await;
+        self.catch_up_from(height, new_scripts).await
+    }
+
+    // Start watching the scripts derived from a keychain
+    pub(crate) async fn add_keychain(&mut self, keychain: Keychain) -> bool {
+        let new_scripts: HashSet<ScriptBuf> = self
+            .keychains
+            .add(keychain)
+            .into_iter()
+            .filter(|script| self.scripts.insert(script.clone()))
+            .collect();
+        self.catch_up(new_scripts).await
+    }
+
+    // Add a script to our list
+    pub(crate) fn put_script(&mut self, script: ScriptBuf) {
+        self.scripts.insert(script);
+    }
+
+    // Stop looking for a script
+    pub(crate) async fn remove_script(&mut self, script: &ScriptBuf) {
+        if self.scripts.remove(script) {
+            self.received
+                .retain(|_, (received, _)| (*received).ne(script));
+            self.drop_unmatched_blocks().await;
+        }
+    }
+
+    // Look for an entirely new set of scripts. The scripts derived from keychains are still watched.
+    pub(crate) async fn replace_scripts(&mut self, mut scripts: HashSet<ScriptBuf>) -> bool {
+        scripts.extend(self.keychains.scripts().cloned());
+        let added = scripts.difference(&self.scripts).cloned().collect();
+        // Spends of outputs to removed scripts are no longer reported
+        self.received
+            .retain(|_, (received, _)| scripts.contains(received));
+        self.scripts = scripts;
+        self.drop_unmatched_blocks().await;
+        self.catch_up(added).await
+    }
+
+    // The scripts we are looking for
+    pub(crate) fn scripts(&self) -> HashSet<ScriptBuf> {
+        self.scripts.clone()
+    }
+
+    // Remove any blocks from the queue that no longer match a script we are looking for
+    async fn drop_unmatched_blocks(&mut self) {
+        let mut unmatched = Vec::new();
+        // Blocks are still needed to resolve unconfirmed transactions
+        let unconfirmed = !self.mempool.is_empty();
+        for (block_hash, filter) in self.queued_filters.iter_mut() {
+            if !self.block_queue.contains(block_hash) {
+                unmatched.push(*block_hash);
+                continue;
+            }
+            if unconfirmed {
+                continue;
+            }
+            let matches = filter
+                .contains_any(self.scripts.iter().chain(self.outpoints.values()))
+                .await
+                .unwrap_or(true);
+            if !matches {
+                self.dialog
+                    .send_dialog(format!(
+                        "Block {} no longer matches any scripts",
+                        block_hash
+                    ))
+                    .await;
+                unmatched.push(*block_hash);
+            }
+        }
+        // The client may still be waiting for some of these blocks
+        let unqueued: Vec<BlockHash> = unmatched
+            .iter()
+            .filter(|block_hash| !self.block_fetches.contains(block_hash))
+            .copied()
+            .collect();
+        self.block_queue.remove(&unqueued);
+        for block_hash in unmatched {
+            self.queued_filters.remove(&block_hash);
+        }
+    }
+
+    // Watch for an outpoint to be spent. The script is required to match the spend in the block filters.
+    pub(crate) fn put_outpoint(&mut self, outpoint: OutPoint, script: ScriptBuf) {
+        self.outpoints.insert(outpoint, script);
+    }
+
+    // Stop watching for an outpoint to be spent
+    pub(crate) fn remove_outpoint(&mut self, outpoint: &OutPoint) {
+        self.outpoints.remove(outpoint);
+    }
+
+    // Explicitly request a block in our chain
+    #[cfg(feature = "filter-control")]
+    pub(crate) async fn get_block(&mut self, hash: BlockHash) {
+        if let Some(height) = self.height_of_hash(hash).await {
+            self.requested_blocks.insert(hash);
+            self.block_queue.add(hash, height)
+        }
     }
 
-    // Should we care about this block
-    #[allow(dead_code)]
-    fn scan_outputs(&mut self, inputs: &[TxOut]) -> bool {
-        inputs
-            .iter()
-            .any(|out| self.scripts.contains(&out.script_pubkey))
+    // Fetch a block for the client alone. The block is sent back on the oneshot instead of to every subscriber.
+    pub(crate) async fn fetch_block(&mut self, request: BlockRequest) {
+        let height = match self.height_of_hash(request.hash).await {
+            Some(height) => height,
+            None => {
+                let _ = request.oneshot.send(Err(FetchBlockError::UnknownHash));
+                return;
+            }
+        };
+        match self.block_fetches.add(request.hash, request.oneshot) {
+            Ok(()) => self.block_queue.add(request.hash, height),
+            Err(oneshot) => {
+                let _ = oneshot.send(Err(FetchBlockError::TooManyRequests));
+            }
+        }
     }
 
-    // Add a script to our list
-    pub(crate) fn put_script(&mut self, script: ScriptBuf) {
-        self.scripts.insert(script);
+    // Stop downloading the blocks the client gave up waiting for, unless they are needed for a scan
+    pub(crate) fn expire_block_fetches(&mut self) {
+        let expired: Vec<BlockHash> = self
+            .block_fetches
+            .expire()
+            .into_iter()
+            .filter(|hash| !self.requested_for_scan(hash))
+            .collect();
+        self.block_queue.remove(&expired);
     }
 
-    // Explicitly request a block
-    #[cfg(feature = "filter-control")]
-    pub(crate) fn get_block(&mut self, hash: BlockHash) {
-        self.block_queue.add(hash)
+    // Blocks with matches in the filters, or explicitly requested blocks, are scanned and passed to every subscriber
+    fn requested_for_scan(&self, hash: &BlockHash) -> bool {
+        #[cfg(feature = "filter-control")]
+        {
+            self.requested_blocks.contains(hash)
+        }
+        #[cfg(not(feature = "filter-control"))]
+        {
+            self.queued_filters.contains_key(hash)
+        }
     }
 
     // Reset the compact filter queue because we received a new block
@@ -887,6 +1937,9 @@ impl<H: HeaderStore> Chain<H> {
     // Clear the filter header cache to rescan the filters for new scripts.
     pub(crate) async fn clear_filters(&mut self) {
         self.filter_chain.clear_cache().await;
+        self.filter_scheduler
+            .clear(self.filter_chain.anchor_height());
+        self.rescan = None;
     }
 }
 
@@ -895,10 +1948,13 @@ mod tests {
     use std::{collections::HashSet, str::FromStr};
 
     use bitcoin::{
+        absolute,
+        bip158::BlockFilter,
         block::Header,
         consensus::deserialize,
         p2p::message_filter::{CFHeaders, CFilter},
-        BlockHash, FilterHash, FilterHeader,
+        transaction, Amount, Block, BlockHash, FilterHash, FilterHeader, OutPoint, ScriptBuf,
+        Transaction, TxIn, TxOut, Txid,
     };
     use bitcoin_hashes::sha256d;
     use bitcoin_hashes::Hash;
@@ -907,42 +1963,69 @@ mod tests {
         chain::{
             checkpoints::{HeaderCheckpoint, HeaderCheckpoints},
             error::HeaderSyncError,
+            serve,
         },
-        core::{dialog::Dialog, messages::NodeMessage},
-        filters::cfheader_chain::AppendAttempt,
+        core::{dialog::Dialog, messages::NodeMessage, ReorgConfig, ReorgPolicy},
+        filters::{cfheader_chain::AppendAttempt, Filter},
+    };
+    #[cfg(not(feature = "filter-control"))]
+    use {
+        crate::chain::keychain::{Keychain, KeychainTracker, ScriptType},
+        crate::filters::filter_cache::FilterCache,
+        bitcoin::bip32::{DerivationPath, Xpub},
     };
 
     use super::Chain;
 
     fn new_regtest(anchor: HeaderCheckpoint) -> Chain<()> {
+        let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(1);
+        new_regtest_reorg_config(anchor, ReorgConfig::default(), sender)
+    }
+
+    fn new_regtest_two_peers(anchor: HeaderCheckpoint) -> Chain<()> {
         let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(1);
         let mut checkpoints = HeaderCheckpoints::new(&bitcoin::Network::Regtest);
         checkpoints.prune_up_to(anchor);
         Chain::new(
             bitcoin::Network::Regtest,
             HashSet::new(),
+            Vec::new(),
             anchor,
             None,
             checkpoints,
+            ReorgConfig::default(),
+            Vec::new(),
+            false,
+            0,
+            false,
             Dialog::new(sender),
             (),
-            1,
+            2,
         )
     }
 
-    fn new_regtest_two_peers(anchor: HeaderCheckpoint) -> Chain<()> {
-        let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(1);
+    fn new_regtest_reorg_config(
+        anchor: HeaderCheckpoint,
+        reorg_config: ReorgConfig,
+        sender: tokio::sync::broadcast::Sender<NodeMessage>,
+    ) -> Chain<()> {
         let mut checkpoints = HeaderCheckpoints::new(&bitcoin::Network::Regtest);
         checkpoints.prune_up_to(anchor);
         Chain::new(
             bitcoin::Network::Regtest,
             HashSet::new(),
+            Vec::new(),
             anchor,
             None,
             checkpoints,
+            reorg_config,
+            Vec::new(),
+            false,
+            0,
+            false,
             Dialog::new(sender),
             (),
-            2,
+            1,
         )
     }
 
@@ -995,6 +2078,58 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_serves_headers_after_locator() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen);
+        let block_8: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let block_9: Header = deserialize(&hex::decode("000000205708a90197d93475975545816b2229401ccff7567cb23900f14f2bd46732c605fd8de19615a1d687e89db365503cdf58cb649b8e935a1d3518fa79b0d408704e71375166ffff7f2000000000").unwrap()).unwrap();
+        let block_10: Header = deserialize(&hex::decode("000000201d062f2162835787db536c55317e08df17c58078c7610328bdced198574093790c9f554a7780a6043a19619d2a4697364bb62abf6336c0568c31f1eedca3c3e171375166ffff7f2000000000").unwrap()).unwrap();
+        chain
+            .sync_chain(vec![block_8, block_9, block_10])
+            .await
+            .unwrap();
+        // The first locator we know of is used
+        let headers = chain
+            .serve_headers(
+                &[BlockHash::all_zeros(), block_8.block_hash()],
+                BlockHash::all_zeros(),
+            )
+            .await;
+        assert_eq!(headers, vec![block_9, block_10]);
+        let headers = chain.serve_headers(&[gen.hash], block_9.block_hash()).await;
+        assert_eq!(headers, vec![block_8, block_9]);
+        assert!(chain
+            .serve_headers(&[BlockHash::all_zeros()], BlockHash::all_zeros())
+            .await
+            .is_empty());
+        // Only ranges of blocks in our chain within the limit are served
+        let blocks = chain
+            .served_blocks(0x00, 8, block_10.block_hash(), 3)
+            .await
+            .unwrap();
+        assert_eq!(blocks[0], (8, block_8.block_hash()));
+        assert_eq!(blocks.len(), 3);
+        assert!(chain
+            .served_blocks(0x00, 8, block_10.block_hash(), 2)
+            .await
+            .is_none());
+        assert!(chain
+            .served_blocks(0x01, 8, block_10.block_hash(), 3)
+            .await
+            .is_none());
+        // Nothing is served without saved filters
+        assert!(
+            serve::filter_headers(chain.db(), 0x00, 9, block_10.block_hash(), blocks)
+                .await
+                .is_none()
+        );
+    }
+
     #[tokio::test]
     async fn test_fork_includes_old_vals() {
         let gen = HeaderCheckpoint::new(
@@ -1062,6 +2197,632 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_deep_reorg_halts() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let (sender, mut recv) = tokio::sync::broadcast::channel::<NodeMessage>(32);
+        let reorg_config = ReorgConfig {
+            max_depth: 5,
+            alarm_depth: 2,
+            policy: ReorgPolicy::Halt,
+            ..Default::default()
+        };
+        let mut chain = new_regtest_reorg_config(gen, reorg_config, sender);
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f575b313ad3ef825cfc204c34da8f3c1fd1784e2553accfa38001010587cb57241f855e66ffff7f2000000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020c81cedd6a989939936f31448e49d010a13c2e750acf02d3fa73c9c7ecfb9476e798da2e5565335929ad303fc746acabc812ee8b06139bcf2a4c0eb533c21b8c420855e66ffff7f2000000000").unwrap()).unwrap();
+        let batch_1 = vec![block_1, block_2];
+        let new_block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f575b313ad3ef825cfc204c34da8f3c1fd1784e2553accfa38001010587cb5724d5855e66ffff7f2004000000").unwrap()).unwrap();
+        let new_block_2: Header = deserialize(&hex::decode("00000020d1d80f53343a084bd0da6d6ab846f9fe4a133de051ea00e7cae16ed19f601065798da2e5565335929ad303fc746acabc812ee8b06139bcf2a4c0eb533c21b8c4d6855e66ffff7f2000000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("0000002080f38c14e898d6646dd426428472888966e0d279d86453f42edc56fdb143241aa66c8fa8837d95be3f85d53f22e86a0d6d456b1ab348e073da4d42a39f50637423865e66ffff7f2000000000").unwrap()).unwrap();
+        let batch_2 = vec![new_block_1, new_block_2, block_3];
+        let chain_sync = chain.sync_chain(batch_1.clone()).await;
+        assert!(chain_sync.is_ok());
+        assert_eq!(chain.height(), 2);
+        // The fork has more work, but it is deep enough to wait on the client
+        let chain_sync = chain.sync_chain(batch_2.clone()).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::PendingReorg);
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.header_chain.values(), vec![block_1, block_2]);
+        let mut alarm = None;
+        while let Ok(message) = recv.try_recv() {
+            if let NodeMessage::DeepReorg(reorg) = message {
+                alarm = Some(reorg);
+            }
+        }
+        let alarm = alarm.unwrap();
+        assert_eq!(alarm.depth, 2);
+        assert_eq!(alarm.old_tip.height, 2);
+        assert_eq!(alarm.old_tip.hash, block_2.block_hash());
+        assert_eq!(alarm.new_tip.height, 3);
+        assert_eq!(alarm.new_tip.hash, block_3.block_hash());
+        // No forks are accepted until the client decides
+        let chain_sync = chain.sync_chain(batch_2).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::PendingReorg);
+        assert!(chain.confirm_reorg().await);
+        assert_eq!(chain.height(), 3);
+        assert_eq!(
+            chain.header_chain.values(),
+            vec![new_block_1, new_block_2, block_3]
+        );
+        assert!(!chain.confirm_reorg().await);
+        let chain_sync = chain.sync_chain(batch_1).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::LessWorkFork);
+    }
+
+    #[tokio::test]
+    async fn test_pending_reorg_accepts_extensions_and_rejects() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(32);
+        let reorg_config = ReorgConfig {
+            max_depth: 5,
+            alarm_depth: 2,
+            policy: ReorgPolicy::Halt,
+            ..Default::default()
+        };
+        let mut chain = new_regtest_reorg_config(gen, reorg_config, sender);
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f575b313ad3ef825cfc204c34da8f3c1fd1784e2553accfa38001010587cb57241f855e66ffff7f2000000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020c81cedd6a989939936f31448e49d010a13c2e750acf02d3fa73c9c7ecfb9476e798da2e5565335929ad303fc746acabc812ee8b06139bcf2a4c0eb533c21b8c420855e66ffff7f2000000000").unwrap()).unwrap();
+        let new_block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f575b313ad3ef825cfc204c34da8f3c1fd1784e2553accfa38001010587cb5724d5855e66ffff7f2004000000").unwrap()).unwrap();
+        let new_block_2: Header = deserialize(&hex::decode("00000020d1d80f53343a084bd0da6d6ab846f9fe4a133de051ea00e7cae16ed19f601065798da2e5565335929ad303fc746acabc812ee8b06139bcf2a4c0eb533c21b8c4d6855e66ffff7f2000000000").unwrap()).unwrap();
+        let new_block_3: Header = deserialize(&hex::decode("0000002080f38c14e898d6646dd426428472888966e0d279d86453f42edc56fdb143241aa66c8fa8837d95be3f85d53f22e86a0d6d456b1ab348e073da4d42a39f50637423865e66ffff7f2000000000").unwrap()).unwrap();
+        let fork = vec![new_block_1, new_block_2, new_block_3];
+        // Extend the current chain by mining a block on top of block 2
+        let mut block_3 = Header {
+            version: block_2.version,
+            prev_blockhash: block_2.block_hash(),
+            merkle_root: block_2.merkle_root,
+            time: block_2.time + 1,
+            bits: block_2.bits,
+            nonce: 0,
+        };
+        while block_3.validate_pow(block_3.target()).is_err() {
+            block_3.nonce += 1;
+        }
+        chain.sync_chain(vec![block_1, block_2]).await.unwrap();
+        let chain_sync = chain.sync_chain(fork.clone()).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::PendingReorg);
+        // Forks wait on the client, but the current chain may still be extended
+        let chain_sync = chain.sync_chain(fork.clone()).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::PendingReorg);
+        chain.sync_chain(vec![block_3]).await.unwrap();
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.tip(), block_3.block_hash());
+        // The fork no longer has more work than the current chain
+        assert!(!chain.confirm_reorg().await);
+        assert_eq!(chain.tip(), block_3.block_hash());
+        assert!(!chain.reject_reorg().await);
+        // Rejected forks are not evaluated again
+        let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(32);
+        let mut chain = new_regtest_reorg_config(gen, reorg_config, sender);
+        chain.sync_chain(vec![block_1, block_2]).await.unwrap();
+        let chain_sync = chain.sync_chain(fork.clone()).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::PendingReorg);
+        assert!(chain.reject_reorg().await);
+        assert!(!chain.confirm_reorg().await);
+        let chain_sync = chain.sync_chain(fork).await;
+        assert_eq!(chain_sync.err().unwrap(), HeaderSyncError::RejectedReorg);
+        assert_eq!(chain.tip(), block_2.block_hash());
+    }
+
+    #[tokio::test]
+    async fn test_reorg_exceeds_max_depth() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(1);
+        let reorg_config = ReorgConfig {
+            max_depth: 1,
+            alarm_depth: 1,
+            policy: ReorgPolicy::Follow,
+            ..Default::default()
+        };
+        let mut chain = new_regtest_reorg_config(gen, reorg_config, sender);
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f575b313ad3ef825cfc204c34da8f3c1fd1784e2553accfa38001010587cb57241f855e66ffff7f2000000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020c81cedd6a989939936f31448e49d010a13c2e750acf02d3fa73c9c7ecfb9476e798da2e5565335929ad303fc746acabc812ee8b06139bcf2a4c0eb533c21b8c420855e66ffff7f2000000000").unwrap()).unwrap();
+        let new_block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f575b313ad3ef825cfc204c34da8f3c1fd1784e2553accfa38001010587cb5724d5855e66ffff7f2004000000").unwrap()).unwrap();
+        let new_block_2: Header = deserialize(&hex::decode("00000020d1d80f53343a084bd0da6d6ab846f9fe4a133de051ea00e7cae16ed19f601065798da2e5565335929ad303fc746acabc812ee8b06139bcf2a4c0eb533c21b8c4d6855e66ffff7f2000000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("0000002080f38c14e898d6646dd426428472888966e0d279d86453f42edc56fdb143241aa66c8fa8837d95be3f85d53f22e86a0d6d456b1ab348e073da4d42a39f50637423865e66ffff7f2000000000").unwrap()).unwrap();
+        let chain_sync = chain.sync_chain(vec![block_1, block_2]).await;
+        assert!(chain_sync.is_ok());
+        let chain_sync = chain
+            .sync_chain(vec![new_block_1, new_block_2, block_3])
+            .await;
+        assert_eq!(
+            chain_sync.err().unwrap(),
+            HeaderSyncError::ExceedsMaxReorgDepth
+        );
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.header_chain.values(), vec![block_1, block_2]);
+    }
+
+    #[test]
+    fn test_scan_inputs() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen);
+        let header: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let watched = OutPoint::new(Txid::from_byte_array([1; 32]), 1);
+        let spend = |previous_output: OutPoint| Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: Vec::new(),
+        };
+        let block = Block {
+            header,
+            txdata: vec![
+                spend(OutPoint::new(Txid::from_byte_array([1; 32]), 0)),
+                spend(watched),
+            ],
+        };
+        assert!(chain.scan_inputs(&block, 8).is_empty());
+        chain.put_outpoint(watched, ScriptBuf::new());
+        let spends = chain.scan_inputs(&block, 8);
+        assert_eq!(spends.len(), 1);
+        assert_eq!(spends[0].outpoint, watched);
+        assert_eq!(spends[0].input_index, 0);
+        assert_eq!(spends[0].height, 8);
+        assert_eq!(spends[0].spending_tx, block.txdata[1]);
+        chain.remove_outpoint(&watched);
+        assert!(chain.scan_inputs(&block, 8).is_empty());
+    }
+
+    #[test]
+    fn test_scan_transactions() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen);
+        let header: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let script = ScriptBuf::from_bytes(vec![0x51]);
+        chain.put_script(script.clone());
+        let tx = |previous_output: OutPoint, script_pubkey: ScriptBuf| Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey,
+            }],
+        };
+        let receive = tx(OutPoint::null(), script.clone());
+        let unrelated = tx(OutPoint::null(), ScriptBuf::new());
+        let spend = tx(OutPoint::new(receive.compute_txid(), 0), ScriptBuf::new());
+        let mut block = Block {
+            header,
+            txdata: vec![unrelated, receive.clone(), spend.clone()],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let transactions = chain.scan_transactions(&block, 8);
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].transaction, receive);
+        assert_eq!(transactions[0].outputs, vec![0]);
+        assert!(transactions[0].inputs.is_empty());
+        assert_eq!(transactions[1].transaction, spend);
+        assert_eq!(transactions[1].inputs, vec![0]);
+        assert_eq!(transactions[1].matched_scripts, vec![script]);
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+        assert_eq!(transactions[1].proof.header, block.header);
+        assert!(transactions[1]
+            .proof
+            .extract_matches(&mut matches, &mut indexes)
+            .is_ok());
+        assert_eq!(matches, vec![receive.compute_txid(), spend.compute_txid()]);
+        assert_eq!(indexes, vec![1, 2]);
+        // Spent outputs are no longer remembered
+        assert!(chain.received.is_empty());
+        let block = Block {
+            header,
+            txdata: vec![receive.clone()],
+        };
+        chain.scan_transactions(&block, 8);
+        let later = tx(
+            OutPoint::new(spend.compute_txid(), 0),
+            ScriptBuf::from_bytes(vec![0x51]),
+        );
+        let block = Block {
+            header,
+            txdata: vec![later.clone()],
+        };
+        chain.scan_transactions(&block, 9);
+        assert_eq!(chain.received.len(), 2);
+        // The outputs received in the oldest blocks are forgotten first
+        chain.prune_received(1);
+        assert_eq!(chain.received.len(), 1);
+        assert!(chain
+            .received
+            .contains_key(&OutPoint::new(later.compute_txid(), 0)));
+        // Each script is only reported once per transaction
+        let other = ScriptBuf::from_bytes(vec![0x52]);
+        chain.put_script(other.clone());
+        let mut both = tx(OutPoint::new(later.compute_txid(), 0), other);
+        both.output.push(TxOut {
+            value: Amount::from_sat(1_000),
+            script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+        });
+        let block = Block {
+            header,
+            txdata: vec![both],
+        };
+        let transactions = chain.scan_transactions(&block, 10);
+        assert_eq!(transactions[0].inputs, vec![0]);
+        assert_eq!(transactions[0].outputs, vec![0, 1]);
+        assert_eq!(transactions[0].matched_scripts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_scanned_in_order() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let (sender, mut recv) = tokio::sync::broadcast::channel::<NodeMessage>(100);
+        let mut chain = new_regtest_reorg_config(gen, ReorgConfig::default(), sender);
+        let header: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let script = ScriptBuf::from_bytes(vec![0x51]);
+        chain.put_script(script.clone());
+        let funding = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: script,
+            }],
+        };
+        let spend = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(funding.compute_txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(900),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mine = |prev_blockhash: BlockHash, time: u32, txdata: Vec<Transaction>| {
+            let mut block = Block {
+                header: Header {
+                    prev_blockhash,
+                    time,
+                    nonce: 0,
+                    ..header
+                },
+                txdata,
+            };
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            while block.header.validate_pow(block.header.target()).is_err() {
+                block.header.nonce += 1;
+            }
+            block
+        };
+        let block_8 = mine(gen.hash, header.time, vec![funding.clone()]);
+        let block_9 = mine(block_8.block_hash(), header.time + 1, vec![spend.clone()]);
+        chain
+            .sync_chain(vec![block_8.header, block_9.header])
+            .await
+            .unwrap();
+        chain.block_queue.add(block_9.block_hash(), 9);
+        chain.block_queue.add(block_8.block_hash(), 8);
+        assert_eq!(chain.next_block(1), Some(block_8.block_hash()));
+        assert_eq!(chain.next_block(2), Some(block_9.block_hash()));
+        // Only the peer the block was requested from may send it
+        assert!(!chain.check_send_block(1, block_9.clone()).await.unwrap());
+        assert!(chain.block_queue.requested_from(2, &block_9.block_hash()));
+        // The spend arrives first, but is only scanned once the output it spends was seen
+        chain.check_send_block(2, block_9).await.unwrap();
+        assert!(!chain.block_queue_empty());
+        chain.check_send_block(1, block_8).await.unwrap();
+        assert!(chain.block_queue_empty());
+        let mut transactions = Vec::new();
+        while let Ok(message) = recv.try_recv() {
+            if let NodeMessage::Transaction(transaction) = message {
+                transactions.push((transaction.height, transaction.transaction));
+            }
+        }
+        assert_eq!(transactions, vec![(8, funding), (9, spend)]);
+    }
+
+    #[tokio::test]
+    async fn test_removed_scripts_drop_blocks() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen);
+        let header: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let script_1 = ScriptBuf::from_bytes(vec![0x51]);
+        let script_2 = ScriptBuf::from_bytes(vec![0x52]);
+        let block = |nonce: u32, script_pubkey: ScriptBuf| {
+            let mut header = header;
+            header.nonce = nonce;
+            let block = Block {
+                header,
+                txdata: vec![Transaction {
+                    version: transaction::Version::TWO,
+                    lock_time: absolute::LockTime::ZERO,
+                    input: vec![TxIn::default()],
+                    output: vec![TxOut {
+                        value: Amount::from_sat(1_000),
+                        script_pubkey,
+                    }],
+                }],
+            };
+            let filter = BlockFilter::new_script_filter(&block, |_| Ok(ScriptBuf::new())).unwrap();
+            (
+                block.block_hash(),
+                Filter::new(filter.content, block.block_hash()),
+            )
+        };
+        let (hash_1, filter_1) = block(1, script_1.clone());
+        let (hash_2, filter_2) = block(2, script_2.clone());
+        chain.put_script(script_1.clone());
+        chain.put_script(script_2.clone());
+        chain.block_queue.add(hash_1, 1);
+        chain.queued_filters.insert(hash_1, filter_1);
+        chain.block_queue.add(hash_2, 2);
+        chain.queued_filters.insert(hash_2, filter_2);
+        chain.remove_script(&script_1).await;
+        assert!(!chain.block_queue.contains(&hash_1));
+        assert!(chain.block_queue.contains(&hash_2));
+        assert_eq!(chain.scripts(), HashSet::from([script_2.clone()]));
+        chain
+            .received
+            .insert(OutPoint::null(), (script_2.clone(), 8));
+        chain.replace_scripts(HashSet::from([script_1])).await;
+        assert!(chain.block_queue.complete());
+        assert!(chain.queued_filters.is_empty());
+        // Spends of outputs to the removed script are no longer reported
+        assert!(chain.received.is_empty());
+    }
+
+    // Matched blocks are left to the client to request with filter control
+    #[tokio::test]
+    #[cfg(not(feature = "filter-control"))]
+    async fn test_rescan_uses_cached_filters() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen);
+        chain.filter_cache = FilterCache::new(10);
+        let block_8: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let block_9: Header = deserialize(&hex::decode("000000205708a90197d93475975545816b2229401ccff7567cb23900f14f2bd46732c605fd8de19615a1d687e89db365503cdf58cb649b8e935a1d3518fa79b0d408704e71375166ffff7f2000000000").unwrap()).unwrap();
+        let block_10: Header = deserialize(&hex::decode("000000201d062f2162835787db536c55317e08df17c58078c7610328bdced198574093790c9f554a7780a6043a19619d2a4697364bb62abf6336c0568c31f1eedca3c3e171375166ffff7f2000000000").unwrap()).unwrap();
+        chain
+            .sync_chain(vec![block_8, block_9, block_10])
+            .await
+            .unwrap();
+        let script = ScriptBuf::from_bytes(vec![0x51]);
+        let block = Block {
+            header: block_9,
+            txdata: vec![Transaction {
+                version: transaction::Version::TWO,
+                lock_time: absolute::LockTime::ZERO,
+                input: vec![TxIn::default()],
+                output: vec![TxOut {
+                    value: Amount::from_sat(1_000),
+                    script_pubkey: script.clone(),
+                }],
+            }],
+        };
+        let filter = BlockFilter::new_script_filter(&block, |_| Ok(ScriptBuf::new())).unwrap();
+        chain
+            .filter_cache
+            .insert(9, &Filter::new(filter.content, block_9.block_hash()));
+        chain.put_script(script);
+        // The sync has not requested any filters yet, so only the cached filter is checked
+        chain.rescan_range(0, Some(9)).await;
+        assert!(chain.block_queue.contains(&block_9.block_hash()));
+        assert!(!chain.is_rescanning());
+        let (start, stop) = chain.filter_scheduler.next_range(10).unwrap();
+        chain
+            .filter_scheduler
+            .assign(1, start, stop, block_10.block_hash());
+        assert!(chain.filter_scheduler.complete(1, &block_10.block_hash()));
+        // The filter headers start at the anchor, so only filters above it may be rescanned
+        chain.rescan_range(0, Some(9)).await;
+        assert!(chain.is_rescanning());
+        let rescan = chain.rescan.as_mut().unwrap();
+        assert_eq!(rescan.scheduler.next_range(10), Some((8, 8)));
+        assert_eq!(rescan.scheduler.next_range(10), None);
+        // Everything in the range is cached
+        chain.rescan = None;
+        chain.rescan_range(9, None).await;
+        assert!(chain.is_rescanning());
+        assert_eq!(
+            chain.rescan.as_mut().unwrap().scheduler.next_range(10),
+            Some((10, 10))
+        );
+        chain.rescan = None;
+        chain.rescan_range(9, Some(9)).await;
+        assert!(!chain.is_rescanning());
+    }
+
+    // Filters are not checked again for added scripts with filter control
+    #[tokio::test]
+    #[cfg(not(feature = "filter-control"))]
+    async fn test_added_script_catches_up() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let (sender, mut recv) = tokio::sync::broadcast::channel::<NodeMessage>(100);
+        let mut chain = new_regtest_reorg_config(gen, ReorgConfig::default(), sender);
+        chain.filter_cache = FilterCache::new(10);
+        let block_8: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let block_9: Header = deserialize(&hex::decode("000000205708a90197d93475975545816b2229401ccff7567cb23900f14f2bd46732c605fd8de19615a1d687e89db365503cdf58cb649b8e935a1d3518fa79b0d408704e71375166ffff7f2000000000").unwrap()).unwrap();
+        let block_10: Header = deserialize(&hex::decode("000000201d062f2162835787db536c55317e08df17c58078c7610328bdced198574093790c9f554a7780a6043a19619d2a4697364bb62abf6336c0568c31f1eedca3c3e171375166ffff7f2000000000").unwrap()).unwrap();
+        chain
+            .sync_chain(vec![block_8, block_9, block_10])
+            .await
+            .unwrap();
+        let old_script = ScriptBuf::from_bytes(vec![0x51]);
+        let new_script = ScriptBuf::from_bytes(vec![0x52]);
+        chain.put_script(old_script.clone());
+        // Both scripts are in block 9, but only the new script is in block 10
+        for (height, header, scripts) in [
+            (8, block_8, vec![]),
+            (9, block_9, vec![old_script.clone(), new_script.clone()]),
+            (10, block_10, vec![new_script.clone()]),
+        ] {
+            let block = Block {
+                header,
+                txdata: vec![Transaction {
+                    version: transaction::Version::TWO,
+                    lock_time: absolute::LockTime::ZERO,
+                    input: vec![TxIn::default()],
+                    output: scripts
+                        .into_iter()
+                        .map(|script_pubkey| TxOut {
+                            value: Amount::from_sat(1_000),
+                            script_pubkey,
+                        })
+                        .collect(),
+                }],
+            };
+            let filter = BlockFilter::new_script_filter(&block, |_| Ok(ScriptBuf::new())).unwrap();
+            chain
+                .filter_cache
+                .insert(height, &Filter::new(filter.content, header.block_hash()));
+        }
+        // No filters were checked yet
+        assert!(
+            !chain
+                .add_scripts(HashSet::from([ScriptBuf::from_bytes(vec![0x53])]), None)
+                .await
+        );
+        // Filters up to block 9 were requested
+        assert_eq!(chain.filter_scheduler.next_range(9), Some((8, 9)));
+        assert!(!chain.add_scripts(HashSet::from([old_script]), None).await);
+        // Only the filters the new script could be in are checked
+        assert!(
+            !chain
+                .add_scripts(HashSet::from([ScriptBuf::from_bytes(vec![0x54])]), Some(10))
+                .await
+        );
+        assert!(
+            chain
+                .add_scripts(HashSet::from([new_script.clone()]), None)
+                .await
+        );
+        assert!(chain.block_queue.contains(&block_9.block_hash()));
+        assert!(!chain.block_queue.contains(&block_10.block_hash()));
+        // Every filter was in the cache
+        assert!(!chain.is_rescanning());
+        let mut catch_up = None;
+        while let Ok(message) = recv.try_recv() {
+            if let NodeMessage::CatchUpComplete(update) = message {
+                catch_up = Some(update);
+            }
+        }
+        let catch_up = catch_up.unwrap();
+        assert_eq!(catch_up.scripts, vec![new_script]);
+        assert_eq!((catch_up.start_height, catch_up.end_height), (8, 9));
+    }
+
+    // New keychain scripts do not catch up on checked filters with filter control
+    #[tokio::test]
+    #[cfg(not(feature = "filter-control"))]
+    async fn test_used_keychain_script_extends_lookahead() {
+        let gen = HeaderCheckpoint::new(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let (sender, mut recv) = tokio::sync::broadcast::channel::<NodeMessage>(100);
+        let mut chain = new_regtest_reorg_config(gen, ReorgConfig::default(), sender);
+        chain.filter_cache = FilterCache::new(10);
+        let block_8: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let block_9: Header = deserialize(&hex::decode("000000205708a90197d93475975545816b2229401ccff7567cb23900f14f2bd46732c605fd8de19615a1d687e89db365503cdf58cb649b8e935a1d3518fa79b0d408704e71375166ffff7f2000000000").unwrap()).unwrap();
+        chain.sync_chain(vec![block_8, block_9]).await.unwrap();
+        let keychain = |gap_limit: u32| {
+            Keychain::new(
+                "external",
+                Xpub::from_str("xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V").unwrap(),
+                DerivationPath::from_str("m/0").unwrap(),
+                ScriptType::P2wpkh,
+            )
+            .unwrap()
+            .gap_limit(gap_limit)
+        };
+        // No filters were checked yet
+        assert!(!chain.add_keychain(keychain(1)).await);
+        assert_eq!(chain.scripts.len(), 1);
+        let first = chain.scripts.iter().next().cloned().unwrap();
+        let block = |header: Header, script_pubkey: ScriptBuf| Block {
+            header,
+            txdata: vec![Transaction {
+                version: transaction::Version::TWO,
+                lock_time: absolute::LockTime::ZERO,
+                input: vec![TxIn::default()],
+                output: vec![TxOut {
+                    value: Amount::from_sat(1_000),
+                    script_pubkey,
+                }],
+            }],
+        };
+        let block_with_first = block(block_8, first.clone());
+        // The next script is derived only after the first is used, but it was already in block 9
+        let second = KeychainTracker::new().add(keychain(2)).pop().unwrap();
+        let block_with_second = block(block_9, second.clone());
+        for (height, block) in [(8, &block_with_first), (9, &block_with_second)] {
+            let filter = BlockFilter::new_script_filter(block, |_| Ok(ScriptBuf::new())).unwrap();
+            chain.filter_cache.insert(
+                height,
+                &Filter::new(filter.content, block.header.block_hash()),
+            );
+        }
+        assert_eq!(chain.filter_scheduler.next_range(9), Some((8, 9)));
+        let transactions = chain.scan_transactions(&block_with_first, 8);
+        assert!(chain.update_keychains(&transactions, 8).await);
+        assert!(chain.scripts.contains(&second));
+        assert!(chain.block_queue.contains(&block_9.block_hash()));
+        let mut keychain_match = None;
+        while let Ok(message) = recv.try_recv() {
+            if let NodeMessage::KeychainMatch(update) = message {
+                keychain_match = Some(update);
+            }
+        }
+        let keychain_match = keychain_match.unwrap();
+        assert_eq!(keychain_match.keychain, "external");
+        assert_eq!(keychain_match.index, 0);
+        assert_eq!(keychain_match.script, first);
+        assert_eq!(keychain_match.height, 8);
+        // Replacing the scripts keeps the scripts derived from keychains
+        chain.replace_scripts(HashSet::new()).await;
+        assert!(chain.scripts.contains(&first));
+        assert!(chain.scripts.contains(&second));
+    }
+
     #[tokio::test]
     async fn test_filters_out_of_order() {
         let gen = HeaderCheckpoint::new(
@@ -1108,37 +2869,49 @@ mod tests {
         let append_attempt = cf_header_sync_res.unwrap();
         assert_eq!(AppendAttempt::Extended, append_attempt);
         assert!(chain.is_cf_headers_synced());
-        chain.next_filter_message().await;
+        chain.next_filter_message(0).await;
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_1.block_hash(),
-                filter: filter_1,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_1.block_hash(),
+                    filter: filter_1,
+                },
+            )
             .await;
         assert!(sync_filter_1.is_ok());
         let sync_filter_3 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_3.block_hash(),
-                filter: filter_3,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_3.block_hash(),
+                    filter: filter_3,
+                },
+            )
             .await;
         assert!(sync_filter_3.is_ok());
         let sync_filter_2 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_2.block_hash(),
-                filter: filter_2,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_2.block_hash(),
+                    filter: filter_2,
+                },
+            )
             .await;
         assert!(sync_filter_2.is_ok());
         let sync_filter_4 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_4.block_hash(),
-                filter: filter_4,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_4.block_hash(),
+                    filter: filter_4,
+                },
+            )
             .await;
         assert!(sync_filter_4.is_ok());
         assert!(chain.is_filters_synced());
@@ -1190,21 +2963,27 @@ mod tests {
         let append_attempt = cf_header_sync_res.unwrap();
         assert_eq!(AppendAttempt::Extended, append_attempt);
         assert!(chain.is_cf_headers_synced());
-        chain.next_filter_message().await;
+        chain.next_filter_message(0).await;
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_1.block_hash(),
-                filter: filter_2,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_1.block_hash(),
+                    filter: filter_2,
+                },
+            )
             .await;
         assert!(sync_filter_1.is_err());
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_1.block_hash(),
-                filter: filter_1,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_1.block_hash(),
+                    filter: filter_1,
+                },
+            )
             .await;
         assert!(sync_filter_1.is_ok());
     }
@@ -1270,21 +3049,27 @@ mod tests {
         let append_attempt = cf_header_sync_res.unwrap();
         assert_eq!(AppendAttempt::Extended, append_attempt);
         assert!(chain.is_cf_headers_synced());
-        chain.next_filter_message().await;
+        chain.next_filter_message(0).await;
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_2.block_hash(),
-                filter: filter_1.clone(),
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_2.block_hash(),
+                    filter: filter_1.clone(),
+                },
+            )
             .await;
         assert!(sync_filter_1.is_err());
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_1.block_hash(),
-                filter: filter_1,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_1.block_hash(),
+                    filter: filter_1,
+                },
+            )
             .await;
         assert!(sync_filter_1.is_ok());
     }
@@ -1560,29 +3345,38 @@ mod tests {
         let cf_header_sync_res = chain.sync_cf_headers(2, cf_headers).await;
         assert!(cf_header_sync_res.is_ok());
         assert_eq!(cf_header_sync_res.unwrap(), AppendAttempt::Extended);
-        chain.next_filter_message().await;
+        chain.next_filter_message(0).await;
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_1.block_hash(),
-                filter: filter_1,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_1.block_hash(),
+                    filter: filter_1,
+                },
+            )
             .await;
         assert!(sync_filter_1.is_ok());
         let sync_filter_4 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_4.block_hash(),
-                filter: filter_4,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_4.block_hash(),
+                    filter: filter_4,
+                },
+            )
             .await;
         assert!(sync_filter_4.is_err());
         let sync_filter_4 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: new_block_4.block_hash(),
-                filter: new_filter_4,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: new_block_4.block_hash(),
+                    filter: new_filter_4,
+                },
+            )
             .await;
         assert!(sync_filter_4.is_ok());
     }
@@ -1749,13 +3543,16 @@ mod tests {
         let cf_header_sync_res = chain.sync_cf_headers(0, cf_headers).await;
         assert!(cf_header_sync_res.is_ok());
         assert_eq!(cf_header_sync_res.unwrap(), AppendAttempt::Extended);
-        chain.next_filter_message().await;
+        chain.next_filter_message(0).await;
         let sync_filter_1 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_1.block_hash(),
-                filter: filter_1,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_1.block_hash(),
+                    filter: filter_1,
+                },
+            )
             .await;
         assert!(sync_filter_1.is_ok());
         // Reorganize the blocks
@@ -1791,20 +3588,38 @@ mod tests {
         let cf_header_sync_res = chain.sync_cf_headers(1, cf_headers).await;
         assert!(cf_header_sync_res.is_ok());
         assert_eq!(cf_header_sync_res.unwrap(), AppendAttempt::Extended);
+        // The first peer is still responding to a request made before the reorganization
         let sync_filter_4 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: block_4.block_hash(),
-                filter: filter_4,
-            })
+            .sync_filter(
+                0,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_4.block_hash(),
+                    filter: filter_4.clone(),
+                },
+            )
+            .await;
+        assert!(sync_filter_4.is_ok());
+        let sync_filter_4 = chain
+            .sync_filter(
+                1,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: block_4.block_hash(),
+                    filter: filter_4,
+                },
+            )
             .await;
         assert!(sync_filter_4.is_err());
         let sync_filter_4 = chain
-            .sync_filter(CFilter {
-                filter_type: 0x00,
-                block_hash: new_block_4.block_hash(),
-                filter: new_filter_4,
-            })
+            .sync_filter(
+                1,
+                CFilter {
+                    filter_type: 0x00,
+                    block_hash: new_block_4.block_hash(),
+                    filter: new_filter_4,
+                },
+            )
             .await;
         assert!(sync_filter_4.is_ok());
     }
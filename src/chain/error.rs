@@ -14,6 +14,9 @@ pub(crate) enum HeaderSyncError {
     InvalidBits,
     FloatingHeaders,
     LessWorkFork,
+    ExceedsMaxReorgDepth,
+    PendingReorg,
+    RejectedReorg,
     DbError,
 }
 
@@ -46,6 +49,18 @@ impl Display for HeaderSyncError {
             HeaderSyncError::LessWorkFork => {
                 write!(f, "a peer sent us a fork with less work than our chain.")
             }
+            HeaderSyncError::ExceedsMaxReorgDepth => {
+                write!(f, "a fork is deeper than the maximum reorganization depth.")
+            }
+            HeaderSyncError::PendingReorg => {
+                write!(
+                    f,
+                    "a deep reorganization is waiting on the client to be confirmed."
+                )
+            }
+            HeaderSyncError::RejectedReorg => {
+                write!(f, "the client rejected a deep reorganization to this fork.")
+            }
             HeaderSyncError::DbError => write!(f, "the database could not load a fork."),
             HeaderSyncError::InvalidBits => write!(
                 f,
@@ -57,10 +72,35 @@ impl Display for HeaderSyncError {
 
 impl_sourceless_error!(HeaderSyncError);
 
+impl HeaderSyncError {
+    // The misbehaviour score added to the peer that caused this error, out of a ban threshold of 100.
+    // Honest peers may send headers that do not connect or a stale fork while a reorganization propagates.
+    pub(crate) fn penalty(&self) -> u32 {
+        match self {
+            HeaderSyncError::InvalidHeaderWork
+            | HeaderSyncError::PreCheckpointFork
+            | HeaderSyncError::InvalidCheckpoint
+            | HeaderSyncError::MiscalculatedDifficulty
+            | HeaderSyncError::InvalidBits => 100,
+            HeaderSyncError::InvalidHeaderTimes => 50,
+            HeaderSyncError::HeadersNotConnected | HeaderSyncError::FloatingHeaders => 20,
+            HeaderSyncError::LessWorkFork => 10,
+            HeaderSyncError::EmptyMessage
+            | HeaderSyncError::ExceedsMaxReorgDepth
+            | HeaderSyncError::PendingReorg
+            | HeaderSyncError::RejectedReorg
+            | HeaderSyncError::DbError => 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum BlockScanError {
     NoBlockHash,
     InvalidMerkleRoot,
+    InvalidWitnessCommitment,
+    InvalidCoinbaseHeight,
+    FilterMismatch,
 }
 
 impl Display for BlockScanError {
@@ -72,8 +112,37 @@ impl Display for BlockScanError {
             BlockScanError::InvalidMerkleRoot => {
                 write!(f, "the block sent to us does not have a merkle root that matches its header commitment.")
             }
+            BlockScanError::InvalidWitnessCommitment => {
+                write!(f, "the block sent to us does not have witness data that matches its coinbase commitment.")
+            }
+            BlockScanError::InvalidCoinbaseHeight => {
+                write!(
+                    f,
+                    "the block sent to us does not commit to its height in the coinbase."
+                )
+            }
+            BlockScanError::FilterMismatch => {
+                write!(
+                    f,
+                    "the block sent to us has output scripts missing from its compact filter."
+                )
+            }
         }
     }
 }
 
 impl_sourceless_error!(BlockScanError);
+
+impl BlockScanError {
+    // The misbehaviour score added to the peer that sent the block, out of a ban threshold of 100.
+    // A block that does not match its filter may be the fault of the peer that served the filter.
+    pub(crate) fn penalty(&self) -> u32 {
+        match self {
+            BlockScanError::InvalidMerkleRoot
+            | BlockScanError::InvalidWitnessCommitment
+            | BlockScanError::InvalidCoinbaseHeight => 100,
+            BlockScanError::FilterMismatch => 50,
+            BlockScanError::NoBlockHash => 20,
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use bitcoin::{
+    bip32::{ChildNumber, DerivationPath, Xpub},
+    key::Secp256k1,
+    secp256k1::VerifyOnly,
+    ScriptBuf, Txid,
+};
+
+use crate::impl_sourceless_error;
+
+const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// The type of script to derive from each public key of a [`Keychain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScriptType {
+    /// Pay to public key hash.
+    P2pkh,
+    /// Pay to witness public key hash, nested in a pay to script hash.
+    P2shP2wpkh,
+    /// Pay to witness public key hash.
+    P2wpkh,
+    /// Pay to taproot, with the derived key as the internal key and no script tree, as in BIP86.
+    P2tr,
+}
+
+/// Scripts derived from an extended public key, watched up to a gap limit past the last used index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keychain {
+    /// A human readable name for the keychain, like `external`.
+    pub name: String,
+    /// The extended public key to derive from.
+    pub xpub: Xpub,
+    /// The path from the extended public key to the parent of each derived key, like `m/0`.
+    pub path: DerivationPath,
+    /// The type of script to derive.
+    pub script_type: ScriptType,
+    /// The number of unused scripts to watch past the last used index.
+    pub gap_limit: u32,
+}
+
+impl Keychain {
+    /// Create a new keychain to watch. Keys are derived at `path/i` from the extended public key.
+    ///
+    /// # Errors
+    ///
+    /// If the path contains a hardened step, which may not be derived from a public key.
+    pub fn new(
+        name: impl Into<String>,
+        xpub: Xpub,
+        path: DerivationPath,
+        script_type: ScriptType,
+    ) -> Result<Self, KeychainError> {
+        if path.into_iter().any(|child| child.is_hardened()) {
+            return Err(KeychainError::HardenedPath);
+        }
+        Ok(Self {
+            name: name.into(),
+            xpub,
+            path,
+            script_type,
+            gap_limit: DEFAULT_GAP_LIMIT,
+        })
+    }
+
+    /// The number of unused scripts to watch past the last used index.
+    /// If none is provided, a gap limit of 20 is used.
+    pub fn gap_limit(mut self, gap_limit: u32) -> Self {
+        self.gap_limit = gap_limit;
+        self
+    }
+}
+
+/// Errors creating a [`Keychain`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeychainError {
+    /// The derivation path contains a hardened step.
+    HardenedPath,
+}
+
+impl core::fmt::Display for KeychainError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KeychainError::HardenedPath => write!(
+                f,
+                "hardened keys may not be derived from an extended public key."
+            ),
+        }
+    }
+}
+
+impl_sourceless_error!(KeychainError);
+
+/// A transaction paid to or spent from a script derived from a [`Keychain`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeychainMatch {
+    /// The name of the keychain.
+    pub keychain: String,
+    /// The derivation index of the script.
+    pub index: u32,
+    /// The script that matched.
+    pub script: ScriptBuf,
+    /// The transaction that paid to or spent from the script.
+    pub txid: Txid,
+    /// The height of the block that includes the transaction.
+    pub height: u32,
+}
+
+#[derive(Debug)]
+struct KeychainState {
+    keychain: Keychain,
+    // The next index to derive
+    derived: u32,
+}
+
+// Derives scripts for each keychain, and extends the lookahead as scripts are used.
+#[derive(Debug)]
+pub(crate) struct KeychainTracker {
+    secp: Secp256k1<VerifyOnly>,
+    keychains: Vec<KeychainState>,
+    scripts: HashMap<ScriptBuf, (usize, u32)>,
+}
+
+impl KeychainTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            secp: Secp256k1::verification_only(),
+            keychains: Vec::new(),
+            scripts: HashMap::new(),
+        }
+    }
+
+    // Start watching a keychain, returning the scripts up to the gap limit
+    pub(crate) fn add(&mut self, keychain: Keychain) -> Vec<ScriptBuf> {
+        let gap_limit = keychain.gap_limit;
+        self.keychains.push(KeychainState {
+            keychain,
+            derived: 0,
+        });
+        self.derive_up_to(self.keychains.len() - 1, gap_limit)
+    }
+
+    // Every script derived so far
+    pub(crate) fn scripts(&self) -> impl Iterator<Item = &ScriptBuf> {
+        self.scripts.keys()
+    }
+
+    // The keychain name and derivation index of a script
+    pub(crate) fn lookup(&self, script: &ScriptBuf) -> Option<(&str, u32)> {
+        self.scripts
+            .get(script)
+            .map(|(keychain, index)| (self.keychains[*keychain].keychain.name.as_str(), *index))
+    }
+
+    // A script was used, so make sure there are enough scripts derived past it. Returns any new scripts.
+    pub(crate) fn mark_used(&mut self, script: &ScriptBuf) -> Vec<ScriptBuf> {
+        match self.scripts.get(script).copied() {
+            Some((keychain, index)) => {
+                let gap_limit = self.keychains[keychain].keychain.gap_limit;
+                self.derive_up_to(keychain, index.saturating_add(gap_limit).saturating_add(1))
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn derive_up_to(&mut self, keychain: usize, end: u32) -> Vec<ScriptBuf> {
+        let mut new_scripts = Vec::new();
+        while self.keychains[keychain].derived < end {
+            let index = self.keychains[keychain].derived;
+            self.keychains[keychain].derived += 1;
+            if let Some(script) = self.derive(&self.keychains[keychain].keychain, index) {
+                self.scripts.insert(script.clone(), (keychain, index));
+                new_scripts.push(script);
+            }
+        }
+        new_scripts
+    }
+
+    fn derive(&self, keychain: &Keychain, index: u32) -> Option<ScriptBuf> {
+        let child = ChildNumber::from_normal_idx(index).ok()?;
+        let path = keychain.path.child(child);
+        let xpub = keychain.xpub.derive_pub(&self.secp, &path).ok()?;
+        let script = match keychain.script_type {
+            ScriptType::P2pkh => ScriptBuf::new_p2pkh(&xpub.to_pub().pubkey_hash()),
+            ScriptType::P2shP2wpkh => {
+                let redeem_script = ScriptBuf::new_p2wpkh(&xpub.to_pub().wpubkey_hash());
+                ScriptBuf::new_p2sh(&redeem_script.script_hash())
+            }
+            ScriptType::P2wpkh => ScriptBuf::new_p2wpkh(&xpub.to_pub().wpubkey_hash()),
+            ScriptType::P2tr => ScriptBuf::new_p2tr(&self.secp, xpub.to_x_only_pub(), None),
+        };
+        Some(script)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        bip32::{DerivationPath, Xpub},
+        Address, Network,
+    };
+
+    use super::{Keychain, KeychainError, KeychainTracker, ScriptType};
+
+    // The account level public key of the BIP84 test vector
+    const BIP84_XPUB: &str = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+
+    #[test]
+    fn test_derives_bip84_addresses() {
+        let xpub = Xpub::from_str(BIP84_XPUB).unwrap();
+        let keychain = Keychain::new(
+            "external",
+            xpub,
+            DerivationPath::from_str("m/0").unwrap(),
+            ScriptType::P2wpkh,
+        )
+        .unwrap()
+        .gap_limit(2);
+        let mut tracker = KeychainTracker::new();
+        let scripts = tracker.add(keychain);
+        assert_eq!(scripts.len(), 2);
+        let first = Address::from_str("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu")
+            .unwrap()
+            .require_network(Network::Bitcoin)
+            .unwrap()
+            .script_pubkey();
+        let second = Address::from_str("bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g")
+            .unwrap()
+            .require_network(Network::Bitcoin)
+            .unwrap()
+            .script_pubkey();
+        assert_eq!(scripts, vec![first.clone(), second.clone()]);
+        assert_eq!(tracker.lookup(&second), Some(("external", 1)));
+        // Using the last script extends the lookahead
+        let new_scripts = tracker.mark_used(&second);
+        assert_eq!(new_scripts.len(), 2);
+        assert_eq!(tracker.lookup(&new_scripts[1]), Some(("external", 3)));
+        assert!(tracker.mark_used(&first).is_empty());
+    }
+
+    #[test]
+    fn test_hardened_path_is_rejected() {
+        let xpub = Xpub::from_str(BIP84_XPUB).unwrap();
+        let keychain = Keychain::new(
+            "external",
+            xpub,
+            DerivationPath::from_str("m/0'").unwrap(),
+            ScriptType::P2tr,
+        );
+        assert_eq!(keychain.unwrap_err(), KeychainError::HardenedPath);
+    }
+}
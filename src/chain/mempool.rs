@@ -0,0 +1,224 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+
+use bitcoin::{Block, OutPoint, ScriptBuf, Transaction, Txid};
+use tokio::time::Instant;
+
+// Bitcoin Core removes transactions from the mempool after two weeks
+const MEMPOOL_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24 * 14);
+// Peers announce a transaction at about the same time, so a request is only remembered for a while
+const REQUEST_MEMORY: Duration = Duration::from_secs(60 * 10);
+// Forget the oldest requests past this many, so memory stays bounded
+const MAX_REQUESTED: usize = 50_000;
+
+#[derive(Debug)]
+struct Unconfirmed {
+    transaction: Transaction,
+    received: Instant,
+}
+
+// Relevant transactions that were relayed by peers but are not yet in a block.
+#[derive(Debug)]
+pub(crate) struct Mempool {
+    requested: HashSet<Txid>,
+    // The requested transactions, oldest first
+    request_order: VecDeque<(Txid, Instant)>,
+    unconfirmed: HashMap<Txid, Unconfirmed>,
+    // Outputs of unconfirmed transactions that pay to a watched script
+    received: HashMap<OutPoint, ScriptBuf>,
+}
+
+impl Mempool {
+    pub(crate) fn new() -> Self {
+        Self {
+            requested: HashSet::new(),
+            request_order: VecDeque::new(),
+            unconfirmed: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    // Should this announced transaction be requested. Each transaction is only requested once while the request is remembered.
+    pub(crate) fn need(&mut self, txid: Txid) -> bool {
+        if self.unconfirmed.contains_key(&txid) {
+            return false;
+        }
+        if self.requested.contains(&txid) {
+            return false;
+        }
+        self.forget_requests();
+        self.requested.insert(txid);
+        self.request_order.push_back((txid, Instant::now()));
+        true
+    }
+
+    // Forget the requests that were made long ago, or the oldest requests if there are too many
+    fn forget_requests(&mut self) {
+        while let Some((txid, requested)) = self.request_order.front() {
+            if requested.elapsed() < REQUEST_MEMORY && self.request_order.len() < MAX_REQUESTED {
+                break;
+            }
+            self.requested.remove(txid);
+            self.request_order.pop_front();
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.unconfirmed.is_empty()
+    }
+
+    pub(crate) fn contains(&self, txid: &Txid) -> bool {
+        self.unconfirmed.contains_key(txid)
+    }
+
+    // The script of an unconfirmed output that pays to a watched script
+    pub(crate) fn received(&self, outpoint: &OutPoint) -> Option<&ScriptBuf> {
+        self.received.get(outpoint)
+    }
+
+    // Track a relevant transaction, along with the outputs that pay to watched scripts
+    pub(crate) fn insert(&mut self, transaction: Transaction, outputs: &[usize]) {
+        let txid = transaction.compute_txid();
+        for index in outputs {
+            if let Some(output) = transaction.output.get(*index) {
+                self.received.insert(
+                    OutPoint::new(txid, *index as u32),
+                    output.script_pubkey.clone(),
+                );
+            }
+        }
+        self.unconfirmed.insert(
+            txid,
+            Unconfirmed {
+                transaction,
+                received: Instant::now(),
+            },
+        );
+    }
+
+    // Remove the transactions that were included in the block, and any transactions that spend the same
+    // outputs as a transaction in the block. Returns the confirmed and dropped transactions.
+    pub(crate) fn resolve(&mut self, block: &Block) -> (Vec<Txid>, Vec<Txid>) {
+        if self.unconfirmed.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let mut confirmed = Vec::new();
+        let mut spent = HashSet::new();
+        for tx in block.txdata.iter() {
+            let txid = tx.compute_txid();
+            if self.unconfirmed.contains_key(&txid) {
+                confirmed.push(txid);
+                self.remove(&txid);
+            } else {
+                spent.extend(tx.input.iter().map(|input| input.previous_output));
+            }
+        }
+        let dropped: Vec<Txid> = self
+            .unconfirmed
+            .iter()
+            .filter(|(_, unconfirmed)| {
+                unconfirmed
+                    .transaction
+                    .input
+                    .iter()
+                    .any(|input| spent.contains(&input.previous_output))
+            })
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in dropped.iter() {
+            self.remove(txid);
+        }
+        (confirmed, dropped)
+    }
+
+    // Remove the transactions that have been unconfirmed for longer than peers keep them
+    pub(crate) fn expire(&mut self) -> Vec<Txid> {
+        let expired: Vec<Txid> = self
+            .unconfirmed
+            .iter()
+            .filter(|(_, unconfirmed)| unconfirmed.received.elapsed() > MEMPOOL_EXPIRY)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in expired.iter() {
+            self.remove(txid);
+        }
+        expired
+    }
+
+    fn remove(&mut self, txid: &Txid) {
+        self.unconfirmed.remove(txid);
+        self.received.retain(|outpoint, _| outpoint.txid.ne(txid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute, block::Header, consensus::deserialize, hashes::Hash, transaction, Amount, Block,
+        OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Txid,
+    };
+
+    use super::Mempool;
+
+    #[test]
+    fn test_resolves_confirmed_and_conflicting_transactions() {
+        let header: Header = deserialize(&hex::decode("0000002016fe292517eecbbd63227d126a6b1db30ebc5262c61f8f3a4a529206388fc262dfd043cef8454f71f30b5bbb9eb1a4c9aea87390f429721e435cf3f8aa6e2a9171375166ffff7f2000000000").unwrap()).unwrap();
+        let tx = |previous_output: OutPoint, value: u64| Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+            }],
+        };
+        let funding = tx(OutPoint::null(), 1_000);
+        let spend = tx(OutPoint::new(funding.compute_txid(), 0), 900);
+        let double_spend = tx(OutPoint::new(funding.compute_txid(), 0), 800);
+        let mut mempool = Mempool::new();
+        assert!(mempool.need(funding.compute_txid()));
+        assert!(!mempool.need(funding.compute_txid()));
+        mempool.insert(funding.clone(), &[0]);
+        mempool.insert(spend.clone(), &[0]);
+        assert!(mempool
+            .received(&OutPoint::new(funding.compute_txid(), 0))
+            .is_some());
+        let block = Block {
+            header,
+            txdata: vec![funding.clone(), double_spend],
+        };
+        let (confirmed, dropped) = mempool.resolve(&block);
+        assert_eq!(confirmed, vec![funding.compute_txid()]);
+        assert_eq!(dropped, vec![spend.compute_txid()]);
+        assert!(!mempool.contains(&spend.compute_txid()));
+        assert!(mempool
+            .received(&OutPoint::new(funding.compute_txid(), 0))
+            .is_none());
+        assert!(mempool.expire().is_empty());
+    }
+
+    #[test]
+    fn test_oldest_requests_are_forgotten() {
+        let mut mempool = Mempool::new();
+        for i in 0..super::MAX_REQUESTED {
+            assert!(mempool.need(Txid::from_byte_array(index_bytes(i))));
+        }
+        assert!(!mempool.need(Txid::from_byte_array(index_bytes(1))));
+        assert!(mempool.need(Txid::from_byte_array(index_bytes(super::MAX_REQUESTED))));
+        // The oldest request was forgotten to make room
+        assert!(mempool.need(Txid::from_byte_array(index_bytes(0))));
+        assert!(!mempool.need(Txid::from_byte_array(index_bytes(2))));
+        assert_eq!(mempool.requested.len(), super::MAX_REQUESTED);
+    }
+
+    fn index_bytes(index: usize) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(&(index as u64).to_le_bytes());
+        bytes
+    }
+}
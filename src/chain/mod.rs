@@ -2,6 +2,8 @@
 //!
 //! Notably, [`checkpoints`] contains known Bitcoin block hashes and heights with significant work, so Kyoto nodes do not have to sync from genesis.
 
+pub(crate) mod block_check;
+pub(crate) mod block_fetch;
 pub(crate) mod block_queue;
 #[allow(clippy::module_inception)]
 pub(crate) mod chain;
@@ -12,3 +14,9 @@ pub mod checkpoints;
 pub(crate) mod error;
 pub(crate) mod header_batch;
 pub(crate) mod header_chain;
+/// Watching scripts derived from BIP32 extended public keys.
+pub mod keychain;
+pub(crate) mod mempool;
+pub(crate) mod serve;
+/// Soft-fork deployment tracking with BIP9 version bits.
+pub mod versionbits;
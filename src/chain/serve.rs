@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use bitcoin::{
+    hashes::Hash,
+    p2p::message_filter::{CFHeaders, CFilter},
+    BlockHash, FilterHeader,
+};
+use tokio::sync::Mutex;
+
+use crate::db::{traits::HeaderStore, PersistedFilter};
+
+// Filters are read from the database one at a time and without holding the chain, so serving a peer
+// does not hold up the node.
+
+// A filter saved for the block at this height in the chain of most work
+async fn persisted_filter<H: HeaderStore>(
+    db: &Mutex<H>,
+    height: u32,
+    block_hash: &BlockHash,
+) -> Option<PersistedFilter> {
+    let mut db = db.lock().await;
+    db.filter_at(height)
+        .await
+        .ok()
+        .flatten()
+        .filter(|filter| filter.block_hash.eq(block_hash))
+}
+
+// The persisted filter headers for a range of blocks, if every one of them is saved. Unless the range
+// starts at genesis, the first block is the one before the range, which commits to the previous filter header.
+pub(crate) async fn filter_headers<H: HeaderStore>(
+    db: Arc<Mutex<H>>,
+    filter_type: u8,
+    start_height: u32,
+    stop_hash: BlockHash,
+    blocks: Vec<(u32, BlockHash)>,
+) -> Option<CFHeaders> {
+    let mut blocks = blocks.into_iter();
+    let previous_filter_header = match start_height {
+        0 => FilterHeader::all_zeros(),
+        _ => {
+            let (height, block_hash) = blocks.next()?;
+            persisted_filter(&db, height, &block_hash)
+                .await?
+                .filter_header
+        }
+    };
+    let mut filter_hashes = Vec::new();
+    for (height, block_hash) in blocks {
+        filter_hashes.push(
+            persisted_filter(&db, height, &block_hash)
+                .await?
+                .filter_hash,
+        );
+    }
+    Some(CFHeaders {
+        filter_type,
+        stop_hash,
+        previous_filter_header,
+        filter_hashes,
+    })
+}
+
+// The persisted filters for a range of blocks, if every one of them is saved
+pub(crate) async fn filters<H: HeaderStore>(
+    db: Arc<Mutex<H>>,
+    filter_type: u8,
+    blocks: Vec<(u32, BlockHash)>,
+) -> Vec<CFilter> {
+    let mut filters = Vec::new();
+    for (height, block_hash) in blocks {
+        match persisted_filter(&db, height, &block_hash).await {
+            Some(PersistedFilter {
+                filter: Some(filter),
+                ..
+            }) => filters.push(CFilter {
+                filter_type,
+                block_hash,
+                filter,
+            }),
+            _ => return Vec::new(),
+        }
+    }
+    filters
+}
@@ -0,0 +1,422 @@
+use std::collections::BTreeMap;
+
+use bitcoin::{block::Header, Network};
+
+/// A soft-fork deployment signalled with BIP9 version bits.
+///
+/// The state is only evaluated from the headers after the anchor checkpoint. The state of a deployment that
+/// started before the anchor, like any deployment already active at the most recent checkpoint, cannot be
+/// determined unless it is provided with [`Deployment::known_state`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployment {
+    /// A human readable name for the deployment, like `taproot`.
+    pub name: String,
+    /// The bit in the block version used to signal for the deployment.
+    pub bit: u8,
+    /// The median time past at which signalling begins.
+    pub start_time: u32,
+    /// The median time past at which the deployment fails if it has not locked in.
+    pub timeout: u32,
+    /// The earliest height the deployment may become active.
+    pub min_activation_height: u32,
+    /// The number of signalling blocks required in a period to lock in the deployment.
+    /// If none is provided, the network default is used.
+    pub threshold: Option<u32>,
+    /// The state of the deployment at the anchor checkpoint, for deployments that started before it.
+    pub known_state: Option<DeploymentState>,
+}
+
+impl Deployment {
+    /// Create a new deployment to track.
+    pub fn new(name: impl Into<String>, bit: u8, start_time: u32, timeout: u32) -> Self {
+        Self {
+            name: name.into(),
+            bit,
+            start_time,
+            timeout,
+            min_activation_height: 0,
+            threshold: None,
+            known_state: None,
+        }
+    }
+
+    /// The earliest height the deployment may become active, as in BIP341.
+    pub fn min_activation_height(mut self, height: u32) -> Self {
+        self.min_activation_height = height;
+        self
+    }
+
+    /// The number of signalling blocks required in a period to lock in the deployment,
+    /// for deployments that do not use the network default.
+    pub fn threshold(mut self, threshold: u32) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    /// The state of the deployment as of the anchor checkpoint, like [`DeploymentState::Active`] for a
+    /// deployment that activated before it. States are followed from this state, and are reported starting
+    /// at the first period after the anchor.
+    pub fn known_state(mut self, state: DeploymentState) -> Self {
+        self.known_state = Some(state);
+        self
+    }
+}
+
+/// The state of a soft-fork deployment, as defined in BIP9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeploymentState {
+    /// The deployment has not started yet.
+    Defined,
+    /// Miners may signal for the deployment.
+    Started,
+    /// Enough blocks signalled for the deployment, which will become active.
+    LockedIn,
+    /// The rules of the deployment are enforced.
+    Active,
+    /// The deployment timed out before locking in.
+    Failed,
+}
+
+impl core::fmt::Display for DeploymentState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DeploymentState::Defined => write!(f, "DEFINED"),
+            DeploymentState::Started => write!(f, "STARTED"),
+            DeploymentState::LockedIn => write!(f, "LOCKED_IN"),
+            DeploymentState::Active => write!(f, "ACTIVE"),
+            DeploymentState::Failed => write!(f, "FAILED"),
+        }
+    }
+}
+
+/// The state of a deployment, starting at a given height.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeploymentUpdate {
+    /// The name of the deployment.
+    pub name: String,
+    /// The state of the deployment.
+    pub state: DeploymentState,
+    /// The first block height the state applies to.
+    pub height: u32,
+}
+
+#[derive(Debug)]
+struct TrackedDeployment {
+    deployment: Deployment,
+    threshold: u32,
+    // The state of the blocks after each period, indexed by the height of the last block in the period.
+    // If a state could not be determined from the headers available, the entry is empty.
+    states: BTreeMap<u32, Option<DeploymentState>>,
+    // The last state a client was informed of
+    reported: Option<DeploymentState>,
+}
+
+impl TrackedDeployment {
+    // Before any period after the anchor is evaluated, the state provided by the client is used
+    fn current(&self) -> Option<DeploymentState> {
+        match self.states.values().last() {
+            Some(state) => *state,
+            None => self.deployment.known_state,
+        }
+    }
+
+    // The first height of the most recent run of the current state
+    fn current_since(&self) -> Option<DeploymentUpdate> {
+        let state = self.current()?;
+        let since = self
+            .states
+            .iter()
+            .rev()
+            .take_while(|(_, next)| **next == Some(state))
+            .map(|(boundary, _)| *boundary)
+            .last()?;
+        Some(DeploymentUpdate {
+            name: self.deployment.name.clone(),
+            state,
+            height: since + 1,
+        })
+    }
+
+    fn next_state(
+        &self,
+        boundary: u32,
+        median_time_past: Option<u32>,
+        period: Option<&[Header]>,
+    ) -> Option<DeploymentState> {
+        let mtp = median_time_past?;
+        let deployment = &self.deployment;
+        match self.current() {
+            // The median time past is non-decreasing, so no earlier period could have started
+            None => {
+                if mtp < deployment.start_time {
+                    Some(DeploymentState::Defined)
+                } else {
+                    None
+                }
+            }
+            Some(DeploymentState::Defined) => {
+                if mtp >= deployment.start_time {
+                    Some(DeploymentState::Started)
+                } else {
+                    Some(DeploymentState::Defined)
+                }
+            }
+            Some(DeploymentState::Started) => {
+                let signals = period?
+                    .iter()
+                    .filter(|header| header.version.is_signalling_soft_fork(deployment.bit))
+                    .count() as u32;
+                if signals >= self.threshold {
+                    Some(DeploymentState::LockedIn)
+                } else if mtp >= deployment.timeout {
+                    Some(DeploymentState::Failed)
+                } else {
+                    Some(DeploymentState::Started)
+                }
+            }
+            Some(DeploymentState::LockedIn) => {
+                if boundary + 1 >= deployment.min_activation_height {
+                    Some(DeploymentState::Active)
+                } else {
+                    Some(DeploymentState::LockedIn)
+                }
+            }
+            Some(DeploymentState::Active) => Some(DeploymentState::Active),
+            Some(DeploymentState::Failed) => Some(DeploymentState::Failed),
+        }
+    }
+}
+
+// Follow the state of soft-fork deployments for every period of the header chain.
+#[derive(Debug)]
+pub(crate) struct DeploymentTracker {
+    deployments: Vec<TrackedDeployment>,
+    period: u32,
+    first_boundary: u32,
+    last_boundary: Option<u32>,
+}
+
+impl DeploymentTracker {
+    pub(crate) fn new(network: Network, deployments: Vec<Deployment>, anchor_height: u32) -> Self {
+        let params = network.params();
+        let period = params.miner_confirmation_window;
+        let deployments = deployments
+            .into_iter()
+            .map(|deployment| TrackedDeployment {
+                threshold: deployment
+                    .threshold
+                    .unwrap_or(params.rule_change_activation_threshold),
+                deployment,
+                states: BTreeMap::new(),
+                reported: None,
+            })
+            .collect();
+        // The first period that has every block after the anchor
+        let first_boundary = ((anchor_height + 2 * period) / period) * period - 1;
+        Self {
+            deployments,
+            period,
+            first_boundary,
+            last_boundary: None,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.deployments.is_empty()
+    }
+
+    pub(crate) fn period(&self) -> u32 {
+        self.period
+    }
+
+    // The height of the last block in the next period to evaluate
+    pub(crate) fn next_boundary(&self) -> u32 {
+        match self.last_boundary {
+            Some(boundary) => boundary + self.period,
+            None => self.first_boundary,
+        }
+    }
+
+    // Signalling only has to be counted if a deployment has started
+    pub(crate) fn needs_signals(&self) -> bool {
+        self.deployments
+            .iter()
+            .any(|tracked| matches!(tracked.current(), Some(DeploymentState::Started)))
+    }
+
+    // Evaluate the next period, returning any changes in deployment state
+    pub(crate) fn apply(
+        &mut self,
+        boundary: u32,
+        median_time_past: Option<u32>,
+        period: &[Header],
+    ) -> Vec<DeploymentUpdate> {
+        let mut updates = Vec::new();
+        // Signals cannot be counted if any header in the period is missing
+        let period = (period.len() as u32 == self.period).then_some(period);
+        for tracked in self.deployments.iter_mut() {
+            let next = tracked.next_state(boundary, median_time_past, period);
+            tracked.states.insert(boundary, next);
+            if let Some(state) = next {
+                if tracked.reported.ne(&Some(state)) {
+                    tracked.reported = Some(state);
+                    updates.push(DeploymentUpdate {
+                        name: tracked.deployment.name.clone(),
+                        state,
+                        height: boundary + 1,
+                    });
+                }
+            }
+        }
+        self.last_boundary = Some(boundary);
+        updates
+    }
+
+    // Forget the periods that include blocks after the stem of a reorganization
+    pub(crate) fn rewind(&mut self, stem: u32) {
+        for tracked in self.deployments.iter_mut() {
+            tracked.states.retain(|boundary, _| *boundary <= stem);
+        }
+        if let Some(last) = self.last_boundary {
+            if last > stem {
+                let first = self.first_boundary;
+                self.last_boundary = stem
+                    .checked_sub(first)
+                    .map(|distance| first + distance / self.period * self.period);
+            }
+        }
+    }
+
+    // The current state of every deployment with a known state
+    pub(crate) fn states(&self) -> Vec<DeploymentUpdate> {
+        self.deployments
+            .iter()
+            .filter_map(|tracked| tracked.current_since())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        block::{Header, Version},
+        hashes::Hash,
+        BlockHash, CompactTarget, Network, TxMerkleNode,
+    };
+
+    use super::{Deployment, DeploymentState, DeploymentTracker};
+
+    const REGTEST_PERIOD: u32 = 144;
+
+    fn header(version: i32) -> Header {
+        Header {
+            version: Version::from_consensus(version),
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        }
+    }
+
+    fn period(signalling: usize) -> Vec<Header> {
+        let mut headers = vec![header(0x2000_0004); signalling];
+        headers.extend(vec![
+            header(0x2000_0000);
+            REGTEST_PERIOD as usize - signalling
+        ]);
+        headers
+    }
+
+    #[test]
+    fn test_deployment_activates() {
+        let deployment = Deployment::new("test", 2, 1_000, 5_000).min_activation_height(1_000);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        assert_eq!(tracker.next_boundary(), 287);
+        let updates = tracker.apply(287, Some(500), &[]);
+        assert_eq!(updates[0].state, DeploymentState::Defined);
+        assert!(!tracker.needs_signals());
+        let updates = tracker.apply(431, Some(1_500), &[]);
+        assert_eq!(updates[0].state, DeploymentState::Started);
+        assert_eq!(updates[0].height, 432);
+        assert!(tracker.needs_signals());
+        // Regtest requires 108 of 144 blocks to signal
+        let updates = tracker.apply(575, Some(2_000), &period(107));
+        assert!(updates.is_empty());
+        let updates = tracker.apply(719, Some(2_500), &period(108));
+        assert_eq!(updates[0].state, DeploymentState::LockedIn);
+        // The minimum activation height is not yet reached
+        let updates = tracker.apply(863, Some(3_000), &[]);
+        assert!(updates.is_empty());
+        let updates = tracker.apply(1007, Some(3_500), &[]);
+        assert_eq!(updates[0].state, DeploymentState::Active);
+        assert_eq!(updates[0].height, 1008);
+        let updates = tracker.apply(1151, Some(6_000), &[]);
+        assert!(updates.is_empty());
+        assert_eq!(tracker.states()[0].state, DeploymentState::Active);
+        assert_eq!(tracker.states()[0].height, 1008);
+    }
+
+    #[test]
+    fn test_deployment_fails() {
+        let deployment = Deployment::new("test", 2, 1_000, 2_000);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        tracker.apply(287, Some(1_500), &[]);
+        // The deployment may have started before the anchor
+        assert!(tracker.states().is_empty());
+        let deployment = Deployment::new("test", 2, 1_000, 2_000);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        tracker.apply(287, Some(500), &[]);
+        tracker.apply(431, Some(1_500), &[]);
+        // Signals with the wrong version bits are not counted
+        let updates = tracker.apply(575, Some(2_500), &vec![header(0x0000_0004); 144]);
+        assert_eq!(updates[0].state, DeploymentState::Failed);
+        let updates = tracker.apply(719, Some(3_000), &period(144));
+        assert!(updates.is_empty());
+    }
+
+    #[test]
+    fn test_known_state_before_anchor() {
+        let deployment = Deployment::new("test", 2, 1_000, 2_000);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        tracker.apply(287, Some(3_000), &[]);
+        // The deployment started before the anchor, so the outcome is unknown
+        assert!(tracker.states().is_empty());
+        let deployment =
+            Deployment::new("test", 2, 1_000, 2_000).known_state(DeploymentState::Active);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        assert!(!tracker.needs_signals());
+        let updates = tracker.apply(287, Some(3_000), &[]);
+        assert_eq!(updates[0].state, DeploymentState::Active);
+        assert_eq!(updates[0].height, 288);
+        // Signals are counted for a deployment that started before the anchor
+        let deployment =
+            Deployment::new("test", 2, 1_000, 5_000).known_state(DeploymentState::Started);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        assert!(tracker.needs_signals());
+        let updates = tracker.apply(287, Some(3_000), &period(120));
+        assert_eq!(updates[0].state, DeploymentState::LockedIn);
+        tracker.rewind(100);
+        assert!(tracker.needs_signals());
+    }
+
+    #[test]
+    fn test_deployment_reorg() {
+        let deployment = Deployment::new("test", 2, 1_000, 5_000);
+        let mut tracker = DeploymentTracker::new(Network::Regtest, vec![deployment], 0);
+        tracker.apply(287, Some(500), &[]);
+        tracker.apply(431, Some(1_500), &[]);
+        tracker.apply(575, Some(2_000), &period(120));
+        assert_eq!(tracker.states()[0].state, DeploymentState::LockedIn);
+        // A reorganization removes the last period
+        tracker.rewind(570);
+        assert_eq!(tracker.next_boundary(), 575);
+        assert_eq!(tracker.states()[0].state, DeploymentState::Started);
+        let updates = tracker.apply(575, Some(2_000), &period(10));
+        assert_eq!(updates[0].state, DeploymentState::Started);
+        // A reorganization within the first period
+        tracker.rewind(100);
+        assert_eq!(tracker.next_boundary(), 287);
+        assert!(tracker.states().is_empty());
+    }
+}
@@ -1,14 +1,16 @@
-use std::{collections::HashSet, path::PathBuf, time::Duration};
+use std::{collections::HashSet, net::SocketAddr, path::PathBuf, time::Duration};
 
 use bitcoin::{Network, ScriptBuf};
 
-use super::{client::Client, config::NodeConfig, node::Node, FilterSyncPolicy};
+use super::{
+    client::Client, config::NodeConfig, node::Node, FilterSyncPolicy, ReorgPolicy, TransportPolicy,
+};
 #[cfg(feature = "database")]
 use crate::db::error::SqlInitializationError;
 #[cfg(feature = "database")]
 use crate::db::sqlite::{headers::SqliteHeaderDb, peers::SqlitePeerDb};
 use crate::{
-    chain::checkpoints::HeaderCheckpoint,
+    chain::{checkpoints::HeaderCheckpoint, keychain::Keychain, versionbits::Deployment},
     db::traits::{HeaderStore, PeerStore},
 };
 use crate::{ConnectionType, PeerStoreSizeConfig, TrustedPeer};
@@ -92,12 +94,18 @@ impl NodeBuilder {
     }
 
     /// Add Bitcoin scripts to monitor for. You may add more later with the [`Client`].
-    #[cfg(not(feature = "filter-control"))]
     pub fn add_scripts(mut self, addresses: HashSet<ScriptBuf>) -> Self {
         self.config.addresses = addresses;
         self
     }
 
+    /// Watch the scripts derived from an extended public key, up to the gap limit of the [`Keychain`].
+    /// More scripts are derived as they are used. You may add more keychains later with the [`Client`].
+    pub fn add_keychain(mut self, keychain: Keychain) -> Self {
+        self.config.keychains.push(keychain);
+        self
+    }
+
     /// Add a path to the directory where data should be stored. If none is provided, the current
     /// working directory will be used.
     pub fn add_data_dir(mut self, path: impl Into<PathBuf>) -> Self {
@@ -153,6 +161,36 @@ impl NodeBuilder {
         self
     }
 
+    /// Choose between encrypted and plaintext connections with peers. This applies to every [`ConnectionType`].
+    ///
+    /// If none is provided, encrypted connections are preferred, with a plaintext fallback, except when connecting
+    /// over Tor with the `tor` feature, where only plaintext connections are used.
+    pub fn set_transport_policy(mut self, policy: TransportPolicy) -> Self {
+        self.config.transport_policy = Some(policy);
+        self
+    }
+
+    /// Add a DNS seed to query for new peers when the peer database is empty. When the node requires peers that serve
+    /// compact block filters, the seed is queried with a service bit subdomain, like `x49.seed.example.com`.
+    ///
+    /// If none are provided, the seeds of the network are used. There are no seeds for regtest.
+    #[cfg(feature = "dns")]
+    pub fn add_dns_seed(mut self, seed: impl Into<String>) -> Self {
+        self.config.dns_config.seeds.push(seed.into());
+        self
+    }
+
+    /// Add a DNS resolver, typically listening on port 53, to query the seeds with. Resolvers are tried in the
+    /// order they were added. Queries are made over UDP, and repeated over TCP if the response is truncated.
+    ///
+    /// If none are provided, the `nameserver` entries of `/etc/resolv.conf` are used. If that file cannot be read or lists
+    /// no nameservers, as on platforms other than Unix, Cloudflare at `1.1.1.1` is used.
+    #[cfg(feature = "dns")]
+    pub fn add_dns_resolver(mut self, resolver: SocketAddr) -> Self {
+        self.config.dns_config.resolvers.push(resolver);
+        self
+    }
+
     /// Set the time duration a peer has to respond to a message from the local node.
     ///
     /// ## Note
@@ -168,7 +206,7 @@ impl NodeBuilder {
     }
 
     /// The maximum connection time that will be maintained with a remote peer, regardless of
-    /// the quality of the peer.
+    /// the quality of the peer. Peers that connected to the node are not disconnected after this time.
     ///
     /// ## Note
     ///
@@ -190,6 +228,113 @@ impl NodeBuilder {
         self
     }
 
+    /// Emit every block with a match in the compact block filters as a [`NodeMessage::Block`](crate::NodeMessage) event,
+    /// in addition to the relevant transactions within the block. Blocks may be large, and each block is held in memory
+    /// until every receiver has processed it, so this is only recommended if the client requires the full block.
+    ///
+    /// If none is provided, only [`NodeMessage::Transaction`](crate::NodeMessage) events are emitted for matched blocks.
+    pub fn deliver_full_blocks(mut self) -> Self {
+        self.config.deliver_blocks = true;
+        self
+    }
+
+    /// Ask peers to relay unconfirmed transactions, and emit a [`NodeMessage::MempoolTransaction`](crate::NodeMessage)
+    /// for each transaction that pays to or spends from a watched script. A [`NodeMessage::MempoolConfirmed`](crate::NodeMessage)
+    /// or [`NodeMessage::MempoolDropped`](crate::NodeMessage) follows when the transaction is included in a block or is no
+    /// longer tracked. Configured peers that serve bloom filters are also asked for their mempool when connecting.
+    ///
+    /// Once the node is synced, every announced transaction is downloaded to check for matches, and while
+    /// any relevant transaction is unconfirmed, every new block is downloaded to find it or a conflict, so this
+    /// uses significantly more bandwidth. With the `filter-control` feature, only the blocks the client requests
+    /// are checked for unconfirmed transactions.
+    /// If none is provided, unconfirmed transactions are not relayed to the node.
+    pub fn watch_mempool(mut self) -> Self {
+        self.config.watch_mempool = true;
+        self
+    }
+
+    /// The number of recent compact block filters to keep in memory, so ranges of blocks may be rescanned
+    /// for new scripts without downloading the filters again. Filters of lower blocks are removed first.
+    ///
+    /// If none is provided, the 500 most recent filters will be kept.
+    pub fn set_filter_cache_size(mut self, num_filters: usize) -> Self {
+        self.config.filter_cache_size = num_filters;
+        self
+    }
+
+    /// Save the compact block filter headers and filters to the [`HeaderStore`], so they may be served to inbound peers.
+    /// Filters are saved as they are downloaded, so only filters scanned by this node are available.
+    ///
+    /// If none is provided, filters are only kept in memory.
+    pub fn persist_filters(mut self) -> Self {
+        self.config.persist_filters = true;
+        self
+    }
+
+    /// Accept inbound connections on this address. Inbound peers may request block headers, and compact block filter
+    /// headers and filters if [`NodeBuilder::persist_filters`] is set. Inbound peers are not used to sync the chain.
+    ///
+    /// If none is provided, the node does not listen for connections.
+    pub fn listen_on(mut self, address: SocketAddr) -> Self {
+        self.config.listen_config.address = Some(address);
+        self
+    }
+
+    /// The maximum number of inbound connections to maintain when listening for connections.
+    ///
+    /// If none is provided, up to 8 inbound peers will be accepted.
+    pub fn set_max_inbound_peers(mut self, max_inbound: usize) -> Self {
+        self.config.listen_config.max_inbound = max_inbound;
+        self
+    }
+
+    /// The deepest block reorganization, in number of blocks disconnected from the chain, the node will follow.
+    /// Forks that would disconnect more blocks are rejected, even if they represent more work.
+    ///
+    /// If none is provided, a maximum depth of 5,000 blocks will be used.
+    pub fn set_max_reorg_depth(mut self, max_depth: u32) -> Self {
+        self.config.reorg_config.max_depth = max_depth;
+        self
+    }
+
+    /// The depth at which a block reorganization is considered unusually deep. Reorganizations that
+    /// disconnect at least this many blocks emit a [`NodeMessage::DeepReorg`](crate::NodeMessage) event.
+    ///
+    /// If none is provided, reorganizations of 6 or more blocks will issue an alarm.
+    pub fn set_reorg_alarm_depth(mut self, alarm_depth: u32) -> Self {
+        self.config.reorg_config.alarm_depth = alarm_depth;
+        self
+    }
+
+    /// How many blocks below the stored tip the node reports to peers after a restart, so reorganizations
+    /// that happened while the node was offline are found.
+    ///
+    /// If none is provided, the node will look back 7 blocks.
+    pub fn set_reorg_lookback(mut self, lookback: u32) -> Self {
+        self.config.reorg_config.lookback = lookback;
+        self
+    }
+
+    /// Choose if the node follows deep reorganizations automatically or waits for the client to confirm them.
+    /// With [`ReorgPolicy::Halt`], the node only follows extensions of the current chain after an alarm, until
+    /// [`ClientSender::confirm_reorg`](crate::ClientSender) or [`ClientSender::reject_reorg`](crate::ClientSender)
+    /// is called.
+    ///
+    /// If none is provided, the node will follow reorganizations up to the maximum depth.
+    pub fn set_reorg_policy(mut self, policy: ReorgPolicy) -> Self {
+        self.config.reorg_config.policy = policy;
+        self
+    }
+
+    /// Follow the state of a soft-fork deployment signalled with BIP9 version bits. The state is evaluated for every
+    /// period of blocks after the anchor checkpoint, so the anchor should be before the start time of the deployment,
+    /// or the state at the anchor should be set with [`Deployment::known_state`].
+    /// Changes in state are emitted as a [`NodeMessage::DeploymentStateChange`](crate::NodeMessage) event.
+    pub fn track_deployment(mut self, deployment: Deployment) -> Self {
+        self.config.deployments.push(deployment);
+        self
+    }
+
     /// Consume the node builder and receive a [`Node`] and [`Client`].
     ///
     /// # Errors
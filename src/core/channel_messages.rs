@@ -1,12 +1,15 @@
+use std::time::Duration;
+
 use bitcoin::{
     block::Header,
     p2p::{
         address::AddrV2,
+        message_blockdata::GetHeadersMessage,
         message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
         message_network::VersionMessage,
         ServiceFlags,
     },
-    Block, BlockHash, FeeRate, Transaction,
+    Block, BlockHash, FeeRate, Transaction, Txid,
 };
 
 use crate::core::messages::FailurePayload;
@@ -19,9 +22,14 @@ pub(crate) enum MainThreadMessage {
     GetFilterHeaders(GetCFHeaders),
     GetFilters(GetCFilters),
     GetBlock(GetBlockConfig),
+    GetTransactions(Vec<Txid>),
+    GetMempool,
     Disconnect,
     BroadcastTx(Transaction),
     Verack,
+    ServeHeaders(Vec<Header>),
+    ServeFilterHeaders(CFHeaders),
+    ServeFilters(Vec<CFilter>),
 }
 
 #[derive(Debug, Clone)]
@@ -45,16 +53,24 @@ pub(crate) enum PeerMessage {
     Version(VersionMessage),
     Addr(Vec<CombinedAddr>),
     Headers(Vec<Header>),
+    NewHeaders(Vec<Header>),
     FilterHeaders(CFHeaders),
     Filter(CFilter),
     Block(Block),
     NewBlocks(Vec<BlockHash>),
+    BlocksNotFound(Vec<BlockHash>),
+    NewTransactions(Vec<Txid>),
+    Transaction(Transaction),
+    GetHeaders(GetHeadersMessage),
+    GetFilterHeaders(GetCFHeaders),
+    GetFilters(GetCFilters),
     Reject(FailurePayload),
     Disconnect,
     Verack,
     Ping(u64),
-    #[allow(dead_code)]
     Pong(u64),
+    RoundTrip(Duration),
+    Misbehaving(u32),
     FeeFilter(FeeRate),
 }
 
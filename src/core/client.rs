@@ -1,17 +1,21 @@
 use bitcoin::block::Header;
-#[cfg(feature = "filter-control")]
-use bitcoin::BlockHash;
-use bitcoin::ScriptBuf;
-use std::time::Duration;
+use bitcoin::{BlockHash, OutPoint, ScriptBuf};
+use std::{collections::HashSet, time::Duration};
 use tokio::sync::broadcast;
 pub use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
 
-use crate::{IndexedBlock, TrustedPeer, TxBroadcast};
+use crate::{DeploymentUpdate, IndexedBlock, Keychain, TrustedPeer, TxBroadcast};
 
 use super::{
-    error::{ClientError, FetchHeaderError},
-    messages::{ClientMessage, HeaderRequest, NodeMessage, SyncUpdate},
+    error::{
+        ClientError, FetchBlockError, FetchDeploymentError, FetchFeeFilterError, FetchHeaderError,
+        FetchPeerInfoError, FetchScriptsError,
+    },
+    messages::{
+        BlockRequest, ClientMessage, FeeFilterStats, HeaderRequest, NodeMessage, PeerInfo,
+        SyncUpdate,
+    },
 };
 
 /// A [`Client`] allows for communication with a running node.
@@ -56,6 +60,8 @@ impl Client {
     /// which has more performance implications.
     /// For devices like smart phones, it is advisable to
     /// respond to each block in an event loop. See [`Client::split`] or [`Client::receiver`].
+    ///
+    /// Blocks are only received if the node was built with [`NodeBuilder::deliver_full_blocks`](crate::NodeBuilder).
     pub async fn collect_relevant_blocks(&mut self) -> Vec<IndexedBlock> {
         let mut rec = self.nrx.subscribe();
         let mut blocks = Vec::new();
@@ -136,6 +142,9 @@ macro_rules! impl_core_client {
             ///
             /// For more information, see BIP-431 and BIP-331.
             ///
+            /// If the fee of the transaction is provided with [`TxBroadcast::fee`], a [`Warning::FeeRateBelowPeerFloor`](crate::Warning)
+            /// is issued when the fee rate is below the median fee rate connected peers will relay.
+            ///
             /// # Errors
             ///
             /// If the node has stopped running.
@@ -157,36 +166,301 @@ macro_rules! impl_core_client {
                     .map_err(|_| ClientError::SendError)
             }
 
-            /// Add more Bitcoin [`ScriptBuf`] to watch for. Does not rescan the filters.
+            /// Add more Bitcoin [`ScriptBuf`] to watch for. If filters were already scanned, they are checked again
+            /// for the new script, and a [`NodeMessage::CatchUpComplete`] is issued when finished.
             /// If the script was already present in the node's collection, no change will occur.
+            /// With the `filter-control` feature, filters are not checked again, as the client checks each filter itself.
             ///
             /// # Errors
             ///
             /// If the node has stopped running.
-            #[cfg(not(feature = "filter-control"))]
             pub async fn add_script(
                 &self,
                 script: impl Into<ScriptBuf>,
             ) -> Result<(), ClientError> {
                 self.ntx
-                    .send(ClientMessage::AddScript(script.into()))
+                    .send(ClientMessage::AddScripts(
+                        HashSet::from([script.into()]),
+                        None,
+                    ))
                     .await
                     .map_err(|_| ClientError::SendError)
             }
 
-            /// Add more Bitcoin [`ScriptBuf`] to watch for from a synchronus context. Does not rescan the filters.
+            /// Add more Bitcoin [`ScriptBuf`] to watch for from a synchronus context. Filters that were already scanned
+            /// are checked again for the new script.
             /// If the script was already present in the node's collection, no change will occur.
             ///
             /// # Errors
             ///
             /// If the node has stopped running.
-            #[cfg(not(feature = "filter-control"))]
             pub fn add_script_blocking(
                 &self,
                 script: impl Into<ScriptBuf>,
             ) -> Result<(), ClientError> {
                 self.ntx
-                    .blocking_send(ClientMessage::AddScript(script.into()))
+                    .blocking_send(ClientMessage::AddScripts(
+                        HashSet::from([script.into()]),
+                        None,
+                    ))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Add a set of Bitcoin [`ScriptBuf`] to watch for. The filters that were already scanned are checked
+            /// again once for the entire set, starting at the given height, or at the anchor checkpoint if none is given.
+            /// Scripts that were created recently cannot be found in earlier blocks, so giving a height avoids
+            /// checking and downloading filters that cannot match.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn add_scripts(
+                &self,
+                scripts: HashSet<ScriptBuf>,
+                from_height: Option<u32>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::AddScripts(scripts, from_height))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Add a set of Bitcoin [`ScriptBuf`] to watch for from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn add_scripts_blocking(
+                &self,
+                scripts: HashSet<ScriptBuf>,
+                from_height: Option<u32>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::AddScripts(scripts, from_height))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Stop watching for a Bitcoin [`ScriptBuf`]. Blocks that were queued for download only because they
+            /// matched this script will no longer be requested.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn remove_script(
+                &self,
+                script: impl Into<ScriptBuf>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::RemoveScript(script.into()))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Stop watching for a Bitcoin [`ScriptBuf`] from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn remove_script_blocking(
+                &self,
+                script: impl Into<ScriptBuf>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::RemoveScript(script.into()))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Replace every watched Bitcoin [`ScriptBuf`] with a new set. Filters that were already scanned are checked
+            /// again for any scripts that are new to the set.
+            /// Blocks that were queued for download but do not match the new set will no longer be requested.
+            /// Scripts derived from a [`Keychain`] are still watched.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn replace_scripts(
+                &self,
+                scripts: HashSet<ScriptBuf>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::ReplaceScripts(scripts))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Replace every watched Bitcoin [`ScriptBuf`] with a new set from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn replace_scripts_blocking(
+                &self,
+                scripts: HashSet<ScriptBuf>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::ReplaceScripts(scripts))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Get the set of Bitcoin [`ScriptBuf`] the node is watching for.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn list_scripts(&self) -> Result<HashSet<ScriptBuf>, FetchScriptsError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<HashSet<ScriptBuf>>();
+                self.ntx
+                    .send(ClientMessage::GetScripts(tx))
+                    .await
+                    .map_err(|_| FetchScriptsError::SendError)?;
+                rx.await.map_err(|_| FetchScriptsError::RecvError)
+            }
+
+            /// Get the set of Bitcoin [`ScriptBuf`] the node is watching for in a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn list_scripts_blocking(&self) -> Result<HashSet<ScriptBuf>, FetchScriptsError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<HashSet<ScriptBuf>>();
+                self.ntx
+                    .blocking_send(ClientMessage::GetScripts(tx))
+                    .map_err(|_| FetchScriptsError::SendError)?;
+                rx.blocking_recv().map_err(|_| FetchScriptsError::RecvError)
+            }
+
+            /// Watch for an [`OutPoint`] to be spent. The script the outpoint pays to is required to find the spend
+            /// in the block filters. When a block spending the outpoint is found, a [`NodeMessage::OutPointSpent`] is issued.
+            /// Does not rescan the filters.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn watch_outpoint(
+                &self,
+                outpoint: OutPoint,
+                script: impl Into<ScriptBuf>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::WatchOutPoint(outpoint, script.into()))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Watch for an [`OutPoint`] to be spent from a synchronus context. Does not rescan the filters.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn watch_outpoint_blocking(
+                &self,
+                outpoint: OutPoint,
+                script: impl Into<ScriptBuf>,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::WatchOutPoint(outpoint, script.into()))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Stop watching for an [`OutPoint`] to be spent.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn unwatch_outpoint(&self, outpoint: OutPoint) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::UnwatchOutPoint(outpoint))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Stop watching for an [`OutPoint`] to be spent from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn unwatch_outpoint_blocking(&self, outpoint: OutPoint) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::UnwatchOutPoint(outpoint))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Watch the scripts derived from a [`Keychain`], up to its gap limit. When a derived script is used,
+            /// a [`NodeMessage::KeychainMatch`] is issued with the derivation index, and more scripts are derived
+            /// so the gap limit is kept past the last used index. Filters that were already scanned are checked
+            /// again for the new scripts.
+            ///
+            /// With the `filter-control` feature, filters are never checked again by the node. Scripts derived
+            /// past a used index are still watched from then on, but the client must check the filters it already
+            /// scanned for them.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn add_keychain(&self, keychain: Keychain) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::AddKeychain(keychain))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Watch the scripts derived from a [`Keychain`] from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn add_keychain_blocking(&self, keychain: Keychain) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::AddKeychain(keychain))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Follow a deep block reorganization the node is waiting on. Only has an effect if the
+            /// [`ReorgPolicy`](crate::ReorgPolicy) is set to `Halt` and a [`NodeMessage::DeepReorg`] was issued.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn confirm_reorg(&self) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::ConfirmReorg)
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Follow a deep block reorganization the node is waiting on from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn confirm_reorg_blocking(&self) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::ConfirmReorg)
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Stay on the current chain instead of a deep block reorganization the node is waiting on. Headers
+            /// on the rejected fork are ignored for as long as the node runs. Only has an effect if the
+            /// [`ReorgPolicy`](crate::ReorgPolicy) is set to `Halt` and a [`NodeMessage::DeepReorg`] was issued.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn reject_reorg(&self) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::RejectReorg)
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Stay on the current chain instead of a deep block reorganization from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn reject_reorg_blocking(&self) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::RejectReorg)
                     .map_err(|_| ClientError::SendError)
             }
 
@@ -238,6 +512,139 @@ macro_rules! impl_core_client {
                     .map_err(|_| FetchHeaderError::RecvError)?
             }
 
+            /// Fetch the block with this hash from a connected peer. Unlike blocks with matching scripts,
+            /// the block is only sent to the caller, and not emitted as a [`NodeMessage::Block`] event.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running, the hash is not in the chain of most work, too many blocks
+            /// are already being fetched, or no peer sent the block in time.
+            pub async fn get_block(
+                &self,
+                hash: BlockHash,
+            ) -> Result<IndexedBlock, FetchBlockError> {
+                let (tx, rx) =
+                    tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
+                let message = BlockRequest::new(tx, hash);
+                self.ntx
+                    .send(ClientMessage::FetchBlock(message))
+                    .await
+                    .map_err(|_| FetchBlockError::SendError)?;
+                rx.await.map_err(|_| FetchBlockError::RecvError)?
+            }
+
+            /// Fetch the block with this hash from a connected peer in a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running, the hash is not in the chain of most work, too many blocks
+            /// are already being fetched, or no peer sent the block in time.
+            pub fn get_block_blocking(
+                &self,
+                hash: BlockHash,
+            ) -> Result<IndexedBlock, FetchBlockError> {
+                let (tx, rx) =
+                    tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
+                let message = BlockRequest::new(tx, hash);
+                self.ntx
+                    .blocking_send(ClientMessage::FetchBlock(message))
+                    .map_err(|_| FetchBlockError::SendError)?;
+                rx.blocking_recv().map_err(|_| FetchBlockError::RecvError)?
+            }
+
+            /// Get the current state of each tracked soft-fork deployment, if the state is known.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn get_deployment_states(
+                &self,
+            ) -> Result<Vec<DeploymentUpdate>, FetchDeploymentError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Vec<DeploymentUpdate>>();
+                self.ntx
+                    .send(ClientMessage::GetDeployments(tx))
+                    .await
+                    .map_err(|_| FetchDeploymentError::SendError)?;
+                rx.await.map_err(|_| FetchDeploymentError::RecvError)
+            }
+
+            /// Get the current state of each tracked soft-fork deployment in a synchronus context, if the state is known.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn get_deployment_states_blocking(
+                &self,
+            ) -> Result<Vec<DeploymentUpdate>, FetchDeploymentError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Vec<DeploymentUpdate>>();
+                self.ntx
+                    .blocking_send(ClientMessage::GetDeployments(tx))
+                    .map_err(|_| FetchDeploymentError::SendError)?;
+                rx.blocking_recv()
+                    .map_err(|_| FetchDeploymentError::RecvError)
+            }
+
+            /// Get the median, minimum and maximum fee rates connected peers will relay transactions at,
+            /// from the latest `feefilter` message of each peer. Returns `None` if no connected peer sent a fee rate.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn get_fee_filter_stats(
+                &self,
+            ) -> Result<Option<FeeFilterStats>, FetchFeeFilterError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Option<FeeFilterStats>>();
+                self.ntx
+                    .send(ClientMessage::GetFeeFilterStats(tx))
+                    .await
+                    .map_err(|_| FetchFeeFilterError::SendError)?;
+                rx.await.map_err(|_| FetchFeeFilterError::RecvError)
+            }
+
+            /// Get the fee rates connected peers will relay transactions at in a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn get_fee_filter_stats_blocking(
+                &self,
+            ) -> Result<Option<FeeFilterStats>, FetchFeeFilterError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Option<FeeFilterStats>>();
+                self.ntx
+                    .blocking_send(ClientMessage::GetFeeFilterStats(tx))
+                    .map_err(|_| FetchFeeFilterError::SendError)?;
+                rx.blocking_recv()
+                    .map_err(|_| FetchFeeFilterError::RecvError)
+            }
+
+            /// Get the address, services and ping round trip time of each connected peer.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn get_peer_info(&self) -> Result<Vec<PeerInfo>, FetchPeerInfoError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Vec<PeerInfo>>();
+                self.ntx
+                    .send(ClientMessage::GetPeerInfo(tx))
+                    .await
+                    .map_err(|_| FetchPeerInfoError::SendError)?;
+                rx.await.map_err(|_| FetchPeerInfoError::RecvError)
+            }
+
+            /// Get a description of each connected peer in a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn get_peer_info_blocking(&self) -> Result<Vec<PeerInfo>, FetchPeerInfoError> {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Vec<PeerInfo>>();
+                self.ntx
+                    .blocking_send(ClientMessage::GetPeerInfo(tx))
+                    .map_err(|_| FetchPeerInfoError::SendError)?;
+                rx.blocking_recv()
+                    .map_err(|_| FetchPeerInfoError::RecvError)
+            }
+
             /// Starting at the configured anchor checkpoint, look for block inclusions with newly added scripts.
             ///
             /// # Errors
@@ -250,6 +657,63 @@ macro_rules! impl_core_client {
                     .map_err(|_| ClientError::SendError)
             }
 
+            /// Look for block inclusions with the current scripts starting at a height, for instance the birthday of
+            /// a newly imported key. Recently downloaded filters are checked from memory, and the rest are requested
+            /// from peers while new blocks continue to sync. A [`NodeMessage::Synced`] event is issued when complete.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn rescan_from(&self, height: u32) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::RescanRange(height, None))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Look for block inclusions with the current scripts starting at a height from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn rescan_from_blocking(&self, height: u32) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::RescanRange(height, None))
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Look for block inclusions with the current scripts in a range of blocks, including the start and end heights.
+            /// A [`NodeMessage::Synced`] event is issued when complete.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub async fn rescan_range(
+                &self,
+                start_height: u32,
+                end_height: u32,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .send(ClientMessage::RescanRange(start_height, Some(end_height)))
+                    .await
+                    .map_err(|_| ClientError::SendError)
+            }
+
+            /// Look for block inclusions with the current scripts in a range of blocks from a synchronus context.
+            ///
+            /// # Errors
+            ///
+            /// If the node has stopped running.
+            pub fn rescan_range_blocking(
+                &self,
+                start_height: u32,
+                end_height: u32,
+            ) -> Result<(), ClientError> {
+                self.ntx
+                    .blocking_send(ClientMessage::RescanRange(start_height, Some(end_height)))
+                    .map_err(|_| ClientError::SendError)
+            }
+
             /// Set a new connection timeout for peers to respond to messages.
             ///
             /// # Errors
@@ -3,13 +3,15 @@ use std::{collections::HashSet, path::PathBuf, time::Duration};
 use bitcoin::ScriptBuf;
 
 use crate::{
-    chain::checkpoints::HeaderCheckpoint, ConnectionType, PeerStoreSizeConfig, TrustedPeer,
+    chain::{checkpoints::HeaderCheckpoint, keychain::Keychain, versionbits::Deployment},
+    ConnectionType, PeerStoreSizeConfig, TrustedPeer,
 };
 
-use super::FilterSyncPolicy;
+use super::{DnsConfig, FilterSyncPolicy, ListenConfig, ReorgConfig, TransportPolicy};
 
 const REQUIRED_PEERS: u8 = 1;
 const TIMEOUT_SECS: u64 = 5;
+const FILTER_CACHE_SIZE: usize = 500;
 //                    sec  min  hour
 const TWO_HOUR: u64 = 60 * 60 * 2;
 
@@ -17,6 +19,7 @@ pub(crate) struct NodeConfig {
     pub required_peers: u8,
     pub white_list: Vec<TrustedPeer>,
     pub addresses: HashSet<ScriptBuf>,
+    pub keychains: Vec<Keychain>,
     pub data_path: Option<PathBuf>,
     pub header_checkpoint: Option<HeaderCheckpoint>,
     pub filter_startpoint: Option<u32>,
@@ -25,6 +28,15 @@ pub(crate) struct NodeConfig {
     pub response_timeout: Duration,
     pub max_connection_time: Duration,
     pub filter_sync_policy: FilterSyncPolicy,
+    pub reorg_config: ReorgConfig,
+    pub deployments: Vec<Deployment>,
+    pub deliver_blocks: bool,
+    pub filter_cache_size: usize,
+    pub watch_mempool: bool,
+    pub listen_config: ListenConfig,
+    pub persist_filters: bool,
+    pub transport_policy: Option<TransportPolicy>,
+    pub dns_config: DnsConfig,
 }
 
 impl Default for NodeConfig {
@@ -33,6 +45,7 @@ impl Default for NodeConfig {
             required_peers: REQUIRED_PEERS,
             white_list: Default::default(),
             addresses: Default::default(),
+            keychains: Default::default(),
             data_path: Default::default(),
             header_checkpoint: Default::default(),
             filter_startpoint: Default::default(),
@@ -41,6 +54,15 @@ impl Default for NodeConfig {
             response_timeout: Duration::from_secs(TIMEOUT_SECS),
             max_connection_time: Duration::from_secs(TWO_HOUR),
             filter_sync_policy: Default::default(),
+            reorg_config: ReorgConfig::default(),
+            deployments: Default::default(),
+            deliver_blocks: false,
+            filter_cache_size: FILTER_CACHE_SIZE,
+            watch_mempool: false,
+            listen_config: ListenConfig::default(),
+            persist_filters: false,
+            transport_policy: None,
+            dns_config: DnsConfig::default(),
         }
     }
 }
@@ -155,3 +155,146 @@ impl core::fmt::Display for FetchHeaderError {
 }
 
 impl_sourceless_error!(FetchHeaderError);
+
+/// Errors occuring when the client is fetching the state of soft-fork deployments from the node.
+#[derive(Debug)]
+pub enum FetchDeploymentError {
+    /// The channel to the node was likely closed and dropped from memory.
+    /// This implies the node is not running.
+    SendError,
+    /// The channel to the client was likely closed by the node and dropped from memory.
+    RecvError,
+}
+
+impl core::fmt::Display for FetchDeploymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchDeploymentError::SendError => {
+                write!(f, "the receiver of this message was dropped from memory.")
+            }
+            FetchDeploymentError::RecvError => write!(
+                f,
+                "the channel to the client was likely closed by the node and dropped from memory."
+            ),
+        }
+    }
+}
+
+impl_sourceless_error!(FetchDeploymentError);
+
+/// Errors occuring when the client is fetching the watched scripts from the node.
+#[derive(Debug)]
+pub enum FetchScriptsError {
+    /// The channel to the node was likely closed and dropped from memory.
+    /// This implies the node is not running.
+    SendError,
+    /// The channel to the client was likely closed by the node and dropped from memory.
+    RecvError,
+}
+
+impl core::fmt::Display for FetchScriptsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchScriptsError::SendError => {
+                write!(f, "the receiver of this message was dropped from memory.")
+            }
+            FetchScriptsError::RecvError => write!(
+                f,
+                "the channel to the client was likely closed by the node and dropped from memory."
+            ),
+        }
+    }
+}
+
+impl_sourceless_error!(FetchScriptsError);
+
+/// Errors occuring when the client is fetching the fee rates peers will relay from the node.
+#[derive(Debug)]
+pub enum FetchFeeFilterError {
+    /// The channel to the node was likely closed and dropped from memory.
+    /// This implies the node is not running.
+    SendError,
+    /// The channel to the client was likely closed by the node and dropped from memory.
+    RecvError,
+}
+
+impl core::fmt::Display for FetchFeeFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchFeeFilterError::SendError => {
+                write!(f, "the receiver of this message was dropped from memory.")
+            }
+            FetchFeeFilterError::RecvError => write!(
+                f,
+                "the channel to the client was likely closed by the node and dropped from memory."
+            ),
+        }
+    }
+}
+
+impl_sourceless_error!(FetchFeeFilterError);
+
+/// Errors occuring when the client is fetching a description of the connected peers from the node.
+#[derive(Debug)]
+pub enum FetchPeerInfoError {
+    /// The channel to the node was likely closed and dropped from memory.
+    /// This implies the node is not running.
+    SendError,
+    /// The channel to the client was likely closed by the node and dropped from memory.
+    RecvError,
+}
+
+impl core::fmt::Display for FetchPeerInfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchPeerInfoError::SendError => {
+                write!(f, "the receiver of this message was dropped from memory.")
+            }
+            FetchPeerInfoError::RecvError => write!(
+                f,
+                "the channel to the client was likely closed by the node and dropped from memory."
+            ),
+        }
+    }
+}
+
+impl_sourceless_error!(FetchPeerInfoError);
+
+/// Errors occuring when the client is fetching a block from the node.
+#[derive(Debug)]
+pub enum FetchBlockError {
+    /// The channel to the node was likely closed and dropped from memory.
+    /// This implies the node is not running.
+    SendError,
+    /// The channel to the client was likely closed by the node and dropped from memory.
+    RecvError,
+    /// The block hash is not part of the chain of most work known to the node.
+    UnknownHash,
+    /// There are already too many blocks being fetched.
+    TooManyRequests,
+    /// No peer sent the block in time.
+    Timeout,
+}
+
+impl core::fmt::Display for FetchBlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchBlockError::SendError => {
+                write!(f, "the receiver of this message was dropped from memory.")
+            }
+            FetchBlockError::RecvError => write!(
+                f,
+                "the channel to the client was likely closed by the node and dropped from memory."
+            ),
+            FetchBlockError::UnknownHash => {
+                write!(f, "the block hash is not in the chain of most work.")
+            }
+            FetchBlockError::TooManyRequests => {
+                write!(f, "too many blocks are already being fetched.")
+            }
+            FetchBlockError::Timeout => write!(f, "no peer sent the block in time."),
+        }
+    }
+}
+
+impl_sourceless_error!(FetchBlockError);
@@ -1,17 +1,30 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
 
-#[cfg(feature = "filter-control")]
-use bitcoin::BlockHash;
-use bitcoin::{block::Header, p2p::message_network::RejectReason, FeeRate, ScriptBuf, Txid};
+use bitcoin::{
+    block::Header,
+    p2p::{address::AddrV2, message_network::RejectReason, ServiceFlags},
+    BlockHash, FeeRate, OutPoint, ScriptBuf, Transaction, Txid,
+};
 
 #[cfg(feature = "filter-control")]
 use crate::IndexedFilter;
 use crate::{
-    chain::checkpoints::HeaderCheckpoint, DisconnectedHeader, IndexedBlock, TrustedPeer,
-    TxBroadcast,
+    chain::{
+        checkpoints::HeaderCheckpoint,
+        keychain::{Keychain, KeychainMatch},
+        versionbits::DeploymentUpdate,
+    },
+    prelude::Median,
+    DisconnectedHeader, IndexedBlock, IndexedTransaction, TrustedPeer, TxBroadcast,
 };
 
-use super::{error::FetchHeaderError, node::NodeState};
+use super::{
+    error::{FetchBlockError, FetchHeaderError},
+    node::NodeState,
+};
 
 /// Messages receivable by a running node.
 #[derive(Debug, Clone)]
@@ -24,16 +37,43 @@ pub enum NodeMessage {
     StateChange(NodeState),
     /// The node is connected to all required peers.
     ConnectionsMet,
-    /// A relevant [`Block`](crate) based on the user provided scripts.
+    /// A relevant [`Block`](crate) based on the user provided scripts. Only emitted if full block delivery is
+    /// enabled with [`NodeBuilder::deliver_full_blocks`](crate::NodeBuilder), or the block was explicitly requested.
     /// Note that the block may not contain any transactions contained in the script set.
     /// This is due to block filters having a non-zero false-positive rate when compressing data.
     Block(IndexedBlock),
+    /// A transaction in a relevant block that pays to or spends from a user provided script. Spends are
+    /// only found for outputs received while the node was running, or for watched outpoints.
+    Transaction(IndexedTransaction),
+    /// A transaction that pays to or spends from a user provided script was relayed by a peer, but is not yet
+    /// in a block. Only emitted if the mempool is watched with [`NodeBuilder::watch_mempool`](crate::NodeBuilder).
+    MempoolTransaction(UnconfirmedTransaction),
+    /// An unconfirmed transaction was included in a block at this height.
+    MempoolConfirmed(Txid, u32),
+    /// An unconfirmed transaction conflicts with a transaction in a block, or was unconfirmed for longer than
+    /// peers will keep it, so it is no longer tracked.
+    MempoolDropped(Txid),
+    /// A watched [`OutPoint`] was spent by a transaction in a block.
+    OutPointSpent(SpentOutPoint),
+    /// A transaction paid to or spent from a script derived from a [`Keychain`](crate::Keychain). With the
+    /// `filter-control` feature, the filters that were already scanned are not checked for the scripts derived
+    /// past this index.
+    KeychainMatch(KeychainMatch),
+    /// Scripts added after the node started checking filters were checked against the filters that were
+    /// already scanned.
+    CatchUpComplete(CatchUp),
     /// The node is fully synced, having scanned the requested range.
     Synced(SyncUpdate),
     /// The progress of the node during the block filter download process.
     Progress(Progress),
     /// Blocks were reorganized out of the chain.
     BlocksDisconnected(Vec<DisconnectedHeader>),
+    /// A block reorganization at least as deep as the configured alarm depth was found.
+    /// If the [`ReorgPolicy`](crate::ReorgPolicy) is set to `Halt`, the node will not continue syncing
+    /// until the reorganization is confirmed by the client.
+    DeepReorg(DeepReorg),
+    /// A tracked soft-fork deployment changed state. Note that state changes may be reverted by a block reorganization.
+    DeploymentStateChange(DeploymentUpdate),
     /// A transaction was sent to one or more connected peers.
     /// This does not guarentee the transaction will be relayed or accepted by the peers,
     /// only that the message was sent over the wire.
@@ -43,7 +83,8 @@ pub enum NodeMessage {
     /// A connection has a minimum transaction fee requirement to enter its mempool. For proper transaction propagation,
     /// transactions should have a fee rate at least as high as the maximum fee filter received.
     ///
-    /// For more information, refer to BIP133.
+    /// For more information, refer to BIP133. The fee rates of all connected peers are available with
+    /// [`Client::get_fee_filter_stats`](crate::Client).
     FeeFilter(FeeRate),
     /// A compact block filter with associated height and block hash.
     #[cfg(feature = "filter-control")]
@@ -83,6 +124,151 @@ impl SyncUpdate {
     }
 }
 
+/// A block reorganization deeper than the configured alarm depth.
+#[derive(Debug, Clone, Copy)]
+pub struct DeepReorg {
+    /// The number of blocks that are disconnected from the current chain.
+    pub depth: u32,
+    /// The tip of the chain before the reorganization.
+    pub old_tip: HeaderCheckpoint,
+    /// The tip of the competing chain with more work.
+    pub new_tip: HeaderCheckpoint,
+}
+
+impl DeepReorg {
+    pub(crate) fn new(depth: u32, old_tip: HeaderCheckpoint, new_tip: HeaderCheckpoint) -> Self {
+        Self {
+            depth,
+            old_tip,
+            new_tip,
+        }
+    }
+}
+
+/// Scripts that were checked against the filters scanned before the scripts were added.
+#[derive(Debug, Clone)]
+pub struct CatchUp {
+    /// The scripts that were added.
+    pub scripts: Vec<ScriptBuf>,
+    /// The first height that was checked.
+    pub start_height: u32,
+    /// The last height that was checked.
+    pub end_height: u32,
+}
+
+impl CatchUp {
+    pub(crate) fn new(scripts: Vec<ScriptBuf>, start_height: u32, end_height: u32) -> Self {
+        Self {
+            scripts,
+            start_height,
+            end_height,
+        }
+    }
+}
+
+/// A watched [`OutPoint`] and the transaction that spent it.
+#[derive(Debug, Clone)]
+pub struct SpentOutPoint {
+    /// The outpoint that was spent.
+    pub outpoint: OutPoint,
+    /// The transaction spending the outpoint.
+    pub spending_tx: Transaction,
+    /// The index of the input in the spending transaction that refers to the outpoint.
+    pub input_index: usize,
+    /// The height of the block the spending transaction was included in.
+    pub height: u32,
+}
+
+impl SpentOutPoint {
+    pub(crate) fn new(
+        outpoint: OutPoint,
+        spending_tx: Transaction,
+        input_index: usize,
+        height: u32,
+    ) -> Self {
+        Self {
+            outpoint,
+            spending_tx,
+            input_index,
+            height,
+        }
+    }
+}
+
+/// A relevant transaction relayed by a peer that is not yet in a block.
+#[derive(Debug, Clone)]
+pub struct UnconfirmedTransaction {
+    /// The unconfirmed transaction.
+    pub transaction: Transaction,
+    /// The watched scripts the transaction pays to or spends from.
+    pub matched_scripts: Vec<ScriptBuf>,
+    /// The indexes of the inputs that spend a watched output.
+    pub inputs: Vec<usize>,
+    /// The indexes of the outputs that pay to a watched script.
+    pub outputs: Vec<usize>,
+}
+
+impl UnconfirmedTransaction {
+    pub(crate) fn new(
+        transaction: Transaction,
+        matched_scripts: Vec<ScriptBuf>,
+        inputs: Vec<usize>,
+        outputs: Vec<usize>,
+    ) -> Self {
+        Self {
+            transaction,
+            matched_scripts,
+            inputs,
+            outputs,
+        }
+    }
+}
+
+/// The minimum fee rates connected peers will relay transactions at, from the latest `feefilter` message of each peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeFilterStats {
+    /// The median fee rate floor across peers.
+    pub median: FeeRate,
+    /// The lowest fee rate floor of any peer.
+    pub min: FeeRate,
+    /// The highest fee rate floor of any peer.
+    pub max: FeeRate,
+    /// The number of connected peers that sent a fee rate floor.
+    pub num_peers: usize,
+}
+
+impl FeeFilterStats {
+    pub(crate) fn from_fee_rates(fee_rates: impl IntoIterator<Item = FeeRate>) -> Option<Self> {
+        let mut sat_per_kwu: Vec<u64> = fee_rates
+            .into_iter()
+            .map(|fee_rate| fee_rate.to_sat_per_kwu())
+            .collect();
+        let min = *sat_per_kwu.iter().min()?;
+        let max = *sat_per_kwu.iter().max()?;
+        Some(Self {
+            median: FeeRate::from_sat_per_kwu(sat_per_kwu.median()),
+            min: FeeRate::from_sat_per_kwu(min),
+            max: FeeRate::from_sat_per_kwu(max),
+            num_peers: sat_per_kwu.len(),
+        })
+    }
+}
+
+/// A peer the node is connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The network address of the peer.
+    pub address: AddrV2,
+    /// The port of the peer.
+    pub port: u16,
+    /// The services the peer signaled, once the version handshake is complete.
+    pub services: Option<ServiceFlags>,
+    /// If the peer opened the connection to the node.
+    pub inbound: bool,
+    /// The smoothed round trip time of pings to the peer, once a pong was received.
+    pub round_trip_time: Option<Duration>,
+}
+
 /// The progress of the node during the block filter download process.
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -133,14 +319,33 @@ pub(crate) enum ClientMessage {
     Shutdown,
     /// Broadcast a [`crate::Transaction`] with a [`crate::TxBroadcastPolicy`].
     Broadcast(TxBroadcast),
-    /// Add more Bitcoin [`ScriptBuf`] to look for.
-    AddScript(ScriptBuf),
+    /// Add more Bitcoin [`ScriptBuf`] to look for, checking the filters that were already scanned from a height onward.
+    AddScripts(HashSet<ScriptBuf>, Option<u32>),
+    /// Stop watching for a Bitcoin [`ScriptBuf`].
+    RemoveScript(ScriptBuf),
+    /// Replace the set of Bitcoin [`ScriptBuf`] to look for.
+    ReplaceScripts(HashSet<ScriptBuf>),
+    /// Request the set of Bitcoin [`ScriptBuf`] the node is looking for.
+    GetScripts(ScriptSender),
+    /// Watch for an [`OutPoint`] to be spent, along with the script it pays to.
+    WatchOutPoint(OutPoint, ScriptBuf),
+    /// Stop watching for an [`OutPoint`] to be spent.
+    UnwatchOutPoint(OutPoint),
+    /// Watch the scripts derived from a [`Keychain`].
+    AddKeychain(Keychain),
     /// Starting at the configured anchor checkpoint, look for block inclusions with newly added scripts.
     Rescan,
+    /// Look for block inclusions with the current scripts in a range of blocks, up to the tip if no end is given.
+    RescanRange(u32, Option<u32>),
     /// If the [`FilterSyncPolicy`](crate) is set to `Halt`, issuing this command will
     /// start the filter download and checking process. Otherwise, this command will not have any effect
     /// on node operation.
     ContinueDownload,
+    /// If the [`ReorgPolicy`](crate) is set to `Halt`, follow a deep block reorganization the node is waiting on.
+    ConfirmReorg,
+    /// If the [`ReorgPolicy`](crate) is set to `Halt`, stay on the current chain instead of a deep block reorganization
+    /// the node is waiting on.
+    RejectReorg,
     /// Explicitly request a block from the node.
     #[cfg(feature = "filter-control")]
     GetBlock(BlockHash),
@@ -150,8 +355,24 @@ pub(crate) enum ClientMessage {
     AddPeer(TrustedPeer),
     /// Request a header from a specified height.
     GetHeader(HeaderRequest),
+    /// Request the state of the tracked soft-fork deployments.
+    GetDeployments(DeploymentSender),
+    /// Request the fee rates connected peers will relay.
+    GetFeeFilterStats(FeeFilterSender),
+    /// Request a description of the connected peers.
+    GetPeerInfo(PeerInfoSender),
+    /// Fetch a block by hash, answered only to the requester.
+    FetchBlock(BlockRequest),
 }
 
+pub(crate) type ScriptSender = tokio::sync::oneshot::Sender<HashSet<ScriptBuf>>;
+
+pub(crate) type DeploymentSender = tokio::sync::oneshot::Sender<Vec<DeploymentUpdate>>;
+
+pub(crate) type FeeFilterSender = tokio::sync::oneshot::Sender<Option<FeeFilterStats>>;
+
+pub(crate) type PeerInfoSender = tokio::sync::oneshot::Sender<Vec<PeerInfo>>;
+
 type HeaderSender = tokio::sync::oneshot::Sender<Result<Option<Header>, FetchHeaderError>>;
 
 #[derive(Debug)]
@@ -166,6 +387,20 @@ impl HeaderRequest {
     }
 }
 
+pub(crate) type BlockSender = tokio::sync::oneshot::Sender<Result<IndexedBlock, FetchBlockError>>;
+
+#[derive(Debug)]
+pub(crate) struct BlockRequest {
+    pub(crate) oneshot: BlockSender,
+    pub(crate) hash: BlockHash,
+}
+
+impl BlockRequest {
+    pub(crate) fn new(oneshot: BlockSender, hash: BlockHash) -> Self {
+        Self { oneshot, hash }
+    }
+}
+
 /// Warnings a node may issue while running.
 #[derive(Debug, Clone)]
 pub enum Warning {
@@ -204,6 +439,29 @@ pub enum Warning {
     },
     /// A channel that was supposed to receive a message was dropped.
     ChannelDropped,
+    /// A transaction is being broadcast with a fee rate below the median fee rate floor of connected peers,
+    /// so most peers will not relay it.
+    FeeRateBelowPeerFloor {
+        /// The transaction being broadcast.
+        txid: Txid,
+        /// The fee rate of the transaction.
+        fee_rate: FeeRate,
+        /// The median fee rate floor of connected peers.
+        floor: FeeRate,
+    },
+    /// The node could not listen for inbound connections on the configured address.
+    CouldNotListen {
+        /// Additional context as to why the address could not be bound.
+        warning: String,
+    },
+    /// The peers the node was connected to before the last shutdown report a different chain height than
+    /// newly found peers. The peer database may have been filled by an attacker.
+    AnchorPeersDisagree {
+        /// The best height reported by the anchor peers.
+        anchor_height: u32,
+        /// The best height reported by the other peers.
+        peer_height: u32,
+    },
 }
 
 impl core::fmt::Display for Warning {
@@ -255,6 +513,53 @@ impl core::fmt::Display for Warning {
                     "A channel that was supposed to receive a message was dropped."
                 )
             }
+            Warning::FeeRateBelowPeerFloor {
+                txid,
+                fee_rate,
+                floor,
+            } => {
+                write!(
+                    f,
+                    "Transaction {} pays {} sat/vbyte, below the median peer floor of {} sat/vbyte.",
+                    txid,
+                    fee_rate.to_sat_per_vb_floor(),
+                    floor.to_sat_per_vb_ceil()
+                )
+            }
+            Warning::CouldNotListen { warning } => {
+                write!(f, "Could not listen for inbound connections: {warning}")
+            }
+            Warning::AnchorPeersDisagree {
+                anchor_height,
+                peer_height,
+            } => {
+                write!(
+                    f,
+                    "Anchor peers report a height of {anchor_height}, but new peers report {peer_height}."
+                )
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::FeeRate;
+
+    use super::FeeFilterStats;
+
+    #[test]
+    fn test_fee_filter_stats() {
+        assert!(FeeFilterStats::from_fee_rates(Vec::new()).is_none());
+        let stats = FeeFilterStats::from_fee_rates(vec![
+            FeeRate::from_sat_per_vb_unchecked(5),
+            FeeRate::from_sat_per_vb_unchecked(1),
+            FeeRate::from_sat_per_vb_unchecked(2),
+        ])
+        .unwrap();
+        assert_eq!(stats.median, FeeRate::from_sat_per_vb_unchecked(2));
+        assert_eq!(stats.min, FeeRate::from_sat_per_vb_unchecked(1));
+        assert_eq!(stats.max, FeeRate::from_sat_per_vb_unchecked(5));
+        assert_eq!(stats.num_peers, 3);
+    }
+}
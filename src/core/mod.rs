@@ -7,10 +7,13 @@
 //! To build a [`Node`](node::Node) and [`Client`](client::Client), please refer to the [`NodeBuilder`](builder::NodeBuilder), which allows for node
 //! configuration.
 
-use std::time::Duration;
+use bitcoin::p2p::ServiceFlags;
+use std::{net::SocketAddr, time::Duration};
 
 use tokio::time::Instant;
 
+use crate::ConnectionType;
+
 mod broadcaster;
 /// Convenient way to build a compact filters node.
 pub mod builder;
@@ -29,6 +32,10 @@ pub mod node;
 mod peer_map;
 
 const THIRTY_MINS: u64 = 60 * 30;
+const MAX_REORG_DEPTH: u32 = 5_000;
+const REORG_ALARM_DEPTH: u32 = 6;
+const REORG_LOOKBACK: u32 = 7;
+const MAX_INBOUND_PEERS: usize = 8;
 
 // This struct detects for stale tips and requests headers if no blocks were found after 30 minutes of wait time.
 pub(crate) struct LastBlockMonitor {
@@ -62,6 +69,96 @@ pub enum FilterSyncPolicy {
     Continue,
 }
 
+/// How the node should respond to a block reorganization at or deeper than the alarm depth.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReorgPolicy {
+    /// The node will follow the chain of most work without waiting on the client.
+    #[default]
+    Follow,
+    /// The node will only accept headers that extend the current chain until the client explicitly confirms
+    /// or rejects the reorganization.
+    Halt,
+}
+
+/// Which transport to use with peers. Encrypted V2 connections are described in BIP-324.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransportPolicy {
+    /// Only use encrypted connections. Peers known to only support plaintext connections are not connected to,
+    /// and inbound connections are refused.
+    V2Only,
+    /// Attempt an encrypted connection with peers that signal for it or whose services are not yet known,
+    /// and reconnect with plaintext if the handshake fails.
+    #[default]
+    PreferV2,
+    /// Only use plaintext connections. Handshakes for encrypted connections may fail frequently over Tor,
+    /// where messages are already encrypted between relays, so this is the default with [`ConnectionType::Tor`](crate::ConnectionType).
+    V1Only,
+}
+
+impl TransportPolicy {
+    // Encrypted connections are not attempted over Tor unless configured
+    #[cfg_attr(not(feature = "tor"), allow(unused_variables))]
+    pub(crate) fn default_for(connection_type: &ConnectionType) -> Self {
+        #[cfg(feature = "tor")]
+        if matches!(connection_type, ConnectionType::Tor(_)) {
+            return TransportPolicy::V1Only;
+        }
+        TransportPolicy::default()
+    }
+
+    // Should an encrypted connection be attempted with a peer, or `None` if the peer should not be connected to
+    pub(crate) fn v2_transport(&self, services: ServiceFlags) -> Option<bool> {
+        // Peers without known services may support encrypted connections
+        let signals_v2 = services.eq(&ServiceFlags::NONE) || services.has(ServiceFlags::P2P_V2);
+        match self {
+            TransportPolicy::V2Only => signals_v2.then_some(true),
+            TransportPolicy::PreferV2 => Some(signals_v2),
+            TransportPolicy::V1Only => Some(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReorgConfig {
+    pub(crate) max_depth: u32,
+    pub(crate) alarm_depth: u32,
+    pub(crate) lookback: u32,
+    pub(crate) policy: ReorgPolicy,
+}
+
+impl Default for ReorgConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: MAX_REORG_DEPTH,
+            alarm_depth: REORG_ALARM_DEPTH,
+            lookback: REORG_LOOKBACK,
+            policy: ReorgPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ListenConfig {
+    pub(crate) address: Option<SocketAddr>,
+    pub(crate) max_inbound: usize,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            max_inbound: MAX_INBOUND_PEERS,
+        }
+    }
+}
+
+// Seeds and resolvers are taken from the network and the system when empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DnsConfig {
+    pub(crate) seeds: Vec<String>,
+    pub(crate) resolvers: Vec<SocketAddr>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub(crate) struct PeerTimeoutConfig {
     pub(crate) response_timeout: Duration,
@@ -76,3 +173,29 @@ impl PeerTimeoutConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::p2p::ServiceFlags;
+
+    use super::TransportPolicy;
+    use crate::ConnectionType;
+
+    #[test]
+    fn test_transport_policy() {
+        let unknown = ServiceFlags::NONE;
+        let v1 = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let v2 = v1 | ServiceFlags::P2P_V2;
+        assert_eq!(TransportPolicy::V2Only.v2_transport(unknown), Some(true));
+        assert_eq!(TransportPolicy::V2Only.v2_transport(v2), Some(true));
+        assert_eq!(TransportPolicy::V2Only.v2_transport(v1), None);
+        assert_eq!(TransportPolicy::PreferV2.v2_transport(unknown), Some(true));
+        assert_eq!(TransportPolicy::PreferV2.v2_transport(v2), Some(true));
+        assert_eq!(TransportPolicy::PreferV2.v2_transport(v1), Some(false));
+        assert_eq!(TransportPolicy::V1Only.v2_transport(v2), Some(false));
+        assert_eq!(
+            TransportPolicy::default_for(&ConnectionType::ClearNet),
+            TransportPolicy::PreferV2
+        );
+    }
+}
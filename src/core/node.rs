@@ -1,5 +1,6 @@
 use std::{
     collections::HashSet,
+    net::SocketAddr,
     ops::DerefMut,
     sync::{atomic::AtomicBool, Arc},
     time::Duration,
@@ -12,23 +13,29 @@ use bitcoin::{
         message_network::VersionMessage,
         ServiceFlags,
     },
-    Block, BlockHash, Network, ScriptBuf,
+    Block, BlockHash, Network, OutPoint, ScriptBuf, Transaction, Txid,
 };
 use tokio::sync::{broadcast, mpsc::Receiver, Mutex, RwLock};
 use tokio::{
+    net::{TcpListener, TcpStream},
     select,
     sync::mpsc::{self},
 };
 
 use crate::{
     chain::{
-        chain::Chain,
+        block_queue::FAILED_BLOCKS_PENALTY,
+        chain::{Chain, MAX_SERVED_FILTERS, MAX_SERVED_FILTER_HEADERS},
         checkpoints::{HeaderCheckpoint, HeaderCheckpoints},
         error::HeaderSyncError,
+        keychain::Keychain,
+        serve,
+        versionbits::Deployment,
     },
     core::{error::FetchHeaderError, peer_map::PeerMap},
     db::traits::{HeaderStore, PeerStore},
-    filters::{cfheader_chain::AppendAttempt, error::CFilterSyncError},
+    filters::cfheader_chain::AppendAttempt,
+    network::counter::UNRESPONSIVE_PENALTY,
     ConnectionType, FailurePayload, PeerStoreSizeConfig, TrustedPeer, TxBroadcastPolicy,
 };
 
@@ -43,7 +50,8 @@ use super::{
     dialog::Dialog,
     error::NodeError,
     messages::{ClientMessage, NodeMessage, SyncUpdate, Warning},
-    FilterSyncPolicy, LastBlockMonitor, PeerTimeoutConfig,
+    DnsConfig, FilterSyncPolicy, LastBlockMonitor, ListenConfig, PeerTimeoutConfig, ReorgConfig,
+    TransportPolicy,
 };
 
 pub(crate) const ADDR_V2_VERSION: u32 = 70015;
@@ -80,6 +88,8 @@ pub struct Node<H: HeaderStore, P: PeerStore> {
     peer_recv: Arc<Mutex<Receiver<PeerThreadMessage>>>,
     is_running: AtomicBool,
     filter_sync_policy: Arc<RwLock<FilterSyncPolicy>>,
+    watch_mempool: bool,
+    listen_address: Option<SocketAddr>,
 }
 
 impl<H: HeaderStore, P: PeerStore> Node<H, P> {
@@ -88,6 +98,7 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         network: Network,
         white_list: Whitelist,
         scripts: HashSet<ScriptBuf>,
+        keychains: Vec<Keychain>,
         header_checkpoint: Option<HeaderCheckpoint>,
         filter_startpoint: Option<u32>,
         required_peers: PeerRequirement,
@@ -95,6 +106,15 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         connection_type: ConnectionType,
         timeout_config: PeerTimeoutConfig,
         filter_sync_policy: FilterSyncPolicy,
+        reorg_config: ReorgConfig,
+        deployments: Vec<Deployment>,
+        deliver_blocks: bool,
+        filter_cache_size: usize,
+        watch_mempool: bool,
+        listen_config: ListenConfig,
+        persist_filters: bool,
+        transport_policy: TransportPolicy,
+        dns_config: DnsConfig,
         peer_store: P,
         header_store: H,
     ) -> (Self, Client) {
@@ -117,6 +137,11 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
             connection_type,
             target_peer_size,
             timeout_config,
+            watch_mempool,
+            listen_config.max_inbound,
+            persist_filters,
+            transport_policy,
+            dns_config,
         )));
         // Set up the transaction broadcaster
         let tx_broadcaster = Arc::new(Mutex::new(Broadcaster::new()));
@@ -128,9 +153,15 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         let chain = Chain::new(
             network,
             scripts,
+            keychains,
             checkpoint,
             filter_startpoint,
             checkpoints,
+            reorg_config,
+            deployments,
+            deliver_blocks,
+            filter_cache_size,
+            persist_filters,
             dialog.clone(),
             header_store,
             required_peers,
@@ -148,6 +179,8 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                 peer_recv: Arc::new(Mutex::new(mrx)),
                 is_running: AtomicBool::new(false),
                 filter_sync_policy: Arc::new(RwLock::new(filter_sync_policy)),
+                watch_mempool,
+                listen_address: listen_config.address,
             },
             client,
         )
@@ -161,10 +194,14 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
     ) -> (Self, Client) {
         let timeout_config =
             PeerTimeoutConfig::new(config.response_timeout, config.max_connection_time);
+        let transport_policy = config
+            .transport_policy
+            .unwrap_or_else(|| TransportPolicy::default_for(&config.connection_type));
         Node::new(
             network,
             config.white_list,
             config.addresses,
+            config.keychains,
             config.header_checkpoint,
             config.filter_startpoint,
             config.required_peers as PeerRequirement,
@@ -172,6 +209,15 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
             config.connection_type,
             timeout_config,
             config.filter_sync_policy,
+            config.reorg_config,
+            config.deployments,
+            config.deliver_blocks,
+            config.filter_cache_size,
+            config.watch_mempool,
+            config.listen_config,
+            config.persist_filters,
+            transport_policy,
+            config.dns_config,
             peer_store,
             header_store,
         )
@@ -198,16 +244,19 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         self.is_running
             .store(true, std::sync::atomic::Ordering::Relaxed);
         self.fetch_headers().await?;
+        let listener = self.listen().await;
         let mut last_block = LastBlockMonitor::new();
         let mut peer_recv = self.peer_recv.lock().await;
         let mut client_recv = self.client_recv.lock().await;
         loop {
             // Try to advance the state of the node
-            self.advance_state(&last_block).await;
+            self.advance_state(&mut last_block).await;
             // Connect to more peers if we need them and remove old connections
             self.dispatch().await?;
             // If there are blocks we need in the queue, we should request them of a random peer
             self.get_blocks().await;
+            // Request ranges of filters from any peer that is not already sending us filters
+            self.dispatch_filters().await;
             // If we have a transaction to broadcast and we are connected to peers, we should broadcast them
             self.broadcast_transactions().await;
             // Either handle a message from a remote peer or from our client
@@ -217,13 +266,16 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                         Ok(Some(peer_thread)) => {
                             match peer_thread.message {
                                 PeerMessage::Version(version) => {
-                                    let best = {
+                                    let (best, mismatch) = {
                                         let mut peer_map = self.peer_map.lock().await;
                                         peer_map.set_offset(peer_thread.nonce, version.timestamp);
                                         peer_map.set_services(peer_thread.nonce, version.services);
                                         peer_map.set_height(peer_thread.nonce, version.start_height as u32);
-                                        *peer_map.best_height().unwrap_or(&0)
+                                        (*peer_map.best_height().unwrap_or(&0), peer_map.anchor_tip_mismatch())
                                     };
+                                    if let Some((anchor_height, peer_height)) = mismatch {
+                                        self.dialog.send_warning(Warning::AnchorPeersDisagree { anchor_height, peer_height }).await;
+                                    }
                                     let response = self.handle_version(peer_thread.nonce, version, best).await?;
                                     self.send_message(peer_thread.nonce, response).await;
                                     self.dialog.send_dialog(format!("[Peer {}]: version", peer_thread.nonce))
@@ -241,6 +293,14 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                                         None => continue,
                                     }
                                 }
+                                PeerMessage::NewHeaders(headers) => {
+                                    last_block.update();
+                                    self.dialog.send_dialog(format!("[Peer {}]: headers announcement", peer_thread.nonce))
+                                        .await;
+                                    if let Some(response) = self.handle_header_announcement(peer_thread.nonce, headers).await {
+                                        self.send_message(peer_thread.nonce, response).await;
+                                    }
+                                }
                                 PeerMessage::FilterHeaders(cf_headers) => {
                                     self.dialog.send_dialog(format!("[Peer {}]: filter headers", peer_thread.nonce)).await;
                                     match self.handle_cf_headers(peer_thread.nonce, cf_headers).await {
@@ -274,33 +334,122 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                                         None => continue,
                                     }
                                 }
+                                PeerMessage::BlocksNotFound(blocks) => {
+                                    self.dialog.send_dialog(format!("[Peer {}]: notfound", peer_thread.nonce))
+                                        .await;
+                                    let mut chain = self.chain.lock().await;
+                                    chain.blocks_not_found(peer_thread.nonce, &blocks);
+                                }
+                                PeerMessage::NewTransactions(txids) => {
+                                    if let Some(response) = self.handle_inventory_transactions(txids).await {
+                                        self.send_message(peer_thread.nonce, response).await;
+                                    }
+                                }
+                                PeerMessage::Transaction(transaction) => self.handle_transaction(transaction).await,
+                                PeerMessage::FeeFilter(fee_rate) => {
+                                    let mut peer_map = self.peer_map.lock().await;
+                                    peer_map.set_fee_filter(peer_thread.nonce, fee_rate);
+                                    self.dialog.send_data(NodeMessage::FeeFilter(fee_rate)).await;
+                                }
+                                PeerMessage::Misbehaving(penalty) => {
+                                    let mut peer_map = self.peer_map.lock().await;
+                                    peer_map.misbehaving(peer_thread.nonce, penalty).await;
+                                    peer_map.send_message(peer_thread.nonce, MainThreadMessage::Disconnect).await;
+                                }
+                                PeerMessage::RoundTrip(rtt) => {
+                                    let mut peer_map = self.peer_map.lock().await;
+                                    peer_map.set_rtt(peer_thread.nonce, rtt);
+                                }
                                 PeerMessage::Reject(payload) => {
                                     self.dialog
                                         .send_warning(Warning::TransactionRejected).await;
                                     self.dialog.send_data(NodeMessage::TxBroadcastFailure(payload)).await;
                                 }
+                                PeerMessage::GetHeaders(request) => {
+                                    let chain = self.chain.lock().await;
+                                    let headers = chain.serve_headers(&request.locator_hashes, request.stop_hash).await;
+                                    drop(chain);
+                                    self.send_message(peer_thread.nonce, MainThreadMessage::ServeHeaders(headers)).await;
+                                }
+                                PeerMessage::GetFilterHeaders(request) => {
+                                    let chain = self.chain.lock().await;
+                                    // The filter header before the range is sent along with it
+                                    let blocks = chain.served_blocks(request.filter_type, request.start_height.saturating_sub(1), request.stop_hash, MAX_SERVED_FILTER_HEADERS + 1).await;
+                                    let db = chain.db();
+                                    drop(chain);
+                                    let cf_headers = match blocks {
+                                        Some(blocks) => serve::filter_headers(db, request.filter_type, request.start_height, request.stop_hash, blocks).await,
+                                        None => None,
+                                    };
+                                    if let Some(cf_headers) = cf_headers {
+                                        self.send_message(peer_thread.nonce, MainThreadMessage::ServeFilterHeaders(cf_headers)).await;
+                                    }
+                                }
+                                PeerMessage::GetFilters(request) => {
+                                    let chain = self.chain.lock().await;
+                                    let blocks = chain.served_blocks(request.filter_type, request.start_height, request.stop_hash, MAX_SERVED_FILTERS).await;
+                                    let db = chain.db();
+                                    drop(chain);
+                                    let filters = match blocks {
+                                        Some(blocks) => serve::filters(db, request.filter_type, blocks).await,
+                                        None => Vec::new(),
+                                    };
+                                    if !filters.is_empty() {
+                                        self.send_message(peer_thread.nonce, MainThreadMessage::ServeFilters(filters)).await;
+                                    }
+                                }
                                 _ => continue,
                             }
                         },
                         _ => continue,
                     }
                 },
+                connection = accept(&listener) => {
+                    if let Ok((stream, addr)) = connection {
+                        let mut peer_map = self.peer_map.lock().await;
+                        if let Err(e) = peer_map.accept(stream, addr).await {
+                            self.dialog.send_dialog(format!("Refused a connection from {addr}: {e}")).await;
+                        }
+                    }
+                },
                 message = client_recv.recv() => {
                     if let Some(message) = message {
                         match message {
-                            ClientMessage::Shutdown => return Ok(()),
+                            ClientMessage::Shutdown => {
+                                let mut peer_map = self.peer_map.lock().await;
+                                peer_map.save_anchors().await;
+                                return Ok(());
+                            }
                             ClientMessage::Broadcast(transaction) => self.tx_broadcaster.lock().await.add(transaction),
-                            ClientMessage::AddScript(script) =>  self.add_script(script).await,
-                            ClientMessage::Rescan => {
-                                if let Some(response) = self.rescan().await {
-                                    self.broadcast(response).await;
-                                }
-                            },
+                            ClientMessage::AddScripts(scripts, from_height) => self.add_scripts(scripts, from_height).await,
+                            ClientMessage::RemoveScript(script) => self.remove_script(script).await,
+                            ClientMessage::ReplaceScripts(scripts) => self.replace_scripts(scripts).await,
+                            ClientMessage::GetScripts(oneshot) => {
+                                let chain = self.chain.lock().await;
+                                if oneshot.send(chain.scripts()).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped).await
+                                };
+                            }
+                            ClientMessage::WatchOutPoint(outpoint, script) => self.watch_outpoint(outpoint, script).await,
+                            ClientMessage::UnwatchOutPoint(outpoint) => self.unwatch_outpoint(outpoint).await,
+                            ClientMessage::AddKeychain(keychain) => self.add_keychain(keychain).await,
+                            ClientMessage::Rescan => self.rescan().await,
+                            ClientMessage::RescanRange(start, end) => self.rescan_range(start, end).await,
                             ClientMessage::ContinueDownload => {
                                 if let Some(response) = self.start_filter_download().await {
                                     self.broadcast(response).await
                                 }
                             },
+                            ClientMessage::ConfirmReorg => {
+                                if let Some(response) = self.confirm_reorg().await {
+                                    self.broadcast(response).await
+                                }
+                            },
+                            ClientMessage::RejectReorg => {
+                                if let Some(response) = self.reject_reorg().await {
+                                    self.broadcast(response).await
+                                }
+                            },
                             #[cfg(feature = "filter-control")]
                             ClientMessage::GetBlock(hash) => {
                                 let mut state = self.state.write().await;
@@ -309,7 +458,7 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                                 }
                                 drop(state);
                                 let mut chain = self.chain.lock().await;
-                                chain.get_block(hash);
+                                chain.get_block(hash).await;
                             },
                             ClientMessage::SetDuration(duration) => {
                                 let mut peer_map = self.peer_map.lock().await;
@@ -327,6 +476,28 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                                     self.dialog.send_warning(Warning::ChannelDropped).await
                                 };
                             }
+                            ClientMessage::FetchBlock(request) => {
+                                let mut chain = self.chain.lock().await;
+                                chain.fetch_block(request).await;
+                            }
+                            ClientMessage::GetFeeFilterStats(oneshot) => {
+                                let peer_map = self.peer_map.lock().await;
+                                if oneshot.send(peer_map.fee_filter_stats()).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped).await
+                                };
+                            }
+                            ClientMessage::GetPeerInfo(oneshot) => {
+                                let peer_map = self.peer_map.lock().await;
+                                if oneshot.send(peer_map.peer_info()).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped).await
+                                };
+                            }
+                            ClientMessage::GetDeployments(oneshot) => {
+                                let chain = self.chain.lock().await;
+                                if oneshot.send(chain.deployment_states()).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped).await
+                                };
+                            }
                         }
                     }
                 }
@@ -334,6 +505,27 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         }
     }
 
+    // Listen for inbound connections if the node is configured to
+    async fn listen(&self) -> Option<TcpListener> {
+        let address = self.listen_address?;
+        match TcpListener::bind(address).await {
+            Ok(listener) => {
+                self.dialog
+                    .send_dialog(format!("Listening for connections on {address}"))
+                    .await;
+                Some(listener)
+            }
+            Err(e) => {
+                self.dialog
+                    .send_warning(Warning::CouldNotListen {
+                        warning: format!("{address}: {e}"),
+                    })
+                    .await;
+                None
+            }
+        }
+    }
+
     // Send a message to a specified peer
     async fn send_message(&self, nonce: u32, message: MainThreadMessage) {
         let mut peer_map = self.peer_map.lock().await;
@@ -346,14 +538,9 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         peer_map.broadcast(message).await;
     }
 
-    // Send a message to a random peer
-    async fn send_random(&self, message: MainThreadMessage) {
-        let mut peer_map = self.peer_map.lock().await;
-        peer_map.send_random(message).await;
-    }
-
     // Connect to a new peer if we are not connected to enough
     async fn dispatch(&self) -> Result<(), NodeError<H::Error, P::Error>> {
+        let required = self.required_services().await;
         let mut peer_map = self.peer_map.lock().await;
         peer_map.clean().await;
         // Find more peers when lower than the desired threshold.
@@ -361,21 +548,104 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
             self.dialog
                 .send_warning(Warning::NotEnoughConnections)
                 .await;
-            let address = peer_map.next_peer().await?;
+            let address = peer_map.next_peer(required).await?;
             if peer_map.dispatch(address).await.is_err() {
                 self.dialog.send_warning(Warning::CouldNotConnect).await;
             }
+        } else if peer_map.needs_extra_peer() {
+            if let Some(address) = peer_map.next_extra_peer(required).await? {
+                if peer_map.dispatch_extra(address).await.is_err() {
+                    self.dialog.send_warning(Warning::CouldNotConnect).await;
+                }
+            }
         }
         Ok(())
     }
 
-    // If there are blocks in the queue, we should request them of a random peer
+    // If there are blocks in the queue, we should request them of the peers that serve filters.
+    // Blocks fetched by the client may be in the queue at any time.
     async fn get_blocks(&self) {
-        if let Some(block_request) = self.pop_block_queue().await {
+        let mut chain = self.chain.lock().await;
+        chain.expire_block_fetches();
+        // Blocks waiting on blocks that are no longer wanted may be scanned now
+        if chain.scan_downloaded().await {
+            drop(chain);
+            let mut state = self.state.write().await;
+            self.check_filters_again(&mut state).await;
+            drop(state);
+            chain = self.chain.lock().await;
+        }
+        let mut peer_map = self.peer_map.lock().await;
+        let peers = peer_map.block_peers();
+        for peer in chain.expire_block_requests(&peers) {
+            self.dialog
+                .send_dialog(format!(
+                    "Peer {} did not respond to a block request in time",
+                    peer
+                ))
+                .await;
+        }
+        for peer in chain.failing_block_peers() {
+            self.dialog
+                .send_dialog(format!(
+                    "Peer {} failed too many block requests, disconnecting",
+                    peer
+                ))
+                .await;
+            peer_map.misbehaving(peer, FAILED_BLOCKS_PENALTY).await;
+            peer_map
+                .send_message(peer, MainThreadMessage::Disconnect)
+                .await;
+        }
+        for peer in peers {
+            while let Some(block_hash) = chain.next_block(peer) {
+                self.dialog
+                    .send_dialog(format!(
+                        "Requesting block {} from peer {}",
+                        block_hash, peer
+                    ))
+                    .await;
+                peer_map
+                    .send_message(
+                        peer,
+                        MainThreadMessage::GetBlock(GetBlockConfig {
+                            locator: block_hash,
+                        }),
+                    )
+                    .await;
+            }
+        }
+    }
+
+    // Spread the remaining filters over the peers that serve them
+    async fn dispatch_filters(&self) {
+        if !matches!(*self.state.read().await, NodeState::FilterHeadersSynced) {
+            return;
+        }
+        if !matches!(
+            *self.filter_sync_policy.read().await,
+            FilterSyncPolicy::Continue
+        ) {
+            return;
+        }
+        let mut chain = self.chain.lock().await;
+        let mut peer_map = self.peer_map.lock().await;
+        let peers = peer_map.cbf_peers();
+        for peer in chain.expire_filter_requests(&peers) {
             self.dialog
-                .send_dialog("Sending block request to a random peer")
+                .send_dialog(format!(
+                    "Peer {} did not respond to a filter request in time",
+                    peer
+                ))
                 .await;
-            self.send_random(block_request).await;
+            peer_map.misbehaving(peer, UNRESPONSIVE_PENALTY).await;
+        }
+        for peer in peers {
+            if let Some(message) = chain.next_filter_message(peer).await {
+                peer_map
+                    .send_message(peer, MainThreadMessage::GetFilters(message))
+                    .await;
+            }
         }
     }
 
@@ -387,8 +657,20 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         }
         let mut peer_map = self.peer_map.lock().await;
         if peer_map.live().ge(&self.required_peers) {
+            let fee_filter_stats = peer_map.fee_filter_stats();
             for transaction in broadcaster.queue() {
                 let txid = transaction.tx.compute_txid();
+                if let (Some(fee_rate), Some(stats)) = (transaction.fee_rate(), fee_filter_stats) {
+                    if fee_rate < stats.median {
+                        self.dialog
+                            .send_warning(Warning::FeeRateBelowPeerFloor {
+                                txid,
+                                fee_rate,
+                                floor: stats.median,
+                            })
+                            .await;
+                    }
+                }
                 let did_broadcast = match transaction.broadcast_policy {
                     TxBroadcastPolicy::AllPeers => {
                         self.dialog
@@ -424,8 +706,18 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
     }
 
     // Try to continue with the syncing process
-    async fn advance_state(&self, last_block: &LastBlockMonitor) {
+    async fn advance_state(&self, last_block: &mut LastBlockMonitor) {
         let mut state = self.state.write().await;
+        // Look for a better chain with an extra peer, whether the node is syncing or synced
+        if last_block.stale() {
+            self.dialog.send_warning(Warning::PotentialStaleTip).await;
+            self.dialog
+                .send_dialog("Connecting to an extra peer to look for new blocks")
+                .await;
+            let mut peer_map = self.peer_map.lock().await;
+            peer_map.request_extra_peer();
+            last_block.update();
+        }
         match *state {
             NodeState::Behind => {
                 let mut header_chain = self.chain.lock().await;
@@ -448,7 +740,7 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
             }
             NodeState::FilterHeadersSynced => {
                 let header_chain = self.chain.lock().await;
-                if header_chain.is_filters_synced() {
+                if header_chain.is_filters_synced() && !header_chain.is_rescanning() {
                     self.dialog
                         .send_data(NodeMessage::StateChange(NodeState::FiltersSynced))
                         .await;
@@ -469,15 +761,7 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                     let _ = self.dialog.send_data(NodeMessage::Synced(update)).await;
                 }
             }
-            NodeState::TransactionsSynced => {
-                if last_block.stale() {
-                    self.dialog.send_warning(Warning::PotentialStaleTip).await;
-                    self.dialog
-                        .send_dialog("Disconnecting from remote nodes to find new connections")
-                        .await;
-                    self.broadcast(MainThreadMessage::Disconnect).await;
-                }
-            }
+            NodeState::TransactionsSynced => (),
         }
     }
 
@@ -490,6 +774,15 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         }
     }
 
+    // Any peer may serve headers, but afterwards only peers that serve filters are useful
+    async fn required_services(&self) -> ServiceFlags {
+        let state = self.state.read().await;
+        match *state {
+            NodeState::Behind => ServiceFlags::NONE,
+            _ => ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+        }
+    }
+
     // After we receiving some chain-syncing message, we decide what chain of data needs to be
     // requested next.
     async fn next_stateful_message(&self, chain: &mut Chain<H>) -> Option<MainThreadMessage> {
@@ -503,14 +796,8 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
             return Some(MainThreadMessage::GetFilterHeaders(
                 chain.next_cf_header_message().await,
             ));
-        } else if !chain.is_filters_synced() {
-            let filter_download = self.filter_sync_policy.read().await;
-            if matches!(*filter_download, FilterSyncPolicy::Continue) {
-                return Some(MainThreadMessage::GetFilters(
-                    chain.next_filter_message().await,
-                ));
-            }
         }
+        // Filters are requested from every peer that serves them in the main loop
         None
     }
 
@@ -521,12 +808,21 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         version_message: VersionMessage,
         best_height: u32,
     ) -> Result<MainThreadMessage, NodeError<H::Error, P::Error>> {
+        // Inbound peers are only served, so there is nothing to request of them
+        let trusted = {
+            let peer_map = self.peer_map.lock().await;
+            if peer_map.is_inbound(nonce) {
+                return Ok(MainThreadMessage::Verack);
+            }
+            peer_map.is_trusted(nonce)
+        };
         let state = self.state.read().await;
         match *state {
             NodeState::Behind => (),
             _ => {
+                // Configured peers may serve filters without serving blocks, like another light client
                 if !version_message.services.has(ServiceFlags::COMPACT_FILTERS)
-                    || !version_message.services.has(ServiceFlags::NETWORK)
+                    || (!trusted && !version_message.services.has(ServiceFlags::NETWORK))
                 {
                     self.dialog.send_warning(Warning::NoCompactFilters).await;
                     return Ok(MainThreadMessage::Disconnect);
@@ -556,6 +852,18 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                 .send_message(nonce, MainThreadMessage::GetAddr)
                 .await;
         }
+        // Trusted peers that serve bloom filters will share their mempool
+        if self.watch_mempool
+            && peer_map.is_trusted(nonce)
+            && version_message.services.has(ServiceFlags::BLOOM)
+        {
+            self.dialog
+                .send_dialog(format!("Requesting the mempool of peer {nonce}"))
+                .await;
+            peer_map
+                .send_message(nonce, MainThreadMessage::GetMempool)
+                .await;
+        }
         // Inform the user we are connected to all required peers
         if peer_map.live().eq(&self.required_peers) {
             self.dialog.send_data(NodeMessage::ConnectionsMet).await
@@ -587,12 +895,14 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         headers: Vec<Header>,
     ) -> Option<MainThreadMessage> {
         let mut chain = self.chain.lock().await;
+        let tip = chain.tip();
         if let Err(e) = chain.sync_chain(headers).await {
             match e {
                 HeaderSyncError::EmptyMessage => {
                     if !chain.is_synced() {
                         return Some(MainThreadMessage::Disconnect);
                     }
+                    self.evaluate_extra_peer(peer_id, false).await;
                     return self.next_stateful_message(chain.deref_mut()).await;
                 }
                 HeaderSyncError::LessWorkFork => {
@@ -601,8 +911,16 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                             warning: "A peer sent us a fork with less work.".into(),
                         })
                         .await;
+                    let mut lock = self.peer_map.lock().await;
+                    lock.misbehaving(peer_id, e.penalty()).await;
+                    return Some(MainThreadMessage::Disconnect);
+                }
+                // The fork may be valid, but we will not follow it
+                HeaderSyncError::ExceedsMaxReorgDepth | HeaderSyncError::RejectedReorg => {
                     return Some(MainThreadMessage::Disconnect);
                 }
+                // Wait on the client before requesting more headers
+                HeaderSyncError::PendingReorg => return None,
                 _ => {
                     self.dialog
                         .send_warning(Warning::UnexpectedSyncError {
@@ -610,14 +928,59 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                         })
                         .await;
                     let mut lock = self.peer_map.lock().await;
-                    lock.ban(peer_id).await;
+                    lock.misbehaving(peer_id, e.penalty()).await;
                     return Some(MainThreadMessage::Disconnect);
                 }
             }
         }
+        // Every peer lags behind a chain that is still syncing, so the extra peer waits to be judged
+        if chain.is_synced() {
+            self.evaluate_extra_peer(peer_id, chain.tip().ne(&tip))
+                .await;
+        }
         self.next_stateful_message(chain.deref_mut()).await
     }
 
+    // An extra peer that knows a better chain replaces the worst existing peer, and is dropped otherwise
+    async fn evaluate_extra_peer(&self, nonce: u32, found_blocks: bool) {
+        let mut peer_map = self.peer_map.lock().await;
+        if let Some(evict) = peer_map.evaluate_extra_peer(nonce, found_blocks) {
+            self.dialog
+                .send_dialog(format!(
+                    "Disconnecting from peer {evict} after peer {nonce} was evaluated for a better chain"
+                ))
+                .await;
+            peer_map
+                .send_message(evict, MainThreadMessage::Disconnect)
+                .await;
+        }
+    }
+
+    // A peer announced new blocks with headers instead of an `inv`. Headers that extend our chain are added
+    // without another round trip, otherwise the blocks are handled as if they were announced with an `inv`.
+    async fn handle_header_announcement(
+        &self,
+        nonce: u32,
+        headers: Vec<Header>,
+    ) -> Option<MainThreadMessage> {
+        let extends_tip = {
+            let chain = self.chain.lock().await;
+            headers
+                .first()
+                .map_or(false, |header| header.prev_blockhash.eq(&chain.tip()))
+        };
+        if extends_tip {
+            let mut peer_map = self.peer_map.lock().await;
+            for _ in headers.iter() {
+                peer_map.add_one_height(nonce);
+            }
+            drop(peer_map);
+            return self.handle_headers(nonce, headers).await;
+        }
+        let hashes = headers.iter().map(|header| header.block_hash()).collect();
+        self.handle_inventory_blocks(nonce, hashes).await
+    }
+
     // Compact filter headers may result in a number of outcomes, including the need to audit filters.
     async fn handle_cf_headers(
         &self,
@@ -650,7 +1013,7 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                     })
                     .await;
                 let mut lock = self.peer_map.lock().await;
-                lock.ban(peer_id).await;
+                lock.misbehaving(peer_id, e.penalty()).await;
                 Some(MainThreadMessage::Disconnect)
             }
         }
@@ -659,7 +1022,7 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
     // Handle a new compact block filter
     async fn handle_filter(&self, peer_id: u32, filter: CFilter) -> Option<MainThreadMessage> {
         let mut chain = self.chain.lock().await;
-        match chain.sync_filter(filter).await {
+        match chain.sync_filter(peer_id, filter).await {
             Ok(potential_message) => potential_message.map(MainThreadMessage::GetFilters),
             Err(e) => {
                 self.dialog
@@ -667,14 +1030,9 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
                         warning: format!("Compact filter syncing encountered an error: {}", e),
                     })
                     .await;
-                match e {
-                    CFilterSyncError::Filter(_) => Some(MainThreadMessage::Disconnect),
-                    _ => {
-                        let mut lock = self.peer_map.lock().await;
-                        lock.ban(peer_id).await;
-                        Some(MainThreadMessage::Disconnect)
-                    }
-                }
+                let mut lock = self.peer_map.lock().await;
+                lock.misbehaving(peer_id, e.penalty()).await;
+                Some(MainThreadMessage::Disconnect)
             }
         }
     }
@@ -682,41 +1040,49 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
     // Scan a block for transactions.
     async fn handle_block(&self, peer_id: u32, block: Block) -> Option<MainThreadMessage> {
         let mut chain = self.chain.lock().await;
-        if let Err(e) = chain.check_send_block(block).await {
-            self.dialog
-                .send_warning(Warning::UnexpectedSyncError {
-                    warning: format!("Unexpected block scanning error: {}", e),
-                })
-                .await;
-            let mut lock = self.peer_map.lock().await;
-            lock.ban(peer_id).await;
-            return Some(MainThreadMessage::Disconnect);
+        match chain.check_send_block(peer_id, block).await {
+            Ok(catching_up) => {
+                if catching_up {
+                    drop(chain);
+                    let mut state = self.state.write().await;
+                    self.check_filters_again(&mut state).await;
+                }
+            }
+            Err(e) => {
+                self.dialog
+                    .send_warning(Warning::UnexpectedSyncError {
+                        warning: format!("Unexpected block scanning error: {}", e),
+                    })
+                    .await;
+                let mut lock = self.peer_map.lock().await;
+                lock.misbehaving(peer_id, e.penalty()).await;
+                return Some(MainThreadMessage::Disconnect);
+            }
         }
         None
     }
 
-    // The block queue holds all the block hashes we may be interested in
-    async fn pop_block_queue(&self) -> Option<MainThreadMessage> {
-        let state = self.state.read().await;
-        if matches!(
-            *state,
-            NodeState::FilterHeadersSynced | NodeState::FiltersSynced
-        ) {
-            let mut chain = self.chain.lock().await;
-            let next_block_hash = chain.next_block();
-            return match next_block_hash {
-                Some(block_hash) => {
-                    self.dialog
-                        .send_dialog(format!("Next block in queue: {}", block_hash))
-                        .await;
-                    Some(MainThreadMessage::GetBlock(GetBlockConfig {
-                        locator: block_hash,
-                    }))
-                }
-                None => None,
-            };
+    // Request any transactions a peer announced that we have not seen, if we are watching the mempool
+    async fn handle_inventory_transactions(&self, txids: Vec<Txid>) -> Option<MainThreadMessage> {
+        if !self.watch_mempool {
+            return None;
         }
-        None
+        let mut chain = self.chain.lock().await;
+        chain.expire_unconfirmed().await;
+        let txids = chain.need_transactions(txids);
+        if txids.is_empty() {
+            return None;
+        }
+        Some(MainThreadMessage::GetTransactions(txids))
+    }
+
+    // Check a transaction relayed by a peer for watched scripts
+    async fn handle_transaction(&self, transaction: Transaction) {
+        if !self.watch_mempool {
+            return;
+        }
+        let mut chain = self.chain.lock().await;
+        chain.check_unconfirmed(transaction).await;
     }
 
     // If new inventory came in, we need to download the headers and update the node state
@@ -761,28 +1127,87 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         }
     }
 
-    // Add more scripts to the chain to look for. Does not imply a rescan.
-    async fn add_script(&self, script: ScriptBuf) {
+    // Add more scripts to the chain to look for, checking the filters that were already scanned for the new scripts.
+    async fn add_scripts(&self, scripts: HashSet<ScriptBuf>, from_height: Option<u32>) {
+        let mut state = self.state.write().await;
+        let mut chain = self.chain.lock().await;
+        if chain.add_scripts(scripts, from_height).await {
+            self.check_filters_again(&mut state).await;
+        }
+    }
+
+    // Stop looking for a script, dropping any blocks that were only queued because of it.
+    async fn remove_script(&self, script: ScriptBuf) {
+        let mut chain = self.chain.lock().await;
+        chain.remove_script(&script).await;
+    }
+
+    // Look for a new set of scripts, checking the filters that were already scanned for any new scripts.
+    async fn replace_scripts(&self, scripts: HashSet<ScriptBuf>) {
+        let mut state = self.state.write().await;
         let mut chain = self.chain.lock().await;
-        chain.put_script(script);
+        if chain.replace_scripts(scripts).await {
+            self.check_filters_again(&mut state).await;
+        }
+    }
+
+    // Watch the scripts derived from a keychain, checking the filters that were already scanned for them.
+    async fn add_keychain(&self, keychain: Keychain) {
+        let mut state = self.state.write().await;
+        let mut chain = self.chain.lock().await;
+        if chain.add_keychain(keychain).await {
+            self.check_filters_again(&mut state).await;
+        }
+    }
+
+    // Watch for an outpoint to be spent. Does not imply a rescan.
+    async fn watch_outpoint(&self, outpoint: OutPoint, script: ScriptBuf) {
+        let mut chain = self.chain.lock().await;
+        chain.put_outpoint(outpoint, script);
+    }
+
+    // Stop watching for an outpoint to be spent
+    async fn unwatch_outpoint(&self, outpoint: OutPoint) {
+        let mut chain = self.chain.lock().await;
+        chain.remove_outpoint(&outpoint);
     }
 
     // Clear the filter hash cache and redownload the filters.
-    async fn rescan(&self) -> Option<MainThreadMessage> {
+    async fn rescan(&self) {
         let mut state = self.state.write().await;
         let mut chain = self.chain.lock().await;
         match *state {
-            NodeState::Behind => None,
-            NodeState::HeadersSynced => None,
+            NodeState::Behind => (),
+            NodeState::HeadersSynced => (),
             _ => {
                 chain.clear_filters().await;
                 self.dialog
                     .send_data(NodeMessage::StateChange(NodeState::FilterHeadersSynced))
                     .await;
                 *state = NodeState::FilterHeadersSynced;
-                Some(MainThreadMessage::GetFilters(
-                    chain.next_filter_message().await,
-                ))
+            }
+        }
+    }
+
+    // Check a range of filters again while continuing to sync new blocks.
+    async fn rescan_range(&self, start_height: u32, end_height: Option<u32>) {
+        let mut state = self.state.write().await;
+        let mut chain = self.chain.lock().await;
+        chain.rescan_range(start_height, end_height).await;
+        self.check_filters_again(&mut state).await;
+    }
+
+    // Filters that were already checked are being checked again, so the node is no longer synced.
+    async fn check_filters_again(&self, state: &mut NodeState) {
+        match *state {
+            NodeState::Behind => (),
+            NodeState::HeadersSynced => (),
+            NodeState::FilterHeadersSynced => (),
+            _ => {
+                self.dialog
+                    .send_data(NodeMessage::StateChange(NodeState::FilterHeadersSynced))
+                    .await;
+                *state = NodeState::FilterHeadersSynced;
             }
         }
     }
@@ -803,6 +1228,37 @@ impl<H: HeaderStore, P: PeerStore> Node<H, P> {
         }
     }
 
+    // Follow a deep reorganization by explicit command
+    async fn confirm_reorg(&self) -> Option<MainThreadMessage> {
+        let mut state = self.state.write().await;
+        let mut chain = self.chain.lock().await;
+        if !chain.confirm_reorg().await {
+            return None;
+        }
+        self.dialog
+            .send_data(NodeMessage::StateChange(NodeState::Behind))
+            .await;
+        *state = NodeState::Behind;
+        let next_headers = GetHeaderConfig {
+            locators: chain.locators().await,
+            stop_hash: None,
+        };
+        Some(MainThreadMessage::GetHeaders(next_headers))
+    }
+
+    // Stay on the current chain by explicit command, and continue syncing headers from peers on it
+    async fn reject_reorg(&self) -> Option<MainThreadMessage> {
+        let mut chain = self.chain.lock().await;
+        if !chain.reject_reorg().await {
+            return None;
+        }
+        let next_headers = GetHeaderConfig {
+            locators: chain.locators().await,
+            stop_hash: None,
+        };
+        Some(MainThreadMessage::GetHeaders(next_headers))
+    }
+
     // When the application starts, fetch any headers we know about from the database.
     async fn fetch_headers(&self) -> Result<(), NodeError<H::Error, P::Error>> {
         self.dialog
@@ -833,3 +1289,11 @@ impl core::fmt::Display for NodeState {
         }
     }
 }
+
+// Wait for an inbound connection, or forever if the node is not listening
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
@@ -8,10 +9,11 @@ use std::{
 use bitcoin::{
     key::rand,
     p2p::{address::AddrV2, ServiceFlags},
-    Network,
+    FeeRate, Network,
 };
 use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
 use tokio::{
+    net::TcpStream,
     sync::{
         mpsc::{self, Sender},
         Mutex,
@@ -20,11 +22,13 @@ use tokio::{
 };
 
 use crate::{
-    db::{traits::PeerStore, PeerStatus, PersistedPeer},
+    db::{traits::PeerStore, AddressType, PeerStatus, PersistedPeer},
     network::{
         error::PeerError,
+        i2p::I2pConnection,
         peer::Peer,
-        traits::{ClearNetConnection, NetworkConnector},
+        socks::Socks5Connection,
+        traits::{ClearNetConnection, NetworkConnector, StreamReader, StreamWriter},
     },
     prelude::{default_port_from_network, Median, Netgroup},
     ConnectionType, PeerStoreSizeConfig, TrustedPeer,
@@ -34,11 +38,17 @@ use super::{
     channel_messages::{CombinedAddr, MainThreadMessage, PeerThreadMessage},
     dialog::Dialog,
     error::PeerManagerError,
-    messages::Warning,
-    PeerTimeoutConfig,
+    messages::{FeeFilterStats, PeerInfo, Warning},
+    DnsConfig, PeerTimeoutConfig, TransportPolicy,
 };
 
 const MAX_TRIES: usize = 50;
+// Peers are banned once their misbehaviour score reaches this threshold
+const BAN_SCORE: u32 = 100;
+// The number of outbound peers saved on shutdown to reconnect to on the next start
+const MAX_ANCHORS: usize = 2;
+// Anchor peers and new peers may be a couple blocks apart while a block propagates
+const ANCHOR_HEIGHT_TOLERANCE: u32 = 2;
 
 // Preferred peers to connect to based on the user configuration
 type Whitelist = Vec<TrustedPeer>;
@@ -50,6 +60,14 @@ pub(crate) struct ManagedPeer {
     address: AddrV2,
     port: u16,
     service_flags: Option<ServiceFlags>,
+    fee_filter: Option<FeeRate>,
+    rtt: Option<Duration>,
+    misbehaviour: u32,
+    // The handshake completed and the peer has not been penalized since
+    clean_session: bool,
+    trusted: bool,
+    anchor: bool,
+    inbound: bool,
     ptx: Sender<MainThreadMessage>,
     handle: JoinHandle<Result<(), PeerError>>,
 }
@@ -65,10 +83,26 @@ pub(crate) struct PeerMap<P: PeerStore> {
     db: Arc<Mutex<P>>,
     connector: Arc<Mutex<dyn NetworkConnector + Send + Sync>>,
     whitelist: Whitelist,
+    trusted: HashSet<AddrV2>,
+    // Inbound peers are not saved to the peer database, so their bans last until the node stops
+    banned_inbound: HashSet<IpAddr>,
     dialog: Dialog,
     target_db_size: PeerStoreSizeConfig,
     net_groups: HashSet<String>,
     timeout_config: PeerTimeoutConfig,
+    relay: bool,
+    max_inbound: usize,
+    local_services: ServiceFlags,
+    transport_policy: TransportPolicy,
+    anchors: Option<Vec<PersistedPeer>>,
+    anchor_addrs: HashSet<AddrV2>,
+    warned_anchor_tip: bool,
+    extra_wanted: bool,
+    extra_peer: Option<u32>,
+    #[cfg(feature = "dns")]
+    seeded_services: bool,
+    #[cfg_attr(not(feature = "dns"), allow(dead_code))]
+    dns_config: DnsConfig,
 }
 
 #[allow(dead_code)]
@@ -83,6 +117,11 @@ impl<P: PeerStore> PeerMap<P> {
         connection_type: ConnectionType,
         target_db_size: PeerStoreSizeConfig,
         timeout_config: PeerTimeoutConfig,
+        relay: bool,
+        max_inbound: usize,
+        serve_filters: bool,
+        transport_policy: TransportPolicy,
+        dns_config: DnsConfig,
     ) -> Self {
         let connector: Arc<Mutex<dyn NetworkConnector + Send + Sync>> = match connection_type {
             ConnectionType::ClearNet => Arc::new(Mutex::new(ClearNetConnection::new())),
@@ -91,6 +130,10 @@ impl<P: PeerStore> PeerMap<P> {
                 use crate::network::tor::TorConnection;
                 Arc::new(Mutex::new(TorConnection::new(client)))
             }
+            ConnectionType::Socks5 { proxy, auth } => {
+                Arc::new(Mutex::new(Socks5Connection::new(proxy, auth)))
+            }
+            ConnectionType::I2p { sam } => Arc::new(Mutex::new(I2pConnection::new(sam))),
         };
         Self {
             num_peers: 0,
@@ -101,24 +144,73 @@ impl<P: PeerStore> PeerMap<P> {
             db: Arc::new(Mutex::new(db)),
             connector,
             whitelist,
+            trusted: HashSet::new(),
+            banned_inbound: HashSet::new(),
             dialog,
             target_db_size,
             net_groups: HashSet::new(),
             timeout_config,
+            relay,
+            max_inbound,
+            local_services: if serve_filters {
+                ServiceFlags::COMPACT_FILTERS
+            } else {
+                ServiceFlags::NONE
+            },
+            transport_policy,
+            anchors: None,
+            anchor_addrs: HashSet::new(),
+            warned_anchor_tip: false,
+            extra_wanted: false,
+            extra_peer: None,
+            #[cfg(feature = "dns")]
+            seeded_services: false,
+            dns_config,
         }
     }
 
-    // Remove any finished connections
+    // Remove any finished connections. Outbound peers that did not misbehave during the connection
+    // are forgiven their past penalties.
     pub async fn clean(&mut self) {
+        let forgiven: Vec<u32> = self
+            .map
+            .iter_mut()
+            .filter(|(_, peer)| {
+                peer.handle.is_finished()
+                    && !peer.inbound
+                    && peer.clean_session
+                    && peer.misbehaviour > 0
+            })
+            .map(|(nonce, peer)| {
+                peer.misbehaviour = 0;
+                *nonce
+            })
+            .collect();
+        for nonce in forgiven {
+            self.persist(nonce, PeerStatus::Tried).await;
+        }
         self.map.retain(|_, peer| !peer.handle.is_finished());
         self.heights.retain(|peer, _| self.map.contains_key(peer));
+        if let Some(nonce) = self.extra_peer {
+            if !self.map.contains_key(&nonce) {
+                self.extra_peer = None;
+            }
+        }
     }
 
-    // The number of peers with live connections
+    // The number of outbound peers with live connections
     pub fn live(&mut self) -> usize {
         self.map
             .values()
-            .filter(|peer| !peer.handle.is_finished())
+            .filter(|peer| !peer.handle.is_finished() && !peer.inbound)
+            .count()
+    }
+
+    // The number of inbound peers with live connections
+    pub fn live_inbound(&self) -> usize {
+        self.map
+            .values()
+            .filter(|peer| !peer.handle.is_finished() && peer.inbound)
             .count()
     }
 
@@ -126,7 +218,7 @@ impl<P: PeerStore> PeerMap<P> {
     pub fn num_cpf_peers(&mut self) -> usize {
         self.map
             .values()
-            .filter(|peer| !peer.handle.is_finished())
+            .filter(|peer| !peer.handle.is_finished() && !peer.inbound)
             .filter(|peer| {
                 if let Some(flags) = peer.service_flags {
                     flags.has(ServiceFlags::COMPACT_FILTERS)
@@ -137,9 +229,41 @@ impl<P: PeerStore> PeerMap<P> {
             .count()
     }
 
+    // The outbound peers with live connections that serve compact block filters
+    pub fn cbf_peers(&mut self) -> Vec<u32> {
+        self.peers_with_services(ServiceFlags::COMPACT_FILTERS)
+    }
+
+    // The outbound peers with live connections that serve compact block filters and blocks
+    pub fn block_peers(&mut self) -> Vec<u32> {
+        self.peers_with_services(ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK)
+    }
+
+    // Peers are ordered by round trip time, so work is handed to the fastest peers first
+    fn peers_with_services(&self, services: ServiceFlags) -> Vec<u32> {
+        let mut peers: Vec<(u32, Option<Duration>)> = self
+            .map
+            .iter()
+            .filter(|(_, peer)| !peer.handle.is_finished() && !peer.inbound)
+            .filter(|(_, peer)| {
+                peer.service_flags
+                    .map_or(false, |flags| flags.has(services))
+            })
+            .map(|(nonce, peer)| (*nonce, peer.rtt))
+            .collect();
+        // Peers without a measured round trip time go last
+        peers.sort_by_key(|(_, rtt)| (rtt.is_none(), *rtt));
+        peers.into_iter().map(|(nonce, _)| nonce).collect()
+    }
+
     // Get the median time adjustment for the currently connected peers
     pub fn median_time_adjustment(&self) -> i64 {
-        let mut time_offsets: Vec<i64> = self.map.values().map(|peer| peer.net_time).collect();
+        let mut time_offsets: Vec<i64> = self
+            .map
+            .values()
+            .filter(|peer| !peer.inbound)
+            .map(|peer| peer.net_time)
+            .collect();
         time_offsets.median()
     }
 
@@ -169,14 +293,20 @@ impl<P: PeerStore> PeerMap<P> {
         let (ptx, prx) = mpsc::channel::<MainThreadMessage>(32);
         let peer_num = self.num_peers + 1;
         self.num_peers = peer_num;
+        let v2_transport = self
+            .transport_policy
+            .v2_transport(loaded_peer.services)
+            .ok_or(PeerError::PlaintextRefused)?;
+        let fallback = v2_transport && self.transport_policy.eq(&TransportPolicy::PreferV2);
         let mut peer = Peer::new(
             peer_num,
             self.network,
             self.mtx.clone(),
             prx,
-            loaded_peer.services,
+            v2_transport,
             self.dialog.clone(),
             self.timeout_config,
+            self.relay,
         );
         let mut connector = self.connector.lock().await;
         if !connector.can_connect(&loaded_peer.addr) {
@@ -191,11 +321,44 @@ impl<P: PeerStore> PeerMap<P> {
         let (reader, writer) = connector
             .connect(loaded_peer.addr.clone(), loaded_peer.port)
             .await?;
-        let handle = tokio::spawn(async move { peer.run(reader, writer).await });
+        let reconnector = Arc::clone(&self.connector);
+        let (addr, port) = (loaded_peer.addr.clone(), loaded_peer.port);
+        let dialog = self.dialog.clone();
+        let handle = tokio::spawn(async move {
+            match peer.run(reader, writer).await {
+                // The stream is unusable after a failed handshake, so a new connection is made
+                Err(PeerError::HandshakeFailed) if fallback => {
+                    peer.fallback_to_v1();
+                    let connection = {
+                        let mut connector = reconnector.lock().await;
+                        connector.connect(addr, port).await
+                    };
+                    match connection {
+                        Ok((reader, writer)) => peer.run(reader, writer).await,
+                        Err(e) => {
+                            dialog.send_warning(Warning::CouldNotConnect).await;
+                            Err(e)
+                        }
+                    }
+                }
+                Err(PeerError::HandshakeFailed) => {
+                    dialog.send_warning(Warning::CouldNotConnect).await;
+                    Err(PeerError::HandshakeFailed)
+                }
+                result => result,
+            }
+        });
         self.map.insert(
             peer_num,
             ManagedPeer {
                 service_flags: None,
+                fee_filter: None,
+                rtt: None,
+                misbehaviour: loaded_peer.misbehaviour,
+                clean_session: false,
+                trusted: self.trusted.contains(&loaded_peer.addr),
+                anchor: self.anchor_addrs.contains(&loaded_peer.addr),
+                inbound: false,
                 address: loaded_peer.addr,
                 port: loaded_peer.port,
                 net_time: 0,
@@ -206,6 +369,123 @@ impl<P: PeerStore> PeerMap<P> {
         Ok(())
     }
 
+    // Ask for one more outbound connection than required, to look for a better chain
+    pub fn request_extra_peer(&mut self) {
+        if self.extra_peer.is_none() {
+            self.extra_wanted = true;
+        }
+    }
+
+    pub fn needs_extra_peer(&self) -> bool {
+        self.extra_wanted && self.extra_peer.is_none()
+    }
+
+    // Connect to the extra peer, only if it is in a different netgroup than every connected peer
+    pub async fn dispatch_extra(&mut self, loaded_peer: PersistedPeer) -> Result<(), PeerError> {
+        let netgroup = loaded_peer.addr.netgroup();
+        if self
+            .map
+            .values()
+            .any(|peer| !peer.handle.is_finished() && peer.address.netgroup().eq(&netgroup))
+        {
+            return Ok(());
+        }
+        self.dispatch(loaded_peer).await?;
+        self.extra_wanted = false;
+        self.extra_peer = Some(self.num_peers);
+        Ok(())
+    }
+
+    // After the extra peer answers a request for headers with the chain synced, return the peer that should be disconnected.
+    // If the extra peer found blocks, the worst performing peer is replaced, otherwise the extra peer is dropped.
+    pub fn evaluate_extra_peer(&mut self, nonce: u32, found_blocks: bool) -> Option<u32> {
+        if self.extra_peer.ne(&Some(nonce)) {
+            return None;
+        }
+        self.extra_peer = None;
+        if !found_blocks {
+            return Some(nonce);
+        }
+        // Peers that report a lower height are worse, then peers that respond slower
+        let latency = |peer: &ManagedPeer| peer.rtt.unwrap_or(Duration::MAX);
+        self.map
+            .iter()
+            .filter(|(other, peer)| {
+                other.ne(&&nonce) && !peer.handle.is_finished() && !peer.inbound && !peer.trusted
+            })
+            .min_by(|(a, peer_a), (b, peer_b)| {
+                let height_a = self.heights.get(a).unwrap_or(&0);
+                let height_b = self.heights.get(b).unwrap_or(&0);
+                height_a
+                    .cmp(height_b)
+                    .then_with(|| latency(peer_b).cmp(&latency(peer_a)))
+            })
+            .map(|(worst, _)| *worst)
+    }
+
+    // Serve a peer that connected to us, if there is room for another inbound peer
+    pub async fn accept(&mut self, stream: TcpStream, addr: SocketAddr) -> Result<(), PeerError> {
+        if self.banned_inbound.contains(&addr.ip()) {
+            return Err(PeerError::Banned);
+        }
+        if self.live_inbound() >= self.max_inbound {
+            return Err(PeerError::TooManyInbound);
+        }
+        if self.transport_policy.eq(&TransportPolicy::V2Only) {
+            return Err(PeerError::PlaintextRefused);
+        }
+        let (ptx, prx) = mpsc::channel::<MainThreadMessage>(32);
+        let peer_num = self.num_peers + 1;
+        self.num_peers = peer_num;
+        // Inbound peers send plaintext messages
+        let mut peer = Peer::new(
+            peer_num,
+            self.network,
+            self.mtx.clone(),
+            prx,
+            false,
+            self.dialog.clone(),
+            self.timeout_config,
+            false,
+        );
+        peer.serve_inbound(self.local_services);
+        self.dialog
+            .send_dialog(format!("Accepted a connection from {addr}"))
+            .await;
+        let (reader, writer) = stream.into_split();
+        let reader: StreamReader = Mutex::new(Box::new(reader));
+        let writer: StreamWriter = Mutex::new(Box::new(writer));
+        let handle = tokio::spawn(async move { peer.run(reader, writer).await });
+        let address = match addr.ip() {
+            IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+            IpAddr::V6(ip) => AddrV2::Ipv6(ip),
+        };
+        self.map.insert(
+            peer_num,
+            ManagedPeer {
+                service_flags: None,
+                fee_filter: None,
+                rtt: None,
+                misbehaviour: 0,
+                clean_session: false,
+                trusted: false,
+                anchor: false,
+                inbound: true,
+                address,
+                port: addr.port(),
+                net_time: 0,
+                ptx,
+                handle,
+            },
+        );
+        Ok(())
+    }
+
+    // Did this peer connect to us
+    pub fn is_inbound(&self, nonce: u32) -> bool {
+        self.map.get(&nonce).map_or(false, |peer| peer.inbound)
+    }
+
     // Set the services of a peer
     pub fn set_services(&mut self, nonce: u32, flags: ServiceFlags) {
         if let Some(peer) = self.map.get_mut(&nonce) {
@@ -213,9 +493,55 @@ impl<P: PeerStore> PeerMap<P> {
         }
     }
 
-    // Set the height of a peer upon receiving the version message
+    // Set the minimum fee rate a peer will relay
+    pub fn set_fee_filter(&mut self, nonce: u32, fee_rate: FeeRate) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.fee_filter = Some(fee_rate)
+        }
+    }
+
+    // Set the smoothed round trip time of pings to a peer
+    pub fn set_rtt(&mut self, nonce: u32, rtt: Duration) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.rtt = Some(rtt)
+        }
+    }
+
+    // Describe the peers with live connections
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        self.map
+            .values()
+            .filter(|peer| !peer.handle.is_finished())
+            .map(|peer| PeerInfo {
+                address: peer.address.clone(),
+                port: peer.port,
+                services: peer.service_flags,
+                inbound: peer.inbound,
+                round_trip_time: peer.rtt,
+            })
+            .collect()
+    }
+
+    // The fee rates the connected peers will relay
+    pub fn fee_filter_stats(&self) -> Option<FeeFilterStats> {
+        FeeFilterStats::from_fee_rates(
+            self.map
+                .values()
+                .filter(|peer| !peer.handle.is_finished())
+                .filter_map(|peer| peer.fee_filter),
+        )
+    }
+
+    // Was this peer configured by the user
+    pub fn is_trusted(&self, nonce: u32) -> bool {
+        self.map.get(&nonce).map_or(false, |peer| peer.trusted)
+    }
+
+    // Set the height of a peer upon receiving the version message. Inbound peers are not synced from.
     pub fn set_height(&mut self, nonce: u32, height: u32) {
-        self.heights.insert(nonce, height);
+        if !self.is_inbound(nonce) {
+            self.heights.insert(nonce, height);
+        }
     }
 
     // Add one to the height of a peer when receiving inventory
@@ -237,9 +563,12 @@ impl<P: PeerStore> PeerMap<P> {
         }
     }
 
-    // Broadcast to all connected peers, returning if at least one peer received the message.
+    // Broadcast to all connected outbound peers, returning if at least one peer received the message.
     pub async fn broadcast(&mut self, message: MainThreadMessage) -> bool {
-        let active = self.map.values().filter(|peer| !peer.handle.is_finished());
+        let active = self
+            .map
+            .values()
+            .filter(|peer| !peer.handle.is_finished() && !peer.inbound);
         let mut sends = Vec::new();
         for peer in active {
             let res = peer.ptx.send(message.clone()).await;
@@ -251,7 +580,12 @@ impl<P: PeerStore> PeerMap<P> {
     // Send to a random peer, returning true if the message was sent.
     pub async fn send_random(&mut self, message: MainThreadMessage) -> bool {
         let mut rng = StdRng::from_entropy();
-        if let Some((_, peer)) = self.map.iter().choose(&mut rng) {
+        if let Some((_, peer)) = self
+            .map
+            .iter()
+            .filter(|(_, peer)| !peer.inbound)
+            .choose(&mut rng)
+        {
             let res = peer.ptx.send(message).await;
             return res.is_ok();
         }
@@ -260,9 +594,15 @@ impl<P: PeerStore> PeerMap<P> {
 
     // Pull a peer from the configuration if we have one. If not, select a random peer from the database,
     // as long as it is not from the same netgroup. If there are no peers in the database, try DNS.
-    pub async fn next_peer(&mut self) -> Result<PersistedPeer, PeerManagerError<P::Error>> {
+    // Peers that serve the required services and are reachable with the configured connection are preferred.
+    // Peers with unknown services are only a last resort, so the node does not waste attempts on them.
+    pub async fn next_peer(
+        &mut self,
+        required: ServiceFlags,
+    ) -> Result<PersistedPeer, PeerManagerError<P::Error>> {
         if let Some(peer) = self.whitelist.pop() {
             self.dialog.send_dialog("Using a configured peer").await;
+            self.trusted.insert(peer.address.clone());
             let port = peer
                 .port
                 .unwrap_or(default_port_from_network(&self.network));
@@ -270,6 +610,12 @@ impl<P: PeerStore> PeerMap<P> {
                 PersistedPeer::new(peer.address, port, peer.known_services, PeerStatus::Tried);
             return Ok(peer);
         }
+        if let Some(peer) = self.next_anchor().await? {
+            self.dialog
+                .send_dialog("Reconnecting to an anchor peer")
+                .await;
+            return Ok(peer);
+        }
         let current_count = {
             let mut peer_manager = self.db.lock().await;
             peer_manager.num_unbanned().await?
@@ -277,21 +623,66 @@ impl<P: PeerStore> PeerMap<P> {
         if current_count < 1 {
             self.dialog.send_warning(Warning::EmptyPeerDatabase).await;
             #[cfg(feature = "dns")]
-            self.bootstrap().await?;
+            self.bootstrap(required).await?;
+        }
+        let address_types = self.connector.lock().await.address_types();
+        if let Some(peer) = self.select_peer(required, address_types).await? {
+            return Ok(peer);
+        }
+        // Ask the seeds for peers that likely serve what we need, but only once per session. Peers with
+        // unknown services are still available if the seeds do not respond.
+        #[cfg(feature = "dns")]
+        if required != ServiceFlags::NONE && !self.seeded_services {
+            self.seeded_services = true;
+            let _ = self.bootstrap(required).await;
+        }
+        if let Some(peer) = self.select_peer(ServiceFlags::NONE, address_types).await? {
+            return Ok(peer);
         }
         let mut peer_manager = self.db.lock().await;
-        let mut tries = 0;
+        peer_manager.random().await.map_err(From::from)
+    }
+
+    // The extra peer is only drawn from the database, so configured and anchor peers are never discarded
+    // when the extra peer shares a netgroup with a connected peer.
+    pub async fn next_extra_peer(
+        &mut self,
+        required: ServiceFlags,
+    ) -> Result<Option<PersistedPeer>, PeerManagerError<P::Error>> {
+        let address_types = self.connector.lock().await.address_types();
+        if let Some(peer) = self.select_peer(required, address_types).await? {
+            return Ok(Some(peer));
+        }
+        self.select_peer(ServiceFlags::NONE, address_types).await
+    }
+
+    // Prefer peers from unused netgroups that have the randomly chosen status
+    async fn select_peer(
+        &mut self,
+        required: ServiceFlags,
+        address_types: &[AddressType],
+    ) -> Result<Option<PersistedPeer>, PeerManagerError<P::Error>> {
+        let mut peer_manager = self.db.lock().await;
         let desired_status = PeerStatus::random();
-        while tries < MAX_TRIES {
-            let peer = peer_manager.random().await?;
-            if self.net_groups.contains(&peer.addr.netgroup()) || desired_status.ne(&peer.status) {
-                tries += 1;
-                continue;
-            } else {
-                return Ok(peer);
+        let mut fallback = None;
+        for _ in 0..MAX_TRIES {
+            match peer_manager
+                .random_matching(required, address_types.to_vec())
+                .await?
+            {
+                Some(peer) => {
+                    if self.net_groups.contains(&peer.addr.netgroup())
+                        || desired_status.ne(&peer.status)
+                    {
+                        fallback = Some(peer);
+                        continue;
+                    }
+                    return Ok(Some(peer));
+                }
+                None => break,
             }
         }
-        peer_manager.random().await.map_err(From::from)
+        Ok(fallback)
     }
 
     // Do we need peers
@@ -306,6 +697,72 @@ impl<P: PeerStore> PeerMap<P> {
         }
     }
 
+    // Anchors are loaded once and removed from the database, so a node that crashes does not return to them
+    async fn next_anchor(&mut self) -> Result<Option<PersistedPeer>, PeerManagerError<P::Error>> {
+        if self.anchors.is_none() {
+            let mut db = self.db.lock().await;
+            let anchors = db.anchors().await?;
+            db.set_anchors(Vec::new()).await?;
+            self.anchor_addrs = anchors.iter().map(|peer| peer.addr.clone()).collect();
+            self.anchors = Some(anchors);
+        }
+        Ok(self.anchors.as_mut().and_then(|anchors| anchors.pop()))
+    }
+
+    // Save the fastest outbound peers that serve filters, so they are connected to first on the next start.
+    // The previous anchors are kept if there are no such peers.
+    pub async fn save_anchors(&mut self) {
+        let anchors: Vec<PersistedPeer> = self
+            .cbf_peers()
+            .into_iter()
+            .filter_map(|nonce| self.map.get(&nonce))
+            .filter(|peer| !peer.trusted)
+            .take(MAX_ANCHORS)
+            .map(|peer| {
+                PersistedPeer::new(
+                    peer.address.clone(),
+                    peer.port,
+                    peer.service_flags.unwrap_or(ServiceFlags::NONE),
+                    PeerStatus::Tried,
+                )
+            })
+            .collect();
+        if anchors.is_empty() {
+            return;
+        }
+        let mut db = self.db.lock().await;
+        if let Err(e) = db.set_anchors(anchors).await {
+            self.dialog
+                .send_warning(Warning::FailedPersistance {
+                    warning: format!("Encountered an error saving the anchor peers ... {e}"),
+                })
+                .await;
+        }
+    }
+
+    // The best heights of the anchor peers and the other outbound peers, if they are too far apart.
+    // An attacker that filled the peer database may only know of a chain with less work.
+    pub fn anchor_tip_mismatch(&mut self) -> Option<(u32, u32)> {
+        if self.warned_anchor_tip {
+            return None;
+        }
+        let best_height = |anchor: bool| {
+            self.map
+                .iter()
+                .filter(|(_, peer)| !peer.handle.is_finished() && !peer.inbound)
+                .filter(|(_, peer)| peer.anchor == anchor)
+                .filter_map(|(nonce, _)| self.heights.get(nonce))
+                .max()
+                .copied()
+        };
+        let (anchor_height, peer_height) = (best_height(true)?, best_height(false)?);
+        if anchor_height.abs_diff(peer_height) > ANCHOR_HEIGHT_TOLERANCE {
+            self.warned_anchor_tip = true;
+            return Some((anchor_height, peer_height));
+        }
+        None
+    }
+
     // Add peers to the database that were gossiped over the p2p network
     pub async fn add_gossiped_peers(&mut self, peers: Vec<CombinedAddr>) {
         let mut db = self.db.lock().await;
@@ -333,44 +790,71 @@ impl<P: PeerStore> PeerMap<P> {
 
     // We tried this peer and successfully connected.
     pub async fn tried(&mut self, nonce: u32) {
-        if let Some(peer) = self.map.get(&nonce) {
-            let mut db = self.db.lock().await;
-            if let Err(e) = db
-                .update(PersistedPeer::new(
-                    peer.address.clone(),
-                    peer.port,
-                    peer.service_flags.unwrap_or(ServiceFlags::NONE),
-                    PeerStatus::Tried,
-                ))
-                .await
-            {
-                self.dialog
-                    .send_warning(Warning::FailedPersistance {
-                        warning: format!(
-                            "Encountered an error adding {:?}:{} flags: {} ... {e}",
-                            peer.address,
-                            peer.port,
-                            peer.service_flags.unwrap_or(ServiceFlags::NONE)
-                        ),
-                    })
-                    .await;
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.clean_session = true;
+        }
+        self.persist(nonce, PeerStatus::Tried).await;
+    }
+
+    // This peer misbehaved in some way, so the penalty is added to its score. The peer is banned once
+    // the score reaches the threshold, and otherwise the score is saved for the next connection. The
+    // score is cleared after a later connection where the peer does not misbehave.
+    pub async fn misbehaving(&mut self, nonce: u32, penalty: u32) {
+        if penalty == 0 {
+            return;
+        }
+        let (score, inbound) = match self.map.get_mut(&nonce) {
+            Some(peer) => {
+                peer.misbehaviour = peer.misbehaviour.saturating_add(penalty);
+                peer.clean_session = false;
+                (peer.misbehaviour, peer.inbound)
             }
+            None => return,
+        };
+        if score >= BAN_SCORE {
+            self.dialog
+                .send_dialog(format!(
+                    "Peer {nonce} reached a misbehaviour score of {score}, banning"
+                ))
+                .await;
+            self.ban(nonce).await;
+        } else if !inbound {
+            self.persist(nonce, PeerStatus::Tried).await;
         }
     }
 
     // This peer misbehaved in some way.
     pub async fn ban(&mut self, nonce: u32) {
+        let inbound_ip = match self.map.get_mut(&nonce) {
+            Some(peer) => {
+                peer.clean_session = false;
+                match (&peer.address, peer.inbound) {
+                    (AddrV2::Ipv4(ip), true) => Some(IpAddr::V4(*ip)),
+                    (AddrV2::Ipv6(ip), true) => Some(IpAddr::V6(*ip)),
+                    _ => None,
+                }
+            }
+            None => return,
+        };
+        match inbound_ip {
+            Some(ip) => {
+                self.banned_inbound.insert(ip);
+            }
+            None => self.persist(nonce, PeerStatus::Ban).await,
+        }
+    }
+
+    async fn persist(&mut self, nonce: u32, status: PeerStatus) {
         if let Some(peer) = self.map.get(&nonce) {
             let mut db = self.db.lock().await;
-            if let Err(e) = db
-                .update(PersistedPeer::new(
-                    peer.address.clone(),
-                    peer.port,
-                    peer.service_flags.unwrap_or(ServiceFlags::NONE),
-                    PeerStatus::Ban,
-                ))
-                .await
-            {
+            let mut persisted = PersistedPeer::new(
+                peer.address.clone(),
+                peer.port,
+                peer.service_flags.unwrap_or(ServiceFlags::NONE),
+                status,
+            );
+            persisted.misbehaviour = peer.misbehaviour;
+            if let Err(e) = db.update(persisted).await {
                 self.dialog
                     .send_warning(Warning::FailedPersistance {
                         warning: format!(
@@ -385,14 +869,25 @@ impl<P: PeerStore> PeerMap<P> {
         }
     }
 
+    // Seeds answer for a subdomain like `x49.` with peers that signal those service bits. The seeds are not
+    // trusted to report services, so the peers are recorded with unknown services until a handshake.
     #[cfg(feature = "dns")]
-    async fn bootstrap(&mut self) -> Result<(), PeerManagerError<P::Error>> {
+    async fn bootstrap(
+        &mut self,
+        required: ServiceFlags,
+    ) -> Result<(), PeerManagerError<P::Error>> {
         use crate::network::dns::Dns;
         use std::net::IpAddr;
         self.dialog.send_dialog("Bootstraping peers with DNS").await;
+        // Seeds do not filter for every combination of services, but they do for witness peers
+        let services = if required == ServiceFlags::NONE {
+            required
+        } else {
+            required | ServiceFlags::WITNESS
+        };
         let mut db_lock = self.db.lock().await;
-        let new_peers = Dns::new(self.network)
-            .bootstrap()
+        let new_peers = Dns::new(self.network, &self.dns_config)
+            .bootstrap(services, &self.dialog)
             .await
             .map_err(|_| PeerManagerError::Dns)?
             .into_iter()
@@ -2,12 +2,14 @@ use std::collections::HashMap;
 
 use bitcoin::{
     key::rand::{self, seq::IteratorRandom},
-    p2p::address::AddrV2,
+    p2p::{address::AddrV2, ServiceFlags},
 };
 use rand::{rngs::StdRng, SeedableRng};
 
 use crate::{
-    db::{error::StatelessPeerStoreError, traits::PeerStore, PeerStatus, PersistedPeer},
+    db::{
+        error::StatelessPeerStoreError, traits::PeerStore, AddressType, PeerStatus, PersistedPeer,
+    },
     prelude::FutureResult,
 };
 
@@ -60,6 +62,25 @@ impl StatelessPeerStore {
         }
     }
 
+    async fn random_matching(
+        &mut self,
+        required: ServiceFlags,
+        address_types: Vec<AddressType>,
+    ) -> Result<Option<PersistedPeer>, StatelessPeerStoreError> {
+        let mut rng = StdRng::from_entropy();
+        let random_peer = self
+            .list
+            .iter()
+            .filter(|(_, peer)| {
+                peer.status != PeerStatus::Ban
+                    && peer.services.has(required)
+                    && address_types.contains(&AddressType::from(&peer.addr))
+            })
+            .choose(&mut rng)
+            .map(|(key, _)| key.clone());
+        Ok(random_peer.and_then(|ip| self.list.remove(&ip)))
+    }
+
     async fn num_unbanned(&mut self) -> Result<u32, StatelessPeerStoreError> {
         Ok(self
             .list
@@ -79,6 +100,14 @@ impl PeerStore for StatelessPeerStore {
         Box::pin(self.random())
     }
 
+    fn random_matching(
+        &mut self,
+        required: ServiceFlags,
+        address_types: Vec<AddressType>,
+    ) -> FutureResult<Option<PersistedPeer>, Self::Error> {
+        Box::pin(self.random_matching(required, address_types))
+    }
+
     fn num_unbanned(&mut self) -> FutureResult<u32, Self::Error> {
         Box::pin(self.num_unbanned())
     }
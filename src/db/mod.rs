@@ -9,6 +9,7 @@ use bitcoin::key::rand::prelude::Distribution;
 use bitcoin::key::rand::{thread_rng, Rng};
 use bitcoin::p2p::address::AddrV2;
 use bitcoin::p2p::ServiceFlags;
+use bitcoin::{BlockHash, FilterHash, FilterHeader};
 
 /// Errors a database backend may produce.
 pub mod error;
@@ -31,6 +32,9 @@ pub struct PersistedPeer {
     pub services: ServiceFlags,
     /// A new, tried, or banned status.
     pub status: PeerStatus,
+    /// The penalties this peer accumulated for misbehaving. The peer is banned once the score reaches 100,
+    /// and the score is cleared after a connection where the peer does not misbehave.
+    pub misbehaviour: u32,
 }
 
 impl PersistedPeer {
@@ -41,6 +45,7 @@ impl PersistedPeer {
             port,
             services,
             status,
+            misbehaviour: 0,
         }
     }
 }
@@ -51,6 +56,85 @@ impl From<PersistedPeer> for (AddrV2, u16) {
     }
 }
 
+/// The network an address belongs to, following the network IDs defined in BIP-155.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressType {
+    /// An IPv4 address.
+    Ipv4,
+    /// An IPv6 address.
+    Ipv6,
+    /// A deprecated Tor v2 onion service.
+    TorV2,
+    /// A Tor v3 onion service.
+    TorV3,
+    /// An I2P destination.
+    I2p,
+    /// A CJDNS address.
+    Cjdns,
+    /// A network this version of the software does not recognize.
+    Unknown,
+}
+
+impl AddressType {
+    // The network ID that prefixes a serialized `AddrV2`
+    #[cfg(feature = "database")]
+    pub(crate) fn network_id(&self) -> Option<u8> {
+        match self {
+            AddressType::Ipv4 => Some(1),
+            AddressType::Ipv6 => Some(2),
+            AddressType::TorV2 => Some(3),
+            AddressType::TorV3 => Some(4),
+            AddressType::I2p => Some(5),
+            AddressType::Cjdns => Some(6),
+            AddressType::Unknown => None,
+        }
+    }
+}
+
+impl From<&AddrV2> for AddressType {
+    fn from(value: &AddrV2) -> Self {
+        match value {
+            AddrV2::Ipv4(_) => AddressType::Ipv4,
+            AddrV2::Ipv6(_) => AddressType::Ipv6,
+            AddrV2::TorV2(_) => AddressType::TorV2,
+            AddrV2::TorV3(_) => AddressType::TorV3,
+            AddrV2::I2p(_) => AddressType::I2p,
+            AddrV2::Cjdns(_) => AddressType::Cjdns,
+            AddrV2::Unknown(_, _) => AddressType::Unknown,
+        }
+    }
+}
+
+/// A compact block filter header, and the filter once downloaded, saved to the [`traits::HeaderStore`]
+/// so the filters may be served to other peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PersistedFilter {
+    /// The block the filter was built from.
+    pub block_hash: BlockHash,
+    /// The filter header committing to this filter and all filters before it.
+    pub filter_header: FilterHeader,
+    /// The hash of the filter.
+    pub filter_hash: FilterHash,
+    /// The BIP-158 filter, if it was downloaded.
+    pub filter: Option<Vec<u8>>,
+}
+
+impl PersistedFilter {
+    /// Build a filter header that does not have a filter yet.
+    pub fn new(
+        block_hash: BlockHash,
+        filter_header: FilterHeader,
+        filter_hash: FilterHash,
+    ) -> Self {
+        Self {
+            block_hash,
+            filter_header,
+            filter_hash,
+            filter: None,
+        }
+    }
+}
+
 /// The status of a peer in the database.
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum PeerStatus {
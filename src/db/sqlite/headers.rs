@@ -5,12 +5,13 @@ use std::str::FromStr;
 use std::sync::Arc;
 
 use bitcoin::block::{Header, Version};
-use bitcoin::{BlockHash, CompactTarget, Network, TxMerkleNode};
+use bitcoin::{BlockHash, CompactTarget, FilterHash, FilterHeader, Network, TxMerkleNode};
 use rusqlite::{params, Connection, Result};
 use tokio::sync::Mutex;
 
 use crate::db::error::{SqlHeaderStoreError, SqlInitializationError};
 use crate::db::traits::HeaderStore;
+use crate::db::PersistedFilter;
 use crate::prelude::FutureResult;
 
 use super::{DATA_DIR, DEFAULT_CWD};
@@ -34,6 +35,14 @@ const INITIAL_HEADER_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS headers (
     bits INTEGER NOT NULL,
     nonce INTEGER NOT NULL
 ) STRICT";
+// Filters are only written if the node persists them, and the filter is empty until downloaded
+const FILTER_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS filters (
+    height INTEGER PRIMARY KEY,
+    block_hash TEXT NOT NULL,
+    filter_header TEXT NOT NULL,
+    filter_hash TEXT NOT NULL,
+    filter BLOB
+) STRICT";
 
 /// Header storage implementation with SQL Lite.
 #[derive(Debug)]
@@ -62,6 +71,7 @@ impl SqliteHeaderDb {
         conn.execute(&schema_init_version, params![SCHEMA_KEY, SCHEMA_VERSION])?;
         // Build the table if it doesn't exist
         conn.execute(INITIAL_HEADER_SCHEMA, [])?;
+        conn.execute(FILTER_SCHEMA, [])?;
         // Migrate to any new schema versions
         Self::migrate(&conn)?;
 
@@ -283,6 +293,71 @@ impl SqliteHeaderDb {
             },
         }
     }
+
+    async fn write_filter_headers(
+        &mut self,
+        filter_headers: &BTreeMap<u32, PersistedFilter>,
+    ) -> Result<(), SqlHeaderStoreError> {
+        let mut write_lock = self.conn.lock().await;
+        let tx = write_lock.transaction()?;
+        for (height, filter) in filter_headers {
+            let stmt = "INSERT OR REPLACE INTO filters (height, block_hash, filter_header, filter_hash, filter) VALUES (?1, ?2, ?3, ?4, ?5)";
+            tx.execute(
+                stmt,
+                params![
+                    height,
+                    filter.block_hash.to_string(),
+                    filter.filter_header.to_string(),
+                    filter.filter_hash.to_string(),
+                    filter.filter
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn write_filter(
+        &mut self,
+        height: u32,
+        block_hash: &BlockHash,
+        filter: &[u8],
+    ) -> Result<(), SqlHeaderStoreError> {
+        let write_lock = self.conn.lock().await;
+        let stmt = "UPDATE filters SET filter = ?1 WHERE height = ?2 AND block_hash = ?3";
+        write_lock.execute(stmt, params![filter, height, block_hash.to_string()])?;
+        Ok(())
+    }
+
+    async fn filter_at(
+        &mut self,
+        height: u32,
+    ) -> Result<Option<PersistedFilter>, SqlHeaderStoreError> {
+        let write_lock = self.conn.lock().await;
+        let stmt =
+            "SELECT block_hash, filter_header, filter_hash, filter FROM filters WHERE height = ?1";
+        let query = write_lock.query_row(stmt, params![height], |row| {
+            let block_hash: String = row.get(0)?;
+            let filter_header: String = row.get(1)?;
+            let filter_hash: String = row.get(2)?;
+            let filter: Option<Vec<u8>> = row.get(3)?;
+            Ok((block_hash, filter_header, filter_hash, filter))
+        });
+        let (block_hash, filter_header, filter_hash, filter) = match query {
+            Ok(row) => row,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(SqlHeaderStoreError::SQL(e)),
+        };
+        Ok(Some(PersistedFilter {
+            block_hash: BlockHash::from_str(&block_hash)
+                .map_err(|_| SqlHeaderStoreError::StringConversion)?,
+            filter_header: FilterHeader::from_str(&filter_header)
+                .map_err(|_| SqlHeaderStoreError::StringConversion)?,
+            filter_hash: FilterHash::from_str(&filter_hash)
+                .map_err(|_| SqlHeaderStoreError::StringConversion)?,
+            filter,
+        }))
+    }
 }
 
 impl HeaderStore for SqliteHeaderDb {
@@ -323,6 +398,26 @@ impl HeaderStore for SqliteHeaderDb {
     fn header_at(&mut self, height: u32) -> FutureResult<Option<Header>, Self::Error> {
         Box::pin(self.header_at(height))
     }
+
+    fn write_filter_headers<'a>(
+        &'a mut self,
+        filter_headers: &'a BTreeMap<u32, PersistedFilter>,
+    ) -> FutureResult<'a, (), Self::Error> {
+        Box::pin(self.write_filter_headers(filter_headers))
+    }
+
+    fn write_filter<'a>(
+        &'a mut self,
+        height: u32,
+        block_hash: &'a BlockHash,
+        filter: &'a [u8],
+    ) -> FutureResult<'a, (), Self::Error> {
+        Box::pin(self.write_filter(height, block_hash, filter))
+    }
+
+    fn filter_at(&mut self, height: u32) -> FutureResult<Option<PersistedFilter>, Self::Error> {
+        Box::pin(self.filter_at(height))
+    }
 }
 
 #[cfg(test)]
@@ -407,4 +502,44 @@ mod tests {
         drop(db);
         binding.close().unwrap();
     }
+
+    #[tokio::test]
+    async fn test_sql_filters_are_replaced_with_headers() {
+        use bitcoin::hashes::Hash;
+
+        let binding = tempfile::tempdir().unwrap();
+        let path = binding.path();
+        let mut db = SqliteHeaderDb::new(Network::Regtest, Some(path.into())).unwrap();
+        let block_hash_1 = BlockHash::from_byte_array([1; 32]);
+        let block_hash_2 = BlockHash::from_byte_array([2; 32]);
+        let filter_hash = FilterHash::from_byte_array([3; 32]);
+        let filter_header = filter_hash.filter_header(&FilterHeader::all_zeros());
+        let mut map = BTreeMap::new();
+        map.insert(
+            1,
+            PersistedFilter::new(block_hash_1, filter_header, filter_hash),
+        );
+        db.write_filter_headers(&map).await.unwrap();
+        // Filters for other blocks are not written
+        db.write_filter(1, &block_hash_2, &[0x01]).await.unwrap();
+        assert!(db.filter_at(1).await.unwrap().unwrap().filter.is_none());
+        db.write_filter(1, &block_hash_1, &[0x01]).await.unwrap();
+        let filter = db.filter_at(1).await.unwrap().unwrap();
+        assert_eq!(filter.block_hash, block_hash_1);
+        assert_eq!(filter.filter_header, filter_header);
+        assert_eq!(filter.filter_hash, filter_hash);
+        assert_eq!(filter.filter, Some(vec![0x01]));
+        // A reorganization replaces the filter
+        map.insert(
+            1,
+            PersistedFilter::new(block_hash_2, filter_header, filter_hash),
+        );
+        db.write_filter_headers(&map).await.unwrap();
+        let filter = db.filter_at(1).await.unwrap().unwrap();
+        assert_eq!(filter.block_hash, block_hash_2);
+        assert!(filter.filter.is_none());
+        assert!(db.filter_at(2).await.unwrap().is_none());
+        drop(db);
+        binding.close().unwrap();
+    }
 }
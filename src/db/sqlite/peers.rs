@@ -1,6 +1,8 @@
 use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::key::rand::{self, Rng};
 use bitcoin::p2p::ServiceFlags;
 use bitcoin::Network;
+use rand::{rngs::StdRng, SeedableRng};
 use rusqlite::params;
 use rusqlite::{Connection, Result};
 use std::fs;
@@ -10,7 +12,7 @@ use tokio::sync::Mutex;
 
 use crate::db::error::{SqlInitializationError, SqlPeerStoreError};
 use crate::db::traits::PeerStore;
-use crate::db::{PeerStatus, PersistedPeer};
+use crate::db::{AddressType, PeerStatus, PersistedPeer};
 use crate::prelude::FutureResult;
 
 use super::{DATA_DIR, DEFAULT_CWD};
@@ -21,8 +23,10 @@ const SCHEMA_TABLE_NAME: &str = "peer_schema_versions";
 const SCHEMA_COLUMN: &str = "schema_key";
 const VERSION_COLUMN: &str = "version";
 const SCHEMA_KEY: &str = "current_version";
+// The version of the initial schema, which is brought to the current version with migrations
+const INITIAL_SCHEMA_VERSION: u8 = 0;
 // Update this in the case of schema changes
-const SCHEMA_VERSION: u8 = 0;
+const SCHEMA_VERSION: u8 = 2;
 // Always execute this query and adjust the schema with migrations
 const INITIAL_PEER_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS peers (
     ip_addr BLOB PRIMARY KEY,
@@ -52,11 +56,14 @@ impl SqlitePeerDb {
         let conn = Connection::open(path.join(FILE_NAME))?;
         // Create the schema version
         let schema_table_query = format!("CREATE TABLE IF NOT EXISTS {SCHEMA_TABLE_NAME} ({SCHEMA_COLUMN} TEXT PRIMARY KEY, {VERSION_COLUMN} INTEGER NOT NULL)");
-        // Update the schema version
+        // Record the schema version of a new database
         conn.execute(&schema_table_query, [])?;
         let schema_init_version = format!(
-            "INSERT OR REPLACE INTO {SCHEMA_TABLE_NAME} ({SCHEMA_COLUMN}, {VERSION_COLUMN}) VALUES (?1, ?2)");
-        conn.execute(&schema_init_version, params![SCHEMA_KEY, SCHEMA_VERSION])?;
+            "INSERT OR IGNORE INTO {SCHEMA_TABLE_NAME} ({SCHEMA_COLUMN}, {VERSION_COLUMN}) VALUES (?1, ?2)");
+        conn.execute(
+            &schema_init_version,
+            params![SCHEMA_KEY, INITIAL_SCHEMA_VERSION],
+        )?;
         // Build the table if it doesn't exist
         conn.execute(INITIAL_PEER_SCHEMA, [])?;
         // Migrate to any new schema versions
@@ -66,22 +73,41 @@ impl SqlitePeerDb {
         })
     }
 
-    // This function currently does nothing, but if new columns are required this may be used to alter the tables
-    // without breaking older tables.
+    // Alter the tables one version at a time, so older tables are not broken by new columns.
     fn migrate(conn: &Connection) -> Result<(), SqlInitializationError> {
         let version_query =
             format!("SELECT {VERSION_COLUMN} FROM {SCHEMA_TABLE_NAME} WHERE {SCHEMA_COLUMN} = ?1");
-        let _current_version: u8 =
-            conn.query_row(&version_query, [SCHEMA_KEY], |row| row.get(0))?;
-        // Match on the version and migrate to new schemas in the future
+        let current_version: u8 = conn.query_row(&version_query, [SCHEMA_KEY], |row| row.get(0))?;
+        // Version 1 adds the misbehaviour score of each peer
+        if current_version < 1 {
+            conn.execute(
+                "ALTER TABLE peers ADD COLUMN misbehaviour INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+        // Version 2 adds the anchor peers saved on shutdown
+        if current_version < 2 {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS anchors (
+                    ip_addr BLOB PRIMARY KEY,
+                    port INTEGER NOT NULL,
+                    service_flags BLOB NOT NULL
+                )",
+                [],
+            )?;
+        }
+        let update_version = format!(
+            "UPDATE {SCHEMA_TABLE_NAME} SET {VERSION_COLUMN} = ?1 WHERE {SCHEMA_COLUMN} = ?2"
+        );
+        conn.execute(&update_version, params![SCHEMA_VERSION, SCHEMA_KEY])?;
         Ok(())
     }
 
     async fn update(&mut self, peer: PersistedPeer) -> Result<(), SqlPeerStoreError> {
         let lock = self.conn.lock().await;
         let stmt = match peer.status {
-            PeerStatus::New => "INSERT OR IGNORE INTO peers (ip_addr, port, service_flags, tried, banned) VALUES (?1, ?2, ?3, ?4, ?5)",
-            _ => "INSERT OR REPLACE INTO peers (ip_addr, port, service_flags, tried, banned) VALUES (?1, ?2, ?3, ?4, ?5)",
+            PeerStatus::New => "INSERT OR IGNORE INTO peers (ip_addr, port, service_flags, tried, banned, misbehaviour) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            _ => "INSERT OR REPLACE INTO peers (ip_addr, port, service_flags, tried, banned, misbehaviour) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
         };
         let (tried, banned) = match peer.status {
             PeerStatus::New => (false, false),
@@ -92,15 +118,23 @@ impl SqlitePeerDb {
         let service_blob = peer.services.to_u64().to_le_bytes();
         lock.execute(
             stmt,
-            params![address_blob, peer.port, service_blob, tried, banned,],
+            params![
+                address_blob,
+                peer.port,
+                service_blob,
+                tried,
+                banned,
+                peer.misbehaviour
+            ],
         )?;
         Ok(())
     }
 
     async fn random(&mut self) -> Result<PersistedPeer, SqlPeerStoreError> {
         let lock = self.conn.lock().await;
-        let mut stmt =
-            lock.prepare("SELECT * FROM peers WHERE banned = false ORDER BY RANDOM() LIMIT 1")?;
+        let mut stmt = lock.prepare(
+            "SELECT ip_addr, port, service_flags, tried, misbehaviour FROM peers WHERE banned = false ORDER BY RANDOM() LIMIT 1",
+        )?;
         let mut rows = stmt.query([])?;
         if let Some(row) = rows.next()? {
             let ip_addr: Vec<u8> = row.get(0)?;
@@ -108,6 +142,7 @@ impl SqlitePeerDb {
             let service_blob: [u8; 8] = row.get(2)?;
             let service_flags = u64::from_le_bytes(service_blob);
             let tried: bool = row.get(3)?;
+            let misbehaviour: u32 = row.get(4)?;
             let status = if tried {
                 PeerStatus::Tried
             } else {
@@ -115,18 +150,125 @@ impl SqlitePeerDb {
             };
             let ip = deserialize(&ip_addr)?;
             let services: ServiceFlags = ServiceFlags::from(service_flags);
-            Ok(PersistedPeer::new(ip, port, services, status))
+            let mut peer = PersistedPeer::new(ip, port, services, status);
+            peer.misbehaviour = misbehaviour;
+            Ok(peer)
         } else {
             Err(SqlPeerStoreError::Empty)
         }
     }
 
+    async fn random_matching(
+        &mut self,
+        required: ServiceFlags,
+        address_types: Vec<AddressType>,
+    ) -> Result<Option<PersistedPeer>, SqlPeerStoreError> {
+        // Addresses are stored serialized, so the first byte is the BIP-155 network ID
+        let network_ids = address_types
+            .iter()
+            .filter_map(|address_type| address_type.network_id())
+            .map(|id| format!("'{id:02X}'"))
+            .collect::<Vec<String>>();
+        if network_ids.is_empty() {
+            return Ok(None);
+        }
+        // Service flags are stored as little endian bytes, so each byte with a required bit must be one
+        // of the values that has every required bit of that byte
+        let mut conditions = vec![
+            "banned = false".to_string(),
+            format!("hex(substr(ip_addr, 1, 1)) IN ({})", network_ids.join(", ")),
+        ];
+        for (index, mask) in required.to_u64().to_le_bytes().into_iter().enumerate() {
+            if mask == 0 {
+                continue;
+            }
+            let values = (0..=u8::MAX)
+                .filter(|byte| byte & mask == mask)
+                .map(|byte| format!("'{byte:02X}'"))
+                .collect::<Vec<String>>();
+            conditions.push(format!(
+                "hex(substr(service_flags, {}, 1)) IN ({})",
+                index + 1,
+                values.join(", ")
+            ));
+        }
+        let filter = conditions.join(" AND ");
+        let lock = self.conn.lock().await;
+        // Skip to a random matching row instead of shuffling the whole table
+        let count: u32 = lock.query_row(
+            &format!("SELECT COUNT(*) FROM peers WHERE {filter}"),
+            [],
+            |row| row.get(0),
+        )?;
+        if count == 0 {
+            return Ok(None);
+        }
+        let offset = StdRng::from_entropy().gen_range(0..count);
+        let mut stmt = lock.prepare(&format!(
+            "SELECT ip_addr, port, service_flags, tried, misbehaviour FROM peers WHERE {filter} LIMIT 1 OFFSET ?1"
+        ))?;
+        let mut rows = stmt.query(params![offset])?;
+        if let Some(row) = rows.next()? {
+            let ip_addr: Vec<u8> = row.get(0)?;
+            let port: u16 = row.get(1)?;
+            let service_blob: [u8; 8] = row.get(2)?;
+            let services = ServiceFlags::from(u64::from_le_bytes(service_blob));
+            let tried: bool = row.get(3)?;
+            let misbehaviour: u32 = row.get(4)?;
+            let status = if tried {
+                PeerStatus::Tried
+            } else {
+                PeerStatus::New
+            };
+            let mut peer = PersistedPeer::new(deserialize(&ip_addr)?, port, services, status);
+            peer.misbehaviour = misbehaviour;
+            return Ok(Some(peer));
+        }
+        Ok(None)
+    }
+
     async fn num_unbanned(&mut self) -> Result<u32, SqlPeerStoreError> {
         let lock = self.conn.lock().await;
         let mut stmt = lock.prepare("SELECT COUNT(*) FROM peers WHERE banned = false")?;
         let count: u32 = stmt.query_row([], |row| row.get(0))?;
         Ok(count)
     }
+
+    async fn set_anchors(&mut self, anchors: Vec<PersistedPeer>) -> Result<(), SqlPeerStoreError> {
+        let mut lock = self.conn.lock().await;
+        let tx = lock.transaction()?;
+        tx.execute("DELETE FROM anchors", [])?;
+        for anchor in anchors {
+            let address_blob = serialize(&anchor.addr);
+            let service_blob = anchor.services.to_u64().to_le_bytes();
+            tx.execute(
+                "INSERT OR REPLACE INTO anchors (ip_addr, port, service_flags) VALUES (?1, ?2, ?3)",
+                params![address_blob, anchor.port, service_blob],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn anchors(&mut self) -> Result<Vec<PersistedPeer>, SqlPeerStoreError> {
+        let lock = self.conn.lock().await;
+        let mut stmt = lock.prepare("SELECT ip_addr, port, service_flags FROM anchors")?;
+        let mut rows = stmt.query([])?;
+        let mut anchors = Vec::new();
+        while let Some(row) = rows.next()? {
+            let ip_addr: Vec<u8> = row.get(0)?;
+            let port: u16 = row.get(1)?;
+            let service_blob: [u8; 8] = row.get(2)?;
+            let services = ServiceFlags::from(u64::from_le_bytes(service_blob));
+            anchors.push(PersistedPeer::new(
+                deserialize(&ip_addr)?,
+                port,
+                services,
+                PeerStatus::Tried,
+            ));
+        }
+        Ok(anchors)
+    }
 }
 
 impl PeerStore for SqlitePeerDb {
@@ -139,9 +281,25 @@ impl PeerStore for SqlitePeerDb {
         Box::pin(self.random())
     }
 
+    fn random_matching(
+        &mut self,
+        required: ServiceFlags,
+        address_types: Vec<AddressType>,
+    ) -> FutureResult<Option<PersistedPeer>, Self::Error> {
+        Box::pin(self.random_matching(required, address_types))
+    }
+
     fn num_unbanned(&mut self) -> FutureResult<u32, Self::Error> {
         Box::pin(self.num_unbanned())
     }
+
+    fn set_anchors(&mut self, anchors: Vec<PersistedPeer>) -> FutureResult<(), Self::Error> {
+        Box::pin(self.set_anchors(anchors))
+    }
+
+    fn anchors(&mut self) -> FutureResult<Vec<PersistedPeer>, Self::Error> {
+        Box::pin(self.anchors())
+    }
 }
 
 #[cfg(test)]
@@ -190,4 +348,175 @@ mod tests {
         drop(peer_store);
         binding.close().unwrap();
     }
+
+    #[tokio::test]
+    async fn test_sql_stores_i2p_peers() {
+        let binding = tempfile::tempdir().unwrap();
+        let path = binding.path();
+        let mut peer_store =
+            SqlitePeerDb::new(bitcoin::Network::Testnet, Some(path.into())).unwrap();
+        let i2p = AddrV2::I2p([7; 32]);
+        let peer = PersistedPeer::new(i2p.clone(), 0, ServiceFlags::NETWORK, PeerStatus::New);
+        peer_store.update(peer).await.unwrap();
+        let random = peer_store.random().await.unwrap();
+        assert_eq!(random.addr, i2p);
+        assert_eq!(random.port, 0);
+        assert_eq!(random.services, ServiceFlags::NETWORK);
+        drop(peer_store);
+        binding.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sql_selects_matching_peers() {
+        let binding = tempfile::tempdir().unwrap();
+        let path = binding.path();
+        let mut peer_store =
+            SqlitePeerDb::new(bitcoin::Network::Testnet, Some(path.into())).unwrap();
+        let cbf = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let ip_1 = AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
+        let ip_2 = AddrV2::Ipv4(Ipv4Addr::new(2, 2, 2, 2));
+        let ip_3 = AddrV2::Ipv4(Ipv4Addr::new(3, 3, 3, 3));
+        let i2p = AddrV2::I2p([7; 32]);
+        let peer_1 = PersistedPeer::new(ip_1.clone(), 0, cbf, PeerStatus::New);
+        let peer_2 = PersistedPeer::new(ip_2, 0, ServiceFlags::NETWORK, PeerStatus::New);
+        let peer_3 = PersistedPeer::new(ip_3.clone(), 0, cbf, PeerStatus::Ban);
+        let peer_4 = PersistedPeer::new(i2p.clone(), 0, cbf, PeerStatus::New);
+        peer_store.update(peer_1).await.unwrap();
+        peer_store.update(peer_2).await.unwrap();
+        peer_store.update(peer_3).await.unwrap();
+        peer_store.update(peer_4).await.unwrap();
+        for _ in 0..5 {
+            let peer = peer_store
+                .random_matching(cbf, vec![AddressType::Ipv4, AddressType::Ipv6])
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(peer.addr, ip_1);
+            assert_eq!(peer.services, cbf);
+            let peer = peer_store
+                .random_matching(ServiceFlags::NONE, vec![AddressType::Ipv4])
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(peer.addr, ip_3);
+            assert_ne!(peer.addr, i2p);
+        }
+        let peer = peer_store
+            .random_matching(cbf, vec![AddressType::I2p])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.addr, i2p);
+        assert!(peer_store
+            .random_matching(ServiceFlags::NONE, vec![AddressType::Ipv6])
+            .await
+            .unwrap()
+            .is_none());
+        assert!(peer_store
+            .random_matching(cbf, Vec::new())
+            .await
+            .unwrap()
+            .is_none());
+        // Flags past the first byte are matched as well
+        let v2 = ServiceFlags::NETWORK | ServiceFlags::P2P_V2;
+        assert!(peer_store
+            .random_matching(v2, vec![AddressType::Ipv4])
+            .await
+            .unwrap()
+            .is_none());
+        let ip_4 = AddrV2::Ipv4(Ipv4Addr::new(4, 4, 4, 4));
+        let peer_5 = PersistedPeer::new(ip_4.clone(), 0, v2, PeerStatus::New);
+        peer_store.update(peer_5).await.unwrap();
+        let peer = peer_store
+            .random_matching(v2, vec![AddressType::Ipv4])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.addr, ip_4);
+        drop(peer_store);
+        binding.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sql_replaces_anchors() {
+        let binding = tempfile::tempdir().unwrap();
+        let path = binding.path();
+        let mut peer_store =
+            SqlitePeerDb::new(bitcoin::Network::Testnet, Some(path.into())).unwrap();
+        assert!(peer_store.anchors().await.unwrap().is_empty());
+        let anchor_1 = PersistedPeer::new(
+            AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1)),
+            8333,
+            ServiceFlags::COMPACT_FILTERS,
+            PeerStatus::Tried,
+        );
+        let anchor_2 = PersistedPeer::new(
+            AddrV2::Ipv4(Ipv4Addr::new(2, 2, 2, 2)),
+            8333,
+            ServiceFlags::COMPACT_FILTERS,
+            PeerStatus::Tried,
+        );
+        peer_store
+            .set_anchors(vec![anchor_1.clone(), anchor_2.clone()])
+            .await
+            .unwrap();
+        drop(peer_store);
+        let mut peer_store =
+            SqlitePeerDb::new(bitcoin::Network::Testnet, Some(path.into())).unwrap();
+        let anchors = peer_store.anchors().await.unwrap();
+        assert_eq!(anchors.len(), 2);
+        assert!(anchors.contains(&anchor_1));
+        assert!(anchors.contains(&anchor_2));
+        // Anchors are not peers to be selected at random
+        assert_eq!(peer_store.num_unbanned().await.unwrap(), 0);
+        peer_store
+            .set_anchors(vec![anchor_2.clone()])
+            .await
+            .unwrap();
+        assert_eq!(peer_store.anchors().await.unwrap(), vec![anchor_2]);
+        drop(peer_store);
+        binding.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sql_migrates_misbehaviour() {
+        let binding = tempfile::tempdir().unwrap();
+        let path = binding.path();
+        // A database written before peers had a misbehaviour score
+        let mut dir = path.to_path_buf();
+        dir.push(DATA_DIR);
+        dir.push(bitcoin::Network::Testnet.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let conn = Connection::open(dir.join(FILE_NAME)).unwrap();
+        conn.execute(&format!("CREATE TABLE {SCHEMA_TABLE_NAME} ({SCHEMA_COLUMN} TEXT PRIMARY KEY, {VERSION_COLUMN} INTEGER NOT NULL)"), []).unwrap();
+        conn.execute(
+            &format!("INSERT INTO {SCHEMA_TABLE_NAME} ({SCHEMA_COLUMN}, {VERSION_COLUMN}) VALUES (?1, 0)"),
+            [SCHEMA_KEY],
+        )
+        .unwrap();
+        conn.execute(INITIAL_PEER_SCHEMA, []).unwrap();
+        let old = AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
+        conn.execute(
+            "INSERT INTO peers (ip_addr, port, service_flags, tried, banned) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![serialize(&old), 8333, 0u64.to_le_bytes(), true, false],
+        )
+        .unwrap();
+        drop(conn);
+        let mut peer_store =
+            SqlitePeerDb::new(bitcoin::Network::Testnet, Some(path.into())).unwrap();
+        let random = peer_store.random().await.unwrap();
+        assert_eq!(random.addr, old);
+        assert_eq!(random.misbehaviour, 0);
+        let mut scored = random.clone();
+        scored.misbehaviour = 30;
+        peer_store.update(scored).await.unwrap();
+        drop(peer_store);
+        // Opening the database again does not migrate twice
+        let mut peer_store =
+            SqlitePeerDb::new(bitcoin::Network::Testnet, Some(path.into())).unwrap();
+        let random = peer_store.random().await.unwrap();
+        assert_eq!(random.misbehaviour, 30);
+        drop(peer_store);
+        binding.close().unwrap();
+    }
 }
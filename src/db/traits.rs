@@ -1,12 +1,15 @@
 use std::fmt::Debug;
 use std::{collections::BTreeMap, convert::Infallible, fmt::Display};
 
-use bitcoin::{block::Header, BlockHash};
+use bitcoin::{block::Header, p2p::ServiceFlags, BlockHash};
 
 use crate::prelude::FutureResult;
 
 use super::error::UnitPeerStoreError;
-use super::PersistedPeer;
+use super::{AddressType, PersistedFilter, PersistedPeer};
+
+// Samples drawn by the default implementation of `PeerStore::random_matching`
+const MATCHING_SAMPLES: usize = 32;
 
 /// Methods required to persist the chain of block headers.
 pub trait HeaderStore: Debug + Send + Sync {
@@ -42,6 +45,31 @@ pub trait HeaderStore: Debug + Send + Sync {
 
     /// Return the header at the height in the database, if it exists.
     fn header_at(&mut self, height: u32) -> FutureResult<Option<Header>, Self::Error>;
+
+    /// Write compact filter headers indexed by height, replacing any filters already at these heights.
+    /// Only used if the node persists filters, so stores that do not serve filters may leave this as is.
+    fn write_filter_headers<'a>(
+        &'a mut self,
+        _filter_headers: &'a BTreeMap<u32, PersistedFilter>,
+    ) -> FutureResult<'a, (), Self::Error> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Write the filter at the height, if the filter header at the height is for the same block.
+    /// Only used if the node persists filters, so stores that do not serve filters may leave this as is.
+    fn write_filter<'a>(
+        &'a mut self,
+        _height: u32,
+        _block_hash: &'a BlockHash,
+        _filter: &'a [u8],
+    ) -> FutureResult<'a, (), Self::Error> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Return the filter header, and the filter if it was written, at the height in the database.
+    fn filter_at(&mut self, _height: u32) -> FutureResult<Option<PersistedFilter>, Self::Error> {
+        Box::pin(async { Ok(None) })
+    }
 }
 
 /// This is a simple wrapper for the unit type, signifying that no headers will be stored between sessions.
@@ -115,6 +143,40 @@ pub trait PeerStore: Debug + Send + Sync {
 
     /// The number of peers in the database that are not marked as banned.
     fn num_unbanned(&mut self) -> FutureResult<u32, Self::Error>;
+
+    /// Get a peer at random that signals for all of the `required` services and has one of the
+    /// `address_types`, if any such peer exists. Peers with unknown services only match if nothing is required.
+    ///
+    /// The default implementation samples [`PeerStore::random`] a limited number of times, so stores
+    /// that can filter peers directly should override it.
+    fn random_matching(
+        &mut self,
+        required: ServiceFlags,
+        address_types: Vec<AddressType>,
+    ) -> FutureResult<Option<PersistedPeer>, Self::Error> {
+        Box::pin(async move {
+            for _ in 0..MATCHING_SAMPLES {
+                let peer = self.random().await?;
+                if peer.services.has(required)
+                    && address_types.contains(&AddressType::from(&peer.addr))
+                {
+                    return Ok(Some(peer));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// Replace the anchor peers, the outbound peers that served compact block filters when the node last shut down.
+    /// Stores that are not persisted between sessions may ignore the anchors.
+    fn set_anchors(&mut self, _anchors: Vec<PersistedPeer>) -> FutureResult<(), Self::Error> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// The anchor peers saved when the node last shut down, which are connected to first.
+    fn anchors(&mut self) -> FutureResult<Vec<PersistedPeer>, Self::Error> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
 }
 
 impl PeerStore for () {
@@ -128,6 +128,10 @@ impl CFHeaderChain {
         self.anchor_checkpoint.height + self.hash_chain.len() as u32
     }
 
+    pub(crate) fn anchor_height(&self) -> u32 {
+        self.anchor_checkpoint.height
+    }
+
     pub(crate) fn prev_header(&self) -> Option<FilterHeader> {
         self.prev_header
     }
@@ -39,6 +39,22 @@ impl core::fmt::Display for CFHeaderSyncError {
 
 impl_sourceless_error!(CFHeaderSyncError);
 
+impl CFHeaderSyncError {
+    // The misbehaviour score added to the peer that caused this error, out of a ban threshold of 100.
+    // Stop hashes may be unknown or unrequested if the peer answers a request after a reorganization.
+    pub(crate) fn penalty(&self) -> u32 {
+        match self {
+            CFHeaderSyncError::PrevHeaderMismatch => 100,
+            CFHeaderSyncError::StopHashMismatch => 50,
+            CFHeaderSyncError::EmptyMessage
+            | CFHeaderSyncError::UnknownStophash
+            | CFHeaderSyncError::HeaderChainIndexOverflow => 20,
+            CFHeaderSyncError::UnrequestedStophash
+            | CFHeaderSyncError::UnexpectedCFHeaderMessage => 10,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum CFilterSyncError {
     UnknownStophash,
@@ -75,6 +91,19 @@ impl core::fmt::Display for CFilterSyncError {
 
 impl_sourceless_error!(CFilterSyncError);
 
+impl CFilterSyncError {
+    // The misbehaviour score added to the peer that caused this error, out of a ban threshold of 100.
+    // Reading a filter is a local failure, so it does not count against the peer.
+    pub(crate) fn penalty(&self) -> u32 {
+        match self {
+            CFilterSyncError::MisalignedFilterHash => 100,
+            CFilterSyncError::UnknownStophash | CFilterSyncError::UnknownFilterHash => 20,
+            CFilterSyncError::UnrequestedStophash => 10,
+            CFilterSyncError::Filter(_) => 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum FilterError {
     IORead,
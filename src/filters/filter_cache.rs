@@ -0,0 +1,83 @@
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+use bitcoin::BlockHash;
+
+use super::Filter;
+
+type Height = u32;
+
+// Recently downloaded filters, so they may be checked again for new scripts without asking peers.
+// When the cache is full, the filters of the lowest blocks are removed first.
+#[derive(Debug)]
+pub(crate) struct FilterCache {
+    filters: BTreeMap<Height, Filter>,
+    capacity: usize,
+}
+
+impl FilterCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            filters: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, height: Height, filter: &Filter) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.filters.insert(height, filter.clone());
+        while self.filters.len() > self.capacity {
+            if let Some(lowest) = self.filters.keys().next().copied() {
+                self.filters.remove(&lowest);
+            }
+        }
+    }
+
+    // The filter at this height, if it belongs to the expected block
+    pub(crate) fn get(&self, height: Height, block_hash: &BlockHash) -> Option<&Filter> {
+        self.filters
+            .get(&height)
+            .filter(|filter| filter.block_hash().eq(block_hash))
+    }
+
+    // The heights of the cached filters within the range, in ascending order
+    pub(crate) fn heights(&self, range: RangeInclusive<Height>) -> Vec<Height> {
+        self.filters
+            .range(range)
+            .map(|(height, _)| *height)
+            .collect()
+    }
+
+    // Blocks at and above this height were reorganized
+    pub(crate) fn remove_from(&mut self, height: Height) {
+        self.filters
+            .retain(|filter_height, _| *filter_height < height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, BlockHash};
+
+    use super::{Filter, FilterCache};
+
+    #[test]
+    fn test_lowest_filters_are_evicted() {
+        let mut cache = FilterCache::new(2);
+        let hash_1 = BlockHash::from_byte_array([1; 32]);
+        let hash_2 = BlockHash::from_byte_array([2; 32]);
+        let hash_3 = BlockHash::from_byte_array([3; 32]);
+        cache.insert(3, &Filter::new(vec![0x00], hash_3));
+        cache.insert(1, &Filter::new(vec![0x00], hash_1));
+        cache.insert(2, &Filter::new(vec![0x00], hash_2));
+        assert!(cache.get(1, &hash_1).is_none());
+        assert!(cache.get(2, &hash_2).is_some());
+        assert!(cache.get(3, &hash_2).is_none());
+        assert_eq!(cache.heights(0..=2), vec![2]);
+        assert_eq!(cache.heights(2..=3), vec![2, 3]);
+        cache.remove_from(3);
+        assert!(cache.get(3, &hash_3).is_none());
+        assert!(cache.get(2, &hash_2).is_some());
+    }
+}
@@ -9,12 +9,12 @@ const INITIAL_BUFFER_SIZE: usize = 20_000;
 // Block filters can be 300 bytes or more. Assuming that some users may
 // run the node for extended periods of time, there is little advantage to actually
 // storing them. Instead we keep track of the filters we have seen by saving their block hash.
+// Filters are downloaded from many peers at once, so they may be added in any order.
 #[derive(Debug)]
 pub(crate) struct FilterChain {
     anchor_startpoint: Height,
     // Because we are checking the filters on the fly, we don't actually store them
     chain: HashSet<BlockHash>,
-    prev_stophash_request: Option<BlockHash>,
 }
 
 impl FilterChain {
@@ -22,10 +22,13 @@ impl FilterChain {
         Self {
             anchor_startpoint,
             chain: HashSet::with_capacity(INITIAL_BUFFER_SIZE),
-            prev_stophash_request: None,
         }
     }
 
+    pub(crate) fn contains(&self, hash: &BlockHash) -> bool {
+        self.chain.contains(hash)
+    }
+
     pub(crate) async fn put_hash(&mut self, hash: BlockHash) {
         self.chain.insert(hash);
     }
@@ -45,11 +48,7 @@ impl FilterChain {
         self.anchor_startpoint + self.chain.len() as u32
     }
 
-    pub(crate) fn set_last_stop_hash(&mut self, stop_hash: BlockHash) {
-        self.prev_stophash_request = Some(stop_hash)
-    }
-
-    pub(crate) fn last_stop_hash_request(&mut self) -> &Option<BlockHash> {
-        &self.prev_stophash_request
+    pub(crate) fn anchor_height(&self) -> u32 {
+        self.anchor_startpoint
     }
 }
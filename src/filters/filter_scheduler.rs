@@ -0,0 +1,364 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::BlockHash;
+use tokio::time::{Duration, Instant};
+
+use super::FILTER_BATCH_SIZE;
+
+type Height = u32;
+type PeerId = u32;
+
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+// A range of filters requested from a single peer
+#[derive(Debug)]
+struct Assignment {
+    start: Height,
+    stop: Height,
+    stop_hash: BlockHash,
+    deadline: Instant,
+    // The range is no longer wanted, but the peer may still be sending filters for it
+    cancelled: bool,
+}
+
+// Hands out non-overlapping ranges of block filters to peers, so filters may be downloaded
+// from many peers at once. Ranges that are not completed in time are given to the next available peer.
+#[derive(Debug)]
+pub(crate) struct FilterScheduler {
+    next_height: Height,
+    retry: VecDeque<(Height, Height)>,
+    outstanding: HashMap<PeerId, Assignment>,
+    // No filters are requested above this height
+    end: Option<Height>,
+    timeout: Duration,
+}
+
+impl FilterScheduler {
+    pub(crate) fn new(anchor_height: Height) -> Self {
+        Self {
+            next_height: anchor_height + 1,
+            retry: VecDeque::new(),
+            outstanding: HashMap::new(),
+            end: None,
+            timeout: Duration::from_secs(REQUEST_TIMEOUT_SECS),
+        }
+    }
+
+    // Only request the given ranges of heights, none of which are above the end height
+    pub(crate) fn from_ranges(
+        ranges: impl IntoIterator<Item = (Height, Height)>,
+        end: Height,
+    ) -> Self {
+        let mut scheduler = Self {
+            next_height: end + 1,
+            retry: VecDeque::new(),
+            outstanding: HashMap::new(),
+            end: Some(end),
+            timeout: Duration::from_secs(REQUEST_TIMEOUT_SECS),
+        };
+        scheduler.add_ranges(ranges, end);
+        scheduler
+    }
+
+    // Request more ranges of heights, extending the end height if required
+    pub(crate) fn add_ranges(
+        &mut self,
+        ranges: impl IntoIterator<Item = (Height, Height)>,
+        end: Height,
+    ) {
+        // Heights that were not requested yet do not need to be requested twice
+        let ranges = without(ranges.into_iter().collect(), self.retry.iter().copied());
+        for (start, stop) in ranges {
+            let mut batch_start = start;
+            while batch_start <= stop {
+                let batch_stop = (batch_start + FILTER_BATCH_SIZE).min(stop);
+                self.retry.push_back((batch_start, batch_stop));
+                batch_start = batch_stop + 1;
+            }
+        }
+        if let Some(current_end) = self.end {
+            self.end = Some(current_end.max(end));
+            self.next_height = self.next_height.max(end + 1);
+        }
+    }
+
+    // Remove the heights that are still to be downloaded, as their filters are checked for the current scripts when they arrive.
+    // Ranges already handed to a peer are kept, since some of their filters may have been checked already.
+    pub(crate) fn unscheduled(&self, ranges: Vec<(Height, Height)>) -> Vec<(Height, Height)> {
+        let pending = self
+            .retry
+            .iter()
+            .copied()
+            .chain(std::iter::once((self.next_height, Height::MAX)));
+        without(ranges, pending)
+    }
+
+    // The ranges that were not requested yet
+    pub(crate) fn into_ranges(self) -> Vec<(Height, Height)> {
+        self.retry.into_iter().collect()
+    }
+
+    // Every filter up to this height was requested, and may have already been checked
+    pub(crate) fn requested_height(&self) -> Height {
+        self.next_height - 1
+    }
+
+    // Every range up to the best height was received
+    pub(crate) fn is_done(&self, best_height: Height) -> bool {
+        let best_height = self.end.map_or(best_height, |end| end.min(best_height));
+        self.outstanding.is_empty() && self.retry.is_empty() && self.next_height > best_height
+    }
+
+    // The peer is still working on a range of filters
+    pub(crate) fn is_busy(&self, peer: PeerId) -> bool {
+        self.outstanding.contains_key(&peer)
+    }
+
+    // The peer is sending filters we no longer asked for
+    pub(crate) fn is_cancelled(&self, peer: PeerId) -> bool {
+        self.outstanding
+            .get(&peer)
+            .map_or(false, |assignment| assignment.cancelled)
+    }
+
+    // The next range of heights to request, up to and including the best height
+    pub(crate) fn next_range(&mut self, best_height: Height) -> Option<(Height, Height)> {
+        let best_height = self.end.map_or(best_height, |end| end.min(best_height));
+        while let Some((start, stop)) = self.retry.pop_front() {
+            if start <= best_height {
+                return Some((start, stop.min(best_height)));
+            }
+        }
+        if self.next_height > best_height {
+            return None;
+        }
+        let start = self.next_height;
+        let stop = (start + FILTER_BATCH_SIZE).min(best_height);
+        self.next_height = stop + 1;
+        Some((start, stop))
+    }
+
+    // A range could not be requested, so it should be handed out again
+    pub(crate) fn release(&mut self, start: Height, stop: Height) {
+        self.retry.push_front((start, stop));
+    }
+
+    pub(crate) fn assign(
+        &mut self,
+        peer: PeerId,
+        start: Height,
+        stop: Height,
+        stop_hash: BlockHash,
+    ) {
+        self.outstanding.insert(
+            peer,
+            Assignment {
+                start,
+                stop,
+                stop_hash,
+                deadline: Instant::now() + self.timeout,
+                cancelled: false,
+            },
+        );
+    }
+
+    // Returns true if the filter completes the range assigned to the peer
+    pub(crate) fn complete(&mut self, peer: PeerId, block_hash: &BlockHash) -> bool {
+        let completed = self
+            .outstanding
+            .get(&peer)
+            .map_or(false, |assignment| assignment.stop_hash.eq(block_hash));
+        if completed {
+            self.outstanding.remove(&peer);
+        }
+        completed
+    }
+
+    // Reassign ranges of peers that took too long to respond, returning the peers that timed out
+    pub(crate) fn expire(&mut self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .outstanding
+            .iter()
+            .filter(|(_, assignment)| assignment.deadline < now)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in expired.iter() {
+            self.drop_peer(*peer);
+        }
+        expired
+    }
+
+    // Reassign ranges of peers that are no longer connected
+    pub(crate) fn retain_peers(&mut self, peers: &[PeerId]) {
+        let gone: Vec<PeerId> = self
+            .outstanding
+            .keys()
+            .filter(|peer| !peers.contains(peer))
+            .copied()
+            .collect();
+        for peer in gone {
+            self.drop_peer(peer);
+        }
+    }
+
+    fn drop_peer(&mut self, peer: PeerId) {
+        if let Some(assignment) = self.outstanding.remove(&peer) {
+            if !assignment.cancelled {
+                self.retry.push_back((assignment.start, assignment.stop));
+            }
+        }
+    }
+
+    // Blocks at and above this height were reorganized, so any range including them must be requested again
+    pub(crate) fn reset_from(&mut self, height: Height) {
+        self.next_height = self.next_height.min(height);
+        self.retry = self
+            .retry
+            .iter()
+            .filter(|(start, _)| *start < height)
+            .map(|(start, stop)| (*start, (*stop).min(height - 1)))
+            .collect();
+        for assignment in self.outstanding.values_mut() {
+            if assignment.stop >= height && !assignment.cancelled {
+                assignment.cancelled = true;
+                if assignment.start < height {
+                    self.retry.push_back((assignment.start, height - 1));
+                }
+            }
+        }
+    }
+
+    // Start requesting every filter again after the anchor height
+    pub(crate) fn clear(&mut self, anchor_height: Height) {
+        self.next_height = anchor_height + 1;
+        self.retry.clear();
+        for assignment in self.outstanding.values_mut() {
+            assignment.cancelled = true;
+        }
+    }
+}
+
+// Remove every height in the pending ranges from the ranges
+fn without(
+    ranges: Vec<(Height, Height)>,
+    pending: impl Iterator<Item = (Height, Height)>,
+) -> Vec<(Height, Height)> {
+    let mut remaining = ranges;
+    for (pending_start, pending_stop) in pending {
+        remaining = remaining
+            .into_iter()
+            .flat_map(|(start, stop)| {
+                let mut parts = Vec::new();
+                if start < pending_start {
+                    parts.push((start, stop.min(pending_start - 1)));
+                }
+                if stop > pending_stop {
+                    parts.push((start.max(pending_stop + 1), stop));
+                }
+                parts
+            })
+            .collect();
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, BlockHash};
+    use tokio::time::Duration;
+
+    use super::FilterScheduler;
+
+    #[test]
+    fn test_ranges_do_not_overlap() {
+        let mut scheduler = FilterScheduler::new(100);
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        assert_eq!((start, stop), (101, 200));
+        scheduler.assign(1, start, stop, BlockHash::all_zeros());
+        assert!(scheduler.is_busy(1));
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        assert_eq!((start, stop), (201, 300));
+        scheduler.assign(2, start, stop, BlockHash::from_byte_array([1; 32]));
+        assert!(!scheduler.complete(2, &BlockHash::all_zeros()));
+        assert!(scheduler.complete(1, &BlockHash::all_zeros()));
+        assert!(!scheduler.is_busy(1));
+        assert_eq!(scheduler.next_range(350).unwrap(), (301, 350));
+        assert!(scheduler.next_range(350).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ranges_are_reassigned() {
+        let mut scheduler = FilterScheduler::new(0);
+        scheduler.timeout = Duration::from_millis(10);
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        scheduler.assign(1, start, stop, BlockHash::all_zeros());
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        scheduler.assign(2, start, stop, BlockHash::all_zeros());
+        // The second peer disconnected
+        scheduler.retain_peers(&[1]);
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (101, 200));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(scheduler.expire(), vec![1]);
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (1, 100));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (201, 300));
+    }
+
+    #[test]
+    fn test_only_given_ranges_are_requested() {
+        let mut scheduler = FilterScheduler::from_ranges(vec![(10, 20), (50, 250)], 250);
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (10, 20));
+        scheduler.assign(1, 10, 20, BlockHash::all_zeros());
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (50, 149));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (150, 249));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (250, 250));
+        assert!(scheduler.next_range(1_000).is_none());
+        assert!(!scheduler.is_done(1_000));
+        assert!(scheduler.complete(1, &BlockHash::all_zeros()));
+        assert!(scheduler.is_done(1_000));
+    }
+
+    #[test]
+    fn test_reorg_cancels_ranges() {
+        let mut scheduler = FilterScheduler::new(0);
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        scheduler.assign(1, start, stop, BlockHash::all_zeros());
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        scheduler.assign(2, start, stop, BlockHash::all_zeros());
+        scheduler.reset_from(150);
+        assert!(!scheduler.is_cancelled(1));
+        assert!(scheduler.is_cancelled(2));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (101, 149));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (150, 249));
+        // A cancelled range is not requested again when the peer disconnects
+        scheduler.retain_peers(&[1]);
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (250, 349));
+    }
+
+    #[test]
+    fn test_pending_heights_are_not_rescanned() {
+        let mut scheduler = FilterScheduler::new(0);
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        scheduler.assign(1, start, stop, BlockHash::all_zeros());
+        let (start, stop) = scheduler.next_range(1_000).unwrap();
+        scheduler.assign(2, start, stop, BlockHash::all_zeros());
+        // The range of the second peer is waiting to be requested again
+        scheduler.retain_peers(&[1]);
+        assert_eq!(scheduler.requested_height(), 200);
+        assert_eq!(
+            scheduler.unscheduled(vec![(1, 50), (90, 250)]),
+            vec![(1, 50), (90, 100)]
+        );
+        assert!(scheduler.unscheduled(vec![(150, 160)]).is_empty());
+    }
+
+    #[test]
+    fn test_added_ranges_are_not_requested_twice() {
+        let mut scheduler = FilterScheduler::from_ranges(vec![(10, 20)], 20);
+        scheduler.add_ranges(vec![(5, 30)], 30);
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (10, 20));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (5, 9));
+        assert_eq!(scheduler.next_range(1_000).unwrap(), (21, 30));
+        assert!(scheduler.next_range(1_000).is_none());
+    }
+}
@@ -5,9 +5,10 @@ pub(crate) mod cfheader_batch;
 pub(crate) mod cfheader_chain;
 #[allow(dead_code)]
 pub(crate) mod error;
+pub(crate) mod filter_cache;
 pub(crate) mod filter_chain;
-
-use std::collections::HashSet;
+pub(crate) mod filter_scheduler;
+pub(crate) mod rescan;
 
 use bitcoin::{bip158::BlockFilter, BlockHash, FilterHash, ScriptBuf};
 use bitcoin_hashes::{sha256d, Hash};
@@ -42,14 +43,30 @@ impl Filter {
         &self.block_hash
     }
 
-    pub async fn contains_any(
+    pub fn contents(&self) -> &[u8] {
+        &self.block_filter.content
+    }
+
+    pub async fn contains_any<'a>(
         &mut self,
-        scripts: &HashSet<ScriptBuf>,
+        scripts: impl Iterator<Item = &'a ScriptBuf>,
     ) -> Result<bool, FilterError> {
         self.block_filter
             .match_any(
                 &self.block_hash,
-                &mut scripts.iter().map(|script| script.to_bytes()),
+                &mut scripts.map(|script| script.to_bytes()),
+            )
+            .map_err(|_| FilterError::IORead)
+    }
+
+    pub async fn contains_all<'a>(
+        &mut self,
+        scripts: impl Iterator<Item = &'a ScriptBuf>,
+    ) -> Result<bool, FilterError> {
+        self.block_filter
+            .match_all(
+                &self.block_hash,
+                &mut scripts.map(|script| script.to_bytes()),
             )
             .map_err(|_| FilterError::IORead)
     }
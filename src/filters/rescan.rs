@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+
+use bitcoin::ScriptBuf;
+
+use super::filter_scheduler::FilterScheduler;
+
+type Height = u32;
+
+// Filters that are checked again after they were first scanned, either because the client asked,
+// or because scripts were added after the filters were already checked.
+#[derive(Debug)]
+pub(crate) struct Rescan {
+    pub(crate) scheduler: FilterScheduler,
+    // Only check the filters for these scripts, or for every script if none are given
+    scripts: Option<HashSet<ScriptBuf>>,
+    // Scripts that are catching up to the filters that were already scanned
+    catch_up: Vec<ScriptBuf>,
+    start_height: Height,
+    end_height: Height,
+}
+
+impl Rescan {
+    pub(crate) fn new(
+        ranges: Vec<(Height, Height)>,
+        start_height: Height,
+        end_height: Height,
+        scripts: Option<HashSet<ScriptBuf>>,
+    ) -> Self {
+        let catch_up = scripts
+            .as_ref()
+            .map(|scripts| scripts.iter().cloned().collect())
+            .unwrap_or_default();
+        Self {
+            scheduler: FilterScheduler::from_ranges(ranges, end_height),
+            scripts,
+            catch_up,
+            start_height,
+            end_height,
+        }
+    }
+
+    // Check more filters. Filters that were not requested yet are checked for every script in either rescan,
+    // so heights already waiting to be requested are not added again.
+    pub(crate) fn merge(&mut self, other: Rescan) {
+        self.scheduler
+            .add_ranges(other.scheduler.into_ranges(), other.end_height);
+        self.scripts = match (self.scripts.take(), other.scripts) {
+            (Some(mut scripts), Some(other_scripts)) => {
+                scripts.extend(other_scripts);
+                Some(scripts)
+            }
+            _ => None,
+        };
+        self.catch_up.extend(other.catch_up);
+        self.start_height = self.start_height.min(other.start_height);
+        self.end_height = self.end_height.max(other.end_height);
+    }
+
+    pub(crate) fn scripts(&self) -> Option<&HashSet<ScriptBuf>> {
+        self.scripts.as_ref()
+    }
+
+    // The scripts that caught up to the filters, with the range of heights that were checked
+    pub(crate) fn into_catch_up(self) -> Option<(Vec<ScriptBuf>, Height, Height)> {
+        if self.catch_up.is_empty() {
+            return None;
+        }
+        Some((self.catch_up, self.start_height, self.end_height))
+    }
+}
@@ -99,6 +99,12 @@ pub use chain::checkpoints::{
     HeaderCheckpoint, MAINNET_HEADER_CP, SIGNET_HEADER_CP, TESTNET4_HEADER_CP,
 };
 
+#[doc(inline)]
+pub use chain::keychain::{Keychain, KeychainError, KeychainMatch, ScriptType};
+
+#[doc(inline)]
+pub use chain::versionbits::{Deployment, DeploymentState, DeploymentUpdate};
+
 #[doc(inline)]
 pub use db::memory::peers::StatelessPeerStore;
 
@@ -117,35 +123,67 @@ pub use {
     crate::core::builder::NodeBuilder,
     crate::core::client::{Client, ClientSender},
     crate::core::error::{ClientError, NodeError},
-    crate::core::messages::{FailurePayload, NodeMessage, Progress, SyncUpdate, Warning},
+    crate::core::messages::{
+        CatchUp, DeepReorg, FailurePayload, FeeFilterStats, NodeMessage, PeerInfo, Progress,
+        SpentOutPoint, SyncUpdate, UnconfirmedTransaction, Warning,
+    },
     crate::core::node::{Node, NodeState},
+    crate::core::{ReorgPolicy, TransportPolicy},
 };
 
 #[doc(inline)]
 pub use bitcoin::{
-    block::Header, p2p::address::AddrV2, p2p::message_network::RejectReason, p2p::ServiceFlags,
-    Address, Block, BlockHash, Network, ScriptBuf, Transaction, Txid,
+    bip32::{DerivationPath, Xpub},
+    block::Header,
+    merkle_tree::MerkleBlock,
+    p2p::address::AddrV2,
+    p2p::message_network::RejectReason,
+    p2p::ServiceFlags,
+    Address, Amount, Block, BlockHash, FeeRate, Network, OutPoint, ScriptBuf, Transaction, Txid,
 };
 
-/// A Bitcoin [`Transaction`] with additional context.
+/// A Bitcoin [`Transaction`] that pays to or spends from a watched script, with additional context.
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
-pub(crate) struct IndexedTransaction {
+pub struct IndexedTransaction {
     /// The Bitcoin transaction.
     pub transaction: Transaction,
     /// The height of the block in the chain that includes this transaction.
     pub height: u32,
     /// The hash of the block.
     pub hash: BlockHash,
+    /// The watched scripts this transaction pays to or spends from.
+    pub matched_scripts: Vec<ScriptBuf>,
+    /// The indexes of the inputs that spend from a watched script.
+    ///
+    /// Spends are recognized for the outputs the node saw paying to a watched script while it was running,
+    /// and for outpoints watched with [`ClientSender::watch_outpoint`](crate::ClientSender). Coins received
+    /// before the anchor checkpoint or before the node was restarted should be watched explicitly.
+    pub inputs: Vec<usize>,
+    /// The indexes of the outputs that pay to a watched script.
+    pub outputs: Vec<usize>,
+    /// A proof the transaction is included in the block, which may be checked against the block header.
+    /// The proof is shared by every relevant transaction in the block, so it includes all of them.
+    pub proof: MerkleBlock,
 }
 
-#[allow(dead_code)]
 impl IndexedTransaction {
-    pub(crate) fn new(transaction: Transaction, height: u32, hash: BlockHash) -> Self {
+    pub(crate) fn new(
+        transaction: Transaction,
+        height: u32,
+        hash: BlockHash,
+        matched_scripts: Vec<ScriptBuf>,
+        inputs: Vec<usize>,
+        outputs: Vec<usize>,
+        proof: MerkleBlock,
+    ) -> Self {
         Self {
             transaction,
             height,
             hash,
+            matched_scripts,
+            inputs,
+            outputs,
+            proof,
         }
     }
 }
@@ -206,7 +244,7 @@ impl IndexedFilter {
 
     /// Does the filter contain a positive match for any of the provided scripts
     pub async fn contains_any(&mut self, scripts: &HashSet<ScriptBuf>) -> bool {
-        self.filter.contains_any(scripts).await.unwrap()
+        self.filter.contains_any(scripts.iter()).await.unwrap()
     }
 }
 
@@ -217,6 +255,8 @@ pub struct TxBroadcast {
     pub tx: Transaction,
     /// The strategy for how this transaction should be shared with the network.
     pub broadcast_policy: TxBroadcastPolicy,
+    /// The fee paid by the transaction, if known. Used to warn when peers are unlikely to relay the transaction.
+    pub fee: Option<Amount>,
 }
 
 impl TxBroadcast {
@@ -225,6 +265,7 @@ impl TxBroadcast {
         Self {
             tx,
             broadcast_policy,
+            fee: None,
         }
     }
 
@@ -233,8 +274,21 @@ impl TxBroadcast {
         Self {
             tx,
             broadcast_policy: TxBroadcastPolicy::RandomPeer,
+            fee: None,
         }
     }
+
+    /// The fee paid by the transaction. If provided, a [`Warning::FeeRateBelowPeerFloor`] is issued before broadcasting
+    /// a transaction with a fee rate below the median fee rate connected peers will relay.
+    pub fn fee(mut self, fee: Amount) -> Self {
+        self.fee = Some(fee);
+        self
+    }
+
+    // The fee rate of the transaction, if the fee is known
+    pub(crate) fn fee_rate(&self) -> Option<FeeRate> {
+        self.fee.map(|fee| fee / self.tx.weight())
+    }
 }
 
 /// The strategy for how this transaction should be shared with the network.
@@ -289,7 +343,6 @@ impl TrustedPeer {
     }
 
     /// Create a new peer from a TorV3 service and port.
-    #[cfg(feature = "tor")]
     pub fn new_from_tor_v3(
         public_key: [u8; 32],
         port: Option<u16>,
@@ -331,7 +384,6 @@ impl TrustedPeer {
     }
 
     /// Create a new peer from a TorV3 service.
-    #[cfg(feature = "tor")]
     pub fn from_tor_v3(public_key: [u8; 32]) -> Self {
         let address = AddrV2::TorV3(public_key);
         Self {
@@ -341,6 +393,16 @@ impl TrustedPeer {
         }
     }
 
+    /// Create a new peer from the SHA256 hash of an I2P destination, the 32 bytes encoded in a `.b32.i2p` address.
+    pub fn from_i2p(destination: [u8; 32]) -> Self {
+        let address = AddrV2::I2p(destination);
+        Self {
+            address,
+            port: None,
+            known_services: ServiceFlags::NONE,
+        }
+    }
+
     /// The IP address of the trusted peer.
     pub fn address(&self) -> AddrV2 {
         self.address.clone()
@@ -400,6 +462,37 @@ pub enum ConnectionType {
     /// Connect to peers over Tor
     #[cfg(feature = "tor")]
     Tor(TorClient<PreferredRuntime>),
+    /// Connect to peers through a SOCKS5 proxy, such as a Tor daemon. IPv4, IPv6 and onion v3 peers
+    /// may be reached, and encrypted V2 connections are still attempted with peers that support them.
+    Socks5 {
+        /// The address the proxy is listening on, typically `127.0.0.1:9050` for a Tor daemon.
+        proxy: SocketAddr,
+        /// How to authenticate with the proxy.
+        auth: Socks5Auth,
+    },
+    /// Connect to I2P peers through the SAM v3 bridge of a local I2P router. Only I2P peers are reachable.
+    I2p {
+        /// The address of the SAM bridge, typically `127.0.0.1:7656`.
+        sam: SocketAddr,
+    },
+}
+
+/// Authentication with a SOCKS5 proxy.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Socks5Auth {
+    /// The proxy does not require authentication.
+    #[default]
+    None,
+    /// Authenticate with a username and password.
+    Password {
+        /// The username.
+        username: String,
+        /// The password.
+        password: String,
+    },
+    /// Use random credentials for every connection. Tor uses a separate circuit for each set of credentials,
+    /// so connections to different peers cannot be linked by a common exit.
+    Isolate,
 }
 
 /// Configure how many peers will be stored.
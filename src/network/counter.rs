@@ -4,6 +4,24 @@ use tokio::time::Instant;
 
 // A peer cannot send 10,000 ADDRs in one connection.
 const ADDR_HARD_LIMIT: i32 = 10_000;
+// Inbound peers may ask for headers and filters this many times a minute
+const MAX_REQUESTS_PER_MINUTE: u32 = 120;
+const ONE_MINUTE: Duration = Duration::from_secs(60);
+// Peers that received `sendheaders` announce new blocks with a few unrequested headers
+const MAX_ANNOUNCED_HEADERS: usize = 8;
+// Misbehaviour scores for violations, out of a ban threshold of 100. Chain data may be unsolicited
+// if it answers a request that already timed out, so it is penalized lightly.
+const REPEATED_HANDSHAKE_PENALTY: u32 = 10;
+const EXCESS_ADDR_PENALTY: u32 = 20;
+const UNSOLICITED_DATA_PENALTY: u32 = 10;
+const UNSOLICITED_TX_PENALTY: u32 = 10;
+pub(crate) const UNRESPONSIVE_PENALTY: u32 = 5;
+// Ping a peer that has not sent a message for two minutes
+const PING_INTERVAL: Duration = Duration::from_secs(60 * 2);
+// A peer that does not answer a ping within a minute is disconnected
+const PONG_TIMEOUT: Duration = Duration::from_secs(60);
+// Each new round trip time moves the smoothed value by an eighth of the difference, as in RFC 6298
+const RTT_SMOOTHING: u32 = 8;
 
 // Very simple denial of service protection so a peer cannot spam us with unsolicited messages.
 #[derive(Debug, Clone)]
@@ -11,12 +29,14 @@ pub(crate) struct MessageCounter {
     timer: MessageTimer,
     version: i8,
     verack: i8,
-    header: i32,
+    headers_requested: bool,
+    unsolicited_headers: bool,
     filter_header: i32,
     filters: i64,
     addrs: i32,
     block: i32,
     tx: i32,
+    relayed_tx: i32,
 }
 
 impl MessageCounter {
@@ -25,12 +45,14 @@ impl MessageCounter {
             timer: MessageTimer::new(timeout),
             version: 1,
             verack: 1,
-            header: 0,
+            headers_requested: false,
+            unsolicited_headers: false,
             filter_header: 0,
             filters: 0,
             addrs: 0,
             block: 0,
             tx: 0,
+            relayed_tx: 0,
         }
     }
 
@@ -43,8 +65,21 @@ impl MessageCounter {
         self.verack -= 1;
     }
 
-    pub(crate) fn got_header(&mut self) {
-        self.timer.untrack();
+    // Returns true if the headers answer a request. Unrequested headers announce new blocks, unless
+    // there are too many of them. A few headers may be an announcement that arrived while a request
+    // is pending, so the request stays outstanding until a full batch of headers arrives.
+    pub(crate) fn got_header(&mut self, num_headers: usize) -> bool {
+        if self.headers_requested {
+            self.timer.untrack();
+            if num_headers > MAX_ANNOUNCED_HEADERS {
+                self.headers_requested = false;
+            }
+            return true;
+        }
+        if num_headers > MAX_ANNOUNCED_HEADERS {
+            self.unsolicited_headers = true;
+        }
+        false
     }
 
     pub(crate) fn got_filter_header(&mut self) {
@@ -66,6 +101,15 @@ impl MessageCounter {
         self.block -= 1;
     }
 
+    pub(crate) fn got_relayed_tx(&mut self) {
+        self.relayed_tx -= 1;
+    }
+
+    pub(crate) fn got_block_not_found(&mut self, num_blocks: usize) {
+        self.timer.untrack();
+        self.block -= num_blocks as i32;
+    }
+
     pub(crate) fn got_reject(&mut self) {
         self.tx -= 1;
     }
@@ -76,6 +120,7 @@ impl MessageCounter {
 
     pub(crate) fn sent_header(&mut self) {
         self.timer.track();
+        self.headers_requested = true;
     }
 
     pub(crate) fn sent_filter_header(&mut self) {
@@ -101,15 +146,39 @@ impl MessageCounter {
         self.tx += 1;
     }
 
+    pub(crate) fn sent_tx_request(&mut self, num_txs: usize) {
+        self.relayed_tx += num_txs as i32;
+    }
+
     pub(crate) fn unsolicited(&self) -> bool {
         self.version < 0
-            || self.header < 0
+            || self.unsolicited_headers
             || self.filters < 0
             || self.verack < 0
             || self.filter_header < 0
             || self.addrs < 0
             || self.block < 0
             || self.tx < 0
+            || self.relayed_tx < 0
+    }
+
+    // The misbehaviour score of the unsolicited messages a peer sent
+    pub(crate) fn penalty(&self) -> u32 {
+        let mut penalty = 0;
+        if self.version < 0 || self.verack < 0 {
+            penalty += REPEATED_HANDSHAKE_PENALTY;
+        }
+        if self.addrs < 0 {
+            penalty += EXCESS_ADDR_PENALTY;
+        }
+        if self.unsolicited_headers || self.filter_header < 0 || self.filters < 0 || self.block < 0
+        {
+            penalty += UNSOLICITED_DATA_PENALTY;
+        }
+        if self.tx < 0 || self.relayed_tx < 0 {
+            penalty += UNSOLICITED_TX_PENALTY;
+        }
+        penalty
     }
 
     pub(crate) fn unresponsive(&self) -> bool {
@@ -117,6 +186,86 @@ impl MessageCounter {
     }
 }
 
+// Limit the rate of requests an inbound peer may make, as each request reads from the header store.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestLimiter {
+    window_start: Instant,
+    requests: u32,
+}
+
+impl RequestLimiter {
+    pub(crate) fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            requests: 0,
+        }
+    }
+
+    // Count a request, returning false if the peer has made too many requests recently
+    pub(crate) fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() > ONE_MINUTE {
+            self.window_start = Instant::now();
+            self.requests = 0;
+        }
+        self.requests += 1;
+        self.requests <= MAX_REQUESTS_PER_MINUTE
+    }
+}
+
+// Keep idle connections alive with pings and measure the round trip time of the pongs.
+#[derive(Debug, Clone)]
+pub(crate) struct PingTracker {
+    last_heard: Instant,
+    in_flight: Option<(u64, Instant)>,
+    rtt: Option<Duration>,
+}
+
+impl PingTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_heard: Instant::now(),
+            in_flight: None,
+            rtt: None,
+        }
+    }
+
+    pub(crate) fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    // A ping is due if there is no round trip time yet or the peer has been quiet
+    pub(crate) fn needs_ping(&self) -> bool {
+        self.in_flight.is_none()
+            && (self.rtt.is_none() || self.last_heard.elapsed() > PING_INTERVAL)
+    }
+
+    pub(crate) fn sent_ping(&mut self, nonce: u64) {
+        self.in_flight = Some((nonce, Instant::now()));
+    }
+
+    // Match a pong to the ping in flight, returning the updated round trip time
+    pub(crate) fn got_pong(&mut self, nonce: u64) -> Option<Duration> {
+        match self.in_flight {
+            Some((expected, sent)) if expected == nonce => {
+                self.in_flight = None;
+                let sample = sent.elapsed();
+                let rtt = match self.rtt {
+                    Some(rtt) => (rtt * (RTT_SMOOTHING - 1) + sample) / RTT_SMOOTHING,
+                    None => sample,
+                };
+                self.rtt = Some(rtt);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn unanswered(&self) -> bool {
+        self.in_flight
+            .map_or(false, |(_, sent)| sent.elapsed() > PONG_TIMEOUT)
+    }
+}
+
 //
 #[derive(Debug, Clone)]
 pub(crate) struct MessageTimer {
@@ -183,8 +332,11 @@ mod tests {
         assert!(counter.timer.tracked_time.is_none());
         counter.sent_header();
         assert!(counter.timer.tracked_time.is_some());
-        counter.got_header();
+        assert!(counter.got_header(2_000));
         assert!(counter.timer.tracked_time.is_none());
+        // A block announced with headers is not unsolicited
+        assert!(!counter.got_header(1));
+        assert!(!counter.unsolicited());
         counter.sent_addrs();
         counter.sent_filter_header();
         assert!(counter.timer.tracked_time.is_some());
@@ -200,7 +352,81 @@ mod tests {
         assert!(counter.timer.tracked_time.is_none());
         counter.got_addrs(1);
         assert!(!counter.unsolicited());
+        assert_eq!(counter.penalty(), 0);
         counter.got_verack();
         assert!(counter.unsolicited());
+        assert_eq!(counter.penalty(), REPEATED_HANDSHAKE_PENALTY);
+        counter.got_block();
+        assert_eq!(
+            counter.penalty(),
+            REPEATED_HANDSHAKE_PENALTY + UNSOLICITED_DATA_PENALTY
+        );
+    }
+
+    #[test]
+    fn test_unrequested_headers_over_announcement_size() {
+        let mut counter = MessageCounter::new(Duration::from_secs(3));
+        assert!(!counter.got_header(MAX_ANNOUNCED_HEADERS));
+        assert!(!counter.unsolicited());
+        assert!(!counter.got_header(MAX_ANNOUNCED_HEADERS + 1));
+        assert!(counter.unsolicited());
+    }
+
+    #[test]
+    fn test_announcement_during_pending_request() {
+        let mut counter = MessageCounter::new(Duration::from_secs(3));
+        counter.sent_header();
+        // A block is announced before the peer responds
+        assert!(counter.got_header(1));
+        assert!(counter.got_header(2_000));
+        assert!(!counter.unsolicited());
+        // The request was answered, so no more batches are expected
+        assert!(!counter.got_header(2_000));
+        assert!(counter.unsolicited());
+        // A small response does not allow more than one unrequested batch
+        let mut counter = MessageCounter::new(Duration::from_secs(3));
+        counter.sent_header();
+        assert!(counter.got_header(3));
+        assert!(counter.timer.tracked_time.is_none());
+        assert!(counter.got_header(1));
+        assert!(counter.got_header(2_000));
+        assert!(!counter.unsolicited());
+        assert!(!counter.got_header(2_000));
+        assert!(counter.unsolicited());
+    }
+
+    #[test]
+    fn test_request_limiter_works() {
+        let mut limiter = RequestLimiter::new();
+        for _ in 0..MAX_REQUESTS_PER_MINUTE {
+            assert!(limiter.allow());
+        }
+        assert!(!limiter.allow());
+        limiter.window_start -= ONE_MINUTE + Duration::from_secs(1);
+        assert!(limiter.allow());
+    }
+
+    #[test]
+    fn test_ping_tracker_works() {
+        let mut pings = PingTracker::new();
+        assert!(pings.needs_ping());
+        pings.sent_ping(7);
+        assert!(!pings.needs_ping());
+        assert!(pings.got_pong(8).is_none());
+        pings.in_flight = Some((7, Instant::now() - Duration::from_millis(800)));
+        let rtt = pings.got_pong(7).unwrap();
+        assert!(rtt >= Duration::from_millis(800));
+        assert!(pings.got_pong(7).is_none());
+        assert!(!pings.needs_ping());
+        // A faster pong only moves the round trip time part of the way
+        pings.sent_ping(9);
+        let smoothed = pings.got_pong(9).unwrap();
+        assert!(smoothed < rtt && smoothed >= Duration::from_millis(700));
+        pings.last_heard -= PING_INTERVAL + Duration::from_secs(1);
+        assert!(pings.needs_ping());
+        pings.sent_ping(10);
+        assert!(!pings.unanswered());
+        pings.in_flight = Some((10, Instant::now() - PONG_TIMEOUT - Duration::from_secs(1)));
+        assert!(pings.unanswered());
     }
 }
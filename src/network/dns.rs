@@ -1,14 +1,23 @@
 extern crate alloc;
-use crate::{network::error::DnsBootstrapError, prelude::encode_qname};
+use crate::{
+    core::{dialog::Dialog, DnsConfig},
+    network::error::DnsBootstrapError,
+    prelude::encode_qname,
+};
 use bitcoin::{
     key::rand::{thread_rng, RngCore},
+    p2p::ServiceFlags,
     Network,
 };
 use std::{
     io::Read,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
 };
-use tokio::net::UdpSocket;
 
 use super::error::DNSQueryError;
 
@@ -23,6 +32,11 @@ const TESTNET_SEEDS: &[&str; 4] = &[
     "testnet-seed.bluematt.me",
 ];
 
+const TESTNET4_SEEDS: &[&str; 2] = &[
+    "seed.testnet4.bitcoin.sprovoost.nl",
+    "seed.testnet4.wiz.biz",
+];
+
 const MAINNET_SEEDS: &[&str; 9] = &[
     "seed.bitcoin.sipa.be",
     "dnsseed.bluematt.me",
@@ -35,8 +49,13 @@ const MAINNET_SEEDS: &[&str; 9] = &[
     "seed.bitcoin.wiz.biz",
 ];
 
-const RESOLVER: &str = "1.1.1.1:53";
-const LOCAL_HOST: &str = "0.0.0.0:0";
+// Used if the system does not configure any nameservers
+const FALLBACK_RESOLVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// Responses over UDP are limited to 512 bytes without extensions
+const MAX_UDP_RESPONSE: usize = 512;
 
 const HEADER_BYTES: usize = 12;
 
@@ -44,10 +63,8 @@ const RECURSIVE_FLAGS: [u8; 2] = [
     0x01, 0x00, // Default flags with recursive resolver
 ];
 
-const QTYPE: [u8; 4] = [
-    0x00, 0x01, // QType: A Record
-    0x00, 0x01, // IN
-];
+// Set in the first byte of the flags when the response did not fit in the datagram
+const TRUNCATED_FLAG: u8 = 0x02;
 
 const COUNTS: [u8; 6] = [
     0x00, 0x00, // ANCOUNT
@@ -55,37 +72,89 @@ const COUNTS: [u8; 6] = [
     0x00, 0x00, // ARCOUNT
 ];
 
-const A_RECORD: u16 = 0x01;
-const A_CLASS: u16 = 0x01;
-const EXPECTED_RDATA_LEN: u16 = 0x04;
+const IN_CLASS: u16 = 0x01;
+// The top two bits of a label length mark a pointer to a name earlier in the message
+const NAME_POINTER: u8 = 0xC0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn qtype(&self) -> u16 {
+        match self {
+            RecordType::A => 0x01,
+            RecordType::Aaaa => 0x1C,
+        }
+    }
+
+    fn ip_from_rdata(&self, rdata: &[u8]) -> Option<IpAddr> {
+        match self {
+            RecordType::A => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            RecordType::Aaaa => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+        }
+    }
+}
 
 #[cfg(feature = "dns")]
-pub(crate) struct Dns<'a> {
-    seeds: Vec<&'a str>,
+pub(crate) struct Dns {
+    seeds: Vec<String>,
+    resolvers: Vec<SocketAddr>,
 }
 
-impl<'a> Dns<'a> {
+impl Dns {
     #[cfg(feature = "dns")]
-    pub fn new(network: Network) -> Self {
-        let seeds = match network {
-            Network::Bitcoin => MAINNET_SEEDS.to_vec(),
-            Network::Testnet => TESTNET_SEEDS.to_vec(),
-            Network::Signet => SIGNET_SEEDS.to_vec(),
-            Network::Regtest => Vec::with_capacity(0),
-            Network::Testnet4 => Vec::with_capacity(0),
-            _ => unreachable!(),
+    pub fn new(network: Network, config: &DnsConfig) -> Self {
+        let seeds = if config.seeds.is_empty() {
+            let seeds: &[&str] = match network {
+                Network::Bitcoin => MAINNET_SEEDS,
+                Network::Testnet => TESTNET_SEEDS,
+                Network::Testnet4 => TESTNET4_SEEDS,
+                Network::Signet => SIGNET_SEEDS,
+                Network::Regtest => &[],
+                _ => unreachable!(),
+            };
+            seeds.iter().map(|seed| seed.to_string()).collect()
+        } else {
+            config.seeds.clone()
+        };
+        let resolvers = if config.resolvers.is_empty() {
+            system_resolvers()
+        } else {
+            config.resolvers.clone()
         };
-        Self { seeds }
+        Self { seeds, resolvers }
     }
 
+    // Peers that signal for `services` are requested from the seeds, or any peers if the services are empty.
+    // Seeds that cannot be resolved are reported to the dialog.
     #[cfg(feature = "dns")]
-    pub async fn bootstrap(&self) -> Result<Vec<IpAddr>, DnsBootstrapError> {
+    pub async fn bootstrap(
+        &self,
+        services: ServiceFlags,
+        dialog: &Dialog,
+    ) -> Result<Vec<IpAddr>, DnsBootstrapError> {
         let mut ip_addrs: Vec<IpAddr> = vec![];
 
-        for host in &self.seeds {
-            match DNSQuery::new(host).lookup().await {
-                Ok(addrs) => ip_addrs.extend(addrs),
-                Err(e) => eprintln!("{e}"),
+        for seed in &self.seeds {
+            let host = service_subdomain(seed, services);
+            for record in [RecordType::A, RecordType::Aaaa] {
+                match self.resolve(&host, record).await {
+                    Ok(addrs) => ip_addrs.extend(addrs),
+                    Err(e) => {
+                        dialog
+                            .send_dialog(format!("Could not resolve {host}: {e}"))
+                            .await
+                    }
+                }
             }
         }
 
@@ -96,16 +165,64 @@ impl<'a> Dns<'a> {
 
         Ok(ip_addrs)
     }
+
+    // Ask each resolver in turn until one of them answers
+    async fn resolve(&self, host: &str, record: RecordType) -> Result<Vec<IpAddr>, DNSQueryError> {
+        let query = DNSQuery::new(host, record);
+        let mut error = DNSQueryError::NoResolvers;
+        for resolver in &self.resolvers {
+            match query.lookup(*resolver).await {
+                Ok(ips) => return Ok(ips),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+// Seeds filter peers by service bits when they are prefixed to the seed as a hex subdomain
+fn service_subdomain(seed: &str, services: ServiceFlags) -> String {
+    if services == ServiceFlags::NONE {
+        seed.to_string()
+    } else {
+        format!("x{:x}.{seed}", services.to_u64())
+    }
+}
+
+// Networks that block DNS to public resolvers still allow queries to the resolvers they configure
+fn system_resolvers() -> Vec<SocketAddr> {
+    let resolvers = std::fs::read_to_string(RESOLV_CONF)
+        .map(|conf| parse_resolv_conf(&conf))
+        .unwrap_or_default();
+    if resolvers.is_empty() {
+        vec![SocketAddr::new(IpAddr::V4(FALLBACK_RESOLVER), DNS_PORT)]
+    } else {
+        resolvers
+    }
+}
+
+fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => fields.next()?.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect()
 }
 
 struct DNSQuery {
     message_id: [u8; 2],
     message: Vec<u8>,
     question: Vec<u8>,
+    record: RecordType,
 }
 
 impl DNSQuery {
-    fn new(seed: &str) -> Self {
+    fn new(host: &str, record: RecordType) -> Self {
         // Build a header
         let mut rng = thread_rng();
         let mut message_id = [0, 0];
@@ -115,32 +232,49 @@ impl DNSQuery {
         message.push(0x00); // QDCOUNT
         message.push(0x01); // QDCOUNT
         message.extend(COUNTS);
-        let mut question = encode_qname(seed);
-        question.extend(QTYPE);
+        let mut question = encode_qname(host);
+        question.extend(record.qtype().to_be_bytes());
+        question.extend(IN_CLASS.to_be_bytes());
         message.extend_from_slice(&question);
         Self {
             message_id,
             message,
             question,
+            record,
         }
     }
 
-    async fn lookup(&self) -> Result<Vec<IpAddr>, DNSQueryError> {
-        let sock = UdpSocket::bind(LOCAL_HOST)
+    // Truncated responses are requested again over TCP, which has no limit on the size of the response
+    async fn lookup(&self, resolver: SocketAddr) -> Result<Vec<IpAddr>, DNSQueryError> {
+        match tokio::time::timeout(QUERY_TIMEOUT, self.lookup_udp(resolver)).await {
+            Ok(Err(DNSQueryError::Truncated)) => {
+                tokio::time::timeout(QUERY_TIMEOUT, self.lookup_tcp(resolver))
+                    .await
+                    .map_err(|_| DNSQueryError::Timeout)?
+            }
+            Ok(result) => result,
+            Err(_) => Err(DNSQueryError::Timeout),
+        }
+    }
+
+    async fn lookup_udp(&self, resolver: SocketAddr) -> Result<Vec<IpAddr>, DNSQueryError> {
+        let local: SocketAddr = if resolver.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let sock = UdpSocket::bind(local)
             .await
             .map_err(|_| DNSQueryError::ConnectionDenied)?;
-        sock.connect(RESOLVER)
-            .await
-            .map_err(|_| DNSQueryError::Udp)?;
-        sock.send(&self.message)
+        sock.connect(resolver)
             .await
             .map_err(|_| DNSQueryError::Udp)?;
         sock.send(&self.message)
             .await
             .map_err(|_| DNSQueryError::Udp)?;
-        let mut response_buf = [0u8; 512];
-        let (amt, _src) = sock
-            .recv_from(&mut response_buf)
+        let mut response_buf = [0u8; MAX_UDP_RESPONSE];
+        let amt = sock
+            .recv(&mut response_buf)
             .await
             .map_err(|_| DNSQueryError::Udp)?;
         if amt < HEADER_BYTES {
@@ -150,6 +284,32 @@ impl DNSQuery {
         Ok(ips)
     }
 
+    // Messages over TCP are prefixed with their length
+    async fn lookup_tcp(&self, resolver: SocketAddr) -> Result<Vec<IpAddr>, DNSQueryError> {
+        // Responses are parsed with the synchronous `Read` for byte slices
+        use tokio::io::AsyncReadExt;
+        let mut stream = TcpStream::connect(resolver)
+            .await
+            .map_err(|_| DNSQueryError::ConnectionDenied)?;
+        let mut message = (self.message.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&self.message);
+        stream
+            .write_all(&message)
+            .await
+            .map_err(|_| DNSQueryError::Tcp)?;
+        let len = stream.read_u16().await.map_err(|_| DNSQueryError::Tcp)?;
+        if (len as usize) < HEADER_BYTES {
+            return Err(DNSQueryError::MalformedHeader);
+        }
+        let mut response_buf = vec![0; len as usize];
+        stream
+            .read_exact(&mut response_buf)
+            .await
+            .map_err(|_| DNSQueryError::Tcp)?;
+        let ips = self.parse_message(&response_buf).await?;
+        Ok(ips)
+    }
+
     async fn parse_message(&self, mut response: &[u8]) -> Result<Vec<IpAddr>, DNSQueryError> {
         let mut ips = Vec::with_capacity(10);
         let mut buf: [u8; 2] = [0, 0];
@@ -159,10 +319,13 @@ impl DNSQuery {
         if self.message_id != buf {
             return Err(DNSQueryError::MessageID);
         }
-        // Read flags and ignore
+        // Read flags and ignore all but truncation
         response
             .read_exact(&mut buf)
             .map_err(|_| DNSQueryError::UnexpectedEOF)?; // Read 4 bytes
+        if buf[0] & TRUNCATED_FLAG != 0 {
+            return Err(DNSQueryError::Truncated);
+        }
         response
             .read_exact(&mut buf)
             .map_err(|_| DNSQueryError::UnexpectedEOF)?; // Read 6 bytes
@@ -188,11 +351,9 @@ impl DNSQuery {
             return Err(DNSQueryError::Question);
         }
         for _ in 0..ancount {
+            // Read the NAME field of the record and ignore
+            skip_name(&mut response)?;
             let mut buf: [u8; 2] = [0, 0];
-            // Read the compressed NAME field of the record and ignore
-            response
-                .read_exact(&mut buf)
-                .map_err(|_| DNSQueryError::UnexpectedEOF)?;
             // Read the TYPE
             response
                 .read_exact(&mut buf)
@@ -220,27 +381,207 @@ impl DNSQuery {
             response
                 .read_exact(&mut rdata)
                 .map_err(|_| DNSQueryError::UnexpectedEOF)?;
-            if atype == A_RECORD && aclass == A_CLASS && rdlength == EXPECTED_RDATA_LEN {
-                ips.push(IpAddr::V4(Ipv4Addr::new(
-                    rdata[0], rdata[1], rdata[2], rdata[3],
-                )))
+            // Other records, like aliases, may be in the answer
+            if atype == self.record.qtype() && aclass == IN_CLASS {
+                if let Some(ip) = self.record.ip_from_rdata(&rdata) {
+                    ips.push(ip)
+                }
             }
         }
         Ok(ips)
     }
 }
 
+// A name is a series of labels ending in an empty label, or in a pointer to another name
+fn skip_name(response: &mut &[u8]) -> Result<(), DNSQueryError> {
+    loop {
+        let mut len = [0u8; 1];
+        response
+            .read_exact(&mut len)
+            .map_err(|_| DNSQueryError::UnexpectedEOF)?;
+        if len[0] & NAME_POINTER == NAME_POINTER {
+            return response
+                .read_exact(&mut len)
+                .map_err(|_| DNSQueryError::UnexpectedEOF);
+        }
+        if len[0] == 0 {
+            return Ok(());
+        }
+        let mut label = vec![0; len[0] as usize];
+        response
+            .read_exact(&mut label)
+            .map_err(|_| DNSQueryError::UnexpectedEOF)?;
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Dns;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use bitcoin::p2p::ServiceFlags;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    use crate::core::{dialog::Dialog, DnsConfig};
+
+    use super::{parse_resolv_conf, service_subdomain, Dns, RecordType};
+
+    // Answer a query with every address of the requested type
+    fn answer(query: &[u8], ips: &[IpAddr], truncated: bool) -> Vec<u8> {
+        let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+        let records: Vec<&IpAddr> = ips
+            .iter()
+            .filter(|ip| match ip {
+                IpAddr::V4(_) => qtype == RecordType::A.qtype(),
+                IpAddr::V6(_) => qtype == RecordType::Aaaa.qtype(),
+            })
+            .collect();
+        let mut response = query[..2].to_vec();
+        response.push(if truncated { 0x83 } else { 0x81 });
+        response.push(0x80);
+        response.extend([0x00, 0x01]);
+        response.extend((records.len() as u16).to_be_bytes());
+        response.extend([0x00, 0x00, 0x00, 0x00]);
+        response.extend_from_slice(&query[12..]);
+        for ip in records {
+            // Point to the name in the question
+            response.extend([0xC0, 0x0C]);
+            response.extend(qtype.to_be_bytes());
+            response.extend([0x00, 0x01]);
+            response.extend(60u32.to_be_bytes());
+            let rdata = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            response.extend((rdata.len() as u16).to_be_bytes());
+            response.extend(rdata);
+        }
+        response
+    }
+
+    async fn udp_stand_in(ips: Vec<IpAddr>, truncated: bool) -> SocketAddr {
+        let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = sock.recv_from(&mut buf).await {
+                let response = answer(&buf[..len], &ips, truncated);
+                sock.send_to(&response, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn dns_with_resolvers(resolvers: Vec<SocketAddr>) -> Dns {
+        let config = DnsConfig {
+            seeds: vec!["seed.example.com".into()],
+            resolvers,
+        };
+        Dns::new(bitcoin::Network::Regtest, &config)
+    }
 
     #[tokio::test]
     #[ignore = "dns works"]
     async fn dns_responds() {
-        let addrs = Dns::new(bitcoin::network::Network::Signet)
-            .bootstrap()
+        let (tx, _) = tokio::sync::broadcast::channel(32);
+        let addrs = Dns::new(bitcoin::network::Network::Signet, &DnsConfig::default())
+            .bootstrap(ServiceFlags::NONE, &Dialog::new(tx))
             .await
             .unwrap();
         assert!(addrs.len() > 1);
     }
+
+    #[test]
+    fn test_service_subdomain() {
+        let seed = "seed.bitcoin.sipa.be";
+        assert_eq!(service_subdomain(seed, ServiceFlags::NONE), seed);
+        let services =
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+        assert_eq!(
+            service_subdomain(seed, services),
+            "x49.seed.bitcoin.sipa.be"
+        );
+    }
+
+    #[test]
+    fn test_parses_resolv_conf() {
+        let conf = "# Generated by NetworkManager\nsearch example.com\nnameserver 192.168.1.1\nnameserver  2001:db8::1\nnameserver fe80::1%eth0\n";
+        let resolvers = parse_resolv_conf(conf);
+        assert_eq!(
+            resolvers,
+            vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 53),
+                SocketAddr::new("2001:db8::1".parse().unwrap(), 53),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolves_a_and_aaaa_records() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let resolver = udp_stand_in(vec![ipv4, ipv6], false).await;
+        let dns = dns_with_resolvers(vec![resolver]);
+        let ips = dns
+            .resolve("seed.example.com", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(ips, vec![ipv4]);
+        let ips = dns
+            .resolve("x49.seed.example.com", RecordType::Aaaa)
+            .await
+            .unwrap();
+        assert_eq!(ips, vec![ipv6]);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_response_falls_back_to_tcp() {
+        let ips: Vec<IpAddr> = (1..=40)
+            .map(|i| IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)))
+            .collect();
+        let resolver = udp_stand_in(Vec::new(), true).await;
+        let listener = TcpListener::bind(resolver).await.unwrap();
+        let tcp_ips = ips.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut query = vec![0; len as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            let response = answer(&query, &tcp_ips, false);
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+        let dns = dns_with_resolvers(vec![resolver]);
+        let resolved = dns
+            .resolve("seed.example.com", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(resolved, ips);
+    }
+
+    #[tokio::test]
+    async fn test_tries_the_next_resolver() {
+        // A resolver that responds with a malformed message
+        let broken = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let broken_addr = broken.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((_, from)) = broken.recv_from(&mut buf).await {
+                broken.send_to(&[0x00; 4], from).await.unwrap();
+            }
+        });
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let resolver = udp_stand_in(vec![ip], false).await;
+        let dns = dns_with_resolvers(vec![broken_addr, resolver]);
+        let ips = dns
+            .resolve("seed.example.com", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(ips, vec![ip]);
+    }
 }
@@ -39,6 +39,9 @@ pub enum PeerError {
     DisconnectCommand,
     Reader,
     UnreachableSocketAddr,
+    TooManyInbound,
+    PlaintextRefused,
+    Banned,
 }
 
 impl core::fmt::Display for PeerError {
@@ -59,6 +62,15 @@ impl core::fmt::Display for PeerError {
             PeerError::UnreachableSocketAddr => {
                 write!(f, "cannot make use of provided p2p address.")
             }
+            PeerError::PlaintextRefused => {
+                write!(f, "the connection would not be encrypted.")
+            }
+            PeerError::TooManyInbound => {
+                write!(f, "the maximum number of inbound peers are connected.")
+            }
+            PeerError::Banned => {
+                write!(f, "the peer was banned for misbehaving.")
+            }
             PeerError::MessageSerialization => {
                 write!(f, "serializing a message into bytes failed.")
             }
@@ -97,12 +109,16 @@ pub(crate) enum DNSQueryError {
     Udp,
     MalformedHeader,
     UnexpectedEOF,
+    Truncated,
+    Tcp,
+    Timeout,
+    NoResolvers,
 }
 
 impl core::fmt::Display for DNSQueryError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DNSQueryError::ConnectionDenied => write!(f, "the UDP connection failed."),
+            DNSQueryError::ConnectionDenied => write!(f, "connecting to the resolver failed."),
             DNSQueryError::MalformedHeader => write!(f, "the DNS response header was too short."),
             DNSQueryError::UnexpectedEOF => {
                 write!(f, "the end of the response was reached before we expected.")
@@ -110,6 +126,10 @@ impl core::fmt::Display for DNSQueryError {
             DNSQueryError::Udp => write!(f, "reading or writing from the UDP connection failed."),
             DNSQueryError::MessageID => write!(f, "mismatch of message ID."),
             DNSQueryError::Question => write!(f, "the question of the message does not match."),
+            DNSQueryError::Truncated => write!(f, "the response did not fit in a UDP datagram."),
+            DNSQueryError::Tcp => write!(f, "reading or writing from the TCP connection failed."),
+            DNSQueryError::Timeout => write!(f, "the resolver did not respond in time."),
+            DNSQueryError::NoResolvers => write!(f, "no DNS resolvers are configured."),
         }
     }
 }
@@ -0,0 +1,299 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bitcoin::{key::rand, p2p::address::AddrV2};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use crate::{
+    db::AddressType,
+    prelude::{encode_base32, FutureResult},
+};
+
+use super::{
+    error::PeerError,
+    traits::{NetworkConnector, StreamReader, StreamWriter},
+};
+
+// Building tunnels for a new session may take a while
+const SESSION_TIMEOUT: u64 = 60;
+const CONNECTION_TIMEOUT: u64 = 30;
+const SAM_VERSION: &str = "3.1";
+const SESSION_ID_LEN: usize = 10;
+// A session reply includes the private key of the destination, which is under 1,000 characters
+const MAX_REPLY_LEN: usize = 4_096;
+
+// The stream session shared by every connection. The session lasts as long as the control socket is open.
+struct Session {
+    id: String,
+    _control: TcpStream,
+}
+
+// Connect to I2P peers through the SAM v3 bridge of a local I2P router.
+pub(crate) struct I2pConnection {
+    sam: SocketAddr,
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl I2pConnection {
+    pub(crate) fn new(sam: SocketAddr) -> Self {
+        Self {
+            sam,
+            session: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl NetworkConnector for I2pConnection {
+    fn address_types(&self) -> &'static [AddressType] {
+        &[AddressType::I2p]
+    }
+
+    // I2P streams do not have ports, so the port is ignored
+    fn connect(
+        &mut self,
+        addr: AddrV2,
+        _port: u16,
+    ) -> FutureResult<'_, (StreamReader, StreamWriter), PeerError> {
+        let sam = self.sam;
+        let session = Arc::clone(&self.session);
+        Box::pin(async move {
+            let destination = match addr {
+                AddrV2::I2p(destination) => destination,
+                _ => return Err(PeerError::UnreachableSocketAddr),
+            };
+            let mut session = session.lock().await;
+            if session.is_none() {
+                let created =
+                    tokio::time::timeout(Duration::from_secs(SESSION_TIMEOUT), create_session(sam))
+                        .await
+                        .map_err(|_| PeerError::ConnectionFailed)??;
+                *session = Some(created);
+            }
+            let id = session
+                .as_ref()
+                .map(|session| session.id.clone())
+                .ok_or(PeerError::ConnectionFailed)?;
+            let stream = tokio::time::timeout(
+                Duration::from_secs(CONNECTION_TIMEOUT),
+                stream_connect(sam, &id, &destination),
+            )
+            .await
+            .map_err(|_| PeerError::ConnectionFailed)?;
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // The router may have closed the session, so a new session is created on the next attempt
+                    if matches!(e, SamError::InvalidSession) {
+                        *session = None;
+                    }
+                    return Err(PeerError::ConnectionFailed);
+                }
+            };
+            let (reader, writer) = stream.into_split();
+            let reader: StreamReader = Mutex::new(Box::new(reader));
+            let writer: StreamWriter = Mutex::new(Box::new(writer));
+            Ok((reader, writer))
+        })
+    }
+}
+
+#[derive(Debug)]
+enum SamError {
+    Io,
+    Rejected,
+    InvalidSession,
+}
+
+// Create a stream session with a transient destination, so the node has a new I2P address every run
+async fn create_session(sam: SocketAddr) -> Result<Session, PeerError> {
+    let mut control = hello(sam).await.map_err(|_| PeerError::ConnectionFailed)?;
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_ID_LEN)
+        .map(char::from)
+        .collect();
+    let request =
+        format!("SESSION CREATE STYLE=STREAM ID={id} DESTINATION=TRANSIENT SIGNATURE_TYPE=7\n");
+    command(&mut control, &request)
+        .await
+        .map_err(|_| PeerError::ConnectionFailed)?;
+    Ok(Session {
+        id,
+        _control: control,
+    })
+}
+
+// Open a stream to the destination within the session. After the reply the socket carries the peer-to-peer messages.
+async fn stream_connect(
+    sam: SocketAddr,
+    id: &str,
+    destination: &[u8; 32],
+) -> Result<TcpStream, SamError> {
+    let mut stream = hello(sam).await?;
+    let request = format!(
+        "STREAM CONNECT ID={id} DESTINATION={} SILENT=false\n",
+        b32_host(destination)
+    );
+    command(&mut stream, &request).await?;
+    Ok(stream)
+}
+
+async fn hello(sam: SocketAddr) -> Result<TcpStream, SamError> {
+    let mut stream = TcpStream::connect(sam).await.map_err(|_| SamError::Io)?;
+    let request = format!("HELLO VERSION MIN={SAM_VERSION} MAX={SAM_VERSION}\n");
+    command(&mut stream, &request).await?;
+    Ok(stream)
+}
+
+// Send a command and check the reply for success
+async fn command(stream: &mut TcpStream, request: &str) -> Result<(), SamError> {
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|_| SamError::Io)?;
+    let reply = read_line(stream).await?;
+    let result = reply
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("RESULT="));
+    match result {
+        Some("OK") => Ok(()),
+        Some("INVALID_ID") => Err(SamError::InvalidSession),
+        _ => Err(SamError::Rejected),
+    }
+}
+
+// Replies are read one byte at a time, so no bytes of the peer-to-peer stream are consumed
+async fn read_line(stream: &mut TcpStream) -> Result<String, SamError> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await.map_err(|_| SamError::Io)?;
+        if byte == b'\n' {
+            break;
+        }
+        line.push(byte);
+        if line.len() > MAX_REPLY_LEN {
+            return Err(SamError::Rejected);
+        }
+    }
+    String::from_utf8(line).map_err(|_| SamError::Rejected)
+}
+
+// The hostname of an I2P destination: base32 of the hash of the destination followed by ".b32.i2p"
+pub(crate) fn b32_host(destination: &[u8; 32]) -> String {
+    let mut host = encode_base32(destination);
+    host.push_str(".b32.i2p");
+    host
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use bitcoin::p2p::address::AddrV2;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{network::traits::NetworkConnector, prelude::Netgroup};
+
+    use super::{b32_host, I2pConnection};
+
+    async fn reply(reader: &mut BufReader<TcpStream>, reply: &str) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        line
+    }
+
+    // A SAM bridge that accepts one session and then echos a message on each stream
+    async fn sam_stand_in(listener: TcpListener, streams: usize) -> Vec<String> {
+        let mut requests = Vec::new();
+        let (control, _) = listener.accept().await.unwrap();
+        let mut control = BufReader::new(control);
+        requests.push(reply(&mut control, "HELLO REPLY RESULT=OK VERSION=3.1\n").await);
+        requests.push(
+            reply(
+                &mut control,
+                "SESSION STATUS RESULT=OK DESTINATION=privatekey\n",
+            )
+            .await,
+        );
+        for _ in 0..streams {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            requests.push(reply(&mut stream, "HELLO REPLY RESULT=OK VERSION=3.1\n").await);
+            requests.push(reply(&mut stream, "STREAM STATUS RESULT=OK\n").await);
+            let mut message = [0u8; 4];
+            stream.read_exact(&mut message).await.unwrap();
+            stream.get_mut().write_all(&message).await.unwrap();
+        }
+        requests
+    }
+
+    #[test]
+    fn test_b32_host_and_netgroup() {
+        let mut destination = [0u8; 32];
+        for (i, byte) in destination.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(
+            b32_host(&destination),
+            "aaaqeayeaudaocajbifqydiob4ibceqtcqkrmfyydenbwha5dypq.b32.i2p"
+        );
+        // Destinations are grouped by their first four bits
+        let mut other = destination;
+        other[0] = 0x0F;
+        assert_eq!(
+            AddrV2::I2p(destination).netgroup(),
+            AddrV2::I2p(other).netgroup()
+        );
+        other[0] = 0xF0;
+        assert_ne!(
+            AddrV2::I2p(destination).netgroup(),
+            AddrV2::I2p(other).netgroup()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streams_share_a_session() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let sam: SocketAddr = listener.local_addr().unwrap();
+        let server = tokio::spawn(sam_stand_in(listener, 2));
+        let mut connection = I2pConnection::new(sam);
+        assert!(!connection.can_connect(&AddrV2::Ipv4(Ipv4Addr::LOCALHOST)));
+        for byte in 1..=2 {
+            let destination = AddrV2::I2p([byte; 32]);
+            assert!(connection.can_connect(&destination));
+            let (reader, writer) = connection.connect(destination, 0).await.unwrap();
+            writer.lock().await.write_all(b"ping").await.unwrap();
+            let mut echo = [0u8; 4];
+            reader.lock().await.read_exact(&mut echo).await.unwrap();
+            assert_eq!(&echo, b"ping");
+        }
+        let requests = server.await.unwrap();
+        assert!(requests[1].starts_with("SESSION CREATE STYLE=STREAM ID="));
+        let id = requests[1]
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("ID="))
+            .unwrap();
+        assert_eq!(
+            requests[3],
+            format!(
+                "STREAM CONNECT ID={id} DESTINATION={} SILENT=false\n",
+                b32_host(&[1; 32])
+            )
+        );
+        assert_eq!(
+            requests[5],
+            format!(
+                "STREAM CONNECT ID={id} DESTINATION={} SILENT=false\n",
+                b32_host(&[2; 32])
+            )
+        );
+    }
+}
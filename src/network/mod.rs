@@ -9,11 +9,13 @@ pub(crate) mod counter;
 pub(crate) mod dns;
 #[allow(dead_code)]
 pub(crate) mod error;
+pub(crate) mod i2p;
 pub(crate) mod outbound_messages;
 pub(crate) mod parsers;
 pub(crate) mod peer;
 #[allow(dead_code)]
 pub(crate) mod reader;
+pub(crate) mod socks;
 #[cfg(feature = "tor")]
 pub(crate) mod tor;
 pub(crate) mod traits;
@@ -5,16 +5,17 @@ use std::{
 
 use bip324::{PacketType, PacketWriter};
 use bitcoin::{
+    block::Header,
     consensus::serialize,
     hashes::Hash,
     p2p::{
         message::{NetworkMessage, RawNetworkMessage},
         message_blockdata::{GetHeadersMessage, Inventory},
-        message_filter::{GetCFHeaders, GetCFilters},
+        message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
         message_network::VersionMessage,
         Address, ServiceFlags,
     },
-    BlockHash, Network, Transaction,
+    BlockHash, Network, Transaction, Txid,
 };
 
 use crate::{core::channel_messages::GetBlockConfig, prelude::default_port_from_network};
@@ -34,7 +35,12 @@ impl V1OutboundMessage {
     }
 }
 
-fn make_version(port: Option<u16>, network: &Network) -> VersionMessage {
+fn make_version(
+    port: Option<u16>,
+    network: &Network,
+    services: ServiceFlags,
+    relay: bool,
+) -> VersionMessage {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("time went backwards")
@@ -47,7 +53,7 @@ fn make_version(port: Option<u16>, network: &Network) -> VersionMessage {
     let from_and_recv = Address::new(&ip, ServiceFlags::NONE);
     VersionMessage {
         version: PROTOCOL_VERSION,
-        services: ServiceFlags::NONE,
+        services,
         timestamp: now as i64,
         receiver: from_and_recv.clone(),
         sender: from_and_recv,
@@ -56,21 +62,29 @@ fn make_version(port: Option<u16>, network: &Network) -> VersionMessage {
             "Kyoto Light Client / {KYOTO_VERSION} / rust-bitcoin {RUST_BITCOIN_VERSION}"
         ),
         start_height: 0,
-        relay: false,
+        relay,
     }
 }
 
+fn get_transactions_inv(txids: Vec<Txid>) -> Vec<Inventory> {
+    txids
+        .into_iter()
+        .map(Inventory::WitnessTransaction)
+        .collect()
+}
+
 fn get_block_from_cfg(config: GetBlockConfig) -> Inventory {
-    if cfg!(feature = "filter-control") {
-        Inventory::WitnessBlock(config.locator)
-    } else {
-        Inventory::Block(config.locator)
-    }
+    Inventory::WitnessBlock(config.locator)
 }
 
 impl MessageGenerator for V1OutboundMessage {
-    fn version_message(&mut self, port: Option<u16>) -> Result<Vec<u8>, PeerError> {
-        let msg = make_version(port, &self.network);
+    fn version_message(
+        &mut self,
+        port: Option<u16>,
+        services: ServiceFlags,
+        relay: bool,
+    ) -> Result<Vec<u8>, PeerError> {
+        let msg = make_version(port, &self.network, services, relay);
         let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::Version(msg));
         Ok(serialize(&data))
     }
@@ -80,6 +94,11 @@ impl MessageGenerator for V1OutboundMessage {
         Ok(serialize(&data))
     }
 
+    fn send_headers(&mut self) -> Result<Vec<u8>, PeerError> {
+        let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::SendHeaders);
+        Ok(serialize(&data))
+    }
+
     fn addr(&mut self) -> Result<Vec<u8>, PeerError> {
         let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::GetAddr);
         Ok(serialize(&data))
@@ -119,6 +138,12 @@ impl MessageGenerator for V1OutboundMessage {
         Ok(serialize(&data))
     }
 
+    fn ping(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
+        let msg = NetworkMessage::Ping(nonce);
+        let data = RawNetworkMessage::new(self.network.magic(), msg);
+        Ok(serialize(&data))
+    }
+
     fn pong(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
         let msg = NetworkMessage::Pong(nonce);
         let data = RawNetworkMessage::new(self.network.magic(), msg);
@@ -130,6 +155,39 @@ impl MessageGenerator for V1OutboundMessage {
         let data = RawNetworkMessage::new(self.network.magic(), msg);
         Ok(serialize(&data))
     }
+
+    fn get_transactions(&mut self, txids: Vec<Txid>) -> Result<Vec<u8>, PeerError> {
+        let msg = NetworkMessage::GetData(get_transactions_inv(txids));
+        let data = RawNetworkMessage::new(self.network.magic(), msg);
+        Ok(serialize(&data))
+    }
+
+    fn mempool(&mut self) -> Result<Vec<u8>, PeerError> {
+        let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::MemPool);
+        Ok(serialize(&data))
+    }
+
+    fn serve_headers(&mut self, headers: Vec<Header>) -> Result<Vec<u8>, PeerError> {
+        let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::Headers(headers));
+        Ok(serialize(&data))
+    }
+
+    fn serve_filter_headers(&mut self, cf_headers: CFHeaders) -> Result<Vec<u8>, PeerError> {
+        let data =
+            RawNetworkMessage::new(self.network.magic(), NetworkMessage::CFHeaders(cf_headers));
+        Ok(serialize(&data))
+    }
+
+    // Each filter is a separate message, written back to back
+    fn serve_filters(&mut self, filters: Vec<CFilter>) -> Result<Vec<u8>, PeerError> {
+        let mut bytes = Vec::new();
+        for filter in filters {
+            let data =
+                RawNetworkMessage::new(self.network.magic(), NetworkMessage::CFilter(filter));
+            bytes.extend(serialize(&data));
+        }
+        Ok(bytes)
+    }
 }
 
 pub(crate) struct V2OutboundMessage {
@@ -154,8 +212,13 @@ impl V2OutboundMessage {
 }
 
 impl MessageGenerator for V2OutboundMessage {
-    fn version_message(&mut self, port: Option<u16>) -> Result<Vec<u8>, PeerError> {
-        let msg = make_version(port, &self.network);
+    fn version_message(
+        &mut self,
+        port: Option<u16>,
+        services: ServiceFlags,
+        relay: bool,
+    ) -> Result<Vec<u8>, PeerError> {
+        let msg = make_version(port, &self.network, services, relay);
         let plaintext = self.serialize_network_message(NetworkMessage::Version(msg))?;
         self.encrypt_plaintext(plaintext)
     }
@@ -165,6 +228,11 @@ impl MessageGenerator for V2OutboundMessage {
         self.encrypt_plaintext(plaintext)
     }
 
+    fn send_headers(&mut self) -> Result<Vec<u8>, PeerError> {
+        let plaintext = self.serialize_network_message(NetworkMessage::SendHeaders)?;
+        self.encrypt_plaintext(plaintext)
+    }
+
     fn addr(&mut self) -> Result<Vec<u8>, PeerError> {
         let plaintext = self.serialize_network_message(NetworkMessage::GetAddr)?;
         self.encrypt_plaintext(plaintext)
@@ -202,6 +270,11 @@ impl MessageGenerator for V2OutboundMessage {
         self.encrypt_plaintext(plaintext)
     }
 
+    fn ping(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
+        let plaintext = self.serialize_network_message(NetworkMessage::Ping(nonce))?;
+        self.encrypt_plaintext(plaintext)
+    }
+
     fn pong(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
         let plaintext = self.serialize_network_message(NetworkMessage::Pong(nonce))?;
         self.encrypt_plaintext(plaintext)
@@ -211,4 +284,34 @@ impl MessageGenerator for V2OutboundMessage {
         let plaintext = self.serialize_network_message(NetworkMessage::Tx(transaction))?;
         self.encrypt_plaintext(plaintext)
     }
+
+    fn get_transactions(&mut self, txids: Vec<Txid>) -> Result<Vec<u8>, PeerError> {
+        let plaintext =
+            self.serialize_network_message(NetworkMessage::GetData(get_transactions_inv(txids)))?;
+        self.encrypt_plaintext(plaintext)
+    }
+
+    fn mempool(&mut self) -> Result<Vec<u8>, PeerError> {
+        let plaintext = self.serialize_network_message(NetworkMessage::MemPool)?;
+        self.encrypt_plaintext(plaintext)
+    }
+
+    fn serve_headers(&mut self, headers: Vec<Header>) -> Result<Vec<u8>, PeerError> {
+        let plaintext = self.serialize_network_message(NetworkMessage::Headers(headers))?;
+        self.encrypt_plaintext(plaintext)
+    }
+
+    fn serve_filter_headers(&mut self, cf_headers: CFHeaders) -> Result<Vec<u8>, PeerError> {
+        let plaintext = self.serialize_network_message(NetworkMessage::CFHeaders(cf_headers))?;
+        self.encrypt_plaintext(plaintext)
+    }
+
+    fn serve_filters(&mut self, filters: Vec<CFilter>) -> Result<Vec<u8>, PeerError> {
+        let mut bytes = Vec::new();
+        for filter in filters {
+            let plaintext = self.serialize_network_message(NetworkMessage::CFilter(filter))?;
+            bytes.extend(self.encrypt_plaintext(plaintext)?);
+        }
+        Ok(bytes)
+    }
 }
@@ -2,7 +2,7 @@ extern crate tokio;
 use std::{ops::DerefMut, time::Duration};
 
 use bip324::{AsyncProtocol, PacketReader, PacketWriter, Role};
-use bitcoin::{p2p::ServiceFlags, Network};
+use bitcoin::{key::rand, p2p::ServiceFlags, Network};
 use tokio::{
     io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     select,
@@ -20,25 +20,21 @@ use crate::{
         messages::Warning,
         PeerTimeoutConfig,
     },
-    network::outbound_messages::V1OutboundMessage,
-    NodeMessage,
+    network::outbound_messages::{V1OutboundMessage, V2OutboundMessage},
 };
 
 use super::{
-    counter::MessageCounter,
+    counter::{MessageCounter, PingTracker, RequestLimiter, UNRESPONSIVE_PENALTY},
     error::PeerError,
-    parsers::V1MessageParser,
+    parsers::{V1MessageParser, V2MessageParser},
     reader::Reader,
     traits::{MessageGenerator, StreamReader, StreamWriter},
 };
 
-#[cfg(not(feature = "tor"))]
-use super::outbound_messages::V2OutboundMessage;
-#[cfg(not(feature = "tor"))]
-use super::parsers::V2MessageParser;
-
 const MESSAGE_TIMEOUT: u64 = 2;
 const HANDSHAKE_TIMEOUT: u64 = 4;
+// Peers may announce blocks with headers since this version, as described in BIP-130
+const SENDHEADERS_VERSION: u32 = 70012;
 
 type MutexMessageGenerator = Mutex<Box<dyn MessageGenerator>>;
 
@@ -48,20 +44,32 @@ pub(crate) struct Peer {
     main_thread_recv: Receiver<MainThreadMessage>,
     network: Network,
     message_counter: MessageCounter,
-    services: ServiceFlags,
+    v2_transport: bool,
+    // Did an encrypted handshake with this peer succeed or fail
+    v2_outcome: Option<bool>,
     dialog: Dialog,
     timeout_config: PeerTimeoutConfig,
+    relay: bool,
+    inbound: bool,
+    local_services: ServiceFlags,
+    request_limiter: RequestLimiter,
+    pings: PingTracker,
+    handshake_complete: bool,
+    reported: bool,
+    send_headers: bool,
 }
 
 impl Peer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         nonce: u32,
         network: Network,
         main_thread_sender: Sender<PeerThreadMessage>,
         main_thread_recv: Receiver<MainThreadMessage>,
-        services: ServiceFlags,
+        v2_transport: bool,
         dialog: Dialog,
         timeout_config: PeerTimeoutConfig,
+        relay: bool,
     ) -> Self {
         let message_counter = MessageCounter::new(timeout_config.response_timeout);
         Self {
@@ -70,12 +78,33 @@ impl Peer {
             main_thread_recv,
             network,
             message_counter,
-            services,
+            v2_transport,
+            v2_outcome: None,
             dialog,
             timeout_config,
+            relay,
+            inbound: false,
+            local_services: ServiceFlags::NONE,
+            request_limiter: RequestLimiter::new(),
+            pings: PingTracker::new(),
+            handshake_complete: false,
+            reported: false,
+            send_headers: false,
         }
     }
 
+    // The encrypted handshake failed, so the next connection to this peer is plaintext
+    pub fn fallback_to_v1(&mut self) {
+        self.v2_transport = false;
+        self.v2_outcome = Some(false);
+    }
+
+    // This peer connected to us, so it may request data and is told the services we offer
+    pub fn serve_inbound(&mut self, local_services: ServiceFlags) {
+        self.inbound = true;
+        self.local_services = local_services;
+    }
+
     pub async fn run(
         &mut self,
         reader: StreamReader,
@@ -86,9 +115,8 @@ impl Peer {
         let mut lock = writer.lock().await;
         let writer = lock.deref_mut();
 
-        // If a peer signals for V2 we will use it, otherwise just use plaintext.
-        #[cfg(not(feature = "tor"))]
-        let (message_mutex, mut peer_reader) = if self.services.has(ServiceFlags::P2P_V2) {
+        // Attempt an encrypted connection if the transport policy allows it, otherwise just use plaintext.
+        let (message_mutex, mut peer_reader) = if self.v2_transport {
             let mut lock = reader.lock().await;
             let read_lock = lock.deref_mut();
             let handshake_result = tokio::time::timeout(
@@ -101,9 +129,9 @@ impl Peer {
                 self.dialog
                     .send_dialog(format!("Failed to establish an encrypted connection: {e}"))
                     .await;
-                self.dialog.send_warning(Warning::CouldNotConnect).await;
             }
             let (decryptor, encryptor) = handshake_result?;
+            self.v2_outcome = Some(true);
             let message_mutex: MutexMessageGenerator =
                 Mutex::new(Box::new(V2OutboundMessage::new(self.network, encryptor)));
             drop(lock);
@@ -116,18 +144,9 @@ impl Peer {
             (message_mutex, reader)
         };
 
-        // V2 handshakes fail frequently over Tor and messages are encrypted over relays anyway.
-        #[cfg(feature = "tor")]
-        let (message_mutex, mut peer_reader) = {
-            let outbound_messages = V1OutboundMessage::new(self.network);
-            let message_mutex: MutexMessageGenerator = Mutex::new(Box::new(outbound_messages));
-            let reader = Reader::new(V1MessageParser::new(reader, self.network), tx);
-            (message_mutex, reader)
-        };
-
         let mut message_lock = message_mutex.lock().await;
         let outbound_messages = message_lock.deref_mut();
-        let message = outbound_messages.version_message(None)?;
+        let message = outbound_messages.version_message(None, self.local_services, self.relay)?;
         self.write_bytes(writer, message).await?;
         self.message_counter.sent_version();
         let read_handle = tokio::spawn(async move {
@@ -140,15 +159,39 @@ impl Peer {
             if read_handle.is_finished() {
                 return Ok(());
             }
-            if self.message_counter.unsolicited() {
-                self.dialog.send_warning(Warning::UnsolicitedMessage).await;
-                return Ok(());
+            // Misbehaviour is scored by the main thread, which then tells us to disconnect
+            if !self.reported {
+                let penalty = if self.message_counter.unsolicited() {
+                    self.dialog.send_warning(Warning::UnsolicitedMessage).await;
+                    Some(self.message_counter.penalty())
+                } else if self.message_counter.unresponsive() || self.pings.unanswered() {
+                    self.dialog.send_warning(Warning::PeerTimedOut).await;
+                    Some(UNRESPONSIVE_PENALTY)
+                } else {
+                    None
+                };
+                if let Some(penalty) = penalty {
+                    self.reported = true;
+                    self.main_thread_sender
+                        .send(PeerThreadMessage {
+                            nonce: self.nonce,
+                            message: PeerMessage::Misbehaving(penalty),
+                        })
+                        .await
+                        .map_err(|_| PeerError::ThreadChannel)?;
+                }
             }
-            if self.message_counter.unresponsive() {
-                self.dialog.send_warning(Warning::PeerTimedOut).await;
-                return Ok(());
+            if self.handshake_complete && self.pings.needs_ping() {
+                let nonce = rand::random::<u64>();
+                let message = outbound_messages.ping(nonce)?;
+                self.write_bytes(writer, message).await?;
+                self.pings.sent_ping(nonce);
             }
-            if Instant::now().duration_since(start_time) > self.timeout_config.max_connection_time {
+            // Connections are rotated to find new peers, which does not apply to peers that connected to us
+            if !self.inbound
+                && Instant::now().duration_since(start_time)
+                    > self.timeout_config.max_connection_time
+            {
                 self.dialog
                     .send_dialog(format!(
                         "The connection to peer {} has been maintained for over {} seconds, finding a new peer",
@@ -163,6 +206,7 @@ impl Peer {
                     if let Ok(peer_message) = peer_message {
                         match peer_message {
                             Some(message) => {
+                                self.pings.heard();
                                 match self.handle_peer_message(message, writer, outbound_messages).await {
                                     Ok(()) => continue,
                                     Err(e) => {
@@ -209,9 +253,58 @@ impl Peer {
     where
         W: AsyncWrite + Send + Unpin,
     {
+        // Inbound peers are only served, so anything they announce or send is ignored
+        if self.inbound
+            && !matches!(
+                message,
+                PeerMessage::Version(_)
+                    | PeerMessage::Verack
+                    | PeerMessage::Ping(_)
+                    | PeerMessage::Pong(_)
+                    | PeerMessage::GetHeaders(_)
+                    | PeerMessage::GetFilterHeaders(_)
+                    | PeerMessage::GetFilters(_)
+                    | PeerMessage::Disconnect
+            )
+        {
+            return Ok(());
+        }
         match message {
-            PeerMessage::Version(version) => {
+            PeerMessage::GetHeaders(_)
+            | PeerMessage::GetFilterHeaders(_)
+            | PeerMessage::GetFilters(_) => {
+                // Outbound peers are only asked for data
+                if !self.inbound {
+                    return Ok(());
+                }
+                if !self.request_limiter.allow() {
+                    self.dialog
+                        .send_dialog(format!(
+                            "Inbound peer {} made too many requests, disconnecting",
+                            self.nonce
+                        ))
+                        .await;
+                    return Err(PeerError::DisconnectCommand);
+                }
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message,
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
+            PeerMessage::Version(mut version) => {
                 self.message_counter.got_version();
+                // Only outbound peers announce blocks to us
+                self.send_headers = !self.inbound && version.version >= SENDHEADERS_VERSION;
+                // The services are saved to the peer store, so record if encrypted connections work
+                match self.v2_outcome {
+                    Some(true) => version.services.add(ServiceFlags::P2P_V2),
+                    Some(false) => version.services.remove(ServiceFlags::P2P_V2),
+                    None => version.services,
+                };
                 self.main_thread_sender
                     .send(PeerThreadMessage {
                         nonce: self.nonce,
@@ -233,11 +326,19 @@ impl Peer {
                 Ok(())
             }
             PeerMessage::Headers(headers) => {
-                self.message_counter.got_header();
+                let message = if self.message_counter.got_header(headers.len()) {
+                    PeerMessage::Headers(headers)
+                } else {
+                    // Too many unrequested headers are reported as misbehaviour instead
+                    if self.message_counter.unsolicited() {
+                        return Ok(());
+                    }
+                    PeerMessage::NewHeaders(headers)
+                };
                 self.main_thread_sender
                     .send(PeerThreadMessage {
                         nonce: self.nonce,
-                        message: PeerMessage::Headers(headers),
+                        message,
                     })
                     .await
                     .map_err(|_| PeerError::ThreadChannel)?;
@@ -286,8 +387,46 @@ impl Peer {
                     .map_err(|_| PeerError::ThreadChannel)?;
                 Ok(())
             }
+            PeerMessage::BlocksNotFound(block_hashes) => {
+                self.message_counter.got_block_not_found(block_hashes.len());
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::BlocksNotFound(block_hashes),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
+            PeerMessage::NewTransactions(txids) => {
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::NewTransactions(txids),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
+            PeerMessage::Transaction(transaction) => {
+                self.message_counter.got_relayed_tx();
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::Transaction(transaction),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
             PeerMessage::Verack => {
                 self.message_counter.got_verack();
+                self.handshake_complete = true;
+                // Ask for new blocks to be announced with headers, which saves a round trip over `inv`
+                if self.send_headers {
+                    let message = message_generator.send_headers()?;
+                    self.write_bytes(writer, message).await?;
+                }
                 Ok(())
             }
             PeerMessage::Ping(nonce) => {
@@ -295,9 +434,30 @@ impl Peer {
                 self.write_bytes(writer, message).await?;
                 Ok(())
             }
-            PeerMessage::Pong(_) => Ok(()),
+            PeerMessage::Pong(nonce) => {
+                if let Some(rtt) = self.pings.got_pong(nonce) {
+                    self.main_thread_sender
+                        .send(PeerThreadMessage {
+                            nonce: self.nonce,
+                            message: PeerMessage::RoundTrip(rtt),
+                        })
+                        .await
+                        .map_err(|_| PeerError::ThreadChannel)?;
+                }
+                Ok(())
+            }
+            // Only the peer task sorts out announcements, measures round trips and reports misbehaviour
+            PeerMessage::NewHeaders(_)
+            | PeerMessage::RoundTrip(_)
+            | PeerMessage::Misbehaving(_) => Ok(()),
             PeerMessage::FeeFilter(fee) => {
-                self.dialog.send_data(NodeMessage::FeeFilter(fee)).await;
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::FeeFilter(fee),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
                 Ok(())
             }
             PeerMessage::Reject(payload) => {
@@ -368,10 +528,31 @@ impl Peer {
                 let message = message_generator.transaction(transaction)?;
                 self.write_bytes(writer, message).await?;
             }
+            MainThreadMessage::GetTransactions(txids) => {
+                self.message_counter.sent_tx_request(txids.len());
+                let message = message_generator.get_transactions(txids)?;
+                self.write_bytes(writer, message).await?;
+            }
+            MainThreadMessage::GetMempool => {
+                let message = message_generator.mempool()?;
+                self.write_bytes(writer, message).await?;
+            }
             MainThreadMessage::Verack => {
                 let message = message_generator.verack()?;
                 self.write_bytes(writer, message).await?;
             }
+            MainThreadMessage::ServeHeaders(headers) => {
+                let message = message_generator.serve_headers(headers)?;
+                self.write_bytes(writer, message).await?;
+            }
+            MainThreadMessage::ServeFilterHeaders(cf_headers) => {
+                let message = message_generator.serve_filter_headers(cf_headers)?;
+                self.write_bytes(writer, message).await?;
+            }
+            MainThreadMessage::ServeFilters(filters) => {
+                let message = message_generator.serve_filters(filters)?;
+                self.write_bytes(writer, message).await?;
+            }
             MainThreadMessage::Disconnect => return Err(PeerError::DisconnectCommand),
         }
         Ok(())
@@ -2,7 +2,7 @@ use std::net::IpAddr;
 
 use bitcoin::p2p::address::AddrV2;
 use bitcoin::p2p::{message::NetworkMessage, message_blockdata::Inventory, ServiceFlags};
-use bitcoin::{FeeRate, Txid};
+use bitcoin::{BlockHash, FeeRate, Txid};
 use tokio::sync::mpsc::Sender;
 
 use crate::core::channel_messages::{CombinedAddr, PeerMessage};
@@ -35,14 +35,16 @@ impl Reader {
     pub(crate) async fn read_from_remote(&mut self) -> Result<(), PeerReadError> {
         loop {
             if let Some(message) = self.parser.read_message().await? {
-                let cleaned_message = self.parse_message(message);
-                match cleaned_message {
-                    Some(message) => self
-                        .tx
+                let cleaned_messages = match message {
+                    // An announcement may include both blocks and transactions
+                    NetworkMessage::Inv(inventory) => parse_inventory(inventory),
+                    message => self.parse_message(message).into_iter().collect(),
+                };
+                for message in cleaned_messages {
+                    self.tx
                         .send(message)
                         .await
-                        .map_err(|_| PeerReadError::MpscChannel)?,
-                    None => continue,
+                        .map_err(|_| PeerReadError::MpscChannel)?;
                 }
             }
         }
@@ -77,31 +79,30 @@ impl Reader {
                 }
                 Some(PeerMessage::Addr(addresses))
             }
-            NetworkMessage::Inv(inventory) => {
+            // Announcements may result in more than one message, see `parse_inventory`
+            NetworkMessage::Inv(_) => None,
+            NetworkMessage::GetData(_) => None,
+            NetworkMessage::NotFound(inventory) => {
                 if inventory.len() > MAX_INV {
                     return Some(PeerMessage::Disconnect);
                 }
-                let mut hashes = Vec::new();
-                for i in inventory {
-                    match i {
-                        Inventory::Block(hash) => hashes.push(hash),
-                        Inventory::CompactBlock(hash) => hashes.push(hash),
-                        Inventory::WitnessBlock(hash) => hashes.push(hash),
-                        _ => continue,
-                    }
-                }
-                if !hashes.is_empty() {
-                    Some(PeerMessage::NewBlocks(hashes))
-                } else {
-                    None
+                let hashes: Vec<BlockHash> = inventory
+                    .into_iter()
+                    .filter_map(|i| match i {
+                        Inventory::Block(hash) => Some(hash),
+                        Inventory::WitnessBlock(hash) => Some(hash),
+                        _ => None,
+                    })
+                    .collect();
+                if hashes.is_empty() {
+                    return None;
                 }
+                Some(PeerMessage::BlocksNotFound(hashes))
             }
-            NetworkMessage::GetData(_) => None,
-            NetworkMessage::NotFound(_) => None,
             NetworkMessage::GetBlocks(_) => None,
-            NetworkMessage::GetHeaders(_) => None,
+            NetworkMessage::GetHeaders(message) => Some(PeerMessage::GetHeaders(message)),
             NetworkMessage::MemPool => None,
-            NetworkMessage::Tx(_) => None,
+            NetworkMessage::Tx(transaction) => Some(PeerMessage::Transaction(transaction)),
             NetworkMessage::Block(block) => Some(PeerMessage::Block(block)),
             NetworkMessage::Headers(headers) => {
                 if headers.len() > MAX_HEADERS {
@@ -119,9 +120,9 @@ impl Reader {
             NetworkMessage::FilterLoad(_) => None,
             NetworkMessage::FilterAdd(_) => None,
             NetworkMessage::FilterClear => None,
-            NetworkMessage::GetCFilters(_) => None,
+            NetworkMessage::GetCFilters(message) => Some(PeerMessage::GetFilters(message)),
             NetworkMessage::CFilter(filter) => Some(PeerMessage::Filter(filter)),
-            NetworkMessage::GetCFHeaders(_) => None,
+            NetworkMessage::GetCFHeaders(message) => Some(PeerMessage::GetFilterHeaders(message)),
             NetworkMessage::CFHeaders(cf_headers) => Some(PeerMessage::FilterHeaders(cf_headers)),
             NetworkMessage::GetCFCheckpt(_) => None,
             NetworkMessage::CFCheckpt(_) => None,
@@ -175,3 +176,29 @@ impl Reader {
         }
     }
 }
+
+fn parse_inventory(inventory: Vec<Inventory>) -> Vec<PeerMessage> {
+    if inventory.len() > MAX_INV {
+        return vec![PeerMessage::Disconnect];
+    }
+    let mut hashes = Vec::new();
+    let mut txids = Vec::new();
+    for i in inventory {
+        match i {
+            Inventory::Block(hash) => hashes.push(hash),
+            Inventory::CompactBlock(hash) => hashes.push(hash),
+            Inventory::WitnessBlock(hash) => hashes.push(hash),
+            Inventory::Transaction(txid) => txids.push(txid),
+            Inventory::WitnessTransaction(txid) => txids.push(txid),
+            _ => continue,
+        }
+    }
+    let mut messages = Vec::new();
+    if !hashes.is_empty() {
+        messages.push(PeerMessage::NewBlocks(hashes));
+    }
+    if !txids.is_empty() {
+        messages.push(PeerMessage::NewTransactions(txids));
+    }
+    messages
+}
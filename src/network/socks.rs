@@ -0,0 +1,329 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bitcoin::{key::rand, p2p::address::AddrV2};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha3::{Digest, Sha3_256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use crate::{
+    db::AddressType,
+    prelude::{encode_base32, FutureResult},
+    Socks5Auth,
+};
+
+use super::{
+    error::PeerError,
+    traits::{NetworkConnector, StreamReader, StreamWriter},
+};
+
+const CONNECTION_TIMEOUT: u64 = 10;
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const CONNECT: u8 = 0x01;
+const SUCCEEDED: u8 = 0x00;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+// Tor isolates streams that authenticate with different credentials
+const ISOLATION_CREDENTIAL_LEN: usize = 16;
+const ONION_CHECKSUM: &[u8] = b".onion checksum";
+const ONION_VERSION: u8 = 0x03;
+
+// Connect to peers through a SOCKS5 proxy, like a Tor daemon. The proxy resolves onion services.
+pub(crate) struct Socks5Connection {
+    proxy: SocketAddr,
+    auth: Socks5Auth,
+}
+
+impl Socks5Connection {
+    pub(crate) fn new(proxy: SocketAddr, auth: Socks5Auth) -> Self {
+        Self { proxy, auth }
+    }
+}
+
+impl NetworkConnector for Socks5Connection {
+    fn address_types(&self) -> &'static [AddressType] {
+        &[AddressType::Ipv4, AddressType::Ipv6, AddressType::TorV3]
+    }
+
+    fn connect(
+        &mut self,
+        addr: AddrV2,
+        port: u16,
+    ) -> FutureResult<'_, (StreamReader, StreamWriter), PeerError> {
+        let credentials = match &self.auth {
+            Socks5Auth::None => None,
+            Socks5Auth::Password { username, password } => {
+                Some((username.clone(), password.clone()))
+            }
+            Socks5Auth::Isolate => Some((random_credential(), random_credential())),
+        };
+        let proxy = self.proxy;
+        Box::pin(async move {
+            let stream = tokio::time::timeout(
+                Duration::from_secs(CONNECTION_TIMEOUT),
+                handshake(proxy, credentials, addr, port),
+            )
+            .await
+            .map_err(|_| PeerError::ConnectionFailed)??;
+            let (reader, writer) = stream.into_split();
+            let reader: StreamReader = Mutex::new(Box::new(reader));
+            let writer: StreamWriter = Mutex::new(Box::new(writer));
+            Ok((reader, writer))
+        })
+    }
+}
+
+// Open a connection to the proxy and ask it to connect to the peer, following RFC 1928 and RFC 1929
+async fn handshake(
+    proxy: SocketAddr,
+    credentials: Option<(String, String)>,
+    addr: AddrV2,
+    port: u16,
+) -> Result<TcpStream, PeerError> {
+    let destination = destination(&addr, port)?;
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .map_err(|_| PeerError::ConnectionFailed)?;
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    stream
+        .write_all(&[SOCKS_VERSION, 1, method])
+        .await
+        .map_err(|_| PeerError::BufferWrite)?;
+    let mut choice = [0u8; 2];
+    stream
+        .read_exact(&mut choice)
+        .await
+        .map_err(|_| PeerError::ConnectionFailed)?;
+    if choice[0] != SOCKS_VERSION || choice[1] == NO_ACCEPTABLE_METHOD || choice[1] != method {
+        return Err(PeerError::ConnectionFailed);
+    }
+    if let Some((username, password)) = credentials {
+        if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+            return Err(PeerError::ConnectionFailed);
+        }
+        let mut request = vec![AUTH_VERSION, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream
+            .write_all(&request)
+            .await
+            .map_err(|_| PeerError::BufferWrite)?;
+        let mut status = [0u8; 2];
+        stream
+            .read_exact(&mut status)
+            .await
+            .map_err(|_| PeerError::ConnectionFailed)?;
+        if status[1] != SUCCEEDED {
+            return Err(PeerError::ConnectionFailed);
+        }
+    }
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0x00];
+    request.extend(destination);
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|_| PeerError::BufferWrite)?;
+    let mut reply = [0u8; 4];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(|_| PeerError::ConnectionFailed)?;
+    if reply[0] != SOCKS_VERSION || reply[1] != SUCCEEDED {
+        return Err(PeerError::ConnectionFailed);
+    }
+    // The address the proxy bound to is not used, but must be read off the stream
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream
+            .read_u8()
+            .await
+            .map_err(|_| PeerError::ConnectionFailed)? as usize,
+        _ => return Err(PeerError::ConnectionFailed),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream
+        .read_exact(&mut bound)
+        .await
+        .map_err(|_| PeerError::ConnectionFailed)?;
+    Ok(stream)
+}
+
+// The address type, address and port of a connect request
+fn destination(addr: &AddrV2, port: u16) -> Result<Vec<u8>, PeerError> {
+    let mut destination = match addr {
+        AddrV2::Ipv4(ip) => {
+            let mut bytes = vec![ATYP_IPV4];
+            bytes.extend_from_slice(&ip.octets());
+            bytes
+        }
+        AddrV2::Ipv6(ip) => {
+            let mut bytes = vec![ATYP_IPV6];
+            bytes.extend_from_slice(&ip.octets());
+            bytes
+        }
+        AddrV2::TorV3(public_key) => {
+            let host = onion_host(public_key);
+            let mut bytes = vec![ATYP_DOMAIN, host.len() as u8];
+            bytes.extend_from_slice(host.as_bytes());
+            bytes
+        }
+        _ => return Err(PeerError::UnreachableSocketAddr),
+    };
+    destination.extend_from_slice(&port.to_be_bytes());
+    Ok(destination)
+}
+
+// The hostname of an onion v3 service: base32(public key || checksum || version) followed by ".onion"
+pub(crate) fn onion_host(public_key: &[u8; 32]) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(ONION_CHECKSUM);
+    hasher.update(public_key);
+    hasher.update([ONION_VERSION]);
+    let checksum = hasher.finalize();
+    let mut bytes = public_key.to_vec();
+    bytes.extend_from_slice(&checksum[..2]);
+    bytes.push(ONION_VERSION);
+    let mut host = encode_base32(&bytes);
+    host.push_str(".onion");
+    host
+}
+
+fn random_credential() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ISOLATION_CREDENTIAL_LEN)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use bitcoin::p2p::address::AddrV2;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{network::traits::NetworkConnector, Socks5Auth};
+
+    use super::{onion_host, Socks5Connection};
+
+    // Accept one connection as a SOCKS5 proxy, returning the credentials and destination, then echo a message
+    async fn proxy_stand_in(listener: TcpListener) -> (Option<(String, String)>, Vec<u8>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[0x05, greeting[2]]).await.unwrap();
+        let credentials = if greeting[2] == 0x02 {
+            let _version = stream.read_u8().await.unwrap();
+            let mut username = vec![0u8; stream.read_u8().await.unwrap() as usize];
+            stream.read_exact(&mut username).await.unwrap();
+            let mut password = vec![0u8; stream.read_u8().await.unwrap() as usize];
+            stream.read_exact(&mut password).await.unwrap();
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+            Some((
+                String::from_utf8(username).unwrap(),
+                String::from_utf8(password).unwrap(),
+            ))
+        } else {
+            None
+        };
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await.unwrap();
+        let mut destination = vec![request[3]];
+        let len = match request[3] {
+            0x01 => 4,
+            0x04 => 16,
+            _ => {
+                let len = stream.read_u8().await.unwrap();
+                destination.push(len);
+                len as usize
+            }
+        };
+        let mut addr = vec![0u8; len + 2];
+        stream.read_exact(&mut addr).await.unwrap();
+        destination.extend(addr);
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x20, 0x8D])
+            .await
+            .unwrap();
+        let mut message = [0u8; 4];
+        stream.read_exact(&mut message).await.unwrap();
+        stream.write_all(&message).await.unwrap();
+        (credentials, destination)
+    }
+
+    #[test]
+    fn test_onion_host() {
+        let mut public_key = [0u8; 32];
+        for (i, byte) in public_key.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(
+            onion_host(&public_key),
+            "aaaqeayeaudaocajbifqydiob4ibceqtcqkrmfyydenbwha5dyp3kead.onion"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connects_through_proxy() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy: SocketAddr = listener.local_addr().unwrap();
+        let server = tokio::spawn(proxy_stand_in(listener));
+        let mut connection = Socks5Connection::new(proxy, Socks5Auth::None);
+        let (reader, writer) = connection
+            .connect(AddrV2::Ipv4(Ipv4Addr::new(10, 0, 0, 1)), 8333)
+            .await
+            .unwrap();
+        writer.lock().await.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        reader.lock().await.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+        let (credentials, destination) = server.await.unwrap();
+        assert!(credentials.is_none());
+        assert_eq!(destination, vec![0x01, 10, 0, 0, 1, 0x20, 0x8D]);
+    }
+
+    #[tokio::test]
+    async fn test_isolated_onion_connections() {
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let proxy: SocketAddr = listener.local_addr().unwrap();
+            let server = tokio::spawn(proxy_stand_in(listener));
+            let mut connection = Socks5Connection::new(proxy, Socks5Auth::Isolate);
+            let (reader, writer) = connection
+                .connect(AddrV2::TorV3([0u8; 32]), 8333)
+                .await
+                .unwrap();
+            writer.lock().await.write_all(b"ping").await.unwrap();
+            let mut echo = [0u8; 4];
+            reader.lock().await.read_exact(&mut echo).await.unwrap();
+            let (credentials, destination) = server.await.unwrap();
+            let host = onion_host(&[0u8; 32]);
+            let mut expected = vec![0x03, host.len() as u8];
+            expected.extend_from_slice(host.as_bytes());
+            expected.extend_from_slice(&[0x20, 0x8D]);
+            assert_eq!(destination, expected);
+            seen.push(credentials.unwrap());
+        }
+        // Each connection uses a different circuit
+        assert_ne!(seen[0], seen[1]);
+    }
+}
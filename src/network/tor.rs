@@ -3,7 +3,7 @@ use bitcoin::p2p::address::AddrV2;
 use tokio::sync::Mutex;
 use tor_rtcompat::PreferredRuntime;
 
-use crate::prelude::FutureResult;
+use crate::{db::AddressType, prelude::FutureResult};
 
 use super::{
     error::PeerError,
@@ -21,8 +21,8 @@ impl TorConnection {
 }
 
 impl NetworkConnector for TorConnection {
-    fn can_connect(&self, addr: &AddrV2) -> bool {
-        matches!(addr, AddrV2::Ipv4(_) | AddrV2::Ipv6(_) | AddrV2::TorV3(_))
+    fn address_types(&self) -> &'static [AddressType] {
+        &[AddressType::Ipv4, AddressType::Ipv6, AddressType::TorV3]
     }
 
     // FIXME: (@leonardo) If we receive the AddressV2Message, we wouldn't need the port parameter, and could use the `socket_addr` method too.
@@ -2,12 +2,14 @@ use core::fmt::Debug;
 use std::{net::IpAddr, time::Duration};
 
 use bitcoin::{
+    block::Header,
     p2p::{
         address::AddrV2,
         message::NetworkMessage,
-        message_filter::{GetCFHeaders, GetCFilters},
+        message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
+        ServiceFlags,
     },
-    BlockHash, Transaction,
+    BlockHash, Transaction, Txid,
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -15,7 +17,7 @@ use tokio::{
     sync::Mutex,
 };
 
-use crate::{core::channel_messages::GetBlockConfig, prelude::FutureResult};
+use crate::{core::channel_messages::GetBlockConfig, db::AddressType, prelude::FutureResult};
 
 use super::error::{PeerError, PeerReadError};
 
@@ -26,10 +28,17 @@ pub(crate) type StreamWriter = Mutex<Box<dyn AsyncWrite + Send + Unpin>>;
 
 // Responsible for serializing messages to write over the wire, either encrypted or plaintext.
 pub(crate) trait MessageGenerator: Send + Sync {
-    fn version_message(&mut self, port: Option<u16>) -> Result<Vec<u8>, PeerError>;
+    fn version_message(
+        &mut self,
+        port: Option<u16>,
+        services: ServiceFlags,
+        relay: bool,
+    ) -> Result<Vec<u8>, PeerError>;
 
     fn verack(&mut self) -> Result<Vec<u8>, PeerError>;
 
+    fn send_headers(&mut self) -> Result<Vec<u8>, PeerError>;
+
     fn addr(&mut self) -> Result<Vec<u8>, PeerError>;
 
     fn addrv2(&mut self) -> Result<Vec<u8>, PeerError>;
@@ -46,9 +55,21 @@ pub(crate) trait MessageGenerator: Send + Sync {
 
     fn block(&mut self, config: GetBlockConfig) -> Result<Vec<u8>, PeerError>;
 
+    fn ping(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError>;
+
     fn pong(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError>;
 
     fn transaction(&mut self, transaction: Transaction) -> Result<Vec<u8>, PeerError>;
+
+    fn get_transactions(&mut self, txids: Vec<Txid>) -> Result<Vec<u8>, PeerError>;
+
+    fn mempool(&mut self) -> Result<Vec<u8>, PeerError>;
+
+    fn serve_headers(&mut self, headers: Vec<Header>) -> Result<Vec<u8>, PeerError>;
+
+    fn serve_filter_headers(&mut self, cf_headers: CFHeaders) -> Result<Vec<u8>, PeerError>;
+
+    fn serve_filters(&mut self, filters: Vec<CFilter>) -> Result<Vec<u8>, PeerError>;
 }
 
 // Responsible for parsing plaintext or encrypted messages off of the  wire.
@@ -58,7 +79,12 @@ pub(crate) trait MessageParser: Send + Sync {
 
 // Establishes connections based on the network configuration.
 pub(crate) trait NetworkConnector {
-    fn can_connect(&self, addr: &AddrV2) -> bool;
+    // The kinds of addresses this connection can reach
+    fn address_types(&self) -> &'static [AddressType];
+
+    fn can_connect(&self, addr: &AddrV2) -> bool {
+        self.address_types().contains(&AddressType::from(addr))
+    }
 
     fn connect(
         &mut self,
@@ -76,8 +102,8 @@ impl ClearNetConnection {
 }
 
 impl NetworkConnector for ClearNetConnection {
-    fn can_connect(&self, addr: &AddrV2) -> bool {
-        matches!(addr, AddrV2::Ipv4(_) | AddrV2::Ipv6(_))
+    fn address_types(&self) -> &'static [AddressType] {
+        &[AddressType::Ipv4, AddressType::Ipv6]
     }
 
     fn connect(
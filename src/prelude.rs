@@ -91,7 +91,9 @@ impl Netgroup for AddrV2 {
                 .join("::"),
             AddrV2::TorV2(t) => t.to_lower_hex_string(),
             AddrV2::TorV3(t) => t.to_lower_hex_string(),
-            AddrV2::I2p(t) => t.to_lower_hex_string(),
+            // Like Bitcoin Core, I2P destinations are grouped by their first four bits, as they carry no
+            // information about the network location of the peer
+            AddrV2::I2p(t) => format!("i2p:{:x}", t[0] >> 4),
             AddrV2::Cjdns(cj) => cj
                 .to_string()
                 .replace("::", ".")
@@ -115,6 +117,27 @@ pub(crate) fn default_port_from_network(network: &Network) -> u16 {
     }
 }
 
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// Lowercase base32 without padding, as used by onion and I2P addresses
+pub(crate) fn encode_base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1F) as usize] as char);
+    }
+    encoded
+}
+
 #[cfg(feature = "dns")]
 pub(crate) fn encode_qname(domain: &str) -> Vec<u8> {
     let mut qname = Vec::new();